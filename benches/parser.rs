@@ -0,0 +1,151 @@
+//! Benchmarks of the parsing hot paths.
+
+use byteorder::LittleEndian;
+use criterion::Criterion;
+use criterion::Throughput;
+use criterion::criterion_group;
+use criterion::criterion_main;
+use linux_perf_data::linux_perf_event_reader::RawData;
+use perf_to_statemap::tracepoints::Tracepoint;
+use perf_to_statemap::tracepoints::format::TracepointFormat;
+use perf_to_statemap::tracepoints::parser::LazyParser;
+use perf_to_statemap::tracepoints::sched::SchedSwitch;
+use std::hint::black_box;
+use string_pool::StringPool;
+
+// Private to the binary, since the pool leaks long strings for the rest of
+// the process. Its unit tests are compiled in here too, but not run.
+#[allow(unused_imports)]
+#[path = "../src/string_pool.rs"]
+mod string_pool;
+
+/// Format of `sched_switch` from a 6.x kernel.
+const SCHED_SWITCH_FORMAT: &str = "\
+name: sched_switch
+ID: 308
+format:
+\tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;
+\tfield:unsigned char common_flags;\toffset:2;\tsize:1;\tsigned:0;
+\tfield:unsigned char common_preempt_count;\toffset:3;\tsize:1;\tsigned:0;
+\tfield:int common_pid;\toffset:4;\tsize:4;\tsigned:1;
+
+\tfield:char prev_comm[16];\toffset:8;\tsize:16;\tsigned:0;
+\tfield:pid_t prev_pid;\toffset:24;\tsize:4;\tsigned:1;
+\tfield:int prev_prio;\toffset:28;\tsize:4;\tsigned:1;
+\tfield:long prev_state;\toffset:32;\tsize:8;\tsigned:1;
+\tfield:char next_comm[16];\toffset:40;\tsize:16;\tsigned:0;
+\tfield:pid_t next_pid;\toffset:56;\tsize:4;\tsigned:1;
+\tfield:int next_prio;\toffset:60;\tsize:4;\tsigned:1;
+
+print fmt: \"prev_comm=%s prev_pid=%d prev_prio=%d prev_state=%s%s ==> next_comm=%s next_pid=%d next_prio=%d\", REC->prev_comm, REC->prev_pid, REC->prev_prio, REC->prev_state, REC->next_comm, REC->next_pid, REC->next_prio
+";
+
+/// A `sched_switch` record matching [`SCHED_SWITCH_FORMAT`].
+fn sched_switch_record() -> Vec<u8> {
+    let mut record = vec![0; 64];
+    record[0..2].copy_from_slice(&308u16.to_le_bytes());
+    record[4..8].copy_from_slice(&1234i32.to_le_bytes());
+    record[8..12].copy_from_slice(b"bash");
+    record[24..28].copy_from_slice(&1234i32.to_le_bytes());
+    record[28..32].copy_from_slice(&120i32.to_le_bytes());
+    record[32..40].copy_from_slice(&1i64.to_le_bytes());
+    record[40..55].copy_from_slice(b"kworker/3:1H-kb");
+    record[56..60].copy_from_slice(&5678i32.to_le_bytes());
+    record[60..64].copy_from_slice(&100i32.to_le_bytes());
+    record
+}
+
+fn bench_sched_switch(c: &mut Criterion) {
+    let format = TracepointFormat::parse(SCHED_SWITCH_FORMAT).expect("Valid format");
+    let parser = SchedSwitch::parser_from_format(&format).expect("Valid parser");
+    let record = sched_switch_record();
+    let mut group = c.benchmark_group("sched_switch");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("parse", |b| {
+        b.iter(|| {
+            SchedSwitch::parse::<LittleEndian>(&parser, &RawData::Single(black_box(&record)))
+                .expect("Valid record")
+        });
+    });
+    group.bench_function("parse_compact_string", |b| {
+        let next_comm = parser.index_of("next_comm").expect("Known field");
+        b.iter(|| {
+            parser
+                .parse_compact_string::<LittleEndian>(
+                    next_comm,
+                    &RawData::Single(black_box(&record)),
+                )
+                .expect("Valid record")
+        });
+    });
+    group.finish();
+}
+
+fn bench_format_parse(c: &mut Criterion) {
+    c.bench_function("tracepoint_format/parse_sched_switch", |b| {
+        b.iter(|| TracepointFormat::parse(black_box(SCHED_SWITCH_FORMAT)).expect("Valid format"));
+    });
+}
+
+/// Setting up the parsers for the 15 events `perf sched record` enables,
+/// loading the formats up front or only as events are seen. On a local disk
+/// this is the cost of reading and parsing the format files, over NFS every
+/// file read adds a round trip on top.
+fn bench_startup(c: &mut Criterion) {
+    const EVENTS: usize = 15;
+    let sysroot = std::env::temp_dir().join(format!("bench-sysroot-{}", std::process::id()));
+    let dir = sysroot.join("sys/kernel/tracing/events/sched/sched_switch");
+    std::fs::create_dir_all(&dir).expect("Temporary directory");
+    std::fs::write(dir.join("format"), SCHED_SWITCH_FORMAT).expect("Temporary file");
+    let mut group = c.benchmark_group("startup");
+    group.bench_function("eager_15_formats", |b| {
+        b.iter(|| {
+            for _ in 0..EVENTS {
+                let parser = LazyParser::new::<SchedSwitch>(black_box(&sysroot));
+                black_box(parser.get().expect("Valid format"));
+            }
+        });
+    });
+    group.bench_function("lazy_15_formats", |b| {
+        b.iter(|| {
+            for _ in 0..EVENTS {
+                black_box(LazyParser::new::<SchedSwitch>(black_box(&sysroot)));
+            }
+        });
+    });
+    group.finish();
+    std::fs::remove_dir_all(sysroot).expect("Temporary directory");
+}
+
+fn bench_string_pool(c: &mut Criterion) {
+    // A working set of thread names, as seen on a busy system.
+    let names: Vec<String> = (0..200).map(|i| format!("worker/{i}:{}", i * 7)).collect();
+    let mut pool = StringPool::default();
+    let mut group = c.benchmark_group("string_pool");
+    group.throughput(Throughput::Elements(names.len() as u64));
+    group.bench_function("intern_200", |b| {
+        b.iter(|| {
+            for name in &names {
+                black_box(pool.intern(black_box(name)));
+            }
+        });
+    });
+    // As thread tags are built.
+    group.bench_function("intern_fmt_200", |b| {
+        b.iter(|| {
+            for i in 0..names.len() {
+                black_box(pool.intern_fmt(format_args!("worker/{i}:{}", black_box(i * 7))));
+            }
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_sched_switch,
+    bench_format_parse,
+    bench_startup,
+    bench_string_pool
+);
+criterion_main!(benches);
@@ -0,0 +1,160 @@
+//! Pinning this process to a set of CPUs (Linux only).
+
+use crate::topology::parse_cpu_list;
+use eyre::Context;
+use eyre::eyre;
+use std::path::Path;
+
+/// Where the kernel lists the NUMA nodes.
+pub const NUMA_NODE_DIR: &str = "/sys/devices/system/node";
+
+/// The CPUs in a CPU mask.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuMask(pub Vec<usize>);
+
+/// Parse a hexadecimal CPU mask (as used by `taskset`).
+pub fn parse_mask(mask: &str) -> Result<CpuMask, String> {
+    let digits = mask
+        .strip_prefix("0x")
+        .or_else(|| mask.strip_prefix("0X"))
+        .unwrap_or(mask)
+        .replace(',', "");
+    let mut cpus = Vec::new();
+    for (idx, digit) in digits.chars().rev().enumerate() {
+        let value = digit
+            .to_digit(16)
+            .ok_or_else(|| format!("Invalid hex digit '{digit}' in CPU mask"))?;
+        cpus.extend(
+            (0..4)
+                .filter(|bit| value & (1 << bit) != 0)
+                .map(|bit| idx * 4 + bit),
+        );
+    }
+    if cpus.is_empty() {
+        return Err("CPU mask must contain at least one CPU".into());
+    }
+    Ok(CpuMask(cpus))
+}
+
+/// A NUMA node of this system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumaNode {
+    pub node: u32,
+    /// The CPUs of the node, as a CPU list (`0-3,8-11`).
+    pub cpu_list: String,
+    pub cpus: CpuMask,
+}
+
+/// Read the NUMA nodes from `dir` (normally [`NUMA_NODE_DIR`]), sorted by
+/// node number.
+pub fn numa_nodes(dir: &Path) -> Result<Vec<NumaNode>, eyre::Error> {
+    let entries =
+        std::fs::read_dir(dir).wrap_err_with(|| format!("Failed to read \"{}\"", dir.display()))?;
+    let mut nodes = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Some(node) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("node"))
+            .and_then(|node| node.parse().ok())
+        else {
+            continue;
+        };
+        let path = entry.path().join("cpulist");
+        let cpu_list = std::fs::read_to_string(&path)
+            .wrap_err_with(|| format!("Failed to read \"{}\"", path.display()))?
+            .trim()
+            .to_string();
+        let cpus = parse_cpu_list(&cpu_list)?
+            .into_iter()
+            .map(|cpu| cpu as usize)
+            .collect();
+        nodes.push(NumaNode {
+            node,
+            cpu_list,
+            cpus: CpuMask(cpus),
+        });
+    }
+    nodes.sort_by_key(|node| node.node);
+    Ok(nodes)
+}
+
+/// The CPUs of NUMA node `node`, read from `dir`.
+pub fn numa_node_mask(dir: &Path, node: u32) -> Result<CpuMask, eyre::Error> {
+    let nodes = numa_nodes(dir)?;
+    let found = nodes
+        .iter()
+        .find(|candidate| candidate.node == node)
+        .ok_or_else(|| eyre!("No NUMA node {node} (see --list-numa-nodes)"))?;
+    if found.cpus.0.is_empty() {
+        return Err(eyre!("NUMA node {node} has no CPUs"));
+    }
+    Ok(found.cpus.clone())
+}
+
+/// Restrict the current process to run on the given CPUs.
+#[cfg(target_os = "linux")]
+pub fn set_affinity(CpuMask(cpus): &CpuMask) -> Result<(), eyre::Error> {
+    // SAFETY: cpu_set_t is a plain bit set, for which all zeroes is valid
+    // (the empty set).
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let max_cpus = 8 * size_of::<libc::cpu_set_t>();
+    for &cpu in cpus {
+        if cpu >= max_cpus {
+            return Err(eyre!("CPU {cpu} in affinity mask is out of range"));
+        }
+        // SAFETY: The CPU number was checked to be in range for the set.
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    // SAFETY: The set is initialized and the size passed matches it. PID 0
+    // means the calling thread.
+    let ret = unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) };
+    if ret != 0 {
+        return Err(eyre!(
+            "Failed to set CPU affinity: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_affinity(_mask: &CpuMask) -> Result<(), eyre::Error> {
+    Err(eyre!("Setting CPU affinity is only supported on Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mask() {
+        assert_eq!(parse_mask("0x5").unwrap(), CpuMask(vec![0, 2]));
+        assert_eq!(parse_mask("f0").unwrap(), CpuMask(vec![4, 5, 6, 7]));
+        assert_eq!(parse_mask("1,00000001").unwrap(), CpuMask(vec![0, 32]));
+        assert!(parse_mask("0").is_err());
+        assert!(parse_mask("0xg").is_err());
+    }
+
+    #[test]
+    fn test_numa_nodes() {
+        let dir = std::env::temp_dir().join(format!("numa_test.{}", std::process::id()));
+        for (name, cpu_list) in [("node1", "4-7\n"), ("node0", "0-3\n"), ("node2", "\n")] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+            std::fs::write(dir.join(name).join("cpulist"), cpu_list).unwrap();
+        }
+        std::fs::write(dir.join("possible"), "0-2\n").unwrap();
+
+        let nodes = numa_nodes(&dir).unwrap();
+        assert_eq!(
+            nodes.iter().map(|node| node.node).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert_eq!(nodes[1].cpu_list, "4-7");
+        assert_eq!(numa_node_mask(&dir, 1).unwrap(), CpuMask(vec![4, 5, 6, 7]));
+        assert!(numa_node_mask(&dir, 2).is_err());
+        assert!(numa_node_mask(&dir, 3).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
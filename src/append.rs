@@ -0,0 +1,147 @@
+//! Appending to an existing statemap file.
+
+use crate::statemap::StatemapInputMetadata;
+use eyre::Context;
+use eyre::eyre;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+
+const NS_PER_S: u64 = 1_000_000_000;
+
+/// Check that the statemap at `path` can be appended to with data described
+/// by `metadata`.
+///
+/// The existing file must end with a complete line and have the same states
+/// (names and values) as `metadata`, and the new data must not start before
+/// it. Returns the offset (in ns) to add to times to make them relative to
+/// the start of the existing file.
+pub fn check_existing(path: &Path, metadata: &StatemapInputMetadata) -> Result<u64, eyre::Error> {
+    let mut file = std::fs::File::open(path)
+        .wrap_err_with(|| format!("Failed to open \"{}\" for appending", path.display()))?;
+    if file.seek(SeekFrom::End(0))? == 0 {
+        return Err(eyre!(
+            "\"{}\" is empty, nothing to append to",
+            path.display()
+        ));
+    }
+    file.seek(SeekFrom::End(-1))?;
+    let mut last = [0u8];
+    file.read_exact(&mut last)?;
+    if last[0] != b'\n' {
+        return Err(eyre!(
+            "\"{}\" does not end with a complete line, it may be truncated",
+            path.display()
+        ));
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = String::new();
+    std::io::BufReader::new(file).read_line(&mut header)?;
+    let header: serde_json::Value = serde_json::from_str(&header)
+        .wrap_err_with(|| format!("Failed to parse header of \"{}\"", path.display()))?;
+    check_states(&header, metadata)
+        .wrap_err_with(|| format!("Cannot append to \"{}\"", path.display()))?;
+
+    let start = |start: [u64; 2]| start[0] * NS_PER_S + start[1];
+    let existing_start: [u64; 2] = serde_json::from_value(header["start"].clone())
+        .wrap_err("Existing header has an invalid start time")?;
+    start(metadata.start)
+        .checked_sub(start(existing_start))
+        .ok_or_else(|| {
+            eyre!(
+                "Cannot append to \"{}\": the trace starts at {:?}, before the existing \
+                 file (at {:?})",
+                path.display(),
+                metadata.start,
+                existing_start
+            )
+        })
+}
+
+/// Check that the states in an existing header match `metadata`.
+fn check_states(
+    header: &serde_json::Value,
+    metadata: &StatemapInputMetadata,
+) -> Result<(), eyre::Error> {
+    let existing = header["states"]
+        .as_object()
+        .ok_or_else(|| eyre!("Existing header has no states"))?;
+    let mut problems = Vec::new();
+    for (name, state) in &metadata.states {
+        match existing.get(name.as_str()).map(|s| s["value"].as_u64()) {
+            None => problems.push(format!("state \"{name}\" is missing")),
+            Some(value) if value != Some(state.value as u64) => problems.push(format!(
+                "state \"{name}\" has value {}, expected {}",
+                value.map_or_else(|| "none".to_owned(), |v| v.to_string()),
+                state.value
+            )),
+            Some(_) => {}
+        }
+    }
+    for name in existing.keys() {
+        if !metadata.states.contains_key(name.as_str()) {
+            problems.push(format!("state \"{name}\" is unknown"));
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        problems.sort();
+        Err(eyre!("Incompatible states: {}", problems.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colors::ColorScheme;
+
+    #[test]
+    fn test_check_existing() {
+        let dir = std::env::temp_dir().join(format!("append_test.{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.json");
+        let mut metadata = StatemapInputMetadata {
+            start: [10, 0],
+            title: "CPU".into(),
+            host: None,
+            entityKind: None,
+            arch: None,
+            states: ColorScheme::Default.states(),
+            cycleFrequencyGHz: None,
+            schemaVersion: None,
+            entities: None,
+            duration_ns: None,
+            event_count: None,
+            extensions: Default::default(),
+        };
+        let mut existing = serde_json::to_string(&metadata).unwrap();
+        existing.push('\n');
+        metadata.start = [12, 500];
+        std::fs::write(&path, &existing).unwrap();
+        assert_eq!(check_existing(&path, &metadata).unwrap(), 2_000_000_500);
+
+        // Starting before the existing file
+        metadata.start = [9, 0];
+        assert!(check_existing(&path, &metadata).is_err());
+        metadata.start = [12, 500];
+
+        // Truncated last line
+        std::fs::write(&path, format!("{existing}{{\"time\":")).unwrap();
+        assert!(check_existing(&path, &metadata).is_err());
+
+        // Different states
+        std::fs::write(&path, &existing).unwrap();
+        let value = metadata.states.remove("User").unwrap();
+        metadata.states.insert("Other".into(), value);
+        let err = check_existing(&path, &metadata).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err.root_cause()),
+            "Incompatible states: state \"Other\" is missing, state \"User\" is unknown"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,59 @@
+//! Architecture dependent assumptions about the traced system.
+
+/// Architecture of the traced system, for overriding what is recorded in
+/// perf.data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap_derive::ValueEnum)]
+pub enum Arch {
+    /// 32-bit ARM
+    Arm,
+    /// 64-bit ARM (aarch64)
+    Arm64,
+    /// 32-bit x86
+    X86,
+    /// 64-bit x86
+    #[value(name = "x86_64")]
+    X86_64,
+    /// 32-bit RISC-V
+    Riscv32,
+    /// 64-bit RISC-V
+    Riscv64,
+}
+
+impl Arch {
+    /// Size in bytes of `long` and pointers.
+    pub fn long_size(self) -> u32 {
+        match self {
+            Self::Arm | Self::X86 | Self::Riscv32 => 4,
+            Self::Arm64 | Self::X86_64 | Self::Riscv64 => 8,
+        }
+    }
+}
+
+/// Size in bytes of `long` and pointers on the architecture `arch`, as
+/// reported by `uname -m` (and recorded in perf.data), if known.
+pub fn long_size(arch: &str) -> Option<u32> {
+    match arch {
+        "x86_64" | "aarch64" | "arm64" | "riscv64" | "ppc64" | "ppc64le" | "s390x"
+        | "loongarch64" | "mips64" => Some(8),
+        "i386" | "i486" | "i586" | "i686" | "x86" | "riscv32" | "ppc" | "mips" => Some(4),
+        _ if arch.starts_with("arm") => Some(4),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_size() {
+        assert_eq!(long_size("x86_64"), Some(8));
+        assert_eq!(long_size("aarch64"), Some(8));
+        assert_eq!(long_size("armv7l"), Some(4));
+        assert_eq!(long_size("i686"), Some(4));
+        assert_eq!(long_size("riscv64"), Some(8));
+        assert_eq!(long_size("vax"), None);
+        assert_eq!(Arch::Arm.long_size(), 4);
+        assert_eq!(Arch::X86_64.long_size(), 8);
+    }
+}
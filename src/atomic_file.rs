@@ -0,0 +1,117 @@
+//! Atomic replacement of output files.
+//!
+//! Output is written to a temporary file in the same directory, which is
+//! renamed over the final path once everything has been written. If the
+//! conversion fails the temporary file is removed, so a partially written
+//! output never appears under the final name.
+
+use eyre::Context;
+use std::fs::File;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// A file that only appears at its final path once committed.
+#[derive(Debug)]
+pub struct AtomicFile {
+    file: File,
+    tmp_path: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    /// Check if `path` can be replaced atomically. Existing paths that are
+    /// not regular files (such as `/dev/stdout` or a FIFO) must be written
+    /// directly.
+    pub fn is_supported(path: &Path) -> bool {
+        std::fs::metadata(path).map_or(true, |metadata| metadata.is_file())
+    }
+
+    /// Create the temporary file `<path>.tmp.<PID>`.
+    pub fn create(path: impl Into<PathBuf>) -> Result<Self, eyre::Error> {
+        let path = path.into();
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(format!(".tmp.{}", std::process::id()));
+        let tmp_path = PathBuf::from(tmp_path);
+        let file = File::create(&tmp_path)
+            .wrap_err_with(|| format!("Failed to create \"{}\"", tmp_path.display()))?;
+        Ok(Self {
+            file,
+            tmp_path,
+            path,
+            committed: false,
+        })
+    }
+
+    /// Sync the data and move the file to its final path.
+    pub fn commit(mut self) -> Result<(), eyre::Error> {
+        self.file.sync_all()?;
+        std::fs::rename(&self.tmp_path, &self.path).wrap_err_with(|| {
+            format!(
+                "Failed to rename \"{}\" to \"{}\"",
+                self.tmp_path.display(),
+                self.path.display()
+            )
+        })?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for AtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_atomic_file() {
+        let dir = std::env::temp_dir().join(format!("atomic_file_test.{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.json");
+        std::fs::write(&path, "old").unwrap();
+
+        // Dropped without commit: the original is untouched.
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"partial").unwrap();
+        drop(file);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"new").unwrap();
+        file.commit().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert!(AtomicFile::is_supported(&path));
+        assert!(AtomicFile::is_supported(&dir.join("missing")));
+        assert!(!AtomicFile::is_supported(&dir));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+//! Detection of context switches followed by unusually many cache misses.
+//!
+//! This correlates `sched_switch` events with samples from a last level cache
+//! miss counter. For each context switch we count the misses sampled on that
+//! CPU in the first millisecond after the switch. Switches where that count is
+//! more than two standard deviations above the mean for the CPU are flagged as
+//! likely running on a cold cache.
+
+/// How long after a context switch to attribute cache misses to it.
+const WINDOW_NS: u64 = 1_000_000;
+
+/// A single context switch and the cache misses that followed it.
+#[derive(Debug, Clone)]
+struct SwitchWindow {
+    /// Index of the datum that was emitted for this switch.
+    datum_index: usize,
+    /// Time of the switch.
+    start: u64,
+    /// Number of cache misses sampled in the window after the switch.
+    misses: u64,
+}
+
+/// Collects context switches and cache miss samples per CPU.
+#[derive(Debug, Clone)]
+pub struct CacheFlushDetector {
+    windows: Vec<Vec<SwitchWindow>>,
+}
+
+impl CacheFlushDetector {
+    pub fn new(num_cpus: usize) -> Self {
+        Self {
+            windows: vec![Vec::new(); num_cpus],
+        }
+    }
+
+    /// Track `num_cpus` CPUs, if that is more than currently tracked.
+    pub fn resize(&mut self, num_cpus: usize) {
+        if num_cpus > self.windows.len() {
+            self.windows.resize(num_cpus, Vec::new());
+        }
+    }
+
+    /// Record a context switch on `cpu` at `time`, which produced the datum
+    /// with index `datum_index`.
+    pub fn record_switch(&mut self, cpu: usize, time: u64, datum_index: usize) {
+        self.windows[cpu].push(SwitchWindow {
+            datum_index,
+            start: time,
+            misses: 0,
+        });
+    }
+
+    /// Record `count` cache misses on `cpu` at `time`.
+    pub fn record_misses(&mut self, cpu: usize, time: u64, count: u64) {
+        if let Some(window) = self.windows[cpu].last_mut()
+            && time < window.start + WINDOW_NS
+        {
+            window.misses += count;
+        }
+    }
+
+    /// Get the datum indices of all context switches that were followed by an
+    /// unusually high number of cache misses, in ascending order.
+    pub fn cold_switches(&self) -> Vec<usize> {
+        let mut result = Vec::new();
+        for windows in &self.windows {
+            if windows.len() < 2 {
+                continue;
+            }
+            let n = windows.len() as f64;
+            let mean = windows.iter().map(|w| w.misses as f64).sum::<f64>() / n;
+            let variance = windows
+                .iter()
+                .map(|w| (w.misses as f64 - mean).powi(2))
+                .sum::<f64>()
+                / n;
+            let threshold = mean + 2.0 * variance.sqrt();
+            result.extend(
+                windows
+                    .iter()
+                    .filter(|w| w.misses as f64 > threshold)
+                    .map(|w| w.datum_index),
+            );
+        }
+        result.sort_unstable();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cold_switches() {
+        let mut detector = CacheFlushDetector::new(2);
+        for i in 0..10 {
+            let time = i * 10 * WINDOW_NS;
+            detector.record_switch(0, time, i as usize);
+            detector.record_misses(0, time + 10, 100);
+        }
+        // One switch with a lot more misses than the others.
+        detector.record_misses(0, 90 * WINDOW_NS + 20, 10_000);
+        // Outside the window, should not count.
+        detector.record_misses(0, 50 * WINDOW_NS + WINDOW_NS, 10_000);
+        // Too few switches on CPU 1 to say anything.
+        detector.record_switch(1, 0, 100);
+        detector.record_misses(1, 10, 10_000);
+        assert_eq!(detector.cold_switches(), vec![9]);
+    }
+}
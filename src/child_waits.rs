@@ -0,0 +1,81 @@
+//! Tracking of how long processes wait for their children.
+//!
+//! `sched_process_wait` fires when a process calls `wait()` or `waitpid()`.
+//! The wait lasts until the child it waits for exits, or until the waiting
+//! thread runs again (which also covers waits for any child, where we don't
+//! know which process will end it, and waits that return early).
+
+use compact_str::CompactString;
+use std::collections::HashMap;
+
+/// A wait that hasn't ended yet.
+#[derive(Debug, Clone)]
+struct Wait {
+    comm: CompactString,
+    /// The child waited for, or 0 or less for any child.
+    child: i32,
+    start: u64,
+}
+
+/// Tracks the ongoing child waits.
+#[derive(Debug, Default, Clone)]
+pub struct ChildWaits {
+    /// Ongoing waits, by PID of the waiting thread.
+    waits: HashMap<i32, Wait>,
+}
+
+/// A finished wait: the waiting thread, its name and the duration.
+pub type FinishedWait = (i32, CompactString, u64);
+
+impl ChildWaits {
+    /// Record that thread `pid` started waiting for `child` at `time`.
+    pub fn wait(&mut self, pid: i32, comm: CompactString, child: i32, time: u64) {
+        // Repeated events while already waiting don't restart the wait.
+        self.waits.entry(pid).or_insert(Wait {
+            comm,
+            child,
+            start: time,
+        });
+    }
+
+    /// Record that `pid` exited at `time`, ending the waits for it.
+    pub fn exit(&mut self, pid: i32, time: u64) -> Vec<FinishedWait> {
+        let mut finished = Vec::new();
+        self.waits.retain(|&waiter, wait| {
+            if wait.child != pid {
+                return true;
+            }
+            finished.push((waiter, wait.comm.clone(), time.saturating_sub(wait.start)));
+            false
+        });
+        // A waiting thread can be killed.
+        self.waits.remove(&pid);
+        finished
+    }
+
+    /// Record that `pid` was switched in at `time`, ending its wait.
+    pub fn switch_in(&mut self, pid: i32, time: u64) -> Option<FinishedWait> {
+        let wait = self.waits.remove(&pid)?;
+        Some((pid, wait.comm, time.saturating_sub(wait.start)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_child_waits() {
+        let mut waits = ChildWaits::default();
+        waits.wait(10, "bash".into(), 20, 100);
+        waits.wait(10, "bash".into(), 20, 150);
+        waits.wait(11, "make".into(), -1, 100);
+        waits.wait(12, "init".into(), 30, 100);
+        assert_eq!(waits.exit(20, 400), vec![(10, "bash".into(), 300)]);
+        assert_eq!(waits.switch_in(10, 450), None);
+        assert_eq!(waits.switch_in(11, 500), Some((11, "make".into(), 400)));
+        // The waiting thread itself exits.
+        assert!(waits.exit(12, 600).is_empty());
+        assert!(waits.exit(30, 700).is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+//! User defined classification of threads by comm, loaded from a TOML file.
+//!
+//! The built-in classification only knows about a few kernel threads. This
+//! allows classifying other threads, such as DPDK poll threads or GPU driver
+//! threads:
+//!
+//! ```toml
+//! rules = [["kworker/", "Kernel"], ["GPU_", "User"], ["dpdk_", "Kernel"]]
+//! ```
+//!
+//! A pattern matches comms starting with it, or if it ends in `$` only the
+//! comm without the `$`. The first matching rule wins.
+
+use crate::types::CpuState;
+use compact_str::CompactString;
+use eyre::Context;
+use eyre::eyre;
+use serde_derive::Deserialize;
+use std::path::Path;
+
+/// Contents of a classification file.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClassifyFile {
+    rules: Vec<(CompactString, CompactString)>,
+}
+
+/// A single classification rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ClassifyRule {
+    comm: CompactString,
+    /// Only match `comm` itself, not comms starting with it.
+    exact: bool,
+    state: CpuState,
+}
+
+/// User defined classification rules, checked in order.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ClassifyRules {
+    rules: Vec<ClassifyRule>,
+}
+
+impl ClassifyRules {
+    /// Load rules from a TOML file.
+    pub fn load(path: &Path) -> Result<Self, eyre::Error> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read \"{}\"", path.display()))?;
+        let rules = Self::parse(&contents)
+            .wrap_err_with(|| format!("Failed to load classify file \"{}\"", path.display()))?;
+        log::info!(
+            "Loaded {} classification rules from \"{}\"",
+            rules.rules.len(),
+            path.display()
+        );
+        Ok(rules)
+    }
+
+    /// Parse rules from TOML. All invalid rules are reported at once.
+    fn parse(contents: &str) -> Result<Self, eyre::Error> {
+        let file: ClassifyFile = toml::from_str(contents)?;
+        let mut errors = Vec::new();
+        let mut rules = Vec::with_capacity(file.rules.len());
+        for (idx, (pattern, state)) in file.rules.into_iter().enumerate() {
+            let (comm, exact) = match pattern.strip_suffix('$') {
+                Some(comm) => (CompactString::from(comm), true),
+                None => (pattern.clone(), false),
+            };
+            if comm.is_empty() {
+                errors.push(format!("Rule {idx} has an empty pattern"));
+            }
+            match CpuState::from_name(&state) {
+                Some(state) => rules.push(ClassifyRule { comm, exact, state }),
+                None => errors.push(format!(
+                    "Rule {idx} (\"{pattern}\") has unknown state \"{state}\", expected one of: {}",
+                    CpuState::ALL.map(CpuState::name).join(", ")
+                )),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(eyre!("Invalid rules:\n  {}", errors.join("\n  ")));
+        }
+        Ok(Self { rules })
+    }
+
+    /// State of the first rule matching `comm`, if any.
+    pub fn classify(&self, comm: &[u8]) -> Option<CpuState> {
+        self.rules
+            .iter()
+            .find(|rule| {
+                if rule.exact {
+                    comm == rule.comm.as_bytes()
+                } else {
+                    comm.starts_with(rule.comm.as_bytes())
+                }
+            })
+            .map(|rule| rule.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn test_classify() {
+        let rules = ClassifyRules::parse(indoc! {r#"
+            rules = [["kworker/", "Idle"], ["GPU_", "Kernel"], ["dpdk$", "Kernel"]]
+        "#})
+        .unwrap();
+        assert_eq!(rules.rules.len(), 3);
+        assert_eq!(rules.classify(b"kworker/0:1"), Some(CpuState::Idle));
+        assert_eq!(rules.classify(b"GPU_render"), Some(CpuState::Kernel));
+        assert_eq!(rules.classify(b"dpdk"), Some(CpuState::Kernel));
+        assert_eq!(rules.classify(b"dpdk-worker"), None);
+        assert_eq!(rules.classify(b"bash"), None);
+    }
+
+    #[test]
+    fn test_validation() {
+        let err = ClassifyRules::parse(indoc! {r#"
+            rules = [["$", "Kernel"], ["GPU_", "Gpu"]]
+        "#})
+        .unwrap_err()
+        .to_string();
+        insta::assert_snapshot!(err);
+        assert!(ClassifyRules::parse(r#"rules = [["GPU_"]]"#).is_err());
+        assert!(ClassifyRules::parse(r#"rule = []"#).is_err());
+    }
+}
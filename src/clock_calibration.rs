@@ -0,0 +1,131 @@
+//! Compensation for clock skew between machines.
+//!
+//! When comparing traces from several machines their monotonic clocks drift
+//! relative to each other. Given pairs of timestamps for the same events as
+//! seen by a reference machine and by the machine the trace was recorded on,
+//! we fit a linear model mapping local time to reference time and apply it to
+//! every sample.
+
+use eyre::Context;
+use eyre::eyre;
+use std::path::Path;
+
+/// Linear mapping from local time to reference time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockModel {
+    /// Local time of the first calibration point.
+    local_base: u64,
+    /// Reference time of the first calibration point.
+    reference_base: u64,
+    /// Reference nanoseconds per local nanosecond.
+    slope: f64,
+    /// Offset (in ns) of the fitted line at `local_base`.
+    intercept: f64,
+}
+
+impl ClockModel {
+    /// Load calibration points from a CSV file and fit a model to them.
+    ///
+    /// Each line has the form `<reference_ns>,<local_ns>`. Empty lines, lines
+    /// starting with `#` and a non-numeric header line are ignored.
+    pub fn from_csv(path: &Path) -> Result<Self, eyre::Error> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read \"{}\"", path.display()))?;
+        Self::fit(&parse_csv(&contents)?)
+            .wrap_err_with(|| format!("Failed to calibrate clock from \"{}\"", path.display()))
+    }
+
+    /// Fit a model to `(reference, local)` timestamp pairs using least
+    /// squares.
+    pub fn fit(points: &[(u64, u64)]) -> Result<Self, eyre::Error> {
+        let Some(&(reference_base, local_base)) = points.first() else {
+            return Err(eyre!("No calibration points"));
+        };
+        // Work relative to the first point to not lose precision in f64.
+        let relative: Vec<(f64, f64)> = points
+            .iter()
+            .map(|&(reference, local)| {
+                (
+                    local as f64 - local_base as f64,
+                    reference as f64 - reference_base as f64,
+                )
+            })
+            .collect();
+        let n = relative.len() as f64;
+        let mean_x = relative.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = relative.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let covariance: f64 = relative
+            .iter()
+            .map(|(x, y)| (x - mean_x) * (y - mean_y))
+            .sum();
+        let variance: f64 = relative.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+        // With a single point (or all at the same time) there is no way to
+        // estimate drift, only the offset.
+        let slope = if variance > 0.0 {
+            covariance / variance
+        } else {
+            1.0
+        };
+        if !(slope.is_finite() && slope > 0.0) {
+            return Err(eyre!(
+                "Calibration points give an invalid clock rate {slope}"
+            ));
+        }
+        Ok(Self {
+            local_base,
+            reference_base,
+            slope,
+            intercept: mean_y - slope * mean_x,
+        })
+    }
+
+    /// Convert a local timestamp to reference time.
+    pub fn apply(&self, local: u64) -> u64 {
+        let x = local as f64 - self.local_base as f64;
+        let y = self.intercept + self.slope * x;
+        (self.reference_base as f64 + y).round().max(0.0) as u64
+    }
+}
+
+/// Parse `<reference_ns>,<local_ns>` lines.
+fn parse_csv(contents: &str) -> Result<Vec<(u64, u64)>, eyre::Error> {
+    let mut points = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parse = |s: Option<&str>| s.map(str::trim).and_then(|s| s.parse::<u64>().ok());
+        let mut fields = line.split(',');
+        match (parse(fields.next()), parse(fields.next())) {
+            (Some(reference), Some(local)) => points.push((reference, local)),
+            // Allow a header line
+            _ if points.is_empty() && idx == 0 => {}
+            _ => return Err(eyre!("Invalid calibration line {}: \"{line}\"", idx + 1)),
+        }
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fit() {
+        let csv = "reference_ns,local_ns\n\
+                   1000000500,1000000000\n\
+                   # Reference clock runs 10 ppm fast\n\
+                   2000010500,2000000000\n\
+                   3000020500,3000000000\n";
+        let model = ClockModel::fit(&parse_csv(csv).unwrap()).unwrap();
+        assert_eq!(model.apply(1_000_000_000), 1_000_000_500);
+        assert_eq!(model.apply(4_000_000_000), 4_000_030_500);
+        assert_eq!(model.apply(1_500_000_000), 1_500_005_500);
+
+        assert!(parse_csv("1,2\nx,3\n").is_err());
+        assert!(ClockModel::fit(&[]).is_err());
+        let offset_only = ClockModel::fit(&[(100, 50)]).unwrap();
+        assert_eq!(offset_only.apply(60), 110);
+    }
+}
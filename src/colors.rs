@@ -0,0 +1,83 @@
+//! Color schemes for the states in the statemap.
+
+use crate::statemap::StatemapInputState;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use compact_str::ToCompactString;
+use std::collections::HashMap;
+
+/// Set of colors to use for the CPU states.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap_derive::ValueEnum)]
+pub enum ColorScheme {
+    /// Colors suitable for a light background
+    #[default]
+    Default,
+    /// High contrast colors suitable for a dark background
+    Dark,
+}
+
+impl ColorScheme {
+    /// Get the color of a state in this scheme.
+    pub fn state_color(&self, state: CpuState) -> &'static str {
+        match self {
+            Self::Default => match state {
+                CpuState::Idle => "#e0e0e0",
+                CpuState::Irq => "#FF0000",
+                CpuState::Softirq => "#FF8000",
+                CpuState::Tasklet => "#FFBF00",
+                CpuState::Kernel => "#2E4E00",
+                CpuState::User => "#9BC362",
+                CpuState::RcuStall => "#FF00FF",
+                CpuState::Busy => "#4B0082",
+                CpuState::Migrating => "#800080",
+                CpuState::NumaMove => "#EE82EE",
+            },
+            Self::Dark => match state {
+                CpuState::Idle => "#2a2a2a",
+                CpuState::Irq => "#FF6060",
+                CpuState::Softirq => "#FF9040",
+                CpuState::Tasklet => "#FFD060",
+                CpuState::Kernel => "#6090E0",
+                CpuState::User => "#60E060",
+                CpuState::RcuStall => "#FF40FF",
+                CpuState::Busy => "#A070E0",
+                CpuState::Migrating => "#C060C0",
+                CpuState::NumaMove => "#F0B0F0",
+            },
+        }
+    }
+
+    /// Create the state definitions for the statemap header.
+    pub fn states(&self) -> HashMap<CompactString, StatemapInputState> {
+        CpuState::ALL
+            .iter()
+            .map(|&state| {
+                (
+                    state.name().to_compact_string(),
+                    StatemapInputState {
+                        color: Some(self.state_color(state).to_compact_string()),
+                        value: state as usize,
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_default_states() {
+        let states: BTreeMap<_, _> = ColorScheme::Default.states().into_iter().collect();
+        insta::assert_debug_snapshot!(states);
+    }
+
+    #[test]
+    fn test_dark_states() {
+        let states: BTreeMap<_, _> = ColorScheme::Dark.states().into_iter().collect();
+        insta::assert_debug_snapshot!(states);
+    }
+}
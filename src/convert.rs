@@ -0,0 +1,1211 @@
+//! Conversion of the records in a perf.data file to statemap records.
+
+use crate::cache_flush::CacheFlushDetector;
+use crate::child_waits::ChildWaits;
+use crate::cli::Cli;
+use crate::clock_calibration::ClockModel;
+use crate::cpu_states::CpuStates;
+use crate::cpu_states::check_migration;
+use crate::frequency::FreqBands;
+use crate::idle_gaps::IdleGapTracker;
+use crate::irq_threads::IrqThreadTracker;
+use crate::isolation::IsolationChecker;
+use crate::latency_spikes::LatencySpikeDetector;
+use crate::merge_tags::TagMerger;
+use crate::output::OutputSink;
+use crate::parsers::Action;
+use crate::parsers::AuxRecord;
+use crate::parsers::Event;
+use crate::perf_data_stats::PerfDataStats;
+use crate::preemption_storms::PreemptionStormDetector;
+use crate::progress::EventRate;
+use crate::prometheus;
+use crate::report;
+use crate::report::StatsFormat;
+use crate::report::SystemInfo;
+use crate::runqueue::RunqueueDepths;
+use crate::statemap::StatemapInputDatum;
+use crate::stats::Stats;
+use crate::string_pool::StringPool;
+use crate::switch_histogram::SwitchHistogram;
+use crate::switch_limiter::SwitchRateLimiter;
+use crate::types::CpuState;
+use byteorder::BigEndian;
+use byteorder::LittleEndian;
+use compact_str::CompactString;
+use compact_str::ToCompactString;
+use compact_str::format_compact;
+use eyre::Context;
+use eyre::eyre;
+use linux_perf_data::Endianness;
+use linux_perf_data::PerfFile;
+use linux_perf_data::PerfFileRecord;
+use linux_perf_data::linux_perf_event_reader::EventRecord;
+use linux_perf_data::linux_perf_event_reader::RawEventRecord;
+use linux_perf_data::linux_perf_event_reader::RecordType;
+use linux_perf_data::linux_perf_event_reader::SampleRecord;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Conversion of perf timestamps to the times written to the output.
+pub struct Clock {
+    pub model: Option<ClockModel>,
+    /// Time of the first sample in the trace. While `perf record` is still
+    /// writing the trace this isn't known, and the first sample converted
+    /// is used instead.
+    pub start_time: Option<u64>,
+    /// When appending, times are relative to the start of the existing file.
+    pub offset: u64,
+    pub normalize_to_hz: Option<f64>,
+}
+
+impl Clock {
+    /// Convert a perf timestamp to the time relative to the start of the
+    /// trace, and the time as written to the output.
+    fn convert(&mut self, timestamp: u64) -> (u64, u64) {
+        let start_time = *self.start_time.get_or_insert(timestamp);
+        // Samples from before the first one converted count as the start.
+        let time = match &self.model {
+            Some(model) => model
+                .apply(timestamp)
+                .saturating_sub(model.apply(start_time)),
+            None => timestamp.saturating_sub(start_time),
+        };
+        let datum_time = time + self.offset;
+        let datum_time = self
+            .normalize_to_hz
+            .map_or(datum_time, |ghz| (datum_time as f64 * ghz).round() as u64);
+        (time, datum_time)
+    }
+}
+
+/// The state of each CPU, along with everything else tracked per CPU. With
+/// `--allow-extra-cpus` these all grow together when a CPU out of range
+/// shows up.
+pub struct CpuTrackers<'a> {
+    num_cpus: usize,
+    allow_extra_cpus: bool,
+    entity_name: &'a dyn Fn(usize) -> CompactString,
+    /// The current state of each CPU. This is needed to restore state after
+    /// a IRQ exit or softirq exit.
+    cpus: CpuStates,
+    idle_gaps: IdleGapTracker,
+    tag_merger: Option<TagMerger>,
+    switch_limiter: Option<SwitchRateLimiter>,
+    irq_threads: Option<IrqThreadTracker>,
+    latency_spikes: Option<LatencySpikeDetector>,
+    preemption_storms: Option<PreemptionStormDetector>,
+    switch_histogram: Option<SwitchHistogram>,
+    runqueues: Option<RunqueueDepths>,
+    cache_flush: Option<CacheFlushDetector>,
+}
+
+impl<'a> CpuTrackers<'a> {
+    pub fn new(
+        cli: &Cli,
+        num_cpus: usize,
+        entity_name: &'a dyn Fn(usize) -> CompactString,
+    ) -> Self {
+        Self {
+            num_cpus,
+            allow_extra_cpus: cli.allow_extra_cpus,
+            entity_name,
+            cpus: CpuStates::new((0..num_cpus).map(entity_name)),
+            idle_gaps: IdleGapTracker::new(num_cpus, cli.report_idle_gaps),
+            tag_merger: cli.merge_consecutive_tags.then(|| TagMerger::new(num_cpus)),
+            switch_limiter: cli
+                .max_switches_per_cpu_per_ms
+                .map(|max| SwitchRateLimiter::new(num_cpus, max)),
+            irq_threads: cli
+                .detect_irq_threading
+                .then(|| IrqThreadTracker::new(num_cpus)),
+            latency_spikes: cli
+                .detect_latency_spikes
+                .map(|threshold| LatencySpikeDetector::new(num_cpus, threshold)),
+            preemption_storms: cli
+                .detect_preemption_storms
+                .map(|window_ms| PreemptionStormDetector::new(num_cpus, window_ms * 1_000_000)),
+            switch_histogram: cli
+                .context_switch_histogram
+                .map(|bucket_ns| SwitchHistogram::new(num_cpus, bucket_ns)),
+            runqueues: (cli.show_runqueue_depth || cli.rq_depth_alert.is_some())
+                .then(|| RunqueueDepths::new(num_cpus, cli.rq_depth_alert)),
+            cache_flush: cli
+                .detect_cache_flush
+                .then(|| CacheFlushDetector::new(num_cpus)),
+        }
+    }
+
+    /// Check that a CPU number from the trace is in range. With
+    /// `--allow-extra-cpus` everything tracked per CPU is grown instead.
+    pub fn ensure_cpu(&mut self, cpu: usize) -> Result<(), eyre::Error> {
+        // Destructured, so that adding a tracker without growing it here
+        // fails to compile.
+        let Self {
+            num_cpus,
+            allow_extra_cpus,
+            entity_name,
+            cpus,
+            idle_gaps,
+            tag_merger,
+            switch_limiter,
+            irq_threads,
+            latency_spikes,
+            preemption_storms,
+            switch_histogram,
+            runqueues,
+            cache_flush,
+        } = self;
+        if cpu < *num_cpus {
+            return Ok(());
+        }
+        if !*allow_extra_cpus {
+            return Err(eyre!("CPU {cpu} out of range 0..{num_cpus}"));
+        }
+        cpus.extend((*num_cpus..=cpu).map(*entity_name));
+        *num_cpus = cpu + 1;
+        idle_gaps.resize(*num_cpus);
+        if let Some(merger) = tag_merger {
+            merger.resize(*num_cpus);
+        }
+        if let Some(limiter) = switch_limiter {
+            limiter.resize(*num_cpus);
+        }
+        if let Some(tracker) = irq_threads {
+            tracker.resize(*num_cpus);
+        }
+        if let Some(detector) = latency_spikes {
+            detector.resize(*num_cpus);
+        }
+        if let Some(detector) = preemption_storms {
+            detector.resize(*num_cpus);
+        }
+        if let Some(histogram) = switch_histogram {
+            histogram.resize(*num_cpus);
+        }
+        if let Some(runqueues) = runqueues {
+            runqueues.resize(*num_cpus);
+        }
+        if let Some(detector) = cache_flush {
+            detector.resize(*num_cpus);
+        }
+        Ok(())
+    }
+}
+
+/// Where converted records go.
+struct Output<'a> {
+    sink: &'a mut dyn OutputSink,
+    writer: &'a mut dyn Write,
+    /// When detecting cache flushes we need to see the whole trace before we
+    /// know which datums to annotate, so the output is buffered here.
+    cache_flush_buffer: Option<Vec<StatemapInputDatum<CpuState>>>,
+    stats_only: bool,
+}
+
+impl Output<'_> {
+    /// Write a datum that made it through the filters in [`Converter::emit`].
+    fn write(&mut self, datum: &StatemapInputDatum<CpuState>) -> Result<(), eyre::Error> {
+        match &mut self.cache_flush_buffer {
+            Some(buffer) => buffer.push(datum.clone()),
+            None if !self.stats_only => self.sink.write_datum(self.writer, datum)?,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// Converts records one at a time, keeping track of the state of each CPU
+/// between them.
+pub struct Converter<'a> {
+    cli: &'a Cli,
+    clock: Clock,
+    trackers: CpuTrackers<'a>,
+    /// Lookup table from event attribute index to conversion action.
+    action_map: Vec<Action>,
+    output: Output<'a>,
+    /// The frequency statemap, if requested.
+    freq_output: Option<(FreqBands, BufWriter<File>)>,
+    event_rate: Option<EventRate>,
+    isolation: Option<IsolationChecker>,
+    stats: Stats,
+    perf_data_stats: PerfDataStats,
+    /// Time in state is only needed for reports.
+    track_states: bool,
+    /// Time each softirq was raised at, by CPU and vector, until it runs.
+    softirq_raises: HashMap<(usize, u32), u64>,
+    /// Time each thread went to sleep at, to check against
+    /// `sched_stat_sleep`.
+    sleep_starts: HashMap<i32, u64>,
+    child_waits: ChildWaits,
+    /// Time each thread was last woken up at, for computing wakeup latency.
+    wakeups: HashMap<i32, u64>,
+    /// Tags repeat a lot (the same threads and IRQs over and over), so intern
+    /// them instead of allocating for every event.
+    string_pool: StringPool,
+    replay_start: Instant,
+    /// Number of samples processed.
+    ctr: u64,
+    /// Time of the last sample, relative to the start of the trace.
+    end_time: u64,
+    /// Which CPUs had samples, to check against the header.
+    cpus_seen: Vec<bool>,
+    /// Number of CPUs the perf header claims are available.
+    nr_cpus_available: usize,
+    /// Number of records read, to locate errors in a damaged file.
+    records_read: u64,
+}
+
+impl<'a> Converter<'a> {
+    pub fn new(
+        cli: &'a Cli,
+        clock: Clock,
+        trackers: CpuTrackers<'a>,
+        action_map: Vec<Action>,
+        sink: &'a mut dyn OutputSink,
+        writer: &'a mut dyn Write,
+    ) -> Self {
+        if cli.detect_cache_flush && !action_map.iter().any(|a| matches!(a, Action::CacheMiss)) {
+            log::warn!("No cache miss counter found in trace, cannot detect cache flushes");
+        }
+        let num_cpus = trackers.num_cpus;
+        Self {
+            cli,
+            clock,
+            trackers,
+            action_map,
+            output: Output {
+                sink,
+                writer,
+                cache_flush_buffer: cli.detect_cache_flush.then(Vec::new),
+                stats_only: cli.stats_only,
+            },
+            freq_output: None,
+            event_rate: None,
+            isolation: cli
+                .detect_cpu_isolation_violation
+                .as_ref()
+                .map(|mask| IsolationChecker::new(mask, &cli.isolation_allowed_comm)),
+            stats: Stats::default(),
+            perf_data_stats: PerfDataStats::default(),
+            track_states: cli.stats_only || cli.emit_prometheus,
+            softirq_raises: HashMap::new(),
+            sleep_starts: HashMap::new(),
+            child_waits: ChildWaits::default(),
+            wakeups: HashMap::new(),
+            string_pool: StringPool::default(),
+            replay_start: Instant::now(),
+            ctr: 0,
+            end_time: 0,
+            cpus_seen: vec![false; num_cpus],
+            nr_cpus_available: num_cpus,
+            records_read: 0,
+        }
+    }
+
+    /// Also write a frequency statemap.
+    pub fn with_freq_output(mut self, freq_output: Option<(FreqBands, BufWriter<File>)>) -> Self {
+        self.freq_output = freq_output;
+        self
+    }
+
+    /// Show the rate of events processed on the progress bar.
+    pub fn with_event_rate(mut self, event_rate: Option<EventRate>) -> Self {
+        self.event_rate = event_rate;
+        self
+    }
+
+    /// Number of records handled so far.
+    pub fn records_read(&self) -> u64 {
+        self.records_read
+    }
+
+    /// Write out everything converted so far, so that it can be read while
+    /// the input is still being recorded.
+    pub fn flush(&mut self) -> Result<(), eyre::Error> {
+        self.output.writer.flush()?;
+        if let Some((_, freq_writer)) = &mut self.freq_output {
+            freq_writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Context for an error reading the next record.
+    pub fn read_error_context(&self) -> String {
+        format!(
+            "Failed to read record #{} (processed so far: {} events)",
+            self.records_read + 1,
+            self.ctr
+        )
+    }
+
+    pub fn handle_record(&mut self, record: PerfFileRecord<'_>) -> Result<(), eyre::Error> {
+        self.records_read += 1;
+        match record {
+            PerfFileRecord::EventRecord { attr_index, record } => {
+                self.perf_data_stats.count_record(record.record_type);
+                match record.record_type {
+                    // We don't care about these events (we are not doing stack traces)
+                    RecordType::MMAP | RecordType::MMAP2 | RecordType::KSYMBOL => {}
+                    RecordType::FORK | RecordType::EXIT => {
+                        // Process lifecycle events, we don't use these
+                        // (currently) Instead we get data from tracepoints.
+                    }
+                    RecordType::COMM => self.handle_comm(&record)?,
+                    // This we need to handle
+                    RecordType::SAMPLE => self.handle_sample(attr_index, &record)?,
+                    RecordType::AUX => {
+                        // Hardware trace data (Intel PT, ARM CoreSight). We
+                        // don't decode it, just account for it.
+                        let aux = AuxRecord::parse(record.data, record.parse_info.endian)?;
+                        let cpu = record.common_data()?.cpu;
+                        log::debug!(
+                            "AUX record: cpu={cpu:?} size={} offset={} flags={:#x}",
+                            aux.aux_size,
+                            aux.aux_offset,
+                            aux.flags
+                        );
+                        self.stats.record_aux(aux.aux_size);
+                    }
+                    RecordType::LOST | RecordType::LOST_SAMPLES => {
+                        self.stats.lost_events += match record.parse()? {
+                            EventRecord::Lost(lost) => lost.count,
+                            // PERF_RECORD_LOST_SAMPLES, which the reader
+                            // doesn't parse, starts with the number lost.
+                            _ => {
+                                let mut data = record.data;
+                                match record.parse_info.endian {
+                                    Endianness::LittleEndian => data.read_u64::<LittleEndian>()?,
+                                    Endianness::BigEndian => data.read_u64::<BigEndian>()?,
+                                }
+                            }
+                        };
+                        // Warn the user about lost samples
+                        log::warn!(
+                            "There are lost samples. Data is incomplete and may not be \
+                             trustworthy!"
+                        );
+                    }
+                    _ => {
+                        log::warn!("Unhandled record type: {:?}", record.record_type);
+                    }
+                }
+            }
+            PerfFileRecord::UserRecord(raw_user_record) => {
+                self.perf_data_stats
+                    .count_user_record(raw_user_record.record_type);
+                // None of these appear to be useful right now, though
+                // * PERF_TIME_CONV could possibly be useful to convert
+                //   timestamps, but none of the values line up with wall time
+                //   from what I can see.
+            }
+        }
+        Ok(())
+    }
+
+    /// A thread renamed itself (exec is handled with the
+    /// `sched_process_exec` tracepoint instead).
+    fn handle_comm(&mut self, record: &RawEventRecord<'_>) -> Result<(), eyre::Error> {
+        let EventRecord::Comm(comm) = record.parse()? else {
+            return Ok(());
+        };
+        if comm.is_execve {
+            return Ok(());
+        }
+        // Only present if the samples have timestamps.
+        let Some(timestamp) = record.common_data()?.timestamp else {
+            return Ok(());
+        };
+        let cpus = &mut self.trackers.cpus;
+        // Only a thread running on a CPU shows in the statemap.
+        let Some(cpu) = cpus
+            .running
+            .iter()
+            .position(|&running| running == Some(comm.tid))
+        else {
+            return Ok(());
+        };
+        // In an interrupt the tag isn't the thread's.
+        let state = cpus.state_mut(cpu)?;
+        if matches!(
+            state.state,
+            CpuState::Irq | CpuState::Softirq | CpuState::Tasklet
+        ) {
+            return Ok(());
+        }
+        let (time, datum_time) = self.clock.convert(timestamp);
+        let name = comm.name.as_slice();
+        let name = String::from_utf8_lossy(&name);
+        state.time = datum_time;
+        state.tag = Some(match &state.tag {
+            Some(tag) => format_compact!("{tag} renamed→{name}"),
+            None => format_compact!("renamed→{name}"),
+        });
+        self.emit(cpu, time, None, false)
+    }
+
+    fn handle_sample(
+        &mut self,
+        attr_index: usize,
+        record: &RawEventRecord<'_>,
+    ) -> Result<(), eyre::Error> {
+        let cli = self.cli;
+        let ctr = {
+            self.ctr += 1;
+            self.ctr
+        };
+        self.stats.samples += 1;
+        let action = self.action_map[attr_index].clone();
+        if matches!(action, Action::Ignore) {
+            return Ok(()); // Skip ignored actions
+        }
+        self.perf_data_stats.count_action(action.name());
+        let common = record.common_data()?;
+        let records_read = self.records_read;
+        let cpu = common
+            .cpu
+            .ok_or_else(|| eyre!("Sample in record #{records_read} has no CPU"))?
+            as usize;
+        self.trackers.ensure_cpu(cpu)?;
+        if cpu >= self.cpus_seen.len() {
+            self.cpus_seen.resize(cpu + 1, false);
+        }
+        self.cpus_seen[cpu] = true;
+        let timestamp = common
+            .timestamp
+            .ok_or_else(|| eyre!("Sample in record #{records_read} has no timestamp"))?;
+        let (time, mut datum_time) = self.clock.convert(timestamp);
+        self.end_time = self.end_time.max(time);
+        if let Some(event_rate) = &self.event_rate {
+            event_rate.update(ctr, time);
+        }
+        let endian = record.parse_info.endian;
+        let sample = match endian {
+            Endianness::LittleEndian => {
+                SampleRecord::parse::<LittleEndian>(record.data, record.misc, &record.parse_info)?
+            }
+            Endianness::BigEndian => {
+                SampleRecord::parse::<BigEndian>(record.data, record.misc, &record.parse_info)?
+            }
+        };
+        if matches!(action, Action::CacheMiss) {
+            if let Some(detector) = &mut self.trackers.cache_flush {
+                detector.record_misses(cpu, time, sample.period.unwrap_or(1));
+            }
+            return Ok(());
+        }
+        let event = Event::parse(
+            &action,
+            sample.raw.ok_or_else(|| eyre!("No raw data for trace?"))?,
+            endian,
+            time,
+            &mut self.string_pool,
+        )
+        .wrap_err_with(|| {
+            format!(
+                "Failed to parse: {sample:?}, in {action} on CPU {cpu} at {timestamp} ns (at \
+                 {ctr})"
+            )
+        })?;
+        //println!("Event: {event:?} on CPU {cpu} at time {time}");
+        let trackers = &mut self.trackers;
+        match event {
+            Event::Migrate {
+                from,
+                to,
+                ref comm,
+                pid,
+                ..
+            } => {
+                let Some((from, to)) = check_migration(
+                    comm,
+                    pid,
+                    from,
+                    to,
+                    trackers.num_cpus,
+                    cli.allow_extra_cpus,
+                    cli.lenient,
+                )
+                .wrap_err_with(|| format!("At sample {ctr}"))?
+                else {
+                    return Ok(());
+                };
+                trackers.ensure_cpu(from)?;
+                trackers.ensure_cpu(to)?;
+            }
+            Event::CpuFrequency { cpu, .. } => {
+                trackers.ensure_cpu(cpu as usize)?;
+            }
+            Event::Wakeup { target_cpu, .. } => {
+                trackers.ensure_cpu(
+                    usize::try_from(target_cpu)
+                        .map_err(|_| eyre!("Invalid CPU {target_cpu} (at {ctr})"))?,
+                )?;
+            }
+            Event::NumaMove {
+                src_cpu, dst_cpu, ..
+            } => {
+                for numa_cpu in [src_cpu, dst_cpu] {
+                    trackers.ensure_cpu(
+                        usize::try_from(numa_cpu)
+                            .map_err(|_| eyre!("Invalid CPU {numa_cpu} (at {ctr})"))?,
+                    )?;
+                }
+            }
+            _ => {}
+        }
+        let is_switch = matches!(event, Event::BeginThread { .. });
+        let skip_idle = cli.exclude_idle && event.is_switch_to_swapper();
+        let stats = &mut self.stats;
+        let cpus = &mut trackers.cpus;
+        stats.count_event(cpu, event.kind());
+        if let Some(detector) = &mut trackers.latency_spikes
+            && let Some(gap) = detector.record(cpu, event.timestamp())
+        {
+            log::warn!(
+                "No events on CPU {cpu} for {gap} ns, until {} event at {} ns",
+                event.kind(),
+                event.timestamp()
+            );
+        }
+        if is_switch && let Some(detector) = &mut trackers.preemption_storms {
+            detector.record_switch(cpu, event.timestamp());
+        }
+        if is_switch && let Some(histogram) = &mut trackers.switch_histogram {
+            histogram.record_switch(cpu, event.timestamp());
+        }
+        // The CPU whose state to write to the output.
+        let mut out_cpu = cpu;
+        // Interrupts (and the other states entered the same way) replace the
+        // state until they end.
+        if event.is_begin() && !is_switch {
+            let state = event.cpu_state().expect("Begin events have a state");
+            let irq = match event {
+                Event::BeginIrq { irq, .. } => Some(irq),
+                _ => None,
+            };
+            stats.record_interrupt_entry(cpu, state, irq);
+            cpus.save_state(cpu)?;
+            let datum = cpus.state_mut(cpu)?;
+            datum.state = state;
+            datum.tag = event.tag().cloned();
+        }
+        if event.is_end()
+            && matches!(
+                cpus.state(cpu)?.state,
+                CpuState::Irq | CpuState::Softirq | CpuState::Tasklet
+            )
+        {
+            stats.record_interrupt_exit(cpu);
+        }
+        match event {
+            Event::BeginThread {
+                time,
+                state,
+                comm,
+                pid,
+                prio,
+                prev_pid,
+                prev_state,
+            } => {
+                if !prev_state.is_runnable()
+                    && let Some(runqueues) = &mut trackers.runqueues
+                {
+                    runqueues.sleep(cpu);
+                }
+                if prev_state.is_sleeping() {
+                    self.sleep_starts.insert(prev_pid, time);
+                }
+                if let Some((waiter, comm, duration)) = self.child_waits.switch_in(pid, time) {
+                    stats.record_child_wait(waiter, &comm, duration);
+                }
+                if let Some(woken) = self.wakeups.remove(&pid) {
+                    stats.record_wakeup_latency(time.saturating_sub(woken));
+                }
+                // Make it obvious what arrived on the CPU after the source CPU
+                // was shown as migrating.
+                let migrated = cpus.is_migrating(pid);
+                let datum = cpus.state_mut(cpu)?;
+                datum.state = state;
+                let tag = Event::thread_tag(&mut self.string_pool, &comm, pid, prio);
+                datum.tag = Some(if migrated {
+                    format_compact!("migrated: {tag}")
+                } else {
+                    tag
+                });
+                if let Some(tracker) = &mut trackers.irq_threads {
+                    tracker.switch(cpu, &comm);
+                }
+                if let Some(checker) = &self.isolation
+                    && checker.is_violation(cpu, &comm)
+                {
+                    log::error!(
+                        "CPU isolation violation: {comm}:{pid} ran on isolated CPU {cpu} at \
+                         {time} ns"
+                    );
+                }
+                cpus.running[cpu] = Some(pid);
+                if let Some(from) = cpus.finish_migration(pid, datum_time) {
+                    if self.track_states {
+                        stats.record_state(from, CpuState::Idle, time);
+                    }
+                    // The source CPU switched to idle when the task left.
+                    if !cli.exclude_idle {
+                        self.emit(from, time, None, false)?;
+                    }
+                }
+            }
+            Event::BeginIrq { time, irq, .. } => {
+                if let Some(thread_cpu) = trackers
+                    .irq_threads
+                    .as_ref()
+                    .and_then(|t| t.running_on(irq))
+                {
+                    log::warn!(
+                        "IRQ {irq} fired on CPU {cpu} at {time} ns while its thread was still \
+                         running on CPU {thread_cpu}"
+                    );
+                }
+            }
+            Event::BeginSoftirq { time, vec, .. } => {
+                if let Some(raised) = self.softirq_raises.remove(&(cpu, vec)) {
+                    let latency = time.saturating_sub(raised);
+                    stats.record_softirq_latency(latency);
+                    if cli
+                        .softirq_latency_threshold
+                        .is_some_and(|threshold| latency > threshold)
+                    {
+                        log::warn!(
+                            "Softirq {vec} on CPU {cpu} ran {latency} ns after being raised, at \
+                             {time} ns"
+                        );
+                    }
+                }
+            }
+            Event::RaiseSoftirq { time, vec } => {
+                if cli.detect_softirq_latency {
+                    // Raising an already pending softirq does nothing, so keep
+                    // the first raise.
+                    self.softirq_raises.entry((cpu, vec)).or_insert(time);
+                }
+                return Ok(());
+            }
+            Event::BeginOther { time, state, tag } => {
+                if state == CpuState::RcuStall && cli.detect_rcu_stalls {
+                    log::error!("RCU stall on CPU {cpu} at {time} ns: {tag}");
+                }
+            }
+            Event::End { .. } => {
+                if cli.no_prev_state {
+                    let state = cpus.state_mut(cpu)?;
+                    state.state = CpuState::Idle;
+                    // The statemap tool doesn't deal with None correctly.
+                    state.tag = Some("".to_compact_string());
+                } else {
+                    cpus.restore_state(cpu)?;
+                }
+            }
+            Event::UnhandledIrq { irq, .. } => {
+                stats.record_unhandled_irq(irq);
+                if cli.warn_unhandled_irq {
+                    // Show a 1 unit spike before restoring the state.
+                    let spike = StatemapInputDatum::builder()
+                        .time(datum_time)
+                        .entity(cpus.state(cpu)?.entity.clone())
+                        .state(CpuState::Irq)
+                        .tag(Some(
+                            self.string_pool
+                                .intern_fmt(format_args!("IRQ {irq}: UNHANDLED")),
+                        ))
+                        .build();
+                    self.emit(cpu, time, Some(&spike), false)?;
+                    datum_time += 1;
+                }
+                self.trackers.cpus.restore_state(cpu)?;
+            }
+            Event::Exec {
+                state,
+                comm,
+                pid,
+                old_pid,
+                filename,
+                ..
+            } => {
+                // A thread other than the leader took over the PID of the
+                // leader.
+                if cpus.running[cpu] == Some(old_pid) {
+                    cpus.running[cpu] = Some(pid);
+                }
+                stats.record_exec(pid, &comm);
+                let datum = cpus.state_mut(cpu)?;
+                datum.state = state;
+                datum.tag = Some(format_compact!("{comm}:{pid} exec→{filename}"));
+            }
+            Event::CpuFrequency { cpu, khz, .. } => {
+                if let Some((bands, freq_writer)) = &mut self.freq_output {
+                    let datum = StatemapInputDatum::builder()
+                        .time(datum_time)
+                        .entity(cpus.state(cpu as usize)?.entity.clone())
+                        .state(bands.classify(khz))
+                        .tag(Some(format_compact!("{} MHz", khz / 1000)))
+                        .build();
+                    serde_json::to_writer(&mut *freq_writer, &datum)?;
+                    writeln!(freq_writer)?;
+                }
+                return Ok(());
+            }
+            Event::Waking { time, pid } => {
+                self.wakeups.insert(pid, time);
+                return Ok(());
+            }
+            Event::Wakeup { time, target_cpu } => {
+                if let Some(runqueues) = &mut trackers.runqueues {
+                    runqueues.wakeup(target_cpu as usize, time);
+                }
+                return Ok(());
+            }
+            Event::RcuUtilization { phase, .. } => {
+                stats.record_rcu_phase(&phase);
+                if !cpus.annotate_rcu_phase(cpu, &phase)? {
+                    return Ok(());
+                }
+            }
+            Event::StatSleep {
+                time, pid, delay, ..
+            } => {
+                // Samples from different CPUs may be out of order, don't
+                // compare against a negative sleep.
+                if let Some(observed) = self
+                    .sleep_starts
+                    .remove(&pid)
+                    .and_then(|start| time.checked_sub(start))
+                {
+                    stats.record_sleep_delay(observed, delay);
+                }
+                return Ok(());
+            }
+            Event::ProcessWait {
+                time,
+                comm,
+                pid,
+                child,
+            } => {
+                self.child_waits.wait(pid, comm, child, time);
+                return Ok(());
+            }
+            Event::ProcessExit { time, pid } => {
+                for (waiter, comm, duration) in self.child_waits.exit(pid, time) {
+                    stats.record_child_wait(waiter, &comm, duration);
+                }
+                return Ok(());
+            }
+            Event::StatRuntime {
+                comm, pid, runtime, ..
+            } => {
+                stats.record_runtime(pid, &comm, runtime);
+                return Ok(());
+            }
+            Event::PiSetprio {
+                comm,
+                pid,
+                oldprio,
+                newprio,
+                ..
+            } => {
+                // Only boosts are interesting, not the return to the normal
+                // priority.
+                if newprio >= oldprio {
+                    return Ok(());
+                }
+                stats.record_pi_boost(pid, &comm, oldprio - newprio);
+                // Annotate the CPU the boosted thread runs on, if any.
+                let Some(boosted) = cpus
+                    .running
+                    .iter()
+                    .position(|&running| running == Some(pid))
+                else {
+                    return Ok(());
+                };
+                cpus.state_mut(boosted)?.tag =
+                    Some(format_compact!("{comm}:{pid} PI: prio {oldprio}→{newprio}"));
+                out_cpu = boosted;
+            }
+            Event::Migrate {
+                from,
+                to,
+                comm,
+                pid,
+                ..
+            } => {
+                let from =
+                    usize::try_from(from).map_err(|_| eyre!("Invalid CPU {from} (at {ctr})"))?;
+                if cpus.migrate(from, pid, datum_time)? {
+                    out_cpu = from;
+                } else {
+                    // The task wasn't running, so show the migration as a 1
+                    // unit spike on the source CPU, which then continues as
+                    // before.
+                    let tag = format_compact!("migrated: {comm}:{pid} → CPU {to}");
+                    for datum in spike(cpus.state(from)?, datum_time, CpuState::Migrating, tag) {
+                        self.emit(from, time, Some(&datum), false)?;
+                    }
+                    return Ok(());
+                }
+            }
+            Event::NumaMove {
+                pid,
+                src_cpu,
+                src_nid,
+                dst_cpu,
+                dst_nid,
+                ..
+            } => {
+                stats.record_numa_move(src_nid, dst_nid);
+                // A 1 unit spike on both CPUs, after which they continue in
+                // their current state.
+                for (numa_cpu, direction) in [(src_cpu as usize, "out"), (dst_cpu as usize, "in")] {
+                    let tag = format_compact!("NUMA node {src_nid}→{dst_nid}: {pid} {direction}");
+                    let current = self.trackers.cpus.state(numa_cpu)?;
+                    for datum in spike(current, datum_time, CpuState::NumaMove, tag) {
+                        self.emit(numa_cpu, time, Some(&datum), false)?;
+                    }
+                }
+                return Ok(());
+            }
+        }
+        let out_state = self.trackers.cpus.state_mut(out_cpu)?;
+        out_state.time = datum_time;
+        if self.track_states {
+            self.stats.record_state(out_cpu, out_state.state, time);
+            if let Some(tag) = &out_state.tag
+                && !tag.is_empty()
+            {
+                self.stats.record_tag(tag);
+            }
+        }
+        self.trackers
+            .idle_gaps
+            .update(out_cpu, out_state.state == CpuState::Idle, time);
+        if skip_idle {
+            return Ok(());
+        }
+        self.emit(out_cpu, time, None, is_switch)
+    }
+
+    /// Write a datum for `cpu` through the filters of state changes, to the
+    /// output. `extra` is a datum that isn't the current state of the CPU
+    /// (such as a synthetic marker), otherwise the current state is written.
+    /// `time` is in nanoseconds since the start of the trace.
+    fn emit(
+        &mut self,
+        cpu: usize,
+        time: u64,
+        extra: Option<&StatemapInputDatum<CpuState>>,
+        is_switch: bool,
+    ) -> Result<(), eyre::Error> {
+        let CpuTrackers {
+            cpus,
+            tag_merger,
+            switch_limiter,
+            runqueues,
+            cache_flush,
+            ..
+        } = &mut self.trackers;
+        let datum = match extra {
+            Some(datum) => datum,
+            None => cpus.state(cpu)?,
+        };
+        if let Some(merger) = tag_merger
+            && merger.is_continuation(cpu, datum)
+        {
+            return Ok(());
+        }
+        let annotated;
+        let datum = match runqueues {
+            Some(runqueues) if self.cli.show_runqueue_depth => {
+                annotated = runqueues.annotate(cpu, datum);
+                &annotated
+            }
+            _ => datum,
+        };
+        if let Some(limiter) = switch_limiter {
+            let filtered = limiter.filter(cpu, time, is_switch, datum);
+            if let Some(busy) = filtered.busy {
+                self.output.write(&busy)?;
+            }
+            if filtered.suppress {
+                return Ok(());
+            }
+        }
+        if let Some(speed) = self.cli.event_replay {
+            replay_delay(self.replay_start, time, speed);
+        }
+        if is_switch
+            && let Some(detector) = cache_flush
+            && let Some(buffer) = &self.output.cache_flush_buffer
+        {
+            detector.record_switch(cpu, time, buffer.len());
+        }
+        self.output.write(datum)?;
+        if self.cli.event_replay.is_some() {
+            self.output.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write what could only be known at the end of the trace: reports,
+    /// statistics and datums held back until then.
+    pub fn finish(
+        mut self,
+        perf_file: &PerfFile,
+        host: Option<CompactString>,
+        input: &str,
+        datums_written: &Cell<u64>,
+    ) -> Result<(), eyre::Error> {
+        let cli = self.cli;
+        let stats = &mut self.stats;
+        let writer = &mut self.output.writer;
+        if let Some(event_rate) = &self.event_rate {
+            event_rate.finish();
+        }
+
+        if let Some(detector) = &self.trackers.preemption_storms {
+            stats.preemption_storms = detector.storms();
+            for storm in &stats.preemption_storms {
+                log::warn!(
+                    "Preemption storm on CPU {}: {} context switches in window starting at {} ns",
+                    storm.cpu,
+                    storm.switches,
+                    storm.start
+                );
+            }
+        }
+
+        if let Some(histogram) = self.trackers.switch_histogram.take()
+            && let Some(output) = &cli.output
+        {
+            let path = format!("{output}.cs_histogram.csv");
+            let mut csv = BufWriter::new(
+                File::create(&path).wrap_err_with(|| format!("Failed to create \"{path}\""))?,
+            );
+            histogram.write_csv(&mut csv)?;
+            csv.flush()?;
+        }
+
+        if self.track_states {
+            stats.finish_state_times(self.end_time);
+        }
+        if cli.emit_prometheus
+            && let Some(output) = &cli.output
+        {
+            let path = format!("{output}.metrics");
+            let mut metrics = BufWriter::new(
+                File::create(&path).wrap_err_with(|| format!("Failed to create \"{path}\""))?,
+            );
+            prometheus::write_metrics(stats, &mut metrics)?;
+            metrics.flush()?;
+        }
+        let time_range = perf_file
+            .sample_time_range()?
+            .map(|range| [range.first_sample_time, range.last_sample_time]);
+        let perf_data_stats = &mut self.perf_data_stats;
+        perf_data_stats.finish(stats, time_range, self.trackers.num_cpus);
+        if cli.stats_only {
+            stats.system_idle_ns = Some(self.trackers.idle_gaps.total_idle_ns);
+            stats.input_size = std::fs::metadata(input).ok().map(|m| m.len());
+            match cli.stats_format {
+                StatsFormat::Text => {
+                    stats.write(writer)?;
+                    write!(writer, "{perf_data_stats}")?;
+                }
+                StatsFormat::Markdown => {
+                    let info = SystemInfo {
+                        hostname: host,
+                        cpu_model: perf_file
+                            .cpu_desc()
+                            .unwrap_or_default()
+                            .map(CompactString::from),
+                        duration_ns: self.end_time,
+                    };
+                    let report = report::markdown_report(stats, &info)?;
+                    match &cli.output {
+                        Some(output) => {
+                            stats.write(writer)?;
+                            let path = format!("{output}.md");
+                            std::fs::write(&path, report)
+                                .wrap_err_with(|| format!("Failed to write \"{path}\""))?;
+                        }
+                        None => writer.write_all(report.as_bytes())?,
+                    }
+                }
+            }
+        }
+
+        if let Some(limiter) = &mut self.trackers.switch_limiter {
+            for busy in limiter.finish() {
+                self.output.write(&busy)?;
+            }
+        }
+
+        let output = &mut self.output;
+        if let Some(detector) = &self.trackers.cache_flush
+            && let Some(buffer) = &mut output.cache_flush_buffer
+        {
+            let cold = detector.cold_switches();
+            log::info!("Found {} context switches with a cold cache", cold.len());
+            for idx in cold {
+                let datum = &mut buffer[idx];
+                datum.tag = Some(match &datum.tag {
+                    Some(tag) => format_compact!("{tag} (cold cache)"),
+                    None => "cold cache".to_compact_string(),
+                });
+            }
+            for datum in &*buffer {
+                output.sink.write_datum(output.writer, datum)?;
+            }
+        }
+        if !cli.stats_only {
+            output.sink.finish(output.writer)?;
+            // Sinks may hold on to datums until finished, so only count now.
+            perf_data_stats.datums = datums_written.get();
+            log::debug!("{perf_data_stats}");
+        }
+        output.writer.flush()?;
+
+        let nr_cpus_available = self.nr_cpus_available;
+        if let Some(max_seen) = self.cpus_seen.iter().rposition(|&seen| seen) {
+            if max_seen >= nr_cpus_available {
+                log::warn!(
+                    "Saw CPU {max_seen} but perf header claims only {nr_cpus_available} CPUs \
+                     available; header may be stale"
+                );
+            }
+            let num_seen = self.cpus_seen.iter().filter(|&&seen| seen).count();
+            if num_seen < nr_cpus_available / 2 {
+                log::warn!(
+                    "Only {num_seen} of {nr_cpus_available} CPUs had any events, consider \
+                     recording only those CPUs (perf record -C) to reduce the output size"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A 1 unit spike of `state` at `time` on the CPU whose current state is
+/// `current`, followed by a return to the current state.
+fn spike(
+    current: &StatemapInputDatum<CpuState>,
+    time: u64,
+    state: CpuState,
+    tag: CompactString,
+) -> [StatemapInputDatum<CpuState>; 2] {
+    let spike = StatemapInputDatum::builder()
+        .time(time)
+        .entity(current.entity.clone())
+        .state(state)
+        .tag(Some(tag))
+        .build();
+    let restore = StatemapInputDatum {
+        time: time + 1,
+        ..current.clone()
+    };
+    [spike, restore]
+}
+
+/// Sleep until `time` (nanoseconds since trace start) scaled by `speed` has
+/// elapsed since `replay_start`.
+fn replay_delay(replay_start: Instant, time: u64, speed: f64) {
+    if let Some(remaining) = replay_target(time, speed).checked_sub(replay_start.elapsed()) {
+        std::thread::sleep(remaining);
+    }
+}
+
+/// Time since the start of the replay at which to write the event at `time`.
+/// Speeds so slow that this doesn't fit in a `Duration` wait forever.
+fn replay_target(time: u64, speed: f64) -> Duration {
+    Duration::try_from_secs_f64(time as f64 / 1e9 / speed).unwrap_or(Duration::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::json_lines::JsonLinesSink;
+    use clap::Parser;
+
+    #[test]
+    fn test_ensure_cpu() {
+        let entity_name = |cpu: usize| format_compact!("{cpu}");
+        let cli = Cli::parse_from(["perf-to-statemap", "perf.data"]);
+        let mut trackers = CpuTrackers::new(&cli, 2, &entity_name);
+        trackers.ensure_cpu(1).unwrap();
+        assert!(trackers.ensure_cpu(2).is_err());
+
+        let cli = Cli::parse_from([
+            "perf-to-statemap",
+            "--allow-extra-cpus",
+            "--merge-consecutive-tags",
+            "--show-runqueue-depth",
+            "perf.data",
+        ]);
+        let mut trackers = CpuTrackers::new(&cli, 2, &entity_name);
+        trackers.ensure_cpu(3).unwrap();
+        assert_eq!(trackers.num_cpus, 4);
+        // Everything tracked per CPU grew along with the CPU states.
+        let state = trackers.cpus.state(3).unwrap();
+        assert_eq!(state.entity, "3");
+        let merger = trackers.tag_merger.as_mut().unwrap();
+        assert!(!merger.is_continuation(3, state));
+        assert_eq!(trackers.runqueues.as_ref().unwrap().depth(3), 0);
+    }
+
+    #[test]
+    fn test_replay_target() {
+        assert_eq!(replay_target(2_000_000_000, 2.0), Duration::from_secs(1));
+        assert_eq!(replay_target(0, 1e-20), Duration::ZERO);
+        assert_eq!(replay_target(1_000_000_000, 1e-20), Duration::MAX);
+    }
+
+    #[test]
+    fn test_emit_extra() {
+        let entity_name = |cpu: usize| format_compact!("{cpu}");
+        let cli = Cli::parse_from(["perf-to-statemap", "--merge-consecutive-tags", "perf.data"]);
+        let clock = Clock {
+            model: None,
+            start_time: Some(0),
+            offset: 0,
+            normalize_to_hz: None,
+        };
+        let trackers = CpuTrackers::new(&cli, 1, &entity_name);
+        let mut sink = JsonLinesSink::default();
+        let mut out = Vec::new();
+        let mut converter = Converter::new(&cli, clock, trackers, Vec::new(), &mut sink, &mut out);
+        converter.emit(0, 0, None, true).unwrap();
+        // Returning to the state from before a marker isn't merged with that
+        // state, since the marker went through the tag merger too.
+        let [marker, _] = spike(
+            converter.trackers.cpus.state(0).unwrap(),
+            10,
+            CpuState::Irq,
+            "IRQ 5: UNHANDLED".into(),
+        );
+        converter.emit(0, 10, Some(&marker), false).unwrap();
+        converter.trackers.cpus.state_mut(0).unwrap().time = 11;
+        converter.emit(0, 11, None, false).unwrap();
+        drop(converter);
+        let times: Vec<_> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let datum: serde_json::Value = serde_json::from_str(line).unwrap();
+                datum["time"].as_str().unwrap().to_owned()
+            })
+            .collect();
+        assert_eq!(times, ["0", "10", "11"]);
+    }
+}
@@ -0,0 +1,354 @@
+//! Tracking of the current state of each CPU.
+
+use crate::statemap::StatemapInputDatum;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use compact_str::ToCompactString;
+use compact_str::format_compact;
+use eyre::eyre;
+use std::collections::HashMap;
+
+/// Start of the RCU phase appended to tags.
+const RCU_ANNOTATION: &str = " [RCU: ";
+
+/// The current state of every CPU in the trace.
+#[derive(Debug, Clone)]
+pub struct CpuStates {
+    /// Current state of each CPU. We serialize straight from these objects to
+    /// the output stream.
+    pub states: Vec<StatemapInputDatum<CpuState>>,
+    /// State to restore after an IRQ, softirq or tasklet exit.
+    pub prev_states: Vec<StatemapInputDatum<CpuState>>,
+    /// PID of the thread currently scheduled on each CPU, if known.
+    pub running: Vec<Option<i32>>,
+    /// Threads that were migrated but haven't been switched in on the
+    /// destination yet: PID to source CPU, time and whether the thread was
+    /// running on the source CPU.
+    pub migrations: HashMap<i32, (usize, u64, bool)>,
+    /// Last RCU phase seen on each CPU, from `rcu:rcu_utilization`.
+    pub rcu_phases: Vec<Option<CompactString>>,
+}
+
+impl CpuStates {
+    /// Create idle states for CPUs with the given entity names.
+    pub fn new(entities: impl IntoIterator<Item = CompactString>) -> Self {
+        let states: Vec<_> = entities
+            .into_iter()
+            .map(|entity| StatemapInputDatum::builder().entity(entity).build())
+            .collect();
+        Self {
+            prev_states: states.clone(),
+            running: vec![None; states.len()],
+            rcu_phases: vec![None; states.len()],
+            states,
+            migrations: HashMap::new(),
+        }
+    }
+
+    /// Add idle states for more CPUs.
+    pub fn extend(&mut self, entities: impl IntoIterator<Item = CompactString>) {
+        for entity in entities {
+            let state = StatemapInputDatum::builder().entity(entity).build();
+            self.prev_states.push(state.clone());
+            self.states.push(state);
+            self.running.push(None);
+            self.rcu_phases.push(None);
+        }
+    }
+
+    /// Current state of `cpu`.
+    pub fn state(&self, cpu: usize) -> Result<&StatemapInputDatum<CpuState>, eyre::Error> {
+        let len = self.states.len();
+        self.states.get(cpu).ok_or_else(|| out_of_range(cpu, len))
+    }
+
+    /// Mutable access to the current state of `cpu`.
+    pub fn state_mut(
+        &mut self,
+        cpu: usize,
+    ) -> Result<&mut StatemapInputDatum<CpuState>, eyre::Error> {
+        let len = self.states.len();
+        self.states
+            .get_mut(cpu)
+            .ok_or_else(|| out_of_range(cpu, len))
+    }
+
+    /// Save the current state of `cpu`, to restore on interrupt exit.
+    pub fn save_state(&mut self, cpu: usize) -> Result<(), eyre::Error> {
+        let len = self.states.len();
+        match (self.prev_states.get_mut(cpu), self.states.get(cpu)) {
+            (Some(prev), Some(state)) => {
+                prev.clone_from(state);
+                Ok(())
+            }
+            _ => Err(out_of_range(cpu, len)),
+        }
+    }
+
+    /// Restore the state of `cpu` saved by [`Self::save_state`].
+    pub fn restore_state(&mut self, cpu: usize) -> Result<(), eyre::Error> {
+        let len = self.states.len();
+        match (self.states.get_mut(cpu), self.prev_states.get(cpu)) {
+            (Some(state), Some(prev)) => {
+                state.clone_from(prev);
+                Ok(())
+            }
+            _ => Err(out_of_range(cpu, len)),
+        }
+    }
+
+    /// Record that `cpu` entered the RCU `phase`, appending it to the tag of
+    /// the current state as `" [RCU: <phase>]"`, replacing any earlier
+    /// phase.
+    ///
+    /// Returns true if the phase changed, and with it the tag.
+    pub fn annotate_rcu_phase(
+        &mut self,
+        cpu: usize,
+        phase: &CompactString,
+    ) -> Result<bool, eyre::Error> {
+        let len = self.states.len();
+        let last = self
+            .rcu_phases
+            .get_mut(cpu)
+            .ok_or_else(|| out_of_range(cpu, len))?;
+        if last.as_ref() == Some(phase) {
+            return Ok(false);
+        }
+        *last = Some(phase.clone());
+        let state = self.state_mut(cpu)?;
+        let tag = state.tag.as_deref().unwrap_or_default();
+        let base = tag.split_once(RCU_ANNOTATION).map_or(tag, |(base, _)| base);
+        state.tag = Some(format_compact!("{base}{RCU_ANNOTATION}{phase}]"));
+        Ok(true)
+    }
+
+    /// Handle `pid` being migrated away from CPU `from` at `time`.
+    ///
+    /// Most migrations are of runnable but not running tasks, in which case
+    /// no CPU changes state: the destination CPU will get a `sched_switch` once
+    /// the task actually runs there, and the caller shows the migration some
+    /// other way. Only if the task was running on the source CPU does that CPU
+    /// lose its thread, and it is then `Migrating` until the task runs on the
+    /// destination (see [`Self::finish_migration`]).
+    ///
+    /// Either way the task is migrating until it is switched in.
+    ///
+    /// Returns true if the state of the source CPU changed.
+    pub fn migrate(&mut self, from: usize, pid: i32, time: u64) -> Result<bool, eyre::Error> {
+        let running = self.running.get(from).copied().flatten() == Some(pid);
+        if running {
+            let state = self.state_mut(from)?;
+            state.time = time;
+            state.state = CpuState::Migrating;
+            self.running[from] = None;
+        } else if from >= self.states.len() {
+            return Err(out_of_range(from, self.states.len()));
+        }
+        self.migrations.insert(pid, (from, time, running));
+        Ok(running)
+    }
+
+    /// Whether `pid` was migrated, and hasn't been switched in on the
+    /// destination yet.
+    pub fn is_migrating(&self, pid: i32) -> bool {
+        self.migrations.contains_key(&pid)
+    }
+
+    /// Handle `pid` being switched in at `time`, completing any migration of
+    /// it. If it was running on the source CPU, and that is still `Migrating`
+    /// (it hasn't switched to anything else meanwhile), it becomes idle.
+    ///
+    /// Returns the source CPU if its state changed.
+    pub fn finish_migration(&mut self, pid: i32, time: u64) -> Option<usize> {
+        let (from, start, running) = self.migrations.remove(&pid)?;
+        log::debug!(
+            "Migration of {pid} from CPU {from} took {}",
+            time.saturating_sub(start)
+        );
+        if !running {
+            return None;
+        }
+        let state = self.states.get_mut(from)?;
+        if state.state != CpuState::Migrating || self.running[from].is_some() {
+            return None;
+        }
+        state.time = time;
+        state.state = CpuState::Idle;
+        // The statemap tool doesn't deal with None correctly.
+        state.tag = Some("".to_compact_string());
+        Some(from)
+    }
+}
+
+/// Check the CPUs of a migration of `comm:pid` from `sched_migrate_task`.
+/// CPUs at or past `num_cpus` are only valid with `allow_extra_cpus`.
+///
+/// Returns the CPUs if they are valid. If not, this fails, unless `lenient`
+/// is set, in which case it warns and returns `None` so the event can be
+/// skipped.
+pub fn check_migration(
+    comm: &str,
+    pid: i32,
+    from: i32,
+    to: i32,
+    num_cpus: usize,
+    allow_extra_cpus: bool,
+    lenient: bool,
+) -> Result<Option<(usize, usize)>, eyre::Error> {
+    let valid = |cpu: i32| {
+        usize::try_from(cpu)
+            .ok()
+            .filter(|&cpu| allow_extra_cpus || cpu < num_cpus)
+    };
+    if let (Some(from), Some(to)) = (valid(from), valid(to)) {
+        return Ok(Some((from, to)));
+    }
+    let message = format!(
+        "Invalid migration of {comm}:{pid} from CPU {from} to CPU {to}, the trace has \
+         {num_cpus} CPUs"
+    );
+    if lenient {
+        log::warn!("{message}, skipping it");
+        Ok(None)
+    } else {
+        Err(eyre!(message))
+    }
+}
+
+/// Error for a CPU index outside the states vector.
+fn out_of_range(cpu: usize, len: usize) -> eyre::Error {
+    eyre!("CPU index {cpu} is out of range (only {len} CPUs in states vector)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu_states() -> CpuStates {
+        let mut cpus = CpuStates::new((0..2).map(|cpu| format_compact!("{cpu}")));
+        cpus.states[0].state = CpuState::User;
+        cpus.states[0].tag = Some("a:10".into());
+        cpus.running[0] = Some(10);
+        cpus.states[1].state = CpuState::Kernel;
+        cpus.states[1].tag = Some("b:20".into());
+        cpus.running[1] = Some(20);
+        cpus
+    }
+
+    #[test]
+    fn test_migrate_not_running() {
+        let mut cpus = cpu_states();
+        // PID 30 is runnable on CPU 0 but not running, so no state changes.
+        assert!(!cpus.migrate(0, 30, 100).unwrap());
+        assert_eq!(cpus.states[0].state, CpuState::User);
+        assert_eq!(cpus.states[0].tag.as_deref(), Some("a:10"));
+        assert_eq!(cpus.running[0], Some(10));
+        assert_eq!(cpus.states[1].state, CpuState::Kernel);
+        assert_eq!(cpus.states[1].tag.as_deref(), Some("b:20"));
+        // It is still migrating, to tag it when it arrives.
+        assert!(cpus.is_migrating(30));
+        assert_eq!(cpus.finish_migration(30, 150), None);
+        assert!(!cpus.is_migrating(30));
+        assert_eq!(cpus.states[0].state, CpuState::User);
+    }
+
+    #[test]
+    fn test_migrate_running() {
+        let mut cpus = cpu_states();
+        assert!(cpus.migrate(0, 10, 100).unwrap());
+        assert!(cpus.is_migrating(10));
+        assert_eq!(cpus.states[0].state, CpuState::Migrating);
+        assert_eq!(cpus.states[0].tag.as_deref(), Some("a:10"));
+        assert_eq!(cpus.states[0].time, 100);
+        assert_eq!(cpus.running[0], None);
+        // The destination only changes once the task is switched in.
+        assert_eq!(cpus.states[1].state, CpuState::Kernel);
+        assert_eq!(cpus.states[1].tag.as_deref(), Some("b:20"));
+
+        // Switched in on the destination: the source becomes idle.
+        assert_eq!(cpus.finish_migration(10, 150), Some(0));
+        assert_eq!(cpus.states[0].state, CpuState::Idle);
+        assert_eq!(cpus.states[0].time, 150);
+        assert!(!cpus.is_migrating(10));
+        assert_eq!(cpus.finish_migration(10, 200), None);
+    }
+
+    #[test]
+    fn test_migrate_source_switched() {
+        let mut cpus = cpu_states();
+        assert!(cpus.migrate(0, 10, 100).unwrap());
+        // The source CPU runs something else before the task arrives.
+        cpus.states[0].state = CpuState::User;
+        cpus.running[0] = Some(30);
+        assert_eq!(cpus.finish_migration(10, 150), None);
+        assert_eq!(cpus.states[0].state, CpuState::User);
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let mut cpus = cpu_states();
+        assert_eq!(
+            cpus.state_mut(2).unwrap_err().to_string(),
+            "CPU index 2 is out of range (only 2 CPUs in states vector)"
+        );
+        assert!(cpus.save_state(5).is_err());
+        assert!(cpus.restore_state(5).is_err());
+        assert!(cpus.migrate(7, 10, 100).is_err());
+    }
+
+    #[test]
+    fn test_check_migration() {
+        assert_eq!(
+            check_migration("a", 10, 0, 1, 2, false, false).unwrap(),
+            Some((0, 1))
+        );
+        assert_eq!(
+            check_migration("a", 10, 0, 1, 2, false, true).unwrap(),
+            Some((0, 1))
+        );
+        // Past the CPUs in the header, only allowed with allow_extra_cpus.
+        assert_eq!(
+            check_migration("a", 10, 0, 5, 2, true, false).unwrap(),
+            Some((0, 5))
+        );
+        assert_eq!(
+            check_migration("a", 10, 0, 5, 2, false, false)
+                .unwrap_err()
+                .to_string(),
+            "Invalid migration of a:10 from CPU 0 to CPU 5, the trace has 2 CPUs"
+        );
+        assert_eq!(
+            check_migration("a", 10, 0, 5, 2, false, true).unwrap(),
+            None
+        );
+        // Negative CPUs are never valid.
+        assert!(check_migration("a", 10, -1, 0, 2, true, false).is_err());
+        assert_eq!(
+            check_migration("a", 10, -1, 0, 2, true, true).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_annotate_rcu_phase() {
+        let mut cpus = cpu_states();
+        let phase = CompactString::from("Start context switch");
+        assert!(cpus.annotate_rcu_phase(0, &phase).unwrap());
+        assert_eq!(
+            cpus.states[0].tag.as_deref(),
+            Some("a:10 [RCU: Start context switch]")
+        );
+        // Same phase again doesn't change anything.
+        assert!(!cpus.annotate_rcu_phase(0, &phase).unwrap());
+        assert!(
+            cpus.annotate_rcu_phase(0, &"End context switch".into())
+                .unwrap()
+        );
+        assert_eq!(
+            cpus.states[0].tag.as_deref(),
+            Some("a:10 [RCU: End context switch]")
+        );
+        assert!(cpus.annotate_rcu_phase(2, &phase).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+//! When to flush the output while watching a recording, so that a viewer
+//! reading it as it is written gets data promptly even while a large
+//! backlog of records is converted.
+
+use crossbeam_channel::Receiver;
+use crossbeam_channel::TrySendError;
+use std::time::Duration;
+
+/// Flush after a number of records, after an amount of time, or both.
+#[derive(Debug)]
+pub struct FlushPolicy {
+    every: Option<u64>,
+    /// Records since the last flush.
+    records: u64,
+    /// Signals from a timer thread that the interval has passed.
+    ticks: Option<Receiver<()>>,
+}
+
+impl FlushPolicy {
+    pub fn new(every: Option<u64>, interval: Option<Duration>) -> Self {
+        let ticks = interval
+            .filter(|interval| !interval.is_zero())
+            .map(|interval| {
+                // A missed tick means a flush is already due.
+                let (sender, ticks) = crossbeam_channel::bounded(1);
+                std::thread::spawn(move || {
+                    loop {
+                        std::thread::sleep(interval);
+                        if sender.try_send(()) == Err(TrySendError::Disconnected(())) {
+                            break;
+                        }
+                    }
+                });
+                ticks
+            });
+        Self {
+            every: every.filter(|&n| n > 0),
+            records: 0,
+            ticks,
+        }
+    }
+
+    /// Count a converted record, returning true if the output should be
+    /// flushed.
+    pub fn record(&mut self) -> bool {
+        self.records += 1;
+        let tick = self
+            .ticks
+            .as_ref()
+            .is_some_and(|ticks| ticks.try_recv().is_ok());
+        let flush = tick || self.every.is_some_and(|every| self.records >= every);
+        if flush {
+            self.records = 0;
+        }
+        flush
+    }
+
+    /// Note that the output was flushed for another reason, such as having
+    /// caught up with the recording.
+    pub fn flushed(&mut self) {
+        self.records = 0;
+        if let Some(ticks) = &self.ticks {
+            while ticks.try_recv().is_ok() {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flush_every() {
+        let mut policy = FlushPolicy::new(Some(3), None);
+        let flushes: Vec<_> = (0..7).map(|_| policy.record()).collect();
+        assert_eq!(flushes, [false, false, true, false, false, true, false]);
+        policy.flushed();
+        let flushes: Vec<_> = (0..3).map(|_| policy.record()).collect();
+        assert_eq!(flushes, [false, false, true]);
+
+        let mut never = FlushPolicy::new(Some(0), Some(Duration::ZERO));
+        assert!((0..10).all(|_| !never.record()));
+    }
+
+    #[test]
+    fn test_flush_interval() {
+        let mut policy = FlushPolicy::new(None, Some(Duration::from_millis(100)));
+        assert!(!policy.record());
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(policy.record());
+        assert!(!policy.record());
+    }
+}
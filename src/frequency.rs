@@ -0,0 +1,123 @@
+//! Discretisation of CPU frequencies into bands, for the frequency statemap.
+
+use crate::statemap::StatemapInputMetadata;
+use crate::statemap::StatemapInputState;
+use compact_str::CompactString;
+use compact_str::ToCompactString;
+use compact_str::format_compact;
+use std::collections::HashMap;
+
+/// Frequency band a CPU is in. This is the index into the thresholds, with
+/// the last band being above the highest threshold.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde_derive::Serialize)]
+pub struct FreqState(pub usize);
+
+/// Configurable frequency bands.
+#[derive(Debug, Clone)]
+pub struct FreqBands {
+    /// Upper bounds (inclusive) of each band in MHz, in ascending order.
+    thresholds: Vec<u32>,
+}
+
+/// Color of the lowest band.
+const COLD: [u8; 3] = [0x20, 0x40, 0xC0];
+/// Color of the highest band.
+const HOT: [u8; 3] = [0xE0, 0x20, 0x20];
+
+impl FreqBands {
+    pub fn new(mut thresholds: Vec<u32>) -> Result<Self, eyre::Error> {
+        if thresholds.is_empty() {
+            return Err(eyre::eyre!("At least one frequency threshold is needed"));
+        }
+        thresholds.sort_unstable();
+        thresholds.dedup();
+        Ok(Self { thresholds })
+    }
+
+    /// Classify a frequency (in kHz, as reported by the kernel) into a band.
+    pub fn classify(&self, khz: u32) -> FreqState {
+        let mhz = khz / 1000;
+        FreqState(self.thresholds.partition_point(|&t| t < mhz))
+    }
+
+    /// Human readable name of each band.
+    pub fn names(&self) -> Vec<CompactString> {
+        let mut names = Vec::with_capacity(self.thresholds.len() + 1);
+        names.push(format_compact!("≤{}MHz", self.thresholds[0]));
+        for pair in self.thresholds.windows(2) {
+            names.push(format_compact!("{}–{}MHz", pair[0], pair[1]));
+        }
+        names.push(format_compact!(
+            ">{}MHz",
+            self.thresholds[self.thresholds.len() - 1]
+        ));
+        names
+    }
+
+    /// Create the header for the frequency statemap, with a color gradient
+    /// from cold (low frequency) to hot (high frequency).
+    pub fn metadata(&self, start: [u64; 2], host: Option<CompactString>) -> StatemapInputMetadata {
+        let names = self.names();
+        let steps = (names.len() - 1).max(1) as u32;
+        let states: HashMap<_, _> = names
+            .into_iter()
+            .enumerate()
+            .map(|(value, name)| {
+                let channel = |c: usize| {
+                    let (from, to) = (u32::from(COLD[c]), u32::from(HOT[c]));
+                    let pos = value as u32;
+                    (from * (steps - pos) + to * pos) / steps
+                };
+                let color =
+                    format_compact!("#{:02X}{:02X}{:02X}", channel(0), channel(1), channel(2));
+                (
+                    name,
+                    StatemapInputState {
+                        color: Some(color),
+                        value,
+                    },
+                )
+            })
+            .collect();
+        StatemapInputMetadata {
+            start,
+            title: "CPU frequency".to_compact_string(),
+            host,
+            entityKind: Some("CPU".to_compact_string()),
+            arch: None,
+            states,
+            cycleFrequencyGHz: None,
+            schemaVersion: None,
+            entities: None,
+            duration_ns: None,
+            event_count: None,
+            extensions: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_freq_bands() {
+        let bands = FreqBands::new(vec![1200, 800, 2000]).unwrap();
+        assert_eq!(bands.classify(400_000), FreqState(0));
+        assert_eq!(bands.classify(800_000), FreqState(0));
+        assert_eq!(bands.classify(800_001), FreqState(0));
+        assert_eq!(bands.classify(801_000), FreqState(1));
+        assert_eq!(bands.classify(2_000_000), FreqState(2));
+        assert_eq!(bands.classify(3_000_000), FreqState(3));
+        assert_eq!(
+            bands.names(),
+            vec!["≤800MHz", "800–1200MHz", "1200–2000MHz", ">2000MHz"]
+        );
+        let metadata = bands.metadata([0, 0], None);
+        assert_eq!(metadata.states["≤800MHz"].color.as_deref(), Some("#2040C0"));
+        assert_eq!(
+            metadata.states[">2000MHz"].color.as_deref(),
+            Some("#E02020")
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! Detection of periods where all CPUs are idle at the same time.
+
+/// Tracks which CPUs are idle to find system wide idle periods.
+#[derive(Debug, Clone)]
+pub struct IdleGapTracker {
+    /// Whether each CPU is idle. CPUs start out as not idle, since we don't
+    /// know what they are doing until the first event.
+    idle: Vec<bool>,
+    /// Number of CPUs currently idle.
+    num_idle: usize,
+    /// When the current system wide idle period started, if any.
+    all_idle_since: Option<u64>,
+    /// Warn about idle periods longer than this.
+    threshold: Option<u64>,
+    /// Total time all CPUs were idle at the same time.
+    pub total_idle_ns: u64,
+}
+
+impl IdleGapTracker {
+    pub fn new(num_cpus: usize, threshold: Option<u64>) -> Self {
+        Self {
+            idle: vec![false; num_cpus],
+            num_idle: 0,
+            all_idle_since: None,
+            threshold,
+            total_idle_ns: 0,
+        }
+    }
+
+    /// Track `num_cpus` CPUs, if that is more than currently tracked. New
+    /// CPUs start out as not idle.
+    pub fn resize(&mut self, num_cpus: usize) {
+        if num_cpus > self.idle.len() {
+            self.idle.resize(num_cpus, false);
+        }
+    }
+
+    /// Update whether `cpu` is idle as of `time`.
+    pub fn update(&mut self, cpu: usize, is_idle: bool, time: u64) {
+        if self.idle[cpu] == is_idle {
+            return;
+        }
+        self.idle[cpu] = is_idle;
+        if is_idle {
+            self.num_idle += 1;
+            if self.num_idle == self.idle.len() {
+                self.all_idle_since = Some(time);
+            }
+        } else {
+            self.num_idle -= 1;
+            if let Some(since) = self.all_idle_since.take() {
+                let gap = time.saturating_sub(since);
+                self.total_idle_ns += gap;
+                if self.threshold.is_some_and(|threshold| gap > threshold) {
+                    log::warn!(
+                        "All CPUs were idle for {gap} ns (from {since} ns to {time} ns into the \
+                         trace)"
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_gaps() {
+        let mut tracker = IdleGapTracker::new(2, Some(50));
+        tracker.update(0, true, 10);
+        tracker.update(1, true, 20);
+        tracker.update(1, true, 25);
+        tracker.update(0, false, 100);
+        assert_eq!(tracker.total_idle_ns, 80);
+        // Only CPU 1 was idle from 100 to 200, that doesn't count.
+        tracker.update(0, true, 200);
+        assert_eq!(tracker.total_idle_ns, 80);
+        tracker.update(1, false, 300);
+        assert_eq!(tracker.total_idle_ns, 180);
+        tracker.update(1, true, 310);
+        tracker.update(1, false, 330);
+        assert_eq!(tracker.total_idle_ns, 200);
+    }
+}
@@ -0,0 +1,618 @@
+//! Reading of the input file, either buffered, memory mapped or while
+//! `perf record` is still writing it.
+
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+use eyre::WrapErr;
+use eyre::eyre;
+use memmap2::Mmap;
+use notify::EventKind;
+use notify::RecommendedWatcher;
+use notify::RecursiveMode;
+use notify::Watcher;
+use notify::event::AccessKind;
+use notify::event::AccessMode;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+/// Offset of the data section size in the perf.data header.
+const DATA_SIZE_OFFSET: u64 = 48;
+
+/// Input file for `PerfFileReader`.
+///
+/// `PerfFileReader::parse_file` takes any `Read + Seek` rather than a byte
+/// slice, so a memory map is read through a `Cursor`.
+#[derive(Debug)]
+pub enum InputFile {
+    Buffered(BufReader<File>),
+    Mapped(Cursor<Mmap>),
+    Live(LiveFile),
+}
+
+impl InputFile {
+    /// Open `path`, memory mapping it if `mmap` is set.
+    pub fn open(path: &str, mmap: bool) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        if mmap {
+            // SAFETY: The mapping is read-only. If the file is truncated
+            // while we run we may get SIGBUS, same as any other tool reading
+            // a perf.data file that is still being written.
+            let map = unsafe { Mmap::map(&file)? };
+            Ok(Self::Mapped(Cursor::new(map)))
+        } else {
+            Ok(Self::Buffered(BufReader::new(file)))
+        }
+    }
+}
+
+/// Whether `perf record` has finished writing the perf.data file at `path`.
+///
+/// While recording, the size of the data section in the header is zero. It
+/// is only filled in (along with the feature sections after the data) when
+/// the recording stops, so until then there is nothing we can read.
+pub fn is_complete(path: &Path) -> std::io::Result<bool> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+    let mut size = [0; 8];
+    match file.read_exact(&mut size) {
+        // Zero in either byte order.
+        Ok(()) => Ok(size != [0; 8]),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Size of the perf.data header.
+const HEADER_SIZE: u64 = 104;
+/// Offset of the attribute size in the perf.data header.
+const ATTR_SIZE_OFFSET: u64 = 16;
+/// Offset of the attribute section in the perf.data header.
+const ATTR_SECTION_OFFSET: u64 = 24;
+/// Offset of the data section offset in the perf.data header.
+const DATA_OFFSET_OFFSET: u64 = 40;
+/// Offset of the event types section in the perf.data header.
+const EVENT_TYPES_OFFSET: u64 = 56;
+/// Offset of the bitmap of feature sections in the perf.data header.
+const FEATURES_OFFSET: u64 = 72;
+/// Size of a record header, the smallest a record can be.
+const RECORD_HEADER_SIZE: u64 = 8;
+/// How often to check for Ctrl-C when the file isn't changing.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn read_u64(bytes: &[u8], big_endian: bool) -> u64 {
+    if big_endian {
+        BigEndian::read_u64(bytes)
+    } else {
+        LittleEndian::read_u64(bytes)
+    }
+}
+
+fn write_u64(bytes: &mut [u8], value: u64, big_endian: bool) {
+    if big_endian {
+        BigEndian::write_u64(bytes, value);
+    } else {
+        LittleEndian::write_u64(bytes, value);
+    }
+}
+
+/// A perf.data file that `perf record` is still writing.
+///
+/// The header is served with the feature sections left out (they are only
+/// written at the end), the attributes as event types and a data section
+/// that never ends. Reads of the
+/// data section stop with `WouldBlock` at the end of the last complete
+/// record, at which point `PerfRecordIter::next_record` can be called again
+/// once more records have been written.
+#[derive(Debug)]
+pub struct LiveFile {
+    reader: BufReader<File>,
+    /// Separate handle for finding the end of the complete records, so that
+    /// it doesn't throw away the buffer of `reader`.
+    scanner: BufReader<File>,
+    header: [u8; HEADER_SIZE as usize],
+    big_endian: bool,
+    data_offset: u64,
+    /// End of the last complete record found.
+    available: u64,
+    /// Position of the next read. `PerfFileReader` seeks to where the
+    /// feature sections of a never ending data section would be, so `reader`
+    /// only follows when reading.
+    pos: u64,
+    reader_pos: u64,
+}
+
+impl LiveFile {
+    /// Open `path`, or return `None` if `perf record` hasn't written the
+    /// header yet.
+    pub fn open(path: &Path) -> std::io::Result<Option<Self>> {
+        let file = File::open(path)?;
+        let mut header = [0; HEADER_SIZE as usize];
+        match (&file).read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let big_endian = match &header[..8] {
+            b"PERFILE2" => false,
+            b"2ELIFREP" => true,
+            _ => return Ok(None),
+        };
+        let data_offset = read_u64(&header[DATA_OFFSET_OFFSET as usize..], big_endian);
+        if data_offset < HEADER_SIZE || file.metadata()?.len() < data_offset {
+            return Ok(None);
+        }
+        // Large enough to never reach, without overflowing when adding the
+        // offset.
+        write_u64(
+            &mut header[DATA_SIZE_OFFSET as usize..],
+            u64::MAX / 2,
+            big_endian,
+        );
+        // Without the feature sections, only the old event types section
+        // says which event IDs belong to which attribute. Its entries are
+        // the same as in the attribute section, only with the size of the
+        // IDs section not included in the attribute size.
+        let attr_size = read_u64(&header[ATTR_SIZE_OFFSET as usize..], big_endian);
+        let (attrs, event_types) = header[ATTR_SECTION_OFFSET as usize..FEATURES_OFFSET as usize]
+            .split_at_mut(EVENT_TYPES_OFFSET as usize - ATTR_SECTION_OFFSET as usize);
+        event_types.copy_from_slice(&attrs[..16]);
+        write_u64(
+            &mut header[ATTR_SIZE_OFFSET as usize..],
+            attr_size.saturating_sub(16),
+            big_endian,
+        );
+        header[FEATURES_OFFSET as usize..].fill(0);
+        Ok(Some(Self {
+            reader: BufReader::new(file),
+            scanner: BufReader::new(File::open(path)?),
+            header,
+            big_endian,
+            data_offset,
+            available: data_offset,
+            pos: 0,
+            reader_pos: HEADER_SIZE,
+        }))
+    }
+
+    /// Move `available` past the records that have been completely written.
+    fn find_records(&mut self) -> std::io::Result<()> {
+        let mut end = self.scanner.get_ref().metadata()?.len();
+        // Once the recording is done, the data section is followed by the
+        // feature sections.
+        let mut size = [0; 8];
+        self.scanner.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+        self.scanner.read_exact(&mut size)?;
+        let data_size = read_u64(&size, self.big_endian);
+        if data_size != 0 {
+            end = end.min(self.data_offset + data_size);
+        }
+        self.scanner.seek(SeekFrom::Start(self.available))?;
+        let mut header = [0; RECORD_HEADER_SIZE as usize];
+        while self.available + RECORD_HEADER_SIZE <= end {
+            self.scanner.read_exact(&mut header)?;
+            let size = u64::from(if self.big_endian {
+                BigEndian::read_u16(&header[6..])
+            } else {
+                LittleEndian::read_u16(&header[6..])
+            });
+            // Too small sizes are what comes after the records while perf
+            // record is writing the feature sections.
+            if size < RECORD_HEADER_SIZE || self.available + size > end {
+                break;
+            }
+            self.scanner
+                .seek_relative((size - RECORD_HEADER_SIZE) as i64)?;
+            self.available += size;
+        }
+        Ok(())
+    }
+}
+
+impl Read for LiveFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos < HEADER_SIZE {
+            let len = (&self.header[self.pos as usize..]).read(buf)?;
+            self.pos += len as u64;
+            return Ok(len);
+        }
+        let end = if self.pos < self.data_offset {
+            self.data_offset
+        } else {
+            if self.pos >= self.available {
+                self.find_records()?;
+            }
+            self.available
+        };
+        if self.pos >= end {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        if self.pos != self.reader_pos {
+            self.reader
+                .seek_relative(self.pos as i64 - self.reader_pos as i64)?;
+        }
+        let max = usize::try_from(end - self.pos).unwrap_or(usize::MAX);
+        let len = buf.len().min(max);
+        let len = self.reader.read(&mut buf[..len])?;
+        self.pos += len as u64;
+        self.reader_pos = self.pos;
+        Ok(len)
+    }
+}
+
+impl Seek for LiveFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::Current(offset) => self
+                .pos
+                .checked_add_signed(offset)
+                .ok_or(std::io::ErrorKind::InvalidInput)?,
+            // The end keeps moving.
+            SeekFrom::End(_) => return Err(std::io::ErrorKind::Unsupported.into()),
+        };
+        Ok(self.pos)
+    }
+}
+
+/// What happened while waiting for a recording to write more records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    /// More records may have been written.
+    Growing,
+    /// `perf record` finished writing the file.
+    Finished,
+    /// Stopped by Ctrl-C, or `perf record` closed the file without finishing
+    /// it.
+    Stopped,
+}
+
+/// A perf.data file that `perf record` is writing, watched for changes.
+#[derive(Debug)]
+pub struct Recording {
+    path: PathBuf,
+    events: Receiver<notify::Result<notify::Event>>,
+    /// Only kept to keep the events coming.
+    _watcher: RecommendedWatcher,
+    /// Set on Ctrl-C.
+    stop: Arc<AtomicBool>,
+}
+
+impl Recording {
+    /// Watch the perf.data file at `path`, until it is finished or `stop`
+    /// is set.
+    pub fn watch(path: &Path, stop: Arc<AtomicBool>) -> Result<Self, eyre::Error> {
+        let (sender, events) = std::sync::mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        watcher
+            .watch(path, RecursiveMode::NonRecursive)
+            .wrap_err_with(|| format!("Failed to watch \"{}\"", path.display()))?;
+        Ok(Self {
+            path: path.to_owned(),
+            events,
+            _watcher: watcher,
+            stop,
+        })
+    }
+
+    /// Open the file once `perf record` has written the header. If it has
+    /// already finished, the file is read as usual.
+    pub fn open(&self) -> Result<InputFile, eyre::Error> {
+        let mut waiting = false;
+        loop {
+            if is_complete(&self.path)? {
+                return Ok(InputFile::Buffered(BufReader::new(File::open(&self.path)?)));
+            }
+            if let Some(file) = LiveFile::open(&self.path)? {
+                return Ok(InputFile::Live(file));
+            }
+            if !waiting {
+                log::info!(
+                    "Waiting for perf record to start writing \"{}\"",
+                    self.path.display()
+                );
+                waiting = true;
+            }
+            if self.wait()? == Progress::Stopped {
+                return Err(eyre!(
+                    "Stopped before perf record started writing \"{}\"",
+                    self.path.display()
+                ));
+            }
+        }
+    }
+
+    /// Wait for `perf record` to write more to the file.
+    pub fn wait(&self) -> Result<Progress, eyre::Error> {
+        loop {
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok(Progress::Stopped);
+            }
+            if is_complete(&self.path)? {
+                return Ok(Progress::Finished);
+            }
+            let first = match self.events.recv_timeout(POLL_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(eyre!("Stopped watching \"{}\"", self.path.display()));
+                }
+            };
+            // Every write is an event, so handle all that are queued at once.
+            let mut growing = false;
+            let mut closed = false;
+            for event in std::iter::once(first).chain(self.events.try_iter()) {
+                match event?.kind {
+                    EventKind::Remove(_) => {
+                        return Err(eyre!(
+                            "\"{}\" was removed before perf record finished writing it",
+                            self.path.display()
+                        ));
+                    }
+                    EventKind::Access(AccessKind::Close(AccessMode::Write)) => closed = true,
+                    EventKind::Modify(_) => growing = true,
+                    _ => {}
+                }
+            }
+            if closed {
+                if is_complete(&self.path)? {
+                    return Ok(Progress::Finished);
+                }
+                log::warn!(
+                    "perf record closed \"{}\" without finishing it",
+                    self.path.display()
+                );
+                return Ok(Progress::Stopped);
+            }
+            if growing {
+                return Ok(Progress::Growing);
+            }
+        }
+    }
+}
+
+impl Read for InputFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Buffered(reader) => reader.read(buf),
+            Self::Mapped(cursor) => cursor.read(buf),
+            Self::Live(file) => file.read(buf),
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        match self {
+            Self::Buffered(reader) => reader.read_exact(buf),
+            Self::Mapped(cursor) => cursor.read_exact(buf),
+            Self::Live(file) => file.read_exact(buf),
+        }
+    }
+}
+
+impl Seek for InputFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Self::Buffered(reader) => reader.seek(pos),
+            Self::Mapped(cursor) => cursor.seek(pos),
+            Self::Live(file) => file.seek(pos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapped_read() {
+        let path = std::env::temp_dir().join(format!("input-test-{}", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+        let path_str = path.to_str().unwrap();
+        for mmap in [false, true] {
+            let mut input = InputFile::open(path_str, mmap).unwrap();
+            input.seek(SeekFrom::Start(4)).unwrap();
+            let mut buf = [0; 3];
+            input.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"456");
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Write the header of a perf.data file that is still being recorded,
+    /// with the data section right after it.
+    fn recording_header(path: &Path) {
+        let mut header = vec![0u8; HEADER_SIZE as usize];
+        header[..8].copy_from_slice(b"PERFILE2");
+        header[DATA_OFFSET_OFFSET as usize] = HEADER_SIZE as u8;
+        header[FEATURES_OFFSET as usize] = 0xff;
+        std::fs::write(path, &header).unwrap();
+    }
+
+    /// A record of `size` bytes, with only the size filled in.
+    fn record(size: u16) -> Vec<u8> {
+        let mut record = vec![0u8; size.into()];
+        record[6..8].copy_from_slice(&size.to_le_bytes());
+        record
+    }
+
+    fn append(path: &Path, data: &[u8]) {
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        std::io::Write::write_all(&mut file, data).unwrap();
+    }
+
+    #[test]
+    fn test_live_file() {
+        let path = std::env::temp_dir().join(format!("input-live-{}", std::process::id()));
+        std::fs::write(&path, b"PERFILE2").unwrap();
+        assert!(LiveFile::open(&path).unwrap().is_none());
+        recording_header(&path);
+        let mut live = LiveFile::open(&path).unwrap().unwrap();
+
+        let mut header = [0; HEADER_SIZE as usize];
+        live.read_exact(&mut header).unwrap();
+        assert_eq!(
+            read_u64(&header[DATA_SIZE_OFFSET as usize..], false),
+            u64::MAX / 2
+        );
+        assert_eq!(header[FEATURES_OFFSET as usize..], [0; 32]);
+        // Seeking past the end is only done by `PerfFileReader` to look for
+        // feature sections, which there are none of.
+        live.seek(SeekFrom::Start(u64::MAX / 2)).unwrap();
+        live.seek(SeekFrom::Start(HEADER_SIZE)).unwrap();
+
+        // Only complete records are read.
+        let mut partial = record(16);
+        let rest = partial.split_off(12);
+        append(&path, &record(8));
+        append(&path, &partial);
+        let mut buf = [0; 16];
+        live.read_exact(&mut buf[..8]).unwrap();
+        let err = live.read_exact(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        append(&path, &rest);
+        live.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..], record(16));
+
+        // Once finished, what comes after the data section isn't read.
+        append(&path, &record(8));
+        append(&path, &record(24));
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(DATA_SIZE_OFFSET)).unwrap();
+        std::io::Write::write_all(&mut file, &32u64.to_le_bytes()).unwrap();
+        assert!(is_complete(&path).unwrap());
+        live.read_exact(&mut buf[..8]).unwrap();
+        let err = live.read_exact(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_recording() {
+        let path = std::env::temp_dir().join(format!("input-recording-{}", std::process::id()));
+        recording_header(&path);
+        let stop = Arc::new(AtomicBool::new(false));
+        let recording = Recording::watch(&path, Arc::clone(&stop)).unwrap();
+        assert!(matches!(recording.open().unwrap(), InputFile::Live(_)));
+
+        // Kept open like perf record does, since closing it ends the
+        // recording.
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            std::io::Write::write_all(&mut file, &record(8)).unwrap();
+            file
+        });
+        assert_eq!(recording.wait().unwrap(), Progress::Growing);
+        let file = writer.join().unwrap();
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(recording.wait().unwrap(), Progress::Stopped);
+
+        drop(file);
+        stop.store(false, Ordering::Relaxed);
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(DATA_SIZE_OFFSET)).unwrap();
+        std::io::Write::write_all(&mut file, &8u64.to_le_bytes()).unwrap();
+        assert_eq!(recording.wait().unwrap(), Progress::Finished);
+        assert!(matches!(recording.open().unwrap(), InputFile::Buffered(_)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_live_records() {
+        use linux_perf_data::PerfFileReader;
+        use linux_perf_data::PerfFileRecord;
+
+        let path = std::env::temp_dir().join(format!("input-records-{}", std::process::id()));
+        // Two tracepoints with one event ID each, then the data section.
+        let attrs = HEADER_SIZE;
+        let ids = attrs + 2 * 80;
+        let data = ids + 2 * 8;
+        let mut file = vec![0u8; data as usize];
+        file[..8].copy_from_slice(b"PERFILE2");
+        for (offset, value) in [
+            (8, HEADER_SIZE),
+            (ATTR_SIZE_OFFSET, 80),
+            (ATTR_SECTION_OFFSET, attrs),
+            (ATTR_SECTION_OFFSET + 8, 2 * 80),
+            (DATA_OFFSET_OFFSET, data),
+        ] {
+            write_u64(&mut file[offset as usize..], value, false);
+        }
+        for idx in 0..2 {
+            let attr = &mut file[(attrs + idx * 80) as usize..][..80];
+            // PERF_TYPE_TRACEPOINT, PERF_ATTR_SIZE_VER0, tracepoint ID and
+            // PERF_SAMPLE_IDENTIFIER.
+            attr[..4].copy_from_slice(&2u32.to_le_bytes());
+            attr[4..8].copy_from_slice(&64u32.to_le_bytes());
+            write_u64(&mut attr[8..], 100 + idx, false);
+            write_u64(&mut attr[24..], 1 << 16, false);
+            write_u64(&mut attr[64..], ids + idx * 8, false);
+            write_u64(&mut attr[72..], 8, false);
+            write_u64(&mut file[(ids + idx * 8) as usize..], 1000 + idx, false);
+        }
+        std::fs::write(&path, &file).unwrap();
+        let sample = |id: u64| {
+            let mut record = record(16);
+            record[..4].copy_from_slice(&9u32.to_le_bytes());
+            write_u64(&mut record[8..], id, false);
+            record
+        };
+        let mut finished_round = record(8);
+        finished_round[..4].copy_from_slice(&68u32.to_le_bytes());
+
+        let live = LiveFile::open(&path).unwrap().unwrap();
+        let PerfFileReader {
+            mut perf_file,
+            mut record_iter,
+        } = PerfFileReader::parse_file(InputFile::Live(live)).unwrap();
+        let mut read = |perf_file: &mut _| {
+            let mut attrs = Vec::new();
+            loop {
+                match record_iter.next_record(perf_file) {
+                    Ok(Some(PerfFileRecord::EventRecord { attr_index, .. })) => {
+                        attrs.push(attr_index);
+                    }
+                    Ok(_) => {}
+                    Err(linux_perf_data::Error::IoError(err))
+                        if err.kind() == std::io::ErrorKind::WouldBlock =>
+                    {
+                        return attrs;
+                    }
+                    Err(err) => panic!("{err}"),
+                }
+            }
+        };
+        assert!(read(&mut perf_file).is_empty());
+        for record in [
+            sample(1001),
+            sample(1000),
+            finished_round.clone(),
+            sample(1001),
+        ] {
+            append(&path, &record);
+        }
+        append(&path, &finished_round[..4]);
+        let mut attrs = read(&mut perf_file);
+        append(&path, &finished_round[4..]);
+        attrs.extend(read(&mut perf_file));
+        // Event IDs are mapped to their attributes. The last sample is held
+        // back until its round is finished.
+        assert_eq!(attrs, [1, 0]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
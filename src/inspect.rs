@@ -0,0 +1,210 @@
+//! Summary of what is in a perf.data file, without converting it.
+
+use crate::input::InputFile;
+use crate::parsers::ClockData;
+use compact_str::CompactString;
+use compact_str::ToCompactString;
+use eyre::Context;
+use linux_perf_data::PerfFileReader;
+use linux_perf_data::PerfFileRecord;
+use linux_perf_data::linux_perf_event_reader::RawData;
+use linux_perf_data::linux_perf_event_reader::RecordType;
+use serde_derive::Serialize;
+use std::io::Write;
+
+/// Output format of `inspect`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap_derive::ValueEnum)]
+pub enum InspectFormat {
+    /// Human readable text
+    #[default]
+    Text,
+    /// A single JSON object
+    Json,
+}
+
+/// A feature section of the file header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FeatureSection {
+    pub name: CompactString,
+    pub size: usize,
+}
+
+/// The clock reference from the `CLOCK_DATA` feature.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClockInfo {
+    pub version: u32,
+    pub clockid: u32,
+    pub wall_clock_ns: u64,
+    pub clockid_time_ns: u64,
+}
+
+/// Summary of a perf.data file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Inspection {
+    pub features: Vec<FeatureSection>,
+    pub hostname: Option<CompactString>,
+    pub cmdline: Option<Vec<CompactString>>,
+    pub arch: Option<CompactString>,
+    pub nr_cpus_available: Option<u32>,
+    pub nr_cpus_online: Option<u32>,
+    pub samples: u64,
+    /// First and last sample time, in ns.
+    pub sample_time_range: Option<[u64; 2]>,
+    pub clock_data: Option<ClockInfo>,
+}
+
+impl Inspection {
+    /// Read the header of `input`, and count the samples in it.
+    pub fn from_file(input: &str) -> Result<Self, eyre::Error> {
+        let PerfFileReader {
+            mut perf_file,
+            mut record_iter,
+        } = PerfFileReader::parse_file(InputFile::open(input, false)?)?;
+        let features = perf_file
+            .features()
+            .iter()
+            .map(|feature| FeatureSection {
+                name: feature.to_compact_string(),
+                size: perf_file
+                    .feature_section_data(feature)
+                    .map_or(0, <[u8]>::len),
+            })
+            .collect();
+        let clock_data = perf_file
+            .feature_section_data(linux_perf_data::Feature::CLOCK_DATA)
+            .map(|data| ClockData::parse(RawData::Single(data), perf_file.endian()))
+            .transpose()
+            .wrap_err("Failed to parse CLOCK_DATA feature")?
+            .map(|clock| ClockInfo {
+                version: clock.version,
+                clockid: clock.common.clockid,
+                wall_clock_ns: clock.common.wall_clock_ns,
+                clockid_time_ns: clock.common.clockid_time_ns,
+            });
+        let nr_cpus = perf_file.nr_cpus()?;
+        let mut inspection = Self {
+            features,
+            hostname: perf_file.hostname()?.map(CompactString::from),
+            cmdline: perf_file
+                .cmdline()?
+                .map(|args| args.into_iter().map(CompactString::from).collect()),
+            arch: perf_file.arch()?.map(CompactString::from),
+            nr_cpus_available: nr_cpus.as_ref().map(|n| n.nr_cpus_available),
+            nr_cpus_online: nr_cpus.as_ref().map(|n| n.nr_cpus_online),
+            samples: 0,
+            sample_time_range: perf_file
+                .sample_time_range()?
+                .map(|range| [range.first_sample_time, range.last_sample_time]),
+            clock_data,
+        };
+        while let Some(record) = record_iter.next_record(&mut perf_file)? {
+            if let PerfFileRecord::EventRecord { record, .. } = record
+                && record.record_type == RecordType::SAMPLE
+            {
+                inspection.samples += 1;
+            }
+        }
+        Ok(inspection)
+    }
+
+    /// Write the summary in the given format.
+    pub fn write(&self, writer: &mut dyn Write, format: InspectFormat) -> Result<(), eyre::Error> {
+        match format {
+            InspectFormat::Text => self.write_text(writer),
+            InspectFormat::Json => {
+                serde_json::to_writer_pretty(&mut *writer, self)?;
+                writeln!(writer)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn write_text(&self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        writeln!(writer, "features:")?;
+        for feature in &self.features {
+            writeln!(writer, "  {}: {} bytes", feature.name, feature.size)?;
+        }
+        let unknown = "(unknown)";
+        writeln!(
+            writer,
+            "hostname: {}",
+            self.hostname.as_deref().unwrap_or(unknown)
+        )?;
+        match &self.cmdline {
+            Some(cmdline) => writeln!(writer, "cmdline: {}", cmdline.join(" "))?,
+            None => writeln!(writer, "cmdline: {unknown}")?,
+        }
+        writeln!(writer, "arch: {}", self.arch.as_deref().unwrap_or(unknown))?;
+        match (self.nr_cpus_available, self.nr_cpus_online) {
+            (Some(available), Some(online)) => {
+                writeln!(writer, "cpus: {online} online, {available} available")?;
+            }
+            _ => writeln!(writer, "cpus: {unknown}")?,
+        }
+        writeln!(writer, "samples: {}", self.samples)?;
+        match self.sample_time_range {
+            Some([first, last]) => writeln!(
+                writer,
+                "sample_time_range: {first}..{last} ns ({:.3} s)",
+                (last - first) as f64 / 1e9
+            )?,
+            None => writeln!(writer, "sample_time_range: {unknown}")?,
+        }
+        match &self.clock_data {
+            Some(clock) => writeln!(
+                writer,
+                "clock_data: version {}, clockid {}, wall clock {} ns at {} ns",
+                clock.version, clock.clockid, clock.wall_clock_ns, clock.clockid_time_ns
+            )?,
+            None => writeln!(writer, "clock_data: {unknown}")?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inspection() -> Inspection {
+        Inspection {
+            features: vec![
+                FeatureSection {
+                    name: "HOSTNAME".into(),
+                    size: 12,
+                },
+                FeatureSection {
+                    name: "CLOCK_DATA".into(),
+                    size: 28,
+                },
+            ],
+            hostname: Some("buildhost".into()),
+            cmdline: Some(vec!["perf".into(), "record".into(), "-a".into()]),
+            arch: Some("x86_64".into()),
+            nr_cpus_available: Some(8),
+            nr_cpus_online: Some(4),
+            samples: 1234,
+            sample_time_range: Some([1_000_000_000, 3_500_000_000]),
+            clock_data: Some(ClockInfo {
+                version: 1,
+                clockid: 4,
+                wall_clock_ns: 1_700_000_000_000_000_000,
+                clockid_time_ns: 999_000_000,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_text() {
+        let mut out = Vec::new();
+        inspection().write(&mut out, InspectFormat::Text).unwrap();
+        insta::assert_snapshot!(String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn test_json() {
+        let mut out = Vec::new();
+        inspection().write(&mut out, InspectFormat::Json).unwrap();
+        insta::assert_snapshot!(String::from_utf8(out).unwrap());
+    }
+}
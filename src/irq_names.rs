@@ -0,0 +1,108 @@
+//! IRQ device names from `/proc/interrupts`, for when the tracepoint doesn't
+//! name the IRQ.
+
+use compact_str::CompactString;
+use eyre::Context;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Device names of IRQs, loaded from a file in `/proc/interrupts` format.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IrqNames {
+    names: HashMap<i32, CompactString>,
+}
+
+impl IrqNames {
+    /// Parse `/proc/interrupts` format: a header with one column per CPU,
+    /// then `<irq>: <count per CPU> <chip> [<hwirq>-<type>] <devices>`.
+    ///
+    /// Only numbered IRQs with a device are kept, the architecture specific
+    /// ones (`NMI`, `LOC`, ...) never show up in `irq_handler_entry`.
+    pub fn parse(contents: &str) -> Self {
+        let mut lines = contents.lines();
+        let num_cpus = lines
+            .next()
+            .map_or(0, |header| header.split_ascii_whitespace().count());
+        let names = lines
+            .filter_map(|line| {
+                let (irq, rest) = line.split_once(':')?;
+                let irq = irq.trim().parse().ok()?;
+                let mut fields = rest.split_ascii_whitespace().skip(num_cpus);
+                // Chip name, with the type appended on old kernels.
+                fields.next()?;
+                let mut fields = fields.peekable();
+                fields.next_if(|field| is_hwirq_type(field));
+                let name: Vec<_> = fields.collect();
+                (!name.is_empty()).then(|| (irq, CompactString::from(name.join(" "))))
+            })
+            .collect();
+        Self { names }
+    }
+
+    /// Load IRQ names from a file, such as `/proc/interrupts` itself.
+    pub fn from_file(path: &Path) -> Result<Self, eyre::Error> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read \"{}\"", path.display()))?;
+        Ok(Self::parse(&contents))
+    }
+
+    /// Get the name of `irq`, if known.
+    pub fn get(&self, irq: i32) -> Option<&CompactString> {
+        self.names.get(&irq)
+    }
+
+    /// Name to show for `irq`, given the name from the tracepoint. Falls back
+    /// to the name from the map if the tracepoint name is empty or generic
+    /// (`irqN`).
+    pub fn name<'a>(&'a self, irq: i32, name: &'a str) -> &'a str {
+        let generic = name
+            .strip_prefix("irq")
+            .is_some_and(|num| !num.is_empty() && num.bytes().all(|b| b.is_ascii_digit()));
+        if name.is_empty() || generic {
+            self.get(irq).map_or(name, CompactString::as_str)
+        } else {
+            name
+        }
+    }
+}
+
+/// Whether a field is the `<hwirq>-<type>` column (such as `2-edge`).
+fn is_hwirq_type(field: &str) -> bool {
+    field
+        .split_once('-')
+        .is_some_and(|(hwirq, _)| !hwirq.is_empty() && hwirq.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_irq_names() {
+        let names = IrqNames::parse(indoc::indoc! {"
+                       CPU0       CPU1
+              0:         46          0   IO-APIC    2-edge      timer
+              9:          0          0   IO-APIC    9-fasteoi   acpi
+             16:          0          0   IO-APIC   16-fasteoi   ehci_hcd:usb1, i801_smbus
+             24:          0          0   PCI-MSI 65536-edge
+             25:         10          5   PCI-MSI-edge      eth0
+            NMI:          0          0   Non-maskable interrupts
+        "});
+        assert_eq!(names.get(0).map(CompactString::as_str), Some("timer"));
+        assert_eq!(names.get(9).map(CompactString::as_str), Some("acpi"));
+        assert_eq!(
+            names.get(16).map(CompactString::as_str),
+            Some("ehci_hcd:usb1, i801_smbus")
+        );
+        // No device.
+        assert_eq!(names.get(24), None);
+        // Old format, without a hwirq column.
+        assert_eq!(names.get(25).map(CompactString::as_str), Some("eth0"));
+
+        assert_eq!(names.name(0, ""), "timer");
+        assert_eq!(names.name(0, "irq0"), "timer");
+        assert_eq!(names.name(0, "hpet"), "hpet");
+        assert_eq!(names.name(0, "irqbalance"), "irqbalance");
+        assert_eq!(names.name(7, "irq7"), "irq7");
+    }
+}
@@ -0,0 +1,64 @@
+//! Detection of hardware IRQs arriving while their threaded handler is still
+//! running.
+//!
+//! With threaded IRQs, the hard IRQ handler only wakes an `irq/<N>-<name>`
+//! kernel thread which does the actual work. If the IRQ fires again before
+//! that thread has finished, the device is delivering IRQs faster than they
+//! are handled.
+
+/// Tracks which IRQ thread (if any) is running on each CPU.
+#[derive(Debug, Clone)]
+pub struct IrqThreadTracker {
+    /// IRQ number of the IRQ thread running on each CPU.
+    threads: Vec<Option<i32>>,
+}
+
+impl IrqThreadTracker {
+    pub fn new(num_cpus: usize) -> Self {
+        Self {
+            threads: vec![None; num_cpus],
+        }
+    }
+
+    /// Track `num_cpus` CPUs, if that is more than currently tracked.
+    pub fn resize(&mut self, num_cpus: usize) {
+        if num_cpus > self.threads.len() {
+            self.threads.resize(num_cpus, None);
+        }
+    }
+
+    /// Record that the thread `comm` was switched in on `cpu`.
+    pub fn switch(&mut self, cpu: usize, comm: &str) {
+        self.threads[cpu] = irq_of_thread(comm);
+    }
+
+    /// The CPU the thread for `irq` is running on, if any.
+    pub fn running_on(&self, irq: i32) -> Option<usize> {
+        self.threads.iter().position(|&thread| thread == Some(irq))
+    }
+}
+
+/// Get the IRQ number from the name of an IRQ thread (`irq/<N>-<name>`).
+fn irq_of_thread(comm: &str) -> Option<i32> {
+    let (irq, _name) = comm.strip_prefix("irq/")?.split_once('-')?;
+    irq.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_irq_threads() {
+        assert_eq!(irq_of_thread("irq/42-eth0"), Some(42));
+        assert_eq!(irq_of_thread("irq/work"), None);
+        assert_eq!(irq_of_thread("kworker/0:1"), None);
+
+        let mut tracker = IrqThreadTracker::new(2);
+        tracker.switch(1, "irq/42-eth0");
+        assert_eq!(tracker.running_on(42), Some(1));
+        assert_eq!(tracker.running_on(43), None);
+        tracker.switch(1, "bash");
+        assert_eq!(tracker.running_on(42), None);
+    }
+}
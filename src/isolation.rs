@@ -0,0 +1,64 @@
+//! Detection of unexpected threads running on isolated CPUs.
+//!
+//! CPUs isolated with the `isolcpus=` kernel parameter are meant to only run
+//! the threads that were explicitly placed on them.
+
+use crate::affinity::CpuMask;
+use compact_str::CompactString;
+
+/// Checks the threads switched in on the isolated CPUs.
+#[derive(Debug, Clone)]
+pub struct IsolationChecker {
+    /// Whether each CPU is isolated, indexed by CPU.
+    isolated: Vec<bool>,
+    /// Names of the threads that may run on the isolated CPUs. A trailing
+    /// `*` matches any suffix.
+    allowed: Vec<CompactString>,
+}
+
+impl IsolationChecker {
+    pub fn new(CpuMask(cpus): &CpuMask, allowed: &[CompactString]) -> Self {
+        let mut isolated = vec![false; cpus.iter().max().map_or(0, |&max| max + 1)];
+        for &cpu in cpus {
+            isolated[cpu] = true;
+        }
+        Self {
+            isolated,
+            allowed: allowed.to_vec(),
+        }
+    }
+
+    /// Whether the thread `comm` being switched in on `cpu` is a violation.
+    pub fn is_violation(&self, cpu: usize, comm: &str) -> bool {
+        if !self.isolated.get(cpu).copied().unwrap_or(false) || comm.starts_with("swapper/") {
+            return false;
+        }
+        !self
+            .allowed
+            .iter()
+            .any(|allowed| match allowed.strip_suffix('*') {
+                Some(prefix) => comm.starts_with(prefix),
+                None => comm == allowed,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_isolation() {
+        let checker = IsolationChecker::new(
+            &CpuMask(vec![2, 3]),
+            &["rt_app".into(), "ksoftirqd/*".into()],
+        );
+        assert!(!checker.is_violation(0, "bash"));
+        assert!(!checker.is_violation(9, "bash"));
+        assert!(checker.is_violation(2, "bash"));
+        assert!(checker.is_violation(3, "rt_app2"));
+        assert!(!checker.is_violation(3, "rt_app"));
+        assert!(!checker.is_violation(3, "swapper/3"));
+        assert!(!checker.is_violation(2, "ksoftirqd/2"));
+    }
+}
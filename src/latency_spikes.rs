@@ -0,0 +1,58 @@
+//! Detection of long gaps between events on a CPU.
+//!
+//! A CPU going many milliseconds without any event (no context switch, no
+//! IRQ) may have been stuck, or the trace may be missing data.
+
+/// Tracks the time of the last event on each CPU.
+#[derive(Debug, Clone)]
+pub struct LatencySpikeDetector {
+    /// Report gaps longer than this.
+    threshold: u64,
+    /// Time of the last event on each CPU.
+    last_event: Vec<Option<u64>>,
+}
+
+impl LatencySpikeDetector {
+    pub fn new(num_cpus: usize, threshold: u64) -> Self {
+        Self {
+            threshold,
+            last_event: vec![None; num_cpus],
+        }
+    }
+
+    /// Track `num_cpus` CPUs, if that is more than currently tracked.
+    pub fn resize(&mut self, num_cpus: usize) {
+        if num_cpus > self.last_event.len() {
+            self.last_event.resize(num_cpus, None);
+        }
+    }
+
+    /// Record an event on `cpu` at `time`. Returns the length of the gap
+    /// since the previous event on the CPU if it exceeds the threshold.
+    pub fn record(&mut self, cpu: usize, time: u64) -> Option<u64> {
+        // Samples from different CPUs may be out of order, so keep the
+        // latest time rather than going backwards.
+        let last = &mut self.last_event[cpu];
+        let gap = last.map(|last| time.saturating_sub(last));
+        *last = Some(last.map_or(time, |last| last.max(time)));
+        gap.filter(|&gap| gap > self.threshold)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut detector = LatencySpikeDetector::new(2, 100);
+        assert_eq!(detector.record(0, 1000), None);
+        assert_eq!(detector.record(0, 1100), None);
+        assert_eq!(detector.record(1, 1150), None);
+        assert_eq!(detector.record(0, 1201), Some(101));
+        assert_eq!(detector.record(1, 1200), None);
+        // Out of order samples are not a gap.
+        assert_eq!(detector.record(0, 900), None);
+        assert_eq!(detector.record(0, 1250), None);
+    }
+}
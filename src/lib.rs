@@ -0,0 +1,4 @@
+//! The parts of perf-to-statemap that benchmarks need access to. Everything
+//! else lives in the binary.
+
+pub mod tracepoints;
@@ -0,0 +1,104 @@
+//! Logging setup, as human readable text or as JSON for other programs.
+
+/// Format of the log output on stderr.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap_derive::ValueEnum)]
+pub enum LogFormat {
+    /// Human readable text
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Install the logger. The level is controlled by `RUST_LOG` as usual,
+/// defaulting to `info`, or `error` if `quiet`.
+pub fn init(format: LogFormat, quiet: bool) -> Result<(), log::SetLoggerError> {
+    let default_level = if quiet { "error" } else { "info" };
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_level));
+    match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => json::JsonLogger::init(builder.build()),
+    }
+}
+
+mod json {
+    use std::io::Write;
+    use std::time::SystemTime;
+
+    /// A log entry as written by [`JsonLogger`].
+    #[derive(Debug, serde_derive::Serialize)]
+    struct Entry<'a> {
+        level: &'a str,
+        /// Seconds since the Unix epoch.
+        ts: u64,
+        msg: String,
+        target: &'a str,
+    }
+
+    /// Writes each log record as a line of JSON to stderr.
+    #[derive(Debug)]
+    pub struct JsonLogger {
+        /// Only used for filtering records, as configured by `RUST_LOG`.
+        filter: env_logger::Logger,
+    }
+
+    impl JsonLogger {
+        pub fn init(filter: env_logger::Logger) -> Result<(), log::SetLoggerError> {
+            log::set_max_level(filter.filter());
+            log::set_boxed_logger(Box::new(Self { filter }))
+        }
+
+        fn to_json(record: &log::Record<'_>, ts: u64) -> Result<String, serde_json::Error> {
+            serde_json::to_string(&Entry {
+                level: record.level().as_str(),
+                ts,
+                msg: record.args().to_string(),
+                target: record.target(),
+            })
+        }
+    }
+
+    impl log::Log for JsonLogger {
+        fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+            self.filter.enabled(metadata)
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            if !self.filter.matches(record) {
+                return;
+            }
+            let ts = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs());
+            if let Ok(line) = Self::to_json(record, ts) {
+                // Nowhere to report failing to write the log.
+                let _ = writeln!(std::io::stderr().lock(), "{line}");
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_json_log() {
+            let line = JsonLogger::to_json(
+                &log::Record::builder()
+                    .level(log::Level::Warn)
+                    .target("perf_to_statemap")
+                    .args(format_args!("Lost {} samples", 3))
+                    .build(),
+                1234567890,
+            )
+            .unwrap();
+            assert_eq!(
+                line,
+                r#"{"level":"WARN","ts":1234567890,"msg":"Lost 3 samples","target":"perf_to_statemap"}"#
+            );
+        }
+    }
+}
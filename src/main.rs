@@ -1,188 +1,942 @@
+mod affinity;
+mod append;
+mod arch;
+mod atomic_file;
+mod cache_flush;
+mod child_waits;
+mod classify_rules;
+mod clock_calibration;
+mod colors;
+mod convert;
+mod cpu_states;
+mod flush_policy;
+mod frequency;
+mod idle_gaps;
+mod input;
+mod inspect;
+mod irq_names;
+mod irq_threads;
+mod isolation;
+mod latency_spikes;
+mod logging;
+mod merge_tags;
+mod output;
 mod parsers;
+mod perf_data_stats;
+mod preemption_storms;
+mod progress;
+mod prometheus;
+mod report;
+mod runqueue;
+mod state_config;
+mod state_durations;
 mod statemap;
-mod tracepoints;
+mod stats;
+mod string_pool;
+mod switch_histogram;
+mod switch_limiter;
+mod symbols;
+mod topology;
 mod types;
+mod validate;
 
+use crate::atomic_file::AtomicFile;
+use crate::classify_rules::ClassifyRules;
+use crate::clock_calibration::ClockModel;
+use crate::colors::ColorScheme;
+use crate::convert::Clock;
+use crate::convert::Converter;
+use crate::convert::CpuTrackers;
+use crate::flush_policy::FlushPolicy;
+use crate::frequency::FreqBands;
+use crate::input::InputFile;
+use crate::input::Progress;
+use crate::input::Recording;
+use crate::inspect::Inspection;
+use crate::irq_names::IrqNames;
+use crate::output::Encoding;
+use crate::output::OutputFormat;
+use crate::output::OutputSink;
+use crate::output::core_group::CoreGroupSink;
+use crate::output::datum_count::DatumCountSink;
+use crate::output::min_idle::MinIdleSink;
+use crate::output::min_state::MinStateSink;
+use crate::output::no_tag::NoTagSink;
+use crate::output::per_second::PerSecondSink;
+use crate::output::record_count::Placeholder;
+use crate::output::record_count::RecordCountSink;
+use crate::output::record_count::record_count_line;
+use crate::output::sorted::EntitySort;
+use crate::output::sorted::SortedSink;
+use crate::output::split::SplitSink;
 use crate::parsers::Action;
 use crate::parsers::ClockData;
-use crate::parsers::Event;
-use crate::statemap::StatemapInputDatum;
-use crate::statemap::StatemapInputState;
+use crate::progress::EventRate;
+use crate::progress::ProgressReader;
+use crate::report::StatsFormat;
+use crate::state_config::StateConfig;
+use crate::symbols::KallsymsResolver;
+use crate::topology::CpuTopology;
 use crate::tracepoints::Tracepoint;
-use crate::types::CpuState;
-use byteorder::BigEndian;
-use byteorder::LittleEndian;
+use crate::tracepoints::parser::LazyParser;
+use crate::tracepoints::printk_formats::PrintkFormats;
 use clap::Parser;
+use compact_str::CompactString;
 use compact_str::ToCompactString;
 use compact_str::format_compact;
 use eyre::Context;
 use eyre::eyre;
-use linux_perf_data::Endianness;
 use linux_perf_data::PerfFileReader;
 use linux_perf_data::PerfFileRecord;
+use linux_perf_data::linux_perf_event_reader::HardwareCacheId;
+use linux_perf_data::linux_perf_event_reader::HardwareCacheOpResult;
+use linux_perf_data::linux_perf_event_reader::HardwareEventId;
+use linux_perf_data::linux_perf_event_reader::PerfEventType;
 use linux_perf_data::linux_perf_event_reader::RawData;
 use linux_perf_data::linux_perf_event_reader::RecordType;
-use linux_perf_data::linux_perf_event_reader::SampleRecord;
+use linux_perf_data::linux_perf_event_reader::SoftwareCounterType;
+use perf_to_statemap::tracepoints;
 use std::collections::HashMap;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
 use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 mod cli {
     #[derive(clap_derive::Parser)]
-    #[command(version, about)]
+    #[command(
+        version,
+        about,
+        subcommand_negates_reqs = true,
+        args_conflicts_with_subcommands = true
+    )]
     /// Parse perf.data and generate statemeap
     pub struct Cli {
+        #[command(subcommand)]
+        pub command: Option<Command>,
         /// A sysroot to load tracepoint defintions from (instead of
         /// /sys/kernel/tracing on the current system)
         #[clap(short, long, default_value = "/")]
         pub sysroot: std::path::PathBuf,
+        /// Architecture of the traced system, overriding the one recorded in
+        /// perf.data. This sets the size of `long` and pointers, which
+        /// tracepoint fields such as `prev_state` are decoded at.
+        #[clap(long, value_enum)]
+        pub arch: Option<crate::arch::Arch>,
         /// The name of the perf.data file to parse
-        pub input: String,
+        #[clap(required = true)]
+        pub input: Option<String>,
+        /// Pin this process to the CPUs in the given hexadecimal mask (as
+        /// for `taskset`), to avoid disturbing a workload on other CPUs.
+        /// Linux only.
+        #[clap(long, value_name = "HEX_MASK", value_parser = crate::affinity::parse_mask)]
+        pub cpu_affinity_mask: Option<crate::affinity::CpuMask>,
+        /// Pin this process to the CPUs of the given NUMA node, ideally the
+        /// one the input file is stored on, to avoid remote memory accesses.
+        /// Linux only.
+        #[clap(long, value_name = "NODE", conflicts_with = "cpu_affinity_mask")]
+        pub cpu_affinity_hint: Option<u32>,
+        /// List the NUMA nodes of this system and their CPUs, then exit.
+        #[clap(long, exclusive = true)]
+        pub list_numa_nodes: bool,
+        /// Memory map the input file instead of reading it, which is faster
+        /// for large files
+        #[clap(long)]
+        pub mmap: bool,
+        /// Convert the input while `perf record` is still writing it,
+        /// writing out the records as they come in until the recording
+        /// stops or on Ctrl-C. Until the recording is done the perf.data
+        /// file doesn't name its events, so they are looked up in the
+        /// tracepoints of `--sysroot`.
+        #[clap(long, conflicts_with_all = ["mmap", "progress", "emit_event_count"])]
+        pub watch: bool,
+        /// With `--watch`, also flush the output after every N input
+        /// records, rather than only once caught up with the recording.
+        #[clap(long, value_name = "N")]
+        pub flush_every: Option<u64>,
+        /// With `--watch`, also flush the output at least this often.
+        #[clap(long, value_name = "MS")]
+        pub flush_interval_ms: Option<u64>,
+        /// Symbol table (in `/proc/kallsyms` format) from the traced system,
+        /// used to show tasklet functions by name.
+        #[clap(long, value_name = "FILE")]
+        pub kallsyms: Option<std::path::PathBuf>,
+        /// IRQ list (in `/proc/interrupts` format) from the traced system,
+        /// used to name IRQs that the trace only has generic names for.
+        #[clap(long, value_name = "FILE")]
+        pub irq_map: Option<std::path::PathBuf>,
+        /// Replay events in simulated real time, at the given speed multiplier
+        /// (1.0 is real time, 2.0 is double speed). Output is flushed after
+        /// each datum.
+        #[clap(long, value_name = "SPEED")]
+        pub event_replay: Option<f64>,
+        /// Tag context switches followed by an unusually high last level cache
+        /// miss rate with "cold cache". Requires a cache miss counter (such as
+        /// `-e LLC-load-misses`) to be recorded alongside the tracepoints. All
+        /// output is buffered in memory until the end of the trace.
+        #[clap(long, conflicts_with = "event_replay")]
+        pub detect_cache_flush: bool,
+        /// Don't generate a statemap, instead print summary statistics
+        #[clap(long, conflicts_with_all = ["event_replay", "detect_cache_flush"])]
+        pub stats_only: bool,
+        /// Format of the `--stats-only` report. The Markdown report is
+        /// written to `<output>.md`, next to the plain text report in the
+        /// output. Without an output it replaces the plain text report.
+        #[clap(long, value_enum, default_value_t)]
+        pub stats_format: crate::report::StatsFormat,
+        /// Shorthand for `--stats-only --stats-format markdown`.
+        #[clap(long, conflicts_with = "stats_format")]
+        pub emit_markdown_report: bool,
+        /// Express times in clock cycles at the given CPU frequency (in GHz)
+        /// instead of in nanoseconds.
+        ///
+        /// Cycle counts are rounded to the nearest whole cycle, so any time that
+        /// is not a multiple of the cycle period loses precision.
+        #[clap(long = "normalize-to-Hz", value_name = "GHZ")]
+        pub normalize_to_hz: Option<f64>,
+        /// Compensate for clock drift relative to another machine, to line
+        /// up traces recorded on several machines.
+        ///
+        /// The file is a CSV of `<reference_ns>,<local_ns>` pairs with the
+        /// time of the same events on the reference machine and on the
+        /// machine this trace was recorded on. A linear clock skew model is
+        /// fitted to these and applied to all sample times.
+        #[clap(long, value_name = "REFERENCE_EVENT_CSV")]
+        pub calibrate_clock: Option<std::path::PathBuf>,
+        /// Fail if an event refers to a CPU outside of the number of CPUs
+        /// recorded in perf.data. This is the default.
+        #[clap(long, overrides_with = "allow_extra_cpus")]
+        pub strict_cpu_range: bool,
+        /// Instead of failing, add CPUs as they are seen in events when they
+        /// are outside of the number of CPUs recorded in perf.data.
+        #[clap(long, overrides_with = "strict_cpu_range")]
+        pub allow_extra_cpus: bool,
+        /// Warn about and skip invalid events (currently migrations with an
+        /// invalid CPU) instead of failing.
+        #[clap(long)]
+        pub lenient: bool,
+        /// Group CPUs by physical package (socket), by naming entities
+        /// `<package>/<cpu>`. The topology is taken from perf.data if
+        /// recorded, otherwise from sysfs under the sysroot.
+        #[clap(long, visible_alias = "entity-group-by-socket")]
+        pub group_by_package: bool,
+        /// Merge SMT sibling CPUs into one entity per physical core
+        /// (named like `0+4`), showing the state of the busiest sibling.
+        /// The topology is found the same way as for `--group-by-package`.
+        #[clap(long, conflicts_with_all = ["group_by_package", "compress_entity_ids"])]
+        pub group_by_core: bool,
+        /// Write a second statemap tracking CPU frequency to this file. Needs
+        /// `-e power:cpu_frequency` to be recorded.
+        #[clap(long, value_name = "FILE")]
+        pub freq_output: Option<std::path::PathBuf>,
+        /// Boundaries (in MHz) between the frequency bands in the frequency
+        /// statemap.
+        #[clap(
+            long,
+            value_delimiter = ',',
+            default_value = "800,1200,1600,2000,2400,3000"
+        )]
+        pub freq_thresholds: Vec<u32>,
+        /// Don't emit idle states when switching to the idle task
+        /// (`swapper/N`). Idle periods instead appear as extensions of the
+        /// last active state. Idle after an interrupt is still shown, so that
+        /// the interrupt doesn't appear to last the whole idle period. CPUs
+        /// that are only ever idle will not have any records.
+        #[clap(long, alias = "no-idle-datums", overrides_with = "include_swapper")]
+        pub exclude_idle: bool,
+        /// Emit idle states when switching to the idle task (the default).
+        /// Overrides an earlier `--exclude-idle`.
+        #[clap(long, overrides_with = "exclude_idle")]
+        pub include_swapper: bool,
+        /// Drop idle periods shorter than this many ns. The state before
+        /// them instead continues through them.
+        #[clap(long, value_name = "NS", conflicts_with = "exclude_idle")]
+        pub min_idle_duration_ns: Option<u64>,
+        /// Drop IRQs, softirqs and tasklets shorter than this many ns. The
+        /// state before them instead continues through them.
+        #[clap(long, value_name = "NS")]
+        pub min_state_duration_ns: Option<u64>,
+        /// Leave out the tags of all records, for a smaller output. Tags
+        /// are still used for `--stats-only` and when merging records.
+        #[clap(long)]
+        pub no_tag: bool,
+        /// Don't emit records with the same state and tag as the previous
+        /// record for the CPU, so that a continuous run becomes a single
+        /// span.
+        #[clap(long)]
+        pub merge_consecutive_tags: bool,
+        /// When a CPU has more than this many context switches within a
+        /// millisecond, replace the rest of that millisecond with a single
+        /// `Busy` record tagged with the number of switches.
+        #[clap(long, value_name = "N")]
+        pub max_switches_per_cpu_per_ms: Option<u64>,
+        /// Warn about periods where all CPUs are idle for longer than this
+        /// many nanoseconds.
+        #[clap(long, value_name = "THRESHOLD_NS")]
+        pub report_idle_gaps: Option<u64>,
+        /// Warn about windows of this many milliseconds where the context
+        /// switch rate on a CPU is more than three standard deviations above
+        /// the mean for that CPU.
+        #[clap(long, value_name = "WINDOW_MS", value_parser = clap::value_parser!(u64).range(1..))]
+        pub detect_preemption_storms: Option<u64>,
+        /// Write a histogram of the time between context switches on the
+        /// same CPU, with buckets of this many nanoseconds, as CSV to
+        /// `<output>.cs_histogram.csv`.
+        #[clap(
+            long,
+            value_name = "BUCKET_NS",
+            requires = "output",
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        pub context_switch_histogram: Option<u64>,
+        /// Warn when a CPU has no events for longer than this many
+        /// nanoseconds, which may mean it was stuck or data is missing.
+        #[clap(long, value_name = "THRESHOLD_NS")]
+        pub detect_latency_spikes: Option<u64>,
+        /// Show IRQs that no handler claimed (spurious IRQs) as a 1 ns
+        /// `IRQ N: UNHANDLED` spike at the end of the IRQ.
+        #[clap(long)]
+        pub warn_unhandled_irq: bool,
+        /// Don't restore the interrupted state when an IRQ, softirq or
+        /// tasklet exits, go to `Idle` instead. This shows only the states
+        /// explicitly recorded in the trace, without inferred restorations.
+        #[clap(long)]
+        pub no_prev_state: bool,
+        /// Warn when a hardware IRQ fires while its threaded handler
+        /// (`irq/<N>-<name>`) is still running.
+        #[clap(long)]
+        pub detect_irq_threading: bool,
+        /// Append the estimated runqueue depth of the CPU to the tags, as
+        /// `(rq:N)`. Needs `-e sched:sched_wakeup` to be recorded.
+        #[clap(long)]
+        pub show_runqueue_depth: bool,
+        /// Warn when the estimated runqueue depth of a CPU goes above this.
+        /// Needs `-e sched:sched_wakeup` to be recorded.
+        #[clap(long, value_name = "N")]
+        pub rq_depth_alert: Option<u32>,
+        /// Log an error when a thread runs on one of the CPUs in this
+        /// hexadecimal mask (such as CPUs isolated with `isolcpus=`), unless
+        /// it is the idle thread or allowed with `--isolation-allowed-comm`.
+        #[clap(long, value_name = "HEX_MASK", value_parser = crate::affinity::parse_mask)]
+        pub detect_cpu_isolation_violation: Option<crate::affinity::CpuMask>,
+        /// Name of a thread that is expected to run on the isolated CPUs. A
+        /// trailing `*` matches any suffix (such as `ksoftirqd/*`). Can be
+        /// given multiple times.
+        #[clap(long, value_name = "COMM", requires = "detect_cpu_isolation_violation")]
+        pub isolation_allowed_comm: Vec<compact_str::CompactString>,
+        /// Track the time from a softirq being raised until it runs, and
+        /// report the longest in `--stats-only`. Needs `-e irq:softirq_raise`
+        /// to be recorded.
+        #[clap(long)]
+        pub detect_softirq_latency: bool,
+        /// Warn when a softirq runs more than this many nanoseconds after
+        /// being raised.
+        #[clap(long, value_name = "NS", requires = "detect_softirq_latency")]
+        pub softirq_latency_threshold: Option<u64>,
+        /// Log an error for each RCU stall, as seen in `rcu:rcu_utilization`
+        /// and `rcu:rcu_stall_warning` events. Stalls are shown in the
+        /// statemap regardless.
+        #[clap(long)]
+        pub detect_rcu_stalls: bool,
+        /// The colors to use for the states
+        #[clap(long, value_enum, default_value_t)]
+        pub color_scheme: crate::colors::ColorScheme,
+        /// Add a custom field to the statemap header, for use by downstream
+        /// tools. Can be given multiple times. Values that parse as an
+        /// integer, float or bool are written as such, anything else as a
+        /// string.
+        #[clap(long, value_name = "KEY=VALUE", value_parser = crate::statemap::parse_extension)]
+        pub json_extensions: Vec<(String, serde_json::Value)>,
+        /// TOML file with additional state definitions, for use with
+        /// specialized tracepoints. Each `[[states]]` entry has a `name`, a
+        /// `value` and optionally a `color` (as `#RRGGBB` or `#RGB`).
+        #[clap(long, value_name = "FILE")]
+        pub state_config: Option<std::path::PathBuf>,
+        /// TOML file with rules classifying threads by comm, checked before
+        /// the built-in rules. For example `rules = [["dpdk_", "Kernel"]]`
+        /// matches comms starting with `dpdk_`, a pattern ending in `$` only
+        /// matches the comm exactly.
+        #[clap(long, value_name = "FILE")]
+        pub sched_switch_classify_file: Option<std::path::PathBuf>,
+        /// The output format
+        #[clap(long, value_enum, default_value_t)]
+        pub format: crate::output::OutputFormat,
+        /// Encoding of the records of the statemap format.
+        #[clap(long, value_enum, default_value_t)]
+        pub output_format: crate::output::Encoding,
+        /// Order of the entities (CPUs) in the output, which is the order
+        /// the statemap viewer shows them in. Any order other than `id`
+        /// buffers all records in memory until the end of the trace, about
+        /// 100 bytes per record.
+        #[clap(long, value_enum, default_value_t)]
+        pub entity_sort: crate::output::sorted::EntitySort,
+        /// Shorthand for `--entity-sort first-seen`.
+        #[clap(long, conflicts_with = "entity_sort")]
+        pub entity_sort_by_first_seen: bool,
+        /// Use short numeric IDs as entity names in the records, with an
+        /// `entities` section in the header mapping IDs to full names (such
+        /// as `<package>/<cpu>` with `--group-by-package`).
+        #[clap(long)]
+        pub compress_entity_ids: bool,
+        /// Emit the v2 statemap format, which adds `schemaVersion` to the
+        /// header and a `birth` event for each CPU before its first record.
+        #[clap(long)]
+        pub emit_state_map_v2: bool,
+        /// Write one statemap file per CPU (`cpu<N>.json`) into this
+        /// directory, instead of a single output.
+        #[clap(long, value_name = "DIR", conflicts_with_all = ["output", "stats_only"])]
+        pub split_output: Option<std::path::PathBuf>,
+        /// Gzip compress the files written by `--split-output`.
+        #[clap(long, requires = "split_output")]
+        pub split_compress: bool,
+        /// Treat the output as a directory, and write one file per CPU
+        /// (`cpu<N>.ndjson`) into it, each with its own header.
+        #[clap(
+            long,
+            requires = "output",
+            conflicts_with_all = ["stats_only", "split_output", "append"]
+        )]
+        pub split_by_entity: bool,
+        /// Write the records into a directory tree of
+        /// `<cpu>/<second>.ndjson` files, one per CPU and second of trace
+        /// time, plus the header as `header.json`. This allows loading
+        /// just a time window of a large trace.
+        #[clap(
+            long,
+            value_name = "DIR",
+            conflicts_with_all = ["output", "stats_only", "split_output", "split_by_entity"]
+        )]
+        pub emit_dot_per_event: Option<std::path::PathBuf>,
+        /// Also write metrics about the trace (events per CPU and type, time
+        /// in each state, lost events) in the Prometheus text format to
+        /// `<output>.metrics`.
+        #[clap(long, requires = "output")]
+        pub emit_prometheus: bool,
+        /// Write directly to the output file, instead of writing to a
+        /// temporary file and renaming it once the conversion succeeded.
+        #[clap(long)]
+        pub no_atomic: bool,
+        /// Write the number of data records as a `{"record_count": N}` line.
+        /// When writing to a file this is the line after the header. On
+        /// stdout, or when appending, it is a trailer after the last record.
+        #[clap(
+            long,
+            conflicts_with_all = [
+                "stats_only",
+                "split_output",
+                "split_by_entity",
+                "emit_dot_per_event"
+            ]
+        )]
+        pub emit_record_count: bool,
+        /// Write the number of sample records as `event_count` in the
+        /// header. The header is written first, so this reads the whole
+        /// input an extra time before converting it.
+        #[clap(long, conflicts_with_all = ["stats_only", "append"])]
+        pub emit_event_count: bool,
+        /// Only write the header, which is read from the perf.data header
+        /// without processing any events, and exit. The command line, kernel
+        /// version and sample time range are printed to stderr.
+        #[clap(
+            long,
+            conflicts_with_all = ["stats_only", "append", "split_by_entity", "emit_record_count"]
+        )]
+        pub emit_metadata_only: bool,
+        /// Append to an existing statemap instead of overwriting it. The
+        /// header is not written again, and the existing header must have
+        /// the same states. Times are adjusted to be relative to the start
+        /// of the existing file.
+        #[clap(long, requires = "output", conflicts_with = "stats_only")]
+        pub append: bool,
+        /// Show a progress bar on stderr, with the event rate and the
+        /// current time in the trace. Nothing is shown if stderr is not a
+        /// terminal.
+        #[clap(long)]
+        pub progress: bool,
+        /// Only log errors, not warnings and informational messages.
+        /// `RUST_LOG` still takes precedence.
+        #[clap(long, short)]
+        pub quiet: bool,
+        /// If the trace doesn't record the host name, use the name of this
+        /// host instead.
+        #[clap(long)]
+        pub fall_back_to_hostname: bool,
+        /// Leave out the host name, even if the trace records it.
+        #[clap(long, conflicts_with = "fall_back_to_hostname")]
+        pub no_hostname: bool,
+        /// Format of the log messages on stderr. `json` writes one JSON
+        /// object per line, for use in pipelines.
+        #[clap(long, value_enum, default_value_t)]
+        pub log_format: crate::logging::LogFormat,
         /// The name of the output file to write
         pub output: Option<String>,
     }
+
+    #[derive(clap_derive::Subcommand)]
+    pub enum Command {
+        /// Print the feature sections of a perf.data file and a summary of
+        /// their contents, without converting it
+        Inspect {
+            /// The name of the perf.data file to inspect
+            input: String,
+            /// The output format
+            #[clap(long, value_enum, default_value_t)]
+            format: crate::inspect::InspectFormat,
+        },
+        /// Check that a statemap (in JSON Lines) is well formed, listing all
+        /// problems found
+        Validate {
+            /// The name of the statemap file to check
+            input: String,
+        },
+    }
 }
 
 fn main() -> eyre::Result<()> {
     color_eyre::install()?;
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    let cli = cli::Cli::parse();
+    let mut cli = cli::Cli::parse();
+    logging::init(cli.log_format, cli.quiet)?;
+    if let Some(cli::Command::Inspect { input, format }) = &cli.command {
+        let inspection = Inspection::from_file(input)?;
+        return inspection.write(&mut std::io::stdout().lock(), *format);
+    }
+    if let Some(cli::Command::Validate { input }) = &cli.command {
+        let file = std::fs::File::open(input)
+            .wrap_err_with(|| format!("Failed to open \"{input}\" for validating"))?;
+        let violations = validate::validate(std::io::BufReader::new(file))?;
+        let mut stdout = std::io::stdout().lock();
+        for violation in &violations {
+            writeln!(stdout, "{violation}")?;
+        }
+        if !violations.is_empty() {
+            return Err(eyre!("Found {} problems in \"{input}\"", violations.len()));
+        }
+        return Ok(());
+    }
+    if cli.list_numa_nodes {
+        let mut stdout = std::io::stdout().lock();
+        for node in affinity::numa_nodes(Path::new(affinity::NUMA_NODE_DIR))? {
+            writeln!(stdout, "node{}: {}", node.node, node.cpu_list)?;
+        }
+        return Ok(());
+    }
+    // Only optional when there is a subcommand or --list-numa-nodes.
+    let input = cli
+        .input
+        .clone()
+        .ok_or_else(|| eyre!("No input file given"))?;
+    if cli.emit_markdown_report {
+        cli.stats_only = true;
+        cli.stats_format = StatsFormat::Markdown;
+    }
+    if cli.stats_format != StatsFormat::Text && !cli.stats_only {
+        return Err(eyre!("--stats-format requires --stats-only"));
+    }
+    if let Some(speed) = cli.event_replay
+        && !(speed.is_finite() && speed > 0.0)
+    {
+        return Err(eyre!("Replay speed must be a positive number, got {speed}"));
+    }
+    if let Some(ghz) = cli.normalize_to_hz
+        && !(ghz.is_finite() && ghz > 0.0)
+    {
+        return Err(eyre!("CPU frequency must be a positive number, got {ghz}"));
+    }
+    if cli.append && cli.format != OutputFormat::Statemap {
+        return Err(eyre!("--append is only supported with the statemap format"));
+    }
+    if cli.split_output.is_some() && cli.format != OutputFormat::Statemap {
+        return Err(eyre!(
+            "--split-output is only supported with the statemap format"
+        ));
+    }
+    if cli.split_by_entity && cli.format != OutputFormat::Statemap {
+        return Err(eyre!(
+            "--split-by-entity is only supported with the statemap format"
+        ));
+    }
+    if cli.emit_dot_per_event.is_some() && cli.format != OutputFormat::Statemap {
+        return Err(eyre!(
+            "--emit-dot-per-event is only supported with the statemap format"
+        ));
+    }
+    if cli.emit_record_count && cli.format != OutputFormat::Statemap {
+        return Err(eyre!(
+            "--emit-record-count is only supported with the statemap format"
+        ));
+    }
+    if cli.output_format != Encoding::JsonLines {
+        if cli.format != OutputFormat::Statemap {
+            return Err(eyre!(
+                "--output-format is only supported with the statemap format"
+            ));
+        }
+        if cli.append
+            || cli.split_output.is_some()
+            || cli.split_by_entity
+            || cli.emit_dot_per_event.is_some()
+            || cli.emit_record_count
+        {
+            return Err(eyre!(
+                "--output-format can't be combined with options writing JSON lines"
+            ));
+        }
+    }
+
+    if let Some(mask) = &cli.cpu_affinity_mask {
+        affinity::set_affinity(mask)?;
+        log::info!("Pinned to CPUs {:?}", mask.0);
+    }
+    if let Some(node) = cli.cpu_affinity_hint {
+        let mask = affinity::numa_node_mask(Path::new(affinity::NUMA_NODE_DIR), node)?;
+        affinity::set_affinity(&mask)?;
+        log::info!("Pinned to the CPUs of NUMA node {node}: {:?}", mask.0);
+    }
+
+    let clock_model = cli
+        .calibrate_clock
+        .as_deref()
+        .map(ClockModel::from_csv)
+        .transpose()?;
+    if let Some(model) = &clock_model {
+        log::info!("Using clock calibration: {model:?}");
+    }
 
-    let file = std::fs::File::open(cli.input)?;
-    let reader = std::io::BufReader::new(file);
+    let mut recording = None;
+    if cli.watch {
+        let stop = Arc::new(AtomicBool::new(false));
+        ctrlc::set_handler({
+            let stop = Arc::clone(&stop);
+            move || stop.store(true, Ordering::Relaxed)
+        })?;
+        recording = Some(Recording::watch(Path::new(&input), stop)?);
+    }
+    let bar = if cli.progress {
+        progress::progress_bar(std::fs::metadata(&input)?.len())
+    } else {
+        None
+    };
+    let file = match &recording {
+        Some(recording) => recording.open()?,
+        None => InputFile::open(&input, cli.mmap)?,
+    };
+    let reader = ProgressReader::new(file, bar.clone());
+    let event_rate = bar.map(EventRate::new);
     let PerfFileReader {
         mut perf_file,
         mut record_iter,
     } = PerfFileReader::parse_file(reader)?;
+    let extras = extract_metadata(&perf_file);
+    let host = if cli.no_hostname {
+        None
+    } else {
+        hostname(extras.hostname.clone(), cli.fall_back_to_hostname)
+    };
 
-    // A mapping of current state of a given CPU. This is needed to restore state
-    // after a IRQ exit or softirq exit. We also serialize straight from these
-    // objects to the output stream.
-    let num_cups = perf_file
-        .nr_cpus()?
-        .ok_or_else(|| eyre!("Failed to get number of CPUs"))?
-        .nr_cpus_available as usize;
-    let mut states = Vec::with_capacity(num_cups);
-    for cpuid in 0..num_cups {
-        states.push(StatemapInputDatum::<CpuState> {
-            entity: format_compact!("{cpuid}"),
-            ..Default::default()
-        });
-    }
-    let mut prev_states = states.clone();
+    let num_cups = match extras.nr_cpus_available {
+        Some(nr_cpus) => nr_cpus as usize,
+        // Only written once the recording is done.
+        None if cli.watch => topology::possible_cpus(&cli.sysroot)?,
+        None => return Err(eyre!("Failed to get number of CPUs")),
+    };
+    let topology = if cli.group_by_package || cli.group_by_core {
+        Some(CpuTopology::load(&perf_file, &cli.sysroot, num_cups)?)
+    } else {
+        None
+    };
+    let full_entity_name = |cpuid: usize| match topology
+        .as_ref()
+        .filter(|_| cli.group_by_package)
+        .and_then(|t| t.package_of(cpuid))
+    {
+        Some(package) => format_compact!("{package}/{cpuid}"),
+        None => format_compact!("{cpuid}"),
+    };
+    // With compressed IDs the CPU number is the ID, and the header maps it to
+    // the full name.
+    let entity_name = |cpuid: usize| {
+        if cli.compress_entity_ids {
+            format_compact!("{cpuid}")
+        } else {
+            full_entity_name(cpuid)
+        }
+    };
 
-    let file: &mut dyn Write = match cli.output {
+    // Create a lookup table from event attribute index to conversion action
+    let symbols = cli
+        .kallsyms
+        .as_deref()
+        .map(KallsymsResolver::from_file)
+        .transpose()?
+        .map(Rc::new);
+    let irq_names = cli
+        .irq_map
+        .as_deref()
+        .map(IrqNames::from_file)
+        .transpose()?
+        .map(Rc::new);
+    let classify_rules = cli
+        .sched_switch_classify_file
+        .as_deref()
+        .map(ClassifyRules::load)
+        .transpose()?
+        .map(Rc::new);
+    let action_map = action_mapping(
+        &perf_file,
+        &cli.sysroot,
+        symbols.as_ref(),
+        irq_names.as_ref(),
+        classify_rules.as_ref(),
+        cli.arch
+            .map(arch::Arch::long_size)
+            .or_else(|| extras.arch.as_deref().and_then(arch::long_size)),
+    )?;
+    let use_atomic = cli.output.as_deref().is_some_and(|output| {
+        !cli.format.writes_output_file()
+            && !cli.split_by_entity
+            && !cli.append
+            && !cli.no_atomic
+            && !cli.watch
+            && AtomicFile::is_supported(Path::new(output))
+    });
+    let mut atomic_output = None;
+    let file: &mut dyn Write = match &cli.output {
+        Some(_) if cli.format.writes_output_file() || cli.split_by_entity => &mut std::io::sink(),
+        Some(output) if cli.append => &mut std::fs::OpenOptions::new()
+            .append(true)
+            .open(output)
+            .wrap_err_with(|| format!("Failed to open \"{output}\" for appending"))?,
+        Some(output) if use_atomic => atomic_output.insert(AtomicFile::create(output)?),
         Some(output) => &mut std::fs::File::create(output)?,
         None => &mut std::io::stdout().lock(),
     };
     let mut writer = std::io::BufWriter::new(file);
 
     // Write header metadata.
-    write_header(&perf_file, &mut writer)?;
+    let mut sink: Box<dyn OutputSink> = match (&cli.split_output, &cli.emit_dot_per_event) {
+        (Some(dir), _) => Box::new(SplitSink::new(dir, cli.split_compress)?),
+        (None, Some(dir)) => Box::new(PerSecondSink::new(dir)?),
+        (None, None) => match &cli.output {
+            Some(dir) if cli.split_by_entity => {
+                Box::new(SplitSink::new(dir, false)?.with_extension("ndjson"))
+            }
+            _ => cli
+                .format
+                .sink(cli.output.as_deref().map(Path::new), cli.output_format)?,
+        },
+    };
+    // Innermost, to count what is actually written.
+    let (counting, datums_written) = DatumCountSink::new(sink);
+    sink = Box::new(counting);
+    let entity_sort = if cli.entity_sort_by_first_seen {
+        EntitySort::FirstSeen
+    } else {
+        cli.entity_sort
+    };
+    if entity_sort != EntitySort::Id {
+        sink = Box::new(SortedSink::new(sink, entity_sort));
+    }
+    if cli.group_by_core
+        && let Some(topology) = &topology
+    {
+        let groups = topology
+            .thread_siblings
+            .iter()
+            .filter(|siblings| siblings.len() > 1)
+            .map(|siblings| {
+                let name: Vec<_> = siblings.iter().map(u32::to_string).collect();
+                let members = siblings
+                    .iter()
+                    .map(|&cpu| entity_name(cpu as usize))
+                    .collect();
+                (CompactString::from(name.join("+")), members)
+            });
+        sink = Box::new(CoreGroupSink::new(sink, groups));
+    }
+    if let Some(min_duration) = cli.min_idle_duration_ns {
+        sink = Box::new(MinIdleSink::new(sink, min_duration));
+    }
+    if let Some(min_duration) = cli.min_state_duration_ns {
+        sink = Box::new(MinStateSink::new(sink, min_duration));
+    }
+    if cli.no_tag {
+        sink = Box::new(NoTagSink::new(sink));
+    }
+    // The record count can only be filled in after the header when we can
+    // seek back in the output, which we can in the temporary file.
+    let mut record_count = None;
+    if cli.emit_record_count {
+        if use_atomic {
+            let (counting, placeholder) = RecordCountSink::placeholder(sink);
+            sink = Box::new(counting);
+            record_count = Some(placeholder);
+        } else {
+            sink = Box::new(RecordCountSink::trailer(sink));
+        }
+    }
+    // When appending, times are relative to the start of the existing file.
+    let mut time_offset = 0;
+    if !cli.stats_only {
+        let mut metadata = header_metadata(
+            &perf_file,
+            host.clone(),
+            extras.arch.clone(),
+            cli.color_scheme,
+            cli.normalize_to_hz,
+        )?;
+        if let Some(path) = &cli.state_config {
+            StateConfig::load(path)?.merge_into(&mut metadata.states)?;
+        }
+        if cli.compress_entity_ids {
+            metadata.entities = Some(
+                (0..num_cups)
+                    .map(|cpu| (cpu, full_entity_name(cpu)))
+                    .collect(),
+            );
+        }
+        metadata
+            .extensions
+            .extend(cli.json_extensions.iter().cloned());
+        match &cli.output {
+            Some(output) if cli.append => {
+                time_offset = append::check_existing(Path::new(output), &metadata)?;
+            }
+            _ => {
+                if cli.emit_event_count {
+                    metadata.event_count = Some(count_samples(&input, cli.mmap)?);
+                }
+                sink.write_header(&mut writer, &metadata)?;
+            }
+        }
+    }
+    if cli.emit_metadata_only {
+        if let Some(event_rate) = &event_rate {
+            event_rate.finish();
+        }
+        print_file_info(&perf_file, &extras)?;
+        sink.finish(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        if let Some(file) = atomic_output {
+            file.commit()?;
+        }
+        return Ok(());
+    }
 
-    // Create a lookup table from event attribute index to conversion action
-    let action_map = action_mapping(&perf_file, &cli.sysroot)?;
+    // Set up the frequency statemap, if requested.
+    let freq_output = match &cli.freq_output {
+        Some(path) => {
+            let bands = FreqBands::new(cli.freq_thresholds.clone())?;
+            let mut freq_writer = std::io::BufWriter::new(
+                std::fs::File::create(path)
+                    .wrap_err_with(|| format!("Failed to create \"{}\"", path.display()))?,
+            );
+            let metadata = bands.metadata(trace_start(&perf_file)?, host.clone());
+            serde_json::to_writer(&mut freq_writer, &metadata)?;
+            writeln!(freq_writer)?;
+            Some((bands, freq_writer))
+        }
+        None => None,
+    };
 
+    // A trace without samples is valid (such as when recording without any
+    // tracepoints enabled), it just results in an empty statemap.
     let start_time = perf_file
         .sample_time_range()?
-        .ok_or_else(|| eyre!("No sample time range found"))?
-        .first_sample_time;
+        .map(|range| range.first_sample_time);
+    if start_time.is_none() && !cli.watch {
+        log::info!("No samples found in perf.data, writing empty statemap");
+    }
 
-    let mut ctr = 0;
-    while let Some(record) = record_iter.next_record(&mut perf_file)? {
-        match record {
-            PerfFileRecord::EventRecord { attr_index, record } => {
-                match record.record_type {
-                    // We don't care about these events (we are not doing stack traces)
-                    RecordType::MMAP | RecordType::MMAP2 | RecordType::KSYMBOL => {}
-                    RecordType::FORK | RecordType::EXIT | RecordType::COMM => {
-                        // Process lifecycle events, we don't use these
-                        // (currently) Instead we get data from tracepoints.
-                    }
-                    // This we need to handle
-                    RecordType::SAMPLE => {
-                        ctr += 1;
-                        let action = &action_map[attr_index];
-                        if matches!(action, Action::Ignore) {
-                            continue; // Skip ignored actions
-                        }
-                        let common = record.common_data()?;
-                        let endian = record.parse_info.endian;
-                        let sample = match endian {
-                            Endianness::LittleEndian => SampleRecord::parse::<LittleEndian>(
-                                record.data,
-                                record.misc,
-                                &record.parse_info,
-                            )?,
-                            Endianness::BigEndian => SampleRecord::parse::<BigEndian>(
-                                record.data,
-                                record.misc,
-                                &record.parse_info,
-                            )?,
-                        };
-                        let event = Event::parse(
-                            action,
-                            sample.raw.ok_or_else(|| eyre!("No raw data for trace?"))?,
-                            endian,
-                        )
-                        .wrap_err_with(|| {
-                            format!("Failed to parse: {sample:?}, action {action:?} (at {ctr})")
-                        })?;
-                        let cpu = common.cpu.expect("CPU should be present");
-                        let time =
-                            common.timestamp.expect("Timestamp should be present") - start_time;
-                        //println!("Event: {event:?} on CPU {cpu} at time {time}");
-                        match event {
-                            Event::BeginThread { state, comm, pid } => {
-                                states[cpu as usize].state = state;
-                                states[cpu as usize].tag = Some(format_compact!("{comm}:{pid}"));
-                            }
-                            Event::BeginOther { state, tag } => {
-                                prev_states[cpu as usize].clone_from(&states[cpu as usize]);
-                                states[cpu as usize].state = state;
-                                states[cpu as usize].tag = Some(tag);
-                            }
-                            Event::End => {
-                                states[cpu as usize].clone_from(&prev_states[cpu as usize]);
-                            }
-                            Event::Migrate { from, to } => {
-                                assert!(from != to, "Cannot migrate to the same CPU");
-                                states[to as usize].time = time;
-                                states[to as usize].state = states[from as usize].state;
-                                states[to as usize].tag =
-                                    std::mem::take(&mut states[from as usize].tag);
-                                states[from as usize].time = time;
-                                states[from as usize].state = CpuState::Idle;
-                                // The statemap tool doesn't deal with None correctly.
-                                states[from as usize].tag = Some("".to_compact_string());
-                            }
-                        }
-                        states[cpu as usize].time = time;
-                        // Write the current state to the output
-                        serde_json::to_writer(&mut writer, &states[cpu as usize])?;
-                        writeln!(writer)?;
-                    }
-                    RecordType::LOST | RecordType::LOST_SAMPLES => {
-                        // Warn the user about lost samples
-                        log::warn!(
-                            "There are lost samples. Data is incomplete and may not be \
-                             trustworthy!"
-                        );
+    let clock = Clock {
+        model: clock_model,
+        start_time,
+        offset: time_offset,
+        normalize_to_hz: cli.normalize_to_hz,
+    };
+    let trackers = CpuTrackers::new(&cli, num_cups, &entity_name);
+    let mut converter = Converter::new(
+        &cli,
+        clock,
+        trackers,
+        action_map,
+        sink.as_mut(),
+        &mut writer,
+    )
+    .with_freq_output(freq_output)
+    .with_event_rate(event_rate);
+    let mut flush_policy = cli.watch.then(|| {
+        FlushPolicy::new(
+            cli.flush_every,
+            cli.flush_interval_ms.map(Duration::from_millis),
+        )
+    });
+    loop {
+        let record = match record_iter.next_record(&mut perf_file) {
+            Err(linux_perf_data::Error::IoError(err))
+                if err.kind() == std::io::ErrorKind::WouldBlock =>
+            {
+                // Caught up with what `perf record` has written so far.
+                let watched = recording.as_ref().ok_or(err)?;
+                converter.flush()?;
+                if let Some(policy) = &mut flush_policy {
+                    policy.flushed();
+                }
+                match watched.wait()? {
+                    Progress::Growing => continue,
+                    Progress::Stopped => {
+                        log::info!("Stopped watching \"{input}\"");
+                        break;
                     }
-                    _ => {
-                        log::warn!("Unhandled record type: {:?}", record.record_type);
+                    Progress::Finished => {
+                        log::info!("perf record finished writing \"{input}\"");
                     }
                 }
+                // Read the rest from the finished file, which also has the
+                // feature sections now. Records come out in the same order,
+                // so skip the ones already converted.
+                recording = None;
+                PerfFileReader {
+                    perf_file,
+                    record_iter,
+                } = PerfFileReader::parse_file(ProgressReader::new(
+                    InputFile::open(&input, false)?,
+                    None,
+                ))?;
+                for _ in 0..converter.records_read() {
+                    record_iter
+                        .next_record(&mut perf_file)
+                        .wrap_err("Failed to read the finished recording")?;
+                }
+                continue;
             }
-            PerfFileRecord::UserRecord(_raw_user_record) => {
-                // None of these appear to be useful right now, though
-                // * PERF_TIME_CONV could possibly be useful to convert
-                //   timestamps, but none of the values line up with wall time
-                //   from what I can see.
-            }
+            record => record.wrap_err_with(|| converter.read_error_context())?,
+        };
+        let Some(record) = record else {
+            break;
+        };
+        converter.handle_record(record)?;
+        if let Some(policy) = &mut flush_policy
+            && policy.record()
+        {
+            converter.flush()?;
         }
     }
+    converter.finish(&perf_file, host, &input, &datums_written)?;
+    drop(writer);
+    if let Some(mut file) = atomic_output {
+        if let Some(Placeholder { offset, count }) = record_count.and_then(|p| p.get()) {
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(record_count_line(count).as_bytes())?;
+        }
+        file.commit()?;
+    }
 
     Ok(())
 }
@@ -193,45 +947,139 @@ fn main() -> eyre::Result<()> {
 fn action_mapping(
     perf_file: &linux_perf_data::PerfFile,
     sysroot: &Path,
+    symbols: Option<&Rc<KallsymsResolver>>,
+    irq_names: Option<&Rc<IrqNames>>,
+    classify_rules: Option<&Rc<ClassifyRules>>,
+    long_size: Option<u32>,
 ) -> Result<Vec<Action>, eyre::Error> {
+    fn lazy<T: Tracepoint>(sysroot: &Path, long_size: Option<u32>) -> Rc<LazyParser> {
+        Rc::new(LazyParser::new::<T>(sysroot).with_long_size(long_size))
+    }
+
     let mut event_map = Vec::with_capacity(perf_file.event_attributes().len());
+    // Events are only named once `perf record` is done, until then
+    // tracepoints are named by their ID.
+    let tracepoint_names = if perf_file
+        .event_attributes()
+        .iter()
+        .any(|entry| entry.name().is_none())
+    {
+        tracepoints::names_by_id(sysroot)?
+    } else {
+        HashMap::new()
+    };
+    // Only loaded if there are events that need it.
+    let mut printk_formats = None;
+    let mut printk_formats = || {
+        Rc::clone(printk_formats.get_or_insert_with(|| {
+            Rc::new(PrintkFormats::from_sysroot(sysroot).unwrap_or_else(|err| {
+                log::warn!("Cannot resolve RCU strings: {err}");
+                PrintkFormats::default()
+            }))
+        }))
+    };
     for entry in perf_file.event_attributes() {
-        let name = entry
-            .name()
-            .ok_or_else(|| eyre!("Failed to get event name"))?;
+        if matches!(
+            entry.attr.type_,
+            PerfEventType::HwCache(HardwareCacheId::Ll, _, HardwareCacheOpResult::Miss, _)
+                | PerfEventType::Hardware(HardwareEventId::CacheMisses, _)
+        ) {
+            // Only used for cache flush detection, but these are never
+            // tracepoints so handle them before looking at the name.
+            event_map.push(Action::CacheMiss);
+            continue;
+        }
+        let name = match (entry.name(), entry.attr.type_) {
+            (Some(name), _) => name,
+            (None, PerfEventType::Tracepoint(id)) => tracepoint_names
+                .get(&id)
+                .ok_or_else(|| eyre!("Failed to get name of tracepoint {id}"))?,
+            (None, PerfEventType::Software(SoftwareCounterType::Dummy)) => "dummy:u",
+            (None, _) => return Err(eyre!("Failed to get event name")),
+        };
         let action = match name {
             "irq:irq_handler_entry" => Action::EnterIrq(
-                tracepoints::irq::IrqHandlerEntry::parser_from_sysroot(sysroot)?,
-            ),
-            "irq:irq_handler_exit" => Action::ExitIrq(
-                tracepoints::irq::IrqHandlerExit::parser_from_sysroot(sysroot)?,
-            ),
-            "irq:softirq_entry" => Action::EnterSoftirq(
-                tracepoints::irq::SoftirqEntry::parser_from_sysroot(sysroot)?,
+                lazy::<tracepoints::irq::IrqHandlerEntry>(sysroot, long_size),
+                irq_names.cloned(),
             ),
+            "irq:irq_handler_exit" => {
+                Action::ExitIrq(lazy::<tracepoints::irq::IrqHandlerExit>(sysroot, long_size))
+            }
+            "irq:softirq_raise" => {
+                Action::RaiseSoftirq(lazy::<tracepoints::irq::SoftirqRaise>(sysroot, long_size))
+            }
+            "irq:softirq_entry" => {
+                Action::EnterSoftirq(lazy::<tracepoints::irq::SoftirqEntry>(sysroot, long_size))
+            }
             "irq:softirq_exit" => {
-                Action::ExitSoftirq(tracepoints::irq::SoftirqExit::parser_from_sysroot(sysroot)?)
+                Action::ExitSoftirq(lazy::<tracepoints::irq::SoftirqExit>(sysroot, long_size))
             }
             "irq:tasklet_entry" => Action::EnterTasklet(
-                tracepoints::irq::TaskletEntry::parser_from_sysroot(sysroot)?,
+                lazy::<tracepoints::irq::TaskletEntry>(sysroot, long_size),
+                symbols.cloned(),
             ),
             "irq:tasklet_exit" => {
-                Action::ExitTasklet(tracepoints::irq::TaskletExit::parser_from_sysroot(sysroot)?)
+                Action::ExitTasklet(lazy::<tracepoints::irq::TaskletExit>(sysroot, long_size))
             }
-            "sched:sched_migrate_task" => Action::Migrate(
-                tracepoints::sched::SchedMigrateTask::parser_from_sysroot(sysroot)?,
+            "irq:tasklet_hi_entry" => Action::EnterTaskletHi(
+                lazy::<tracepoints::irq::TaskletHiEntry>(sysroot, long_size),
+                symbols.cloned(),
             ),
+            "irq:tasklet_hi_exit" => {
+                Action::ExitTaskletHi(lazy::<tracepoints::irq::TaskletHiExit>(sysroot, long_size))
+            }
+            "power:cpu_frequency" => {
+                Action::CpuFrequency(lazy::<tracepoints::power::CpuFrequency>(sysroot, long_size))
+            }
+            "rcu:rcu_stall_warning" => Action::RcuStallWarning(
+                lazy::<tracepoints::rcu::RcuStallWarning>(sysroot, long_size),
+                printk_formats(),
+            ),
+            "rcu:rcu_utilization" => Action::RcuUtilization(
+                lazy::<tracepoints::rcu::RcuUtilization>(sysroot, long_size),
+                printk_formats(),
+            ),
+            "sched:sched_migrate_task" => Action::Migrate(lazy::<
+                tracepoints::sched::SchedMigrateTask,
+            >(sysroot, long_size)),
+            "sched:sched_move_numa" => Action::NumaMove(lazy::<tracepoints::sched::SchedMoveNuma>(
+                sysroot, long_size,
+            )),
+            "sched:sched_process_exec" => Action::Exec(
+                lazy::<tracepoints::sched::SchedProcessExec>(sysroot, long_size),
+                classify_rules.cloned(),
+            ),
+            "sched:sched_pi_setprio" => Action::PiSetprio(
+                lazy::<tracepoints::sched::SchedPiSetprio>(sysroot, long_size),
+            ),
+            "sched:sched_process_exit" => Action::ProcessExit(lazy::<
+                tracepoints::sched::SchedProcessExit,
+            >(sysroot, long_size)),
             "sched:sched_process_fork" => Action::Ignore,
-            "sched:sched_stat_iowait" => Action::Ignore,
-            "sched:sched_stat_runtime" => Action::Ignore,
-            "sched:sched_stat_sleep" => Action::Ignore,
+            "sched:sched_process_wait" => Action::ProcessWait(lazy::<
+                tracepoints::sched::SchedProcessWait,
+            >(sysroot, long_size)),
+            "sched:sched_stat_iowait" => Action::StatIowait(lazy::<
+                tracepoints::sched::SchedStatIOWait,
+            >(sysroot, long_size)),
+            "sched:sched_stat_runtime" => Action::StatRuntime(lazy::<
+                tracepoints::sched::SchedStatRuntime,
+            >(sysroot, long_size)),
+            "sched:sched_stat_sleep" => Action::StatSleep(
+                lazy::<tracepoints::sched::SchedStatSleep>(sysroot, long_size),
+            ),
             "sched:sched_stat_wait" => Action::Ignore,
             "sched:sched_switch" => Action::Switch(
-                tracepoints::sched::SchedSwitch::parser_from_sysroot(sysroot)?,
+                lazy::<tracepoints::sched::SchedSwitch>(sysroot, long_size),
+                classify_rules.cloned(),
             ),
             "sched:sched_wakeup_new" => Action::Ignore,
-            "sched:sched_wakeup" => Action::Ignore,
-            "sched:sched_waking" => Action::Ignore,
+            "sched:sched_wakeup" => {
+                Action::Wakeup(lazy::<tracepoints::sched::SchedWakeup>(sysroot, long_size))
+            }
+            "sched:sched_waking" => {
+                Action::Waking(lazy::<tracepoints::sched::SchedWaking>(sysroot, long_size))
+            }
             "dummy:u" => Action::Ignore,
             _ => {
                 log::warn!("Unknown event name {name}, ignoring it");
@@ -243,65 +1091,93 @@ fn action_mapping(
     Ok(event_map)
 }
 
-/// Write header with metadata. This is the first entry in the output file.
-fn write_header(
+/// Create the header with metadata. This is the first entry in the output
+/// file.
+fn header_metadata(
     perf_file: &linux_perf_data::PerfFile,
-    writer: &mut impl Write,
+    host: Option<CompactString>,
+    arch: Option<CompactString>,
+    color_scheme: ColorScheme,
+    cycle_frequency_ghz: Option<f64>,
+) -> Result<statemap::StatemapInputMetadata, eyre::Error> {
+    let states = color_scheme.states();
+    let metadata = statemap::StatemapInputMetadata {
+        start: trace_start(perf_file)?,
+        title: "CPU".to_compact_string(),
+        host,
+        entityKind: Some("CPU".to_compact_string()),
+        arch,
+        states, // This can be filled with actual states if needed
+        cycleFrequencyGHz: cycle_frequency_ghz,
+        schemaVersion: None,
+        entities: None,
+        duration_ns: Some(
+            perf_file
+                .sample_time_range()?
+                .map_or(0, |range| range.last_sample_time - range.first_sample_time),
+        ),
+        event_count: None,
+        extensions: Default::default(),
+    };
+    Ok(metadata)
+}
+
+/// Print information from the perf.data header that isn't in the statemap
+/// header to stderr.
+fn print_file_info(
+    perf_file: &linux_perf_data::PerfFile,
+    extras: &StatemapMetadataExtras,
 ) -> Result<(), eyre::Error> {
-    let mut states = HashMap::new();
-    states.insert(
-        "Idle".to_compact_string(),
-        StatemapInputState {
-            color: Some("#e0e0e0".to_compact_string()),
-            value: CpuState::Idle as usize,
-        },
-    );
-    states.insert(
-        "Irq".to_compact_string(),
-        StatemapInputState {
-            color: Some("#FF0000".to_compact_string()),
-            value: CpuState::Irq as usize,
-        },
-    );
-    states.insert(
-        "Softirq".to_compact_string(),
-        StatemapInputState {
-            color: Some("#FF8000".to_compact_string()),
-            value: CpuState::Softirq as usize,
-        },
-    );
-    states.insert(
-        "Tasklet".to_compact_string(),
-        StatemapInputState {
-            color: Some("#FFBF00".to_compact_string()),
-            value: CpuState::Tasklet as usize,
-        },
-    );
-    states.insert(
-        "Kernel".to_compact_string(),
-        StatemapInputState {
-            color: Some("#2E4E00".to_compact_string()),
-            value: CpuState::Kernel as usize,
-        },
-    );
-    states.insert(
-        "User".to_compact_string(),
-        StatemapInputState {
-            color: Some("#9BC362".to_compact_string()),
-            value: CpuState::User as usize,
-        },
-    );
-    // (Attempt to) compute time.
-    let time_range = perf_file
+    if let Some(cmdline) = &extras.cmdline {
+        eprintln!("cmdline: {}", cmdline.join(" "));
+    }
+    if let Some(release) = &extras.os_release {
+        eprintln!("kernel_version: {release}");
+    }
+    if let Some(range) = perf_file.sample_time_range()? {
+        eprintln!(
+            "sample_time_range: {}..{} ns",
+            range.first_sample_time, range.last_sample_time
+        );
+    }
+    Ok(())
+}
+
+/// Count the sample records in the input. The header is written before
+/// the records are processed, so this takes a separate pass over the file.
+fn count_samples(input: &str, mmap: bool) -> Result<u64, eyre::Error> {
+    let PerfFileReader {
+        mut perf_file,
+        mut record_iter,
+    } = PerfFileReader::parse_file(InputFile::open(input, mmap)?)?;
+    let mut count = 0;
+    while let Some(record) = record_iter.next_record(&mut perf_file)? {
+        if let PerfFileRecord::EventRecord { record, .. } = record
+            && record.record_type == RecordType::SAMPLE
+        {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Compute the wall clock time of the start of the trace, as `[seconds,
+/// nanoseconds]`.
+fn trace_start(perf_file: &linux_perf_data::PerfFile) -> Result<[u64; 2], eyre::Error> {
+    let Some(time_range) = perf_file
         .sample_time_range()
         .wrap_err("Failed to get sample time range")?
-        .ok_or_else(|| eyre!("No sample time range found"))?;
+    else {
+        // No samples, so there is nothing to place in time.
+        return Ok([0, 0]);
+    };
     let clock_data = perf_file.feature_section_data(linux_perf_data::Feature::CLOCK_DATA);
     let ts = match clock_data {
         Some(data) => {
             let parser = RawData::Single(data);
             let clock = ClockData::parse(parser, perf_file.endian())
-                .wrap_err("Failed to parse CLOCK_DATA feature")?;
+                .wrap_err("Failed to parse CLOCK_DATA feature")?
+                .common;
             // The first sample is not the same as the clock data sync point. I have seen it
             // be around half a second difference typically on my laptop. So compensate.
             clock.wall_clock_ns + (time_range.first_sample_time - clock.clockid_time_ns)
@@ -315,17 +1191,58 @@ fn write_header(
         }
     };
     const NS_PER_S: u64 = 1_000_000_000;
-    let metadata = statemap::StatemapInputMetadata {
-        start: vec![ts / NS_PER_S, ts % NS_PER_S],
-        title: "CPU".to_compact_string(),
-        host: perf_file
+    Ok([ts / NS_PER_S, ts % NS_PER_S])
+}
+
+/// Information about the traced system from the feature sections of the
+/// perf.data header.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct StatemapMetadataExtras {
+    hostname: Option<CompactString>,
+    os_release: Option<CompactString>,
+    arch: Option<CompactString>,
+    /// The perf command that recorded the trace.
+    cmdline: Option<Vec<CompactString>>,
+    nr_cpus_available: Option<u32>,
+    nr_cpus_online: Option<u32>,
+}
+
+/// Read all the metadata we use from the perf.data header. Feature sections
+/// that are missing or fail to parse are left out.
+fn extract_metadata(perf_file: &linux_perf_data::PerfFile) -> StatemapMetadataExtras {
+    let nr_cpus = perf_file.nr_cpus().unwrap_or_default();
+    let extras = StatemapMetadataExtras {
+        hostname: perf_file
             .hostname()
             .unwrap_or_default()
-            .map(|s| s.to_compact_string()),
-        entityKind: Some("CPU".to_compact_string()),
-        states, // This can be filled with actual states if needed
+            .map(CompactString::from),
+        os_release: perf_file
+            .os_release()
+            .unwrap_or_default()
+            .map(CompactString::from),
+        arch: perf_file
+            .arch()
+            .unwrap_or_default()
+            .map(CompactString::from),
+        cmdline: perf_file
+            .cmdline()
+            .unwrap_or_default()
+            .map(|args| args.into_iter().map(CompactString::from).collect()),
+        nr_cpus_available: nr_cpus.as_ref().map(|n| n.nr_cpus_available),
+        nr_cpus_online: nr_cpus.as_ref().map(|n| n.nr_cpus_online),
     };
-    serde_json::to_writer(&mut *writer, &metadata)?;
-    writeln!(writer)?;
-    Ok(())
+    log::debug!("perf.data metadata: {extras:?}");
+    extras
+}
+
+/// Get the host name the trace was recorded on, if known. With `fall_back`,
+/// use the current host name if the trace doesn't record one.
+fn hostname(recorded: Option<CompactString>, fall_back: bool) -> Option<CompactString> {
+    if recorded.is_some() || !fall_back {
+        return recorded;
+    }
+    let hostname = gethostname::gethostname();
+    let hostname = hostname.to_string_lossy();
+    log::debug!("perf.data has no hostname, using current host: {hostname}");
+    Some(hostname.to_compact_string())
 }
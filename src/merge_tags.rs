@@ -0,0 +1,68 @@
+//! Merging of consecutive records with the same state and tag.
+//!
+//! The same PID can appear in several `sched_switch` events in a row on a
+//! CPU. Each of these produces a record identical to the previous one apart
+//! from the time, which just adds noise to the statemap.
+
+use crate::statemap::StatemapInputDatum;
+use crate::types::CpuState;
+
+/// Tracks the last record written for each CPU.
+#[derive(Debug, Clone)]
+pub struct TagMerger {
+    /// The record that started the current run on each CPU. The run extends
+    /// until a record with a different state or tag is seen.
+    pending: Vec<Option<StatemapInputDatum<CpuState>>>,
+}
+
+impl TagMerger {
+    pub fn new(num_cpus: usize) -> Self {
+        Self {
+            pending: vec![None; num_cpus],
+        }
+    }
+
+    /// Track `num_cpus` CPUs, if that is more than currently tracked.
+    pub fn resize(&mut self, num_cpus: usize) {
+        if num_cpus > self.pending.len() {
+            self.pending.resize(num_cpus, None);
+        }
+    }
+
+    /// Check if `datum` continues the current run on `cpu`, in which case it
+    /// should not be written. Otherwise it starts a new run.
+    pub fn is_continuation(&mut self, cpu: usize, datum: &StatemapInputDatum<CpuState>) -> bool {
+        if let Some(pending) = &self.pending[cpu]
+            && pending.state == datum.state
+            && pending.tag == datum.tag
+        {
+            return true;
+        }
+        self.pending[cpu] = Some(datum.clone());
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let mut merger = TagMerger::new(2);
+        let datum = |time, state, tag: &str| {
+            StatemapInputDatum::builder()
+                .time(time)
+                .entity("0")
+                .state(state)
+                .tag(Some(tag))
+                .build()
+        };
+        assert!(!merger.is_continuation(0, &datum(0, CpuState::User, "a:1")));
+        assert!(merger.is_continuation(0, &datum(10, CpuState::User, "a:1")));
+        assert!(!merger.is_continuation(1, &datum(10, CpuState::User, "a:1")));
+        assert!(!merger.is_continuation(0, &datum(20, CpuState::Kernel, "a:1")));
+        assert!(!merger.is_continuation(0, &datum(30, CpuState::Kernel, "b:2")));
+        assert!(!merger.is_continuation(0, &datum(40, CpuState::Kernel, "a:1")));
+    }
+}
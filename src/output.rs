@@ -0,0 +1,101 @@
+//! Output formats for the converted trace.
+
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use eyre::eyre;
+use std::io::Write;
+use std::path::Path;
+
+pub mod core_group;
+pub mod datum_count;
+pub mod gnuplot;
+pub mod influxdb;
+pub mod json_lines;
+pub mod min_idle;
+pub mod min_state;
+pub mod no_tag;
+pub mod opentelemetry;
+pub mod per_second;
+pub mod record_count;
+pub mod sorted;
+pub mod split;
+pub mod sqlite;
+
+/// The output formats supported.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap_derive::ValueEnum)]
+pub enum OutputFormat {
+    /// Statemap input format (concatenated JSON)
+    #[default]
+    Statemap,
+    /// OpenTelemetry trace data (OTLP/JSON)
+    Opentelemetry,
+    /// InfluxDB line protocol, one measurement per state change
+    Influxdb,
+    /// Gnuplot script rendering a PNG. With `--output`, the data is written
+    /// next to it as a `.dat` file.
+    Gnuplot,
+    /// SQLite database (requires `--output`)
+    Sqlite,
+}
+
+/// How the records of the statemap format are encoded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap_derive::ValueEnum)]
+pub enum Encoding {
+    /// One JSON object per line
+    #[default]
+    JsonLines,
+    /// A JSON array, with the header as the first element
+    JsonArray,
+    /// Concatenated MessagePack maps
+    #[cfg(feature = "msgpack")]
+    Msgpack,
+}
+
+impl OutputFormat {
+    /// Create a sink writing this format. Formats that write additional
+    /// files, or can't write to a stream, use the `output` path.
+    pub fn sink(
+        self,
+        output: Option<&Path>,
+        encoding: Encoding,
+    ) -> Result<Box<dyn OutputSink>, eyre::Error> {
+        Ok(match self {
+            Self::Statemap => Box::new(json_lines::JsonLinesSink::new(encoding)),
+            Self::Opentelemetry => Box::new(opentelemetry::OpenTelemetrySink::default()),
+            Self::Influxdb => Box::new(influxdb::InfluxDbSink::default()),
+            Self::Gnuplot => Box::new(gnuplot::GnuplotSink::new(
+                output.map(|output| output.with_extension("dat")),
+            )),
+            Self::Sqlite => Box::new(sqlite::SqliteSink::create(
+                output.ok_or_else(|| eyre!("--format sqlite requires --output"))?,
+            )?),
+        })
+    }
+
+    /// Whether the sink writes the output file itself, rather than to the
+    /// output stream.
+    pub fn writes_output_file(self) -> bool {
+        matches!(self, Self::Sqlite)
+    }
+}
+
+/// Trait for writing the converted trace in some format.
+pub trait OutputSink {
+    /// Write the header. This is called once, before any datums.
+    fn write_header(
+        &mut self,
+        writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error>;
+
+    /// Write a single state change.
+    fn write_datum(
+        &mut self,
+        writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error>;
+
+    /// Write anything buffered. This is called once, after all datums.
+    fn finish(&mut self, writer: &mut dyn Write) -> Result<(), eyre::Error>;
+}
@@ -0,0 +1,156 @@
+//! Merging of SMT sibling CPUs into one entity per physical core.
+//!
+//! Each core shows the state of its busiest sibling, so a core only looks
+//! idle when all its hardware threads are.
+
+use super::OutputSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// How busy a state is, for picking which sibling a core shows. Interrupts
+/// rank above thread states since they preempt whatever was running.
+fn busyness(state: CpuState) -> u8 {
+    match state {
+        CpuState::Idle => 0,
+        CpuState::Migrating => 1,
+        CpuState::NumaMove => 2,
+        CpuState::Busy => 3,
+        CpuState::User => 4,
+        CpuState::Kernel => 5,
+        CpuState::RcuStall => 6,
+        CpuState::Tasklet => 7,
+        CpuState::Softirq => 8,
+        CpuState::Irq => 9,
+    }
+}
+
+type MemberState = (CpuState, Option<CompactString>);
+
+/// A physical core and the current state of each of its siblings.
+#[derive(Debug)]
+struct Core {
+    entity: CompactString,
+    members: Vec<Option<MemberState>>,
+    /// Last state written for the core.
+    written: Option<MemberState>,
+}
+
+/// Replaces the records of SMT siblings with records for their core.
+/// Entities that aren't part of a core are passed through unchanged.
+pub struct CoreGroupSink {
+    inner: Box<dyn OutputSink>,
+    cores: Vec<Core>,
+    /// Core and sibling index of each member entity.
+    members: HashMap<CompactString, (usize, usize)>,
+}
+
+impl CoreGroupSink {
+    /// Create a sink merging the given groups of entities, each into an
+    /// entity with the given name.
+    pub fn new(
+        inner: Box<dyn OutputSink>,
+        groups: impl IntoIterator<Item = (CompactString, Vec<CompactString>)>,
+    ) -> Self {
+        let mut cores = Vec::new();
+        let mut members = HashMap::new();
+        for (entity, siblings) in groups {
+            for (idx, sibling) in siblings.iter().enumerate() {
+                members.insert(sibling.clone(), (cores.len(), idx));
+            }
+            cores.push(Core {
+                entity,
+                members: vec![None; siblings.len()],
+                written: None,
+            });
+        }
+        Self {
+            inner,
+            cores,
+            members,
+        }
+    }
+}
+
+impl OutputSink for CoreGroupSink {
+    fn write_header(
+        &mut self,
+        writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        self.inner.write_header(writer, metadata)
+    }
+
+    fn write_datum(
+        &mut self,
+        writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        let Some(&(core_idx, member)) = self.members.get(&datum.entity) else {
+            return self.inner.write_datum(writer, datum);
+        };
+        let core = &mut self.cores[core_idx];
+        core.members[member] = Some((datum.state, datum.tag.clone()));
+        let busiest = core
+            .members
+            .iter()
+            .flatten()
+            .max_by_key(|(state, _)| busyness(*state))
+            .cloned();
+        let Some(busiest) = busiest else {
+            return Ok(());
+        };
+        if core.written.as_ref() == Some(&busiest) {
+            return Ok(());
+        }
+        let core_datum = StatemapInputDatum {
+            time: datum.time,
+            entity: core.entity.clone(),
+            state: busiest.0,
+            tag: busiest.1.clone(),
+        };
+        core.written = Some(busiest);
+        self.inner.write_datum(writer, &core_datum)
+    }
+
+    fn finish(&mut self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        self.inner.finish(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::json_lines::JsonLinesSink;
+
+    #[test]
+    fn test_core_group() {
+        let mut sink = CoreGroupSink::new(
+            Box::new(JsonLinesSink::default()),
+            [("0+1".into(), vec!["0".into(), "1".into()])],
+        );
+        let mut out = Vec::new();
+        for (time, entity, state, tag) in [
+            (10, "0", CpuState::User, Some("a:1")),
+            // Idle sibling doesn't change the core.
+            (20, "1", CpuState::Idle, None),
+            (30, "1", CpuState::Irq, Some("eth0:5")),
+            (40, "0", CpuState::Idle, None),
+            (50, "1", CpuState::Idle, None),
+            // Not part of a core.
+            (60, "2", CpuState::Kernel, Some("b:2")),
+        ] {
+            let datum = StatemapInputDatum::builder()
+                .time(time)
+                .entity(entity)
+                .state(state)
+                .tag(tag)
+                .build();
+            sink.write_datum(&mut out, &datum).unwrap();
+        }
+        insta::assert_snapshot!(String::from_utf8(out).unwrap());
+    }
+}
@@ -0,0 +1,74 @@
+//! Counting the datums written, for the conversion metrics.
+
+use super::OutputSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use std::cell::Cell;
+use std::io::Write;
+use std::rc::Rc;
+
+/// Counts the datums written to `inner`, into a shared counter.
+pub struct DatumCountSink {
+    inner: Box<dyn OutputSink>,
+    count: Rc<Cell<u64>>,
+}
+
+impl DatumCountSink {
+    /// Count the datums written to `inner`. The returned counter is updated
+    /// as datums are written.
+    pub fn new(inner: Box<dyn OutputSink>) -> (Self, Rc<Cell<u64>>) {
+        let count = Rc::new(Cell::new(0));
+        let sink = Self {
+            inner,
+            count: Rc::clone(&count),
+        };
+        (sink, count)
+    }
+}
+
+impl OutputSink for DatumCountSink {
+    fn write_header(
+        &mut self,
+        writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        self.inner.write_header(writer, metadata)
+    }
+
+    fn write_datum(
+        &mut self,
+        writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        self.count.set(self.count.get() + 1);
+        self.inner.write_datum(writer, datum)
+    }
+
+    fn finish(&mut self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        self.inner.finish(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::json_lines::JsonLinesSink;
+
+    #[test]
+    fn test_datum_count() {
+        let (mut sink, count) = DatumCountSink::new(Box::new(JsonLinesSink::default()));
+        let mut out = Vec::new();
+        for time in [10, 20, 30] {
+            let datum = StatemapInputDatum::builder()
+                .time(time)
+                .entity("0")
+                .state(CpuState::User)
+                .build();
+            sink.write_datum(&mut out, &datum).unwrap();
+        }
+        sink.finish(&mut out).unwrap();
+        assert_eq!(count.get(), 3);
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 3);
+    }
+}
@@ -0,0 +1,276 @@
+//! Gnuplot output, for viewing traces without a web browser.
+//!
+//! The output is a gnuplot script that renders the states as colored
+//! rectangles into a PNG. The data has one line per interval, with the
+//! columns `time_s cpu state_id duration_s`, where `cpu` is the row in the
+//! plot. It is written to a separate `.dat` file when writing to a file, and
+//! inline in the script otherwise.
+
+use super::OutputSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use eyre::Context;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Color of states without one.
+const DEFAULT_COLOR: &str = "#808080";
+
+/// Collects intervals and writes the gnuplot script at the end.
+#[derive(Debug)]
+pub struct GnuplotSink {
+    /// Where to write the data, if not inline in the script.
+    data_path: Option<PathBuf>,
+    /// The data file, if writing one.
+    data_file: Option<BufWriter<File>>,
+    /// The data, if inline in the script.
+    inline_data: String,
+    /// Title of the plot.
+    title: CompactString,
+    /// Datum time units per second (differs from 1e9 with `--normalize-to-Hz`).
+    units_per_second: f64,
+    /// State names and colors, by value.
+    states: Vec<(usize, CompactString, CompactString)>,
+    /// Row of each entity in the plot, in order of first appearance.
+    rows: HashMap<CompactString, usize>,
+    /// Currently open interval for each entity.
+    open: HashMap<CompactString, StatemapInputDatum<CpuState>>,
+    /// Latest time seen.
+    end_time: u64,
+}
+
+impl GnuplotSink {
+    /// Create a sink writing the data to `data_path`, or inline in the script
+    /// if `None`.
+    pub fn new(data_path: Option<PathBuf>) -> Self {
+        Self {
+            data_path,
+            data_file: None,
+            inline_data: String::new(),
+            title: CompactString::default(),
+            units_per_second: 1e9,
+            states: Vec::new(),
+            rows: HashMap::new(),
+            open: HashMap::new(),
+            end_time: 0,
+        }
+    }
+
+    /// Write the interval of `datum`, which ended at `end`.
+    fn close(&mut self, datum: &StatemapInputDatum<CpuState>, end: u64) -> std::io::Result<()> {
+        let row = self.rows[&datum.entity];
+        let line = format!(
+            "{:.9} {row} {} {:.9}\n",
+            datum.time as f64 / self.units_per_second,
+            datum.state as u8,
+            (end - datum.time) as f64 / self.units_per_second
+        );
+        match &mut self.data_file {
+            Some(file) => file.write_all(line.as_bytes()),
+            None => {
+                self.inline_data.push_str(&line);
+                Ok(())
+            }
+        }
+    }
+
+    /// Name of the PNG file the script renders to.
+    fn png_name(&self) -> String {
+        self.data_path.as_ref().map_or_else(
+            || "statemap.png".into(),
+            |path| path.with_extension("png").display().to_string(),
+        )
+    }
+}
+
+impl OutputSink for GnuplotSink {
+    fn write_header(
+        &mut self,
+        _writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        self.title.clone_from(&metadata.title);
+        if let Some(ghz) = metadata.cycleFrequencyGHz {
+            self.units_per_second = ghz * 1e9;
+        }
+        self.states = metadata
+            .states_sorted_by_value()
+            .into_iter()
+            .map(|(name, state)| {
+                let color = state.color.as_deref().unwrap_or(DEFAULT_COLOR);
+                (state.value, name.clone(), expand_color(color))
+            })
+            .collect();
+        if let Some(path) = &self.data_path {
+            let file = File::create(path)
+                .wrap_err_with(|| format!("Failed to create \"{}\"", path.display()))?;
+            let mut file = BufWriter::new(file);
+            writeln!(file, "# time_s cpu state_id duration_s")?;
+            self.data_file = Some(file);
+        }
+        Ok(())
+    }
+
+    fn write_datum(
+        &mut self,
+        _writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        self.end_time = self.end_time.max(datum.time);
+        let next_row = self.rows.len();
+        self.rows.entry(datum.entity.clone()).or_insert(next_row);
+        if let Some(prev) = self.open.insert(datum.entity.clone(), datum.clone()) {
+            self.close(&prev, datum.time)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        let mut open: Vec<_> = std::mem::take(&mut self.open).into_values().collect();
+        open.sort_by(|a, b| a.entity.cmp(&b.entity));
+        for datum in &open {
+            self.close(datum, self.end_time)?;
+        }
+        if let Some(mut file) = self.data_file.take() {
+            file.flush()?;
+        }
+
+        let mut rows: Vec<_> = self.rows.iter().collect();
+        rows.sort_by_key(|(_, row)| **row);
+        let height = 200 + 20 * rows.len();
+        let mut script = String::new();
+        writeln!(script, "# Render with: gnuplot <this file>")?;
+        writeln!(script, "set terminal pngcairo size 1600,{height}")?;
+        writeln!(script, "set output \"{}\"", self.png_name())?;
+        writeln!(script, "set title \"{}\"", escape(&self.title))?;
+        writeln!(script, "set xlabel \"Time (s)\"")?;
+        writeln!(script, "set ylabel \"CPU\"")?;
+        writeln!(script, "set yrange [-0.5:{}]", rows.len() as f64 - 0.5)?;
+        let labels: Vec<_> = rows
+            .iter()
+            .map(|(entity, row)| format!("\"{}\" {row}", escape(entity)))
+            .collect();
+        writeln!(script, "set ytics ({})", labels.join(", "))?;
+        writeln!(script, "set key outside right")?;
+        writeln!(script, "set style fill solid noborder")?;
+        let colors: Vec<_> = self
+            .states
+            .iter()
+            .map(|(value, _, color)| format!("s == {value} ? {} : ", color.replace('#', "0x")))
+            .collect();
+        writeln!(
+            script,
+            "color(s) = {}{}",
+            colors.concat(),
+            DEFAULT_COLOR.replace('#', "0x")
+        )?;
+        let data = match &self.data_path {
+            Some(path) => format!("\"{}\"", escape(&path.display().to_string())),
+            None => {
+                writeln!(script, "$data << EOD")?;
+                script.push_str(&self.inline_data);
+                writeln!(script, "EOD")?;
+                "$data".into()
+            }
+        };
+        write!(
+            script,
+            "plot {data} using ($1+$4/2):2:1:($1+$4):($2-0.4):($2+0.4):(color($3)) \
+             with boxxyerror lc rgb variable notitle"
+        )?;
+        for (_, name, color) in &self.states {
+            write!(
+                script,
+                ", \\\n     NaN with boxes lc rgb \"{color}\" title \"{}\"",
+                escape(name)
+            )?;
+        }
+        writeln!(script)?;
+        writer.write_all(script.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Expand `#RGB` colors to `#RRGGBB`, as gnuplot only accepts the latter.
+fn expand_color(color: &str) -> CompactString {
+    match color.strip_prefix('#') {
+        Some(hex) if hex.len() == 3 => {
+            hex.chars()
+                .flat_map(|c| [c, c])
+                .fold(CompactString::const_new("#"), |mut acc, c| {
+                    acc.push(c);
+                    acc
+                })
+        }
+        _ => color.into(),
+    }
+}
+
+/// Escape a string for use in a double quoted gnuplot string.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statemap::StatemapInputState;
+
+    #[test]
+    fn test_gnuplot_output() {
+        let mut sink = GnuplotSink::new(None);
+        let mut out = Vec::new();
+        let metadata = StatemapInputMetadata {
+            start: [1, 500],
+            title: "CPU".into(),
+            host: None,
+            entityKind: None,
+            arch: None,
+            states: [
+                (
+                    "Idle".into(),
+                    StatemapInputState {
+                        color: Some("#e0e0e0".into()),
+                        value: 0,
+                    },
+                ),
+                (
+                    "User".into(),
+                    StatemapInputState {
+                        color: Some("#9B6".into()),
+                        value: 5,
+                    },
+                ),
+            ]
+            .into(),
+            cycleFrequencyGHz: None,
+            schemaVersion: None,
+            entities: None,
+            duration_ns: None,
+            event_count: None,
+            extensions: Default::default(),
+        };
+        sink.write_header(&mut out, &metadata).unwrap();
+        for (time, entity, state) in [
+            (0, "1", CpuState::Idle),
+            (500_000_000, "0", CpuState::User),
+            (1_000_000_000, "1", CpuState::User),
+            (1_500_000_000, "0", CpuState::Idle),
+        ] {
+            let datum = StatemapInputDatum::builder()
+                .time(time)
+                .entity(entity)
+                .state(state)
+                .build();
+            sink.write_datum(&mut out, &datum).unwrap();
+        }
+        sink.finish(&mut out).unwrap();
+        insta::assert_snapshot!(String::from_utf8(out).unwrap());
+    }
+}
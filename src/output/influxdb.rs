@@ -0,0 +1,122 @@
+//! InfluxDB line protocol output.
+//!
+//! Each state change becomes a `cpu_state` measurement, for storing traces in
+//! time series databases such as InfluxDB or VictoriaMetrics.
+
+use super::OutputSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use std::io::Write;
+
+const NS_PER_S: u64 = 1_000_000_000;
+
+/// Writes one line per state change.
+#[derive(Debug, Default)]
+pub struct InfluxDbSink {
+    /// Wall clock time of the start of the trace.
+    start_ns: u64,
+    /// Host tag, already escaped.
+    host: Option<CompactString>,
+}
+
+impl OutputSink for InfluxDbSink {
+    fn write_header(
+        &mut self,
+        _writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        self.start_ns = metadata.start[0] * NS_PER_S + metadata.start[1];
+        self.host = metadata.host.as_deref().map(escape_tag);
+        Ok(())
+    }
+
+    fn write_datum(
+        &mut self,
+        writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        write!(writer, "cpu_state,cpu={}", escape_tag(&datum.entity))?;
+        if let Some(host) = &self.host {
+            write!(writer, ",host={host}")?;
+        }
+        write!(writer, " state={}i", datum.state as u8)?;
+        if let Some(tag) = &datum.tag {
+            write!(writer, ",tag=\"{}\"", escape_field(tag))?;
+        }
+        writeln!(writer, " {}", self.start_ns + datum.time)?;
+        Ok(())
+    }
+
+    fn finish(&mut self, _writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        Ok(())
+    }
+}
+
+/// Escape a tag value (commas, equals signs and spaces).
+fn escape_tag(value: &str) -> CompactString {
+    let mut escaped = CompactString::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escape a string field value (double quotes and backslashes).
+fn escape_field(value: &str) -> CompactString {
+    let mut escaped = CompactString::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_influxdb_output() {
+        let mut sink = InfluxDbSink::default();
+        let mut out = Vec::new();
+        let metadata = StatemapInputMetadata {
+            start: [1, 500],
+            title: "CPU".into(),
+            host: Some("my host".into()),
+            entityKind: None,
+            arch: None,
+            states: Default::default(),
+            cycleFrequencyGHz: None,
+            schemaVersion: None,
+            entities: None,
+            duration_ns: None,
+            event_count: None,
+            extensions: Default::default(),
+        };
+        sink.write_header(&mut out, &metadata).unwrap();
+        for (time, state, tag) in [
+            (10, CpuState::User, Some(r#"a "b"\c:1"#)),
+            (30, CpuState::Idle, None),
+        ] {
+            sink.write_datum(
+                &mut out,
+                &StatemapInputDatum {
+                    time,
+                    entity: "0".into(),
+                    state,
+                    tag: tag.map(Into::into),
+                },
+            )
+            .unwrap();
+        }
+        sink.finish(&mut out).unwrap();
+        insta::assert_snapshot!(String::from_utf8(out).unwrap());
+    }
+}
@@ -0,0 +1,204 @@
+//! The statemap input format: one JSON object per line, or one of the
+//! alternative [`Encoding`]s of it.
+
+use super::Encoding;
+use super::OutputSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputEvent;
+use crate::statemap::StatemapInputMetadata;
+use crate::statemap::validate_colors;
+use crate::statemap::validate_states;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use std::collections::HashSet;
+use std::io::Write;
+
+/// Writes the header and each datum as a line of JSON.
+#[derive(Debug, Default)]
+pub struct JsonLinesSink {
+    /// Entities that have been born, if writing the v2 format (with entity
+    /// lifecycle events).
+    born: Option<HashSet<CompactString>>,
+    encoding: Encoding,
+    /// Number of records written so far.
+    records: u64,
+}
+
+impl JsonLinesSink {
+    /// Create a sink writing records with the given encoding.
+    pub fn new(encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            ..Self::default()
+        }
+    }
+
+    fn write_record(
+        &mut self,
+        writer: &mut dyn Write,
+        record: &impl serde::Serialize,
+    ) -> Result<(), eyre::Error> {
+        match self.encoding {
+            Encoding::JsonLines => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writeln!(writer)?;
+            }
+            Encoding::JsonArray => {
+                writer.write_all(if self.records == 0 { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut *writer, record)?;
+            }
+            #[cfg(feature = "msgpack")]
+            Encoding::Msgpack => rmp_serde::encode::write_named(writer, record)?,
+        }
+        self.records += 1;
+        Ok(())
+    }
+}
+
+impl OutputSink for JsonLinesSink {
+    fn write_header(
+        &mut self,
+        writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        validate_states(&metadata.states)?;
+        validate_colors(&metadata.states)?;
+        if metadata.schemaVersion.is_some_and(|version| version >= 2) {
+            self.born = Some(HashSet::new());
+        }
+        self.write_record(writer, &metadata.to_json()?)
+    }
+
+    fn write_datum(
+        &mut self,
+        writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        if let Some(born) = &mut self.born
+            && !born.contains(&datum.entity)
+        {
+            born.insert(datum.entity.clone());
+            let event = StatemapInputEvent {
+                time: datum.time,
+                entity: datum.entity.clone(),
+                event: "birth".into(),
+                target: None,
+            };
+            self.write_record(writer, &event)?;
+        }
+        #[cfg(feature = "msgpack")]
+        if self.encoding == Encoding::Msgpack {
+            datum.encode_msgpack(writer)?;
+            self.records += 1;
+            return Ok(());
+        }
+        self.write_record(writer, datum)
+    }
+
+    fn finish(&mut self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        // CPUs never die, so there are no death events to write.
+        if self.encoding == Encoding::JsonArray {
+            if self.records == 0 {
+                write!(writer, "[")?;
+            }
+            writeln!(writer, "\n]")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colors::ColorScheme;
+
+    #[test]
+    fn test_v2_lifecycle() {
+        let mut sink = JsonLinesSink::default();
+        let mut out = Vec::new();
+        let metadata = StatemapInputMetadata {
+            start: [1, 500],
+            title: "CPU".into(),
+            host: None,
+            entityKind: None,
+            arch: None,
+            states: ColorScheme::Default.states(),
+            cycleFrequencyGHz: None,
+            schemaVersion: Some(2),
+            entities: None,
+            duration_ns: None,
+            event_count: None,
+            extensions: Default::default(),
+        };
+        sink.write_header(&mut out, &metadata).unwrap();
+        out.clear();
+        for (time, entity) in [(10, "0"), (20, "1"), (30, "0")] {
+            let datum = StatemapInputDatum::builder()
+                .time(time)
+                .entity(entity)
+                .state(CpuState::User)
+                .build();
+            sink.write_datum(&mut out, &datum).unwrap();
+        }
+        sink.finish(&mut out).unwrap();
+        insta::assert_snapshot!(String::from_utf8(out).unwrap());
+    }
+
+    fn write_all(mut sink: JsonLinesSink) -> Vec<u8> {
+        let mut out = Vec::new();
+        let metadata = StatemapInputMetadata {
+            start: [1, 500],
+            title: "CPU".into(),
+            host: None,
+            entityKind: None,
+            arch: None,
+            states: Default::default(),
+            cycleFrequencyGHz: None,
+            schemaVersion: None,
+            entities: None,
+            duration_ns: None,
+            event_count: None,
+            extensions: Default::default(),
+        };
+        sink.write_header(&mut out, &metadata).unwrap();
+        for (time, state) in [(10, CpuState::User), (20, CpuState::Idle)] {
+            let datum = StatemapInputDatum::builder()
+                .time(time)
+                .entity("0")
+                .state(state)
+                .build();
+            sink.write_datum(&mut out, &datum).unwrap();
+        }
+        sink.finish(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_json_array() {
+        let out = write_all(JsonLinesSink::new(Encoding::JsonArray));
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value[0]["title"], "CPU");
+        assert_eq!(value[2]["time"], "20");
+        insta::assert_snapshot!(String::from_utf8(out).unwrap());
+
+        let mut out = Vec::new();
+        let mut sink = JsonLinesSink::new(Encoding::JsonArray);
+        sink.finish(&mut out).unwrap();
+        assert_eq!(out, b"[\n]\n");
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack() {
+        let out = write_all(JsonLinesSink::new(Encoding::Msgpack));
+        let mut reader = out.as_slice();
+        let header: serde_json::Value = rmp_serde::from_read(&mut reader).unwrap();
+        assert_eq!(header["title"], "CPU");
+        let datum: serde_json::Value = rmp_serde::from_read(&mut reader).unwrap();
+        assert_eq!(datum["time"], "10");
+        assert_eq!(datum["entity"], "0");
+        let datum: serde_json::Value = rmp_serde::from_read(&mut reader).unwrap();
+        assert_eq!(datum["time"], "20");
+        assert!(reader.is_empty());
+    }
+}
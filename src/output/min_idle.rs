@@ -0,0 +1,189 @@
+//! Dropping of short idle periods.
+//!
+//! Idle records are held back until the next non-idle record of the same
+//! entity, at which point we know how long the idle period was. To keep the
+//! output in time order, the records of other entities that come after a
+//! held back idle record are held back too.
+
+use super::OutputSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io::Write;
+
+/// A record waiting to be written.
+struct Queued {
+    datum: StatemapInputDatum<CpuState>,
+    /// Whether to write the record, `None` for idle records whose duration
+    /// isn't known yet.
+    keep: Option<bool>,
+}
+
+/// Drops idle periods shorter than a minimum duration, so the state before
+/// them continues through them instead.
+pub struct MinIdleSink {
+    inner: Box<dyn OutputSink>,
+    min_duration: u64,
+    /// Records not written yet, in the order they came in.
+    queue: VecDeque<Queued>,
+    /// Sequence number of the first record in `queue`.
+    first_seq: u64,
+    /// Sequence numbers of the undecided idle records of each entity.
+    pending: HashMap<CompactString, Vec<u64>>,
+}
+
+impl MinIdleSink {
+    pub fn new(inner: Box<dyn OutputSink>, min_duration: u64) -> Self {
+        Self {
+            inner,
+            min_duration,
+            queue: VecDeque::new(),
+            first_seq: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Write the records at the front of the queue that are decided.
+    fn write_decided(&mut self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        while let Some(queued) = self.queue.front()
+            && let Some(keep) = queued.keep
+        {
+            if keep {
+                self.inner.write_datum(writer, &queued.datum)?;
+            }
+            self.queue.pop_front();
+            self.first_seq += 1;
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for MinIdleSink {
+    fn write_header(
+        &mut self,
+        writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        self.inner.write_header(writer, metadata)
+    }
+
+    fn write_datum(
+        &mut self,
+        writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        let seq = self.first_seq + self.queue.len() as u64;
+        if datum.state == CpuState::Idle {
+            self.pending
+                .entry(datum.entity.clone())
+                .or_default()
+                .push(seq);
+            self.queue.push_back(Queued {
+                datum: datum.clone(),
+                keep: None,
+            });
+            return Ok(());
+        }
+        if let Some(idle) = self.pending.remove(&datum.entity)
+            && let Some(&first) = idle.first()
+        {
+            let start = self.queue[(first - self.first_seq) as usize].datum.time;
+            let keep = datum.time.saturating_sub(start) >= self.min_duration;
+            for seq in idle {
+                self.queue[(seq - self.first_seq) as usize].keep = Some(keep);
+            }
+        }
+        if self.queue.is_empty() {
+            return self.inner.write_datum(writer, datum);
+        }
+        self.queue.push_back(Queued {
+            datum: datum.clone(),
+            keep: Some(true),
+        });
+        self.write_decided(writer)
+    }
+
+    fn finish(&mut self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        // How long the idle periods at the end of the trace are is unknown,
+        // so keep them.
+        for queued in &mut self.queue {
+            queued.keep.get_or_insert(true);
+        }
+        self.pending.clear();
+        self.write_decided(writer)?;
+        self.inner.finish(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::json_lines::JsonLinesSink;
+
+    #[test]
+    fn test_min_idle() {
+        let mut sink = MinIdleSink::new(Box::new(JsonLinesSink::default()), 100);
+        let mut out = Vec::new();
+        for (time, entity, state, tag) in [
+            (0, "0", CpuState::User, Some("a:1")),
+            // Too short, dropped.
+            (10, "0", CpuState::Idle, None),
+            (50, "0", CpuState::User, Some("b:2")),
+            // Long enough, kept.
+            (100, "0", CpuState::Idle, None),
+            (300, "0", CpuState::Kernel, Some("c:3")),
+            // Idle at the end of the trace is kept.
+            (400, "1", CpuState::Idle, None),
+        ] {
+            let datum = StatemapInputDatum::builder()
+                .time(time)
+                .entity(entity)
+                .state(state)
+                .tag(tag)
+                .build();
+            sink.write_datum(&mut out, &datum).unwrap();
+        }
+        sink.finish(&mut out).unwrap();
+        insta::assert_snapshot!(String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn test_min_idle_time_order() {
+        let mut sink = MinIdleSink::new(Box::new(JsonLinesSink::default()), 100);
+        let mut out = Vec::new();
+        for (time, entity, state) in [
+            (0, "0", CpuState::User),
+            (10, "0", CpuState::Idle),
+            // Held back until the idle period of entity 0 is over.
+            (20, "1", CpuState::Kernel),
+            (150, "1", CpuState::Idle),
+            (200, "0", CpuState::User),
+            // Too short, dropped.
+            (210, "1", CpuState::User),
+        ] {
+            let datum = StatemapInputDatum::builder()
+                .time(time)
+                .entity(entity)
+                .state(state)
+                .build();
+            sink.write_datum(&mut out, &datum).unwrap();
+        }
+        sink.finish(&mut out).unwrap();
+        let written: Vec<_> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let datum: serde_json::Value = serde_json::from_str(line).unwrap();
+                format!(
+                    "{}@{}",
+                    datum["entity"].as_str().unwrap(),
+                    datum["time"].as_str().unwrap()
+                )
+            })
+            .collect();
+        assert_eq!(written, ["0@0", "0@10", "1@20", "0@200", "1@210"]);
+    }
+}
@@ -0,0 +1,218 @@
+//! Dropping of very short interrupts.
+//!
+//! Interrupt records are held back until the next record of the same
+//! entity, at which point we know how long the interrupt lasted. If it was
+//! too short, it is dropped along with the record restoring the previous
+//! state. To keep the output in time order, the records of other entities
+//! that come after a held back interrupt record are held back too.
+
+use super::OutputSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io::Write;
+
+/// A record waiting to be written.
+struct Queued {
+    datum: StatemapInputDatum<CpuState>,
+    /// Whether to write the record, `None` for interrupt records whose
+    /// duration isn't known yet.
+    keep: Option<bool>,
+}
+
+/// Drops IRQs, softirqs and tasklets shorter than a minimum duration, so the
+/// state before them continues through them instead.
+pub struct MinStateSink {
+    inner: Box<dyn OutputSink>,
+    min_duration: u64,
+    /// Records not written yet, in the order they came in.
+    queue: VecDeque<Queued>,
+    /// Sequence number of the first record in `queue`.
+    first_seq: u64,
+    /// Sequence number of the undecided interrupt record of each entity.
+    held: HashMap<CompactString, u64>,
+    /// State and tag of the last record kept for each entity.
+    kept: HashMap<CompactString, (CpuState, Option<CompactString>)>,
+}
+
+impl MinStateSink {
+    pub fn new(inner: Box<dyn OutputSink>, min_duration: u64) -> Self {
+        Self {
+            inner,
+            min_duration,
+            queue: VecDeque::new(),
+            first_seq: 0,
+            held: HashMap::new(),
+            kept: HashMap::new(),
+        }
+    }
+
+    /// Record that `datum` will be written.
+    fn keep(&mut self, datum: &StatemapInputDatum<CpuState>) {
+        self.kept
+            .insert(datum.entity.clone(), (datum.state, datum.tag.clone()));
+    }
+
+    /// Write the records at the front of the queue that are decided.
+    fn write_decided(&mut self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        while let Some(queued) = self.queue.front()
+            && let Some(keep) = queued.keep
+        {
+            if keep {
+                self.inner.write_datum(writer, &queued.datum)?;
+            }
+            self.queue.pop_front();
+            self.first_seq += 1;
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for MinStateSink {
+    fn write_header(
+        &mut self,
+        writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        self.inner.write_header(writer, metadata)
+    }
+
+    fn write_datum(
+        &mut self,
+        writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        let mut dropped = false;
+        if let Some(seq) = self.held.remove(&datum.entity) {
+            let held = &mut self.queue[(seq - self.first_seq) as usize];
+            // Out of order records count as too short.
+            let keep = datum.time.saturating_sub(held.datum.time) >= self.min_duration;
+            held.keep = Some(keep);
+            if keep {
+                let held = held.datum.clone();
+                self.keep(&held);
+            } else {
+                dropped = true;
+            }
+        }
+        let seq = self.first_seq + self.queue.len() as u64;
+        if matches!(
+            datum.state,
+            CpuState::Irq | CpuState::Softirq | CpuState::Tasklet
+        ) {
+            self.held.insert(datum.entity.clone(), seq);
+            self.queue.push_back(Queued {
+                datum: datum.clone(),
+                keep: None,
+            });
+            return self.write_decided(writer);
+        }
+        // Restoring the state from before a dropped interrupt.
+        let restores = dropped
+            && self
+                .kept
+                .get(&datum.entity)
+                .is_some_and(|(state, tag)| *state == datum.state && *tag == datum.tag);
+        if !restores {
+            self.keep(datum);
+            if self.queue.is_empty() {
+                return self.inner.write_datum(writer, datum);
+            }
+            self.queue.push_back(Queued {
+                datum: datum.clone(),
+                keep: Some(true),
+            });
+        }
+        self.write_decided(writer)
+    }
+
+    fn finish(&mut self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        // How long the interrupts at the end of the trace lasted is unknown,
+        // so keep them.
+        for queued in &mut self.queue {
+            queued.keep.get_or_insert(true);
+        }
+        self.held.clear();
+        self.write_decided(writer)?;
+        self.inner.finish(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::json_lines::JsonLinesSink;
+
+    #[test]
+    fn test_min_state() {
+        let mut sink = MinStateSink::new(Box::new(JsonLinesSink::default()), 100);
+        let mut out = Vec::new();
+        for (time, entity, state, tag) in [
+            (0, "0", CpuState::User, Some("a:1")),
+            // Too short, dropped together with the return to a:1.
+            (10, "0", CpuState::Softirq, Some("NET_RX")),
+            (50, "0", CpuState::User, Some("a:1")),
+            // Long enough, kept.
+            (100, "0", CpuState::Irq, Some("IRQ 5: eth0")),
+            (300, "0", CpuState::User, Some("a:1")),
+            // Too short, but the thread changed meanwhile.
+            (400, "0", CpuState::Tasklet, Some("Tasklet 0x10")),
+            (420, "0", CpuState::Kernel, Some("b:2")),
+            // Interrupt at the end of the trace is kept.
+            (500, "1", CpuState::Irq, Some("IRQ 7: ahci")),
+        ] {
+            let datum = StatemapInputDatum::builder()
+                .time(time)
+                .entity(entity)
+                .state(state)
+                .tag(tag)
+                .build();
+            sink.write_datum(&mut out, &datum).unwrap();
+        }
+        sink.finish(&mut out).unwrap();
+        insta::assert_snapshot!(String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn test_min_state_time_order() {
+        let mut sink = MinStateSink::new(Box::new(JsonLinesSink::default()), 100);
+        let mut out = Vec::new();
+        for (time, entity, state) in [
+            (0, "0", CpuState::User),
+            (10, "0", CpuState::Irq),
+            // Held back until the IRQ on entity 0 is over.
+            (20, "1", CpuState::Kernel),
+            (30, "1", CpuState::Softirq),
+            (40, "0", CpuState::Kernel),
+            // Long enough, kept.
+            (150, "1", CpuState::Kernel),
+            (160, "0", CpuState::Tasklet),
+            // Out of order, counts as too short.
+            (155, "0", CpuState::User),
+        ] {
+            let datum = StatemapInputDatum::builder()
+                .time(time)
+                .entity(entity)
+                .state(state)
+                .build();
+            sink.write_datum(&mut out, &datum).unwrap();
+        }
+        sink.finish(&mut out).unwrap();
+        let written: Vec<_> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let datum: serde_json::Value = serde_json::from_str(line).unwrap();
+                format!(
+                    "{}@{}",
+                    datum["entity"].as_str().unwrap(),
+                    datum["time"].as_str().unwrap()
+                )
+            })
+            .collect();
+        assert_eq!(written, ["0@0", "1@20", "1@30", "0@40", "1@150", "0@155"]);
+    }
+}
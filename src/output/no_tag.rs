@@ -0,0 +1,72 @@
+//! Removal of tags, for smaller output.
+
+use super::OutputSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use std::io::Write;
+
+/// Writes every record without its tag.
+pub struct NoTagSink {
+    inner: Box<dyn OutputSink>,
+}
+
+impl NoTagSink {
+    pub fn new(inner: Box<dyn OutputSink>) -> Self {
+        Self { inner }
+    }
+}
+
+impl OutputSink for NoTagSink {
+    fn write_header(
+        &mut self,
+        writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        self.inner.write_header(writer, metadata)
+    }
+
+    fn write_datum(
+        &mut self,
+        writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        if datum.tag.is_none() {
+            return self.inner.write_datum(writer, datum);
+        }
+        let untagged = StatemapInputDatum {
+            tag: None,
+            entity: datum.entity.clone(),
+            ..*datum
+        };
+        self.inner.write_datum(writer, &untagged)
+    }
+
+    fn finish(&mut self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        self.inner.finish(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::json_lines::JsonLinesSink;
+
+    #[test]
+    fn test_no_tag() {
+        let mut sink = NoTagSink::new(Box::new(JsonLinesSink::default()));
+        let mut out = Vec::new();
+        let datum = StatemapInputDatum::builder()
+            .time(10)
+            .entity("0")
+            .state(CpuState::User)
+            .tag(Some("bash:10"))
+            .build();
+        sink.write_datum(&mut out, &datum).unwrap();
+        // The key is left out, not written as null.
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"time\":\"10\",\"entity\":\"0\",\"state\":5}\n"
+        );
+    }
+}
@@ -0,0 +1,277 @@
+//! OpenTelemetry trace output (OTLP/JSON).
+//!
+//! Each interval a CPU spends in a state becomes a span, with a root span
+//! covering the whole trace. This allows importing traces into tools such as
+//! Jaeger, Tempo or Honeycomb.
+
+use super::OutputSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use compact_str::ToCompactString;
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+
+const NS_PER_S: u64 = 1_000_000_000;
+
+/// `SPAN_KIND_INTERNAL`
+const SPAN_KIND_INTERNAL: u8 = 1;
+
+/// Collects spans and writes them as a single OTLP/JSON document at the end.
+#[derive(Debug, Default)]
+pub struct OpenTelemetrySink {
+    /// Wall clock time of the start of the trace.
+    start_ns: u64,
+    /// Host the trace was recorded on.
+    host: Option<CompactString>,
+    /// Names of states, by value.
+    state_names: HashMap<usize, CompactString>,
+    /// Currently open interval for each entity.
+    open: HashMap<CompactString, StatemapInputDatum<CpuState>>,
+    /// Latest time seen.
+    end_time: u64,
+    /// Completed spans.
+    spans: Vec<Span>,
+}
+
+impl OpenTelemetrySink {
+    fn trace_id(&self) -> String {
+        format!("{:032x}", u128::from(self.start_ns).max(1))
+    }
+
+    fn close(&mut self, datum: StatemapInputDatum<CpuState>, end: u64) {
+        let name = self
+            .state_names
+            .get(&(datum.state as usize))
+            .cloned()
+            .unwrap_or_else(|| format!("{:?}", datum.state).to_compact_string());
+        let mut attributes = vec![KeyValue::string("cpu", datum.entity)];
+        if let Some(tag) = datum.tag {
+            attributes.push(KeyValue::string("tag", tag));
+        }
+        self.spans.push(Span {
+            trace_id: self.trace_id(),
+            span_id: span_id(self.spans.len() as u64 + 2),
+            parent_span_id: Some(span_id(1)),
+            name,
+            kind: SPAN_KIND_INTERNAL,
+            start_time_unix_nano: (self.start_ns + datum.time).to_string(),
+            end_time_unix_nano: (self.start_ns + end).to_string(),
+            attributes,
+        });
+    }
+}
+
+impl OutputSink for OpenTelemetrySink {
+    fn write_header(
+        &mut self,
+        _writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        self.start_ns = metadata.start[0] * NS_PER_S + metadata.start[1];
+        self.host.clone_from(&metadata.host);
+        self.state_names = metadata
+            .states
+            .iter()
+            .map(|(name, state)| (state.value, name.clone()))
+            .collect();
+        Ok(())
+    }
+
+    fn write_datum(
+        &mut self,
+        _writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        self.end_time = self.end_time.max(datum.time);
+        if let Some(prev) = self.open.insert(datum.entity.clone(), datum.clone()) {
+            self.close(prev, datum.time);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        let mut open: Vec<_> = std::mem::take(&mut self.open).into_values().collect();
+        open.sort_by(|a, b| a.entity.cmp(&b.entity));
+        for datum in open {
+            self.close(datum, self.end_time);
+        }
+        let root = Span {
+            trace_id: self.trace_id(),
+            span_id: span_id(1),
+            parent_span_id: None,
+            name: "trace".to_compact_string(),
+            kind: SPAN_KIND_INTERNAL,
+            start_time_unix_nano: self.start_ns.to_string(),
+            end_time_unix_nano: (self.start_ns + self.end_time).to_string(),
+            attributes: Vec::new(),
+        };
+        let mut spans = vec![root];
+        spans.append(&mut self.spans);
+
+        let mut attributes = vec![KeyValue::string(
+            "service.name",
+            env!("CARGO_PKG_NAME").to_compact_string(),
+        )];
+        if let Some(host) = self.host.take() {
+            attributes.push(KeyValue::string("host.name", host));
+        }
+        let document = TracesData {
+            resource_spans: vec![ResourceSpans {
+                resource: Resource { attributes },
+                scope_spans: vec![ScopeSpans {
+                    scope: Scope {
+                        name: env!("CARGO_PKG_NAME"),
+                        version: env!("CARGO_PKG_VERSION"),
+                    },
+                    spans,
+                }],
+            }],
+        };
+        serde_json::to_writer(&mut *writer, &document)?;
+        writeln!(writer)?;
+        Ok(())
+    }
+}
+
+fn span_id(id: u64) -> String {
+    format!("{id:016x}")
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TracesData {
+    resource_spans: Vec<ResourceSpans>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans {
+    resource: Resource,
+    scope_spans: Vec<ScopeSpans>,
+}
+
+#[derive(Debug, Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Debug, Serialize)]
+struct ScopeSpans {
+    scope: Scope,
+    spans: Vec<Span>,
+}
+
+#[derive(Debug, Serialize)]
+struct Scope {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Span {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    name: CompactString,
+    kind: u8,
+    // 64-bit integers are strings in the protobuf JSON mapping.
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Debug, Serialize)]
+struct KeyValue {
+    key: &'static str,
+    value: AnyValue,
+}
+
+impl KeyValue {
+    fn string(key: &'static str, value: CompactString) -> Self {
+        Self {
+            key,
+            value: AnyValue::StringValue(value),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum AnyValue {
+    StringValue(CompactString),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statemap::StatemapInputState;
+
+    #[test]
+    fn test_opentelemetry_output() {
+        let mut sink = OpenTelemetrySink::default();
+        let mut out = Vec::new();
+        let metadata = StatemapInputMetadata {
+            start: [1, 500],
+            title: "CPU".into(),
+            host: Some("host".into()),
+            entityKind: None,
+            arch: None,
+            states: [
+                (
+                    "Idle".into(),
+                    StatemapInputState {
+                        color: None,
+                        value: 0,
+                    },
+                ),
+                (
+                    "User".into(),
+                    StatemapInputState {
+                        color: None,
+                        value: 5,
+                    },
+                ),
+            ]
+            .into(),
+            cycleFrequencyGHz: None,
+            schemaVersion: None,
+            entities: None,
+            duration_ns: None,
+            event_count: None,
+            extensions: Default::default(),
+        };
+        sink.write_header(&mut out, &metadata).unwrap();
+        for (time, state, tag) in [
+            (10, CpuState::User, Some("a:1")),
+            (30, CpuState::Idle, None),
+        ] {
+            sink.write_datum(
+                &mut out,
+                &StatemapInputDatum {
+                    time,
+                    entity: "0".into(),
+                    state,
+                    tag: tag.map(Into::into),
+                },
+            )
+            .unwrap();
+        }
+        sink.finish(&mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let spans = &json["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(spans.as_array().unwrap().len(), 3);
+        assert_eq!(spans[0]["name"], "trace");
+        assert_eq!(spans[0]["endTimeUnixNano"], "1000000530");
+        assert_eq!(spans[1]["name"], "User");
+        assert_eq!(spans[1]["parentSpanId"], "0000000000000001");
+        assert_eq!(spans[1]["startTimeUnixNano"], "1000000510");
+        assert_eq!(spans[1]["endTimeUnixNano"], "1000000530");
+        assert_eq!(spans[1]["attributes"][1]["value"]["stringValue"], "a:1");
+        assert_eq!(spans[2]["name"], "Idle");
+    }
+}
@@ -0,0 +1,166 @@
+//! Writing one file per CPU and second of trace time.
+//!
+//! This creates a directory tree of `<cpu>/<second>.ndjson` files, plus
+//! `header.json` with the statemap header, so that a renderer can load just
+//! the time window it needs. A file only exists if the CPU changed state
+//! during that second, so the state at the start of a window is found in the
+//! last earlier file for the CPU.
+
+use super::OutputSink;
+use super::json_lines::JsonLinesSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use eyre::Context;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Routes each datum to the file for its CPU and second.
+#[derive(Debug)]
+pub struct PerSecondSink {
+    /// Directory to write the files to.
+    dir: PathBuf,
+    /// Datum time units per second (differs from 1e9 with `--normalize-to-Hz`).
+    units_per_second: f64,
+    /// Currently open file for each entity, with the second it is for.
+    files: HashMap<CompactString, (u64, BufWriter<File>)>,
+}
+
+impl PerSecondSink {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, eyre::Error> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .wrap_err_with(|| format!("Failed to create \"{}\"", dir.display()))?;
+        Ok(Self {
+            dir,
+            units_per_second: 1e9,
+            files: HashMap::new(),
+        })
+    }
+
+    /// Open the file for an entity and second.
+    fn open(&self, entity: &str, second: u64) -> Result<BufWriter<File>, eyre::Error> {
+        // Entities are named <package>/<cpu> when grouping by package, which
+        // gives one directory level per package.
+        let dir = self.dir.join(entity);
+        std::fs::create_dir_all(&dir)
+            .wrap_err_with(|| format!("Failed to create \"{}\"", dir.display()))?;
+        let path = dir.join(format!("{second}.ndjson"));
+        let file = create(&path)?;
+        Ok(BufWriter::new(file))
+    }
+}
+
+impl OutputSink for PerSecondSink {
+    fn write_header(
+        &mut self,
+        _writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        if let Some(ghz) = metadata.cycleFrequencyGHz {
+            self.units_per_second = ghz * 1e9;
+        }
+        let path = self.dir.join("header.json");
+        let mut file = BufWriter::new(create(&path)?);
+        JsonLinesSink::default().write_header(&mut file, metadata)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn write_datum(
+        &mut self,
+        _writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        let second = (datum.time as f64 / self.units_per_second) as u64;
+        let is_current = self
+            .files
+            .get(&datum.entity)
+            .is_some_and(|(current, _)| *current == second);
+        if !is_current {
+            let file = self.open(&datum.entity, second)?;
+            if let Some((_, mut old)) = self.files.insert(datum.entity.clone(), (second, file)) {
+                old.flush()?;
+            }
+        }
+        let (_, file) = self
+            .files
+            .get_mut(&datum.entity)
+            .expect("File was just opened");
+        serde_json::to_writer(&mut *file, datum)?;
+        writeln!(file)?;
+        Ok(())
+    }
+
+    fn finish(&mut self, _writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        for (_, (_, mut file)) in self.files.drain() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn create(path: &Path) -> Result<File, eyre::Error> {
+    File::create(path).wrap_err_with(|| format!("Failed to create \"{}\"", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colors::ColorScheme;
+
+    #[test]
+    fn test_per_second_output() {
+        let dir = std::env::temp_dir().join(format!("per_second_test.{}", std::process::id()));
+        let mut sink = PerSecondSink::new(&dir).unwrap();
+        let metadata = StatemapInputMetadata {
+            start: [1, 500],
+            title: "CPU".into(),
+            host: None,
+            entityKind: None,
+            arch: None,
+            states: ColorScheme::Default.states(),
+            cycleFrequencyGHz: None,
+            schemaVersion: None,
+            entities: None,
+            duration_ns: None,
+            event_count: None,
+            extensions: Default::default(),
+        };
+        sink.write_header(&mut std::io::sink(), &metadata).unwrap();
+        for (time, entity) in [
+            (10, "0"),
+            (20, "1"),
+            (999_999_999, "0"),
+            (1_000_000_000, "0"),
+            (3_500_000_000, "1"),
+        ] {
+            let datum = StatemapInputDatum::builder()
+                .time(time)
+                .entity(entity)
+                .state(CpuState::User)
+                .build();
+            sink.write_datum(&mut std::io::sink(), &datum).unwrap();
+        }
+        sink.finish(&mut std::io::sink()).unwrap();
+
+        let lines = |name: &str| {
+            std::fs::read_to_string(dir.join(name))
+                .unwrap()
+                .lines()
+                .count()
+        };
+        assert_eq!(lines("header.json"), 1);
+        assert_eq!(lines("0/0.ndjson"), 2);
+        assert_eq!(lines("0/1.ndjson"), 1);
+        assert_eq!(lines("1/0.ndjson"), 1);
+        assert_eq!(lines("1/3.ndjson"), 1);
+        assert!(!dir.join("1/1.ndjson").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+//! Counting the records in the output.
+//!
+//! The count is only known at the end. For seekable outputs, a fixed width
+//! placeholder line is written after the header and filled in at the end.
+//! Otherwise (such as for stdout) the count is written as a trailer line
+//! after all records.
+
+use super::OutputSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use std::cell::Cell;
+use std::io::Write;
+use std::rc::Rc;
+
+/// Where the placeholder line is, and the record count to fill in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placeholder {
+    /// Offset of the placeholder line from the start of the output.
+    pub offset: u64,
+    pub count: u64,
+}
+
+/// The record count line. The number is padded so that the line has the
+/// same length for any count.
+pub fn record_count_line(count: u64) -> String {
+    format!("{{\"record_count\":{count:<20}}}\n")
+}
+
+/// Counts the records written to `inner`.
+pub struct RecordCountSink {
+    inner: Box<dyn OutputSink>,
+    count: u64,
+    /// Set when finished, if using a placeholder.
+    placeholder: Option<Rc<Cell<Option<Placeholder>>>>,
+    /// Length of the header.
+    header_len: u64,
+}
+
+impl RecordCountSink {
+    /// Count the records written to `inner`, writing the count as a trailer.
+    pub fn trailer(inner: Box<dyn OutputSink>) -> Self {
+        Self {
+            inner,
+            count: 0,
+            placeholder: None,
+            header_len: 0,
+        }
+    }
+
+    /// Count the records written to `inner`, writing a placeholder after the
+    /// header. The returned cell is set when the sink is finished, and the
+    /// caller must then fill in the placeholder.
+    pub fn placeholder(inner: Box<dyn OutputSink>) -> (Self, Rc<Cell<Option<Placeholder>>>) {
+        let placeholder = Rc::new(Cell::new(None));
+        let sink = Self {
+            inner,
+            count: 0,
+            placeholder: Some(Rc::clone(&placeholder)),
+            header_len: 0,
+        };
+        (sink, placeholder)
+    }
+}
+
+impl std::fmt::Debug for RecordCountSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RecordCountSink")
+            .field("count", &self.count)
+            .field("placeholder", &self.placeholder)
+            .finish_non_exhaustive()
+    }
+}
+
+impl OutputSink for RecordCountSink {
+    fn write_header(
+        &mut self,
+        writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        let mut header = Vec::new();
+        self.inner.write_header(&mut header, metadata)?;
+        writer.write_all(&header)?;
+        self.header_len = header.len() as u64;
+        if self.placeholder.is_some() {
+            writer.write_all(record_count_line(0).as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn write_datum(
+        &mut self,
+        writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        self.count += 1;
+        self.inner.write_datum(writer, datum)
+    }
+
+    fn finish(&mut self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        self.inner.finish(writer)?;
+        match &self.placeholder {
+            Some(placeholder) => placeholder.set(Some(Placeholder {
+                offset: self.header_len,
+                count: self.count,
+            })),
+            None => writer.write_all(record_count_line(self.count).as_bytes())?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colors::ColorScheme;
+    use crate::output::json_lines::JsonLinesSink;
+
+    fn write(sink: &mut dyn OutputSink) -> Vec<u8> {
+        let mut out = Vec::new();
+        let metadata = StatemapInputMetadata {
+            start: [1, 500],
+            title: "CPU".into(),
+            host: None,
+            entityKind: None,
+            arch: None,
+            states: ColorScheme::Default.states(),
+            cycleFrequencyGHz: None,
+            schemaVersion: None,
+            entities: None,
+            duration_ns: None,
+            event_count: None,
+            extensions: Default::default(),
+        };
+        sink.write_header(&mut out, &metadata).unwrap();
+        for time in [10, 20, 30] {
+            let datum = StatemapInputDatum::builder()
+                .time(time)
+                .entity("0")
+                .state(CpuState::User)
+                .build();
+            sink.write_datum(&mut out, &datum).unwrap();
+        }
+        sink.finish(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_record_count() {
+        let out = write(&mut RecordCountSink::trailer(Box::new(
+            JsonLinesSink::default(),
+        )));
+        let last: serde_json::Value =
+            serde_json::from_str(std::str::from_utf8(&out).unwrap().lines().last().unwrap())
+                .unwrap();
+        assert_eq!(last["record_count"], 3);
+
+        let (mut sink, placeholder) =
+            RecordCountSink::placeholder(Box::new(JsonLinesSink::default()));
+        let mut out = write(&mut sink);
+        let Placeholder { offset, count } = placeholder.get().unwrap();
+        let line = record_count_line(count);
+        out[offset as usize..][..line.len()].copy_from_slice(line.as_bytes());
+        let text = String::from_utf8(out).unwrap();
+        let second: serde_json::Value = serde_json::from_str(text.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(second["record_count"], 3);
+        assert_eq!(text.lines().count(), 5);
+    }
+}
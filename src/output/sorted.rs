@@ -0,0 +1,197 @@
+//! Reordering of entities in the output.
+//!
+//! The statemap viewer shows entities in the order they first appear. To
+//! control that order, all records are buffered until the end of the trace.
+//! Then the first record of each entity is written in the requested order,
+//! followed by the rest in their original order.
+
+use super::OutputSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Order of the entities in the output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap_derive::ValueEnum)]
+pub enum EntitySort {
+    /// The order the entities first appear in the trace (no buffering)
+    #[default]
+    Id,
+    /// Alphabetical by entity name
+    Alpha,
+    /// By the time of the first record, earliest first. Unlike `id`, ties
+    /// are broken by name, so the order doesn't depend on the order records
+    /// with the same time were written in.
+    FirstSeen,
+    /// By the time of the first non-idle record, earliest first
+    FirstActive,
+    /// By total non-idle time, busiest first
+    CpuLoad,
+}
+
+/// Buffers all records and writes them to `inner` with the entities in the
+/// requested order.
+pub struct SortedSink {
+    inner: Box<dyn OutputSink>,
+    order: EntitySort,
+    datums: Vec<StatemapInputDatum<CpuState>>,
+}
+
+impl SortedSink {
+    pub fn new(inner: Box<dyn OutputSink>, order: EntitySort) -> Self {
+        Self {
+            inner,
+            order,
+            datums: Vec::new(),
+        }
+    }
+
+    /// Index of the first record of each entity, in the requested order.
+    fn first_records(&self) -> Vec<usize> {
+        let end_time = self.datums.iter().map(|d| d.time).max().unwrap_or(0);
+        // First record index, first active time and busy time per entity.
+        let mut entities: HashMap<&CompactString, (usize, Option<u64>, u64)> = HashMap::new();
+        let mut current: HashMap<&CompactString, &StatemapInputDatum<CpuState>> = HashMap::new();
+        for (idx, datum) in self.datums.iter().enumerate() {
+            let entry = entities.entry(&datum.entity).or_insert((idx, None, 0));
+            if datum.state != CpuState::Idle && entry.1.is_none() {
+                entry.1 = Some(datum.time);
+            }
+            if let Some(prev) = current.insert(&datum.entity, datum)
+                && prev.state != CpuState::Idle
+            {
+                entry.2 += datum.time - prev.time;
+            }
+        }
+        for (entity, last) in current {
+            if last.state != CpuState::Idle {
+                entities.get_mut(entity).expect("Entity was seen").2 += end_time - last.time;
+            }
+        }
+
+        let mut entities: Vec<_> = entities.into_iter().collect();
+        match self.order {
+            EntitySort::Id => entities.sort_by_key(|(_, (idx, _, _))| *idx),
+            EntitySort::Alpha => entities.sort_by_key(|(name, _)| *name),
+            EntitySort::FirstSeen => {
+                entities.sort_by_key(|(name, (idx, _, _))| (self.datums[*idx].time, *name));
+            }
+            // Entities that are never active go last.
+            EntitySort::FirstActive => {
+                entities.sort_by_key(|(name, (_, first, _))| (first.is_none(), *first, *name));
+            }
+            EntitySort::CpuLoad => {
+                entities.sort_by_key(|(name, (_, _, busy))| (Reverse(*busy), *name));
+            }
+        }
+        entities.into_iter().map(|(_, (idx, _, _))| idx).collect()
+    }
+}
+
+impl std::fmt::Debug for SortedSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SortedSink")
+            .field("order", &self.order)
+            .field("datums", &self.datums.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl OutputSink for SortedSink {
+    fn write_header(
+        &mut self,
+        writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        self.inner.write_header(writer, metadata)
+    }
+
+    fn write_datum(
+        &mut self,
+        _writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        self.datums.push(datum.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        let first = self.first_records();
+        let mut written = vec![false; self.datums.len()];
+        for idx in first {
+            self.inner.write_datum(writer, &self.datums[idx])?;
+            written[idx] = true;
+        }
+        for (datum, written) in self.datums.iter().zip(written) {
+            if !written {
+                self.inner.write_datum(writer, datum)?;
+            }
+        }
+        self.datums = Vec::new();
+        self.inner.finish(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::json_lines::JsonLinesSink;
+
+    const RECORDS: &[(u64, &str, CpuState)] = &[
+        (0, "2", CpuState::Idle),
+        (10, "10", CpuState::Idle),
+        (20, "1", CpuState::User),
+        (25, "2", CpuState::User),
+        (40, "1", CpuState::Idle),
+        (50, "3", CpuState::Idle),
+    ];
+
+    fn entity_order(order: EntitySort) -> Vec<String> {
+        entity_order_of(order, RECORDS)
+    }
+
+    fn entity_order_of(order: EntitySort, records: &[(u64, &str, CpuState)]) -> Vec<String> {
+        let mut sink = SortedSink::new(Box::new(JsonLinesSink::default()), order);
+        let mut out = Vec::new();
+        for &(time, entity, state) in records {
+            let datum = StatemapInputDatum::builder()
+                .time(time)
+                .entity(entity)
+                .state(state)
+                .build();
+            sink.write_datum(&mut out, &datum).unwrap();
+        }
+        sink.finish(&mut out).unwrap();
+        let datums: Vec<serde_json::Value> = serde_json::Deserializer::from_slice(&out)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(datums.len(), records.len());
+        let mut seen = Vec::new();
+        for datum in datums {
+            let entity = datum["entity"].as_str().unwrap().to_owned();
+            if !seen.contains(&entity) {
+                seen.push(entity);
+            }
+        }
+        seen
+    }
+
+    #[test]
+    fn test_entity_sort() {
+        assert_eq!(entity_order(EntitySort::Id), ["2", "10", "1", "3"]);
+        assert_eq!(entity_order(EntitySort::Alpha), ["1", "10", "2", "3"]);
+        assert_eq!(entity_order(EntitySort::FirstSeen), ["2", "10", "1", "3"]);
+        assert_eq!(entity_order(EntitySort::FirstActive), ["1", "2", "10", "3"]);
+        // CPU 2 is busy from 25 until the end at 50, CPU 1 from 20 to 40.
+        assert_eq!(entity_order(EntitySort::CpuLoad), ["2", "1", "10", "3"]);
+
+        // Records at the same time.
+        let tied = [(5, "b", CpuState::User), (5, "a", CpuState::User)];
+        assert_eq!(entity_order_of(EntitySort::Id, &tied), ["b", "a"]);
+        assert_eq!(entity_order_of(EntitySort::FirstSeen, &tied), ["a", "b"]);
+    }
+}
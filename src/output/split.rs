@@ -0,0 +1,222 @@
+//! Writing one statemap file per CPU.
+//!
+//! For very large traces a single statemap can be several gigabytes. This
+//! instead writes `cpu<N>.json` (optionally gzip compressed, or with another
+//! extension) for each CPU into a directory, each with its own header.
+
+use super::OutputSink;
+use super::json_lines::JsonLinesSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use eyre::Context;
+use flate2::write::GzEncoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Routes each datum to the file for its CPU.
+#[derive(Debug)]
+pub struct SplitSink {
+    /// Directory to write the files to.
+    dir: PathBuf,
+    /// Whether to gzip the files.
+    compress: bool,
+    /// File name extension, before any `.gz`.
+    extension: &'static str,
+    /// Header to write to each file.
+    metadata: Option<StatemapInputMetadata>,
+    /// Open files, by entity.
+    files: HashMap<CompactString, (JsonLinesSink, SplitFile)>,
+}
+
+impl SplitSink {
+    pub fn new(dir: impl Into<PathBuf>, compress: bool) -> Result<Self, eyre::Error> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .wrap_err_with(|| format!("Failed to create \"{}\"", dir.display()))?;
+        Ok(Self {
+            dir,
+            compress,
+            extension: "json",
+            metadata: None,
+            files: HashMap::new(),
+        })
+    }
+
+    /// Use `extension` for the files instead of `json`.
+    pub fn with_extension(mut self, extension: &'static str) -> Self {
+        self.extension = extension;
+        self
+    }
+
+    /// Open the file for an entity and write the header to it.
+    fn open(&self, entity: &str) -> Result<(JsonLinesSink, SplitFile), eyre::Error> {
+        // Entities are named <package>/<cpu> when grouping by package.
+        let name = entity.replace('/', "_");
+        let extension = self.extension;
+        let path = if self.compress {
+            self.dir.join(format!("cpu{name}.{extension}.gz"))
+        } else {
+            self.dir.join(format!("cpu{name}.{extension}"))
+        };
+        let file = File::create(&path)
+            .wrap_err_with(|| format!("Failed to create \"{}\"", path.display()))?;
+        let mut file = if self.compress {
+            SplitFile::Gzip(BufWriter::new(GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            )))
+        } else {
+            SplitFile::Plain(BufWriter::new(file))
+        };
+        let mut sink = JsonLinesSink::default();
+        if let Some(metadata) = &self.metadata {
+            sink.write_header(&mut file, metadata)?;
+        }
+        Ok((sink, file))
+    }
+}
+
+impl OutputSink for SplitSink {
+    fn write_header(
+        &mut self,
+        _writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        self.metadata = Some(metadata.clone());
+        Ok(())
+    }
+
+    fn write_datum(
+        &mut self,
+        _writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        if !self.files.contains_key(&datum.entity) {
+            let file = self.open(&datum.entity)?;
+            self.files.insert(datum.entity.clone(), file);
+        }
+        let (sink, file) = self
+            .files
+            .get_mut(&datum.entity)
+            .expect("File was just opened");
+        sink.write_datum(file, datum)
+    }
+
+    fn finish(&mut self, _writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        for (_, (mut sink, mut file)) in self.files.drain() {
+            sink.finish(&mut file)?;
+            file.close()?;
+        }
+        Ok(())
+    }
+}
+
+/// An output file, possibly compressed.
+#[derive(Debug)]
+enum SplitFile {
+    Plain(BufWriter<File>),
+    Gzip(BufWriter<GzEncoder<File>>),
+}
+
+impl SplitFile {
+    /// Flush everything, including the gzip trailer.
+    fn close(self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(mut writer) => writer.flush(),
+            Self::Gzip(writer) => {
+                writer
+                    .into_inner()
+                    .map_err(std::io::IntoInnerError::into_error)?
+                    .finish()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Write for SplitFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(writer) => writer.write(buf),
+            Self::Gzip(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.flush(),
+            Self::Gzip(writer) => writer.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colors::ColorScheme;
+    use std::io::Read;
+
+    #[test]
+    fn test_split_output() {
+        let dir = std::env::temp_dir().join(format!("split_test.{}", std::process::id()));
+        for compress in [false, true] {
+            let mut sink = SplitSink::new(&dir, compress).unwrap();
+            let metadata = StatemapInputMetadata {
+                start: [1, 500],
+                title: "CPU".into(),
+                host: None,
+                entityKind: None,
+                arch: None,
+                states: ColorScheme::Default.states(),
+                cycleFrequencyGHz: None,
+                schemaVersion: None,
+                entities: None,
+                duration_ns: None,
+                event_count: None,
+                extensions: Default::default(),
+            };
+            sink.write_header(&mut std::io::sink(), &metadata).unwrap();
+            for (time, entity) in [(10, "0"), (20, "1/1"), (30, "0")] {
+                let datum = StatemapInputDatum::builder()
+                    .time(time)
+                    .entity(entity)
+                    .state(CpuState::User)
+                    .build();
+                sink.write_datum(&mut std::io::sink(), &datum).unwrap();
+            }
+            sink.finish(&mut std::io::sink()).unwrap();
+
+            let read = |name: &str| {
+                let data = std::fs::read(dir.join(name)).unwrap();
+                if compress {
+                    let mut text = String::new();
+                    flate2::read::GzDecoder::new(&data[..])
+                        .read_to_string(&mut text)
+                        .unwrap();
+                    text
+                } else {
+                    String::from_utf8(data).unwrap()
+                }
+            };
+            let extension = if compress { "json.gz" } else { "json" };
+            let cpu0 = read(&format!("cpu0.{extension}"));
+            assert_eq!(cpu0.lines().count(), 3);
+            assert!(cpu0.lines().next().unwrap().contains("\"states\""));
+            assert_eq!(read(&format!("cpu1_1.{extension}")).lines().count(), 2);
+        }
+
+        let mut sink = SplitSink::new(&dir, false)
+            .unwrap()
+            .with_extension("ndjson");
+        let datum = StatemapInputDatum::builder().entity("2").build();
+        sink.write_datum(&mut std::io::sink(), &datum).unwrap();
+        sink.finish(&mut std::io::sink()).unwrap();
+        assert!(dir.join("cpu2.ndjson").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
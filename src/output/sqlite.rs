@@ -0,0 +1,205 @@
+//! SQLite database output, for querying traces with SQL.
+//!
+//! The database has these tables:
+//!
+//! * `metadata (key, value)`: the header fields.
+//! * `states (id, name, color)`: the state definitions.
+//! * `events (timestamp_ns, cpu_or_pid, state_id, tag, duration_ns)`: one row
+//!   per interval an entity spends in a state.
+//!
+//! The view `events_named` adds the state name to each event as `state_name`,
+//! for queries such as
+//! `SELECT cpu_or_pid, AVG(duration_ns) FROM events_named WHERE state_name = 'Irq' GROUP BY cpu_or_pid`.
+
+use super::OutputSink;
+use crate::statemap::StatemapInputDatum;
+use crate::statemap::StatemapInputMetadata;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use eyre::Context;
+use rusqlite::Connection;
+use rusqlite::params;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE metadata (key TEXT PRIMARY KEY, value TEXT);
+    CREATE TABLE states (id INTEGER PRIMARY KEY, name TEXT NOT NULL, color TEXT);
+    CREATE TABLE events (
+        timestamp_ns INTEGER NOT NULL,
+        cpu_or_pid TEXT NOT NULL,
+        state_id INTEGER NOT NULL,
+        tag TEXT,
+        duration_ns INTEGER NOT NULL
+    );
+    CREATE VIEW events_named AS
+        SELECT events.*, states.name AS state_name
+        FROM events LEFT JOIN states ON events.state_id = states.id;
+";
+
+/// Writes intervals to a SQLite database, in a single transaction.
+#[derive(Debug)]
+pub struct SqliteSink {
+    conn: Connection,
+    /// Currently open interval for each entity.
+    open: HashMap<CompactString, StatemapInputDatum<CpuState>>,
+    /// Latest time seen.
+    end_time: u64,
+}
+
+impl SqliteSink {
+    /// Create the database at `path`, replacing any existing file.
+    pub fn create(path: &Path) -> Result<Self, eyre::Error> {
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(err)
+                    .wrap_err_with(|| format!("Failed to remove \"{}\"", path.display()));
+            }
+            _ => {}
+        }
+        let conn = Connection::open(path)
+            .wrap_err_with(|| format!("Failed to create \"{}\"", path.display()))?;
+        conn.execute_batch(SCHEMA)?;
+        conn.execute_batch("BEGIN")?;
+        Ok(Self {
+            conn,
+            open: HashMap::new(),
+            end_time: 0,
+        })
+    }
+
+    fn close(&self, datum: &StatemapInputDatum<CpuState>, end: u64) -> Result<(), eyre::Error> {
+        self.conn
+            .prepare_cached(
+                "INSERT INTO events (timestamp_ns, cpu_or_pid, state_id, tag, duration_ns) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute(params![
+                datum.time,
+                datum.entity.as_str(),
+                datum.state as u8,
+                datum.tag.as_deref(),
+                end - datum.time,
+            ])?;
+        Ok(())
+    }
+}
+
+impl OutputSink for SqliteSink {
+    fn write_header(
+        &mut self,
+        _writer: &mut dyn Write,
+        metadata: &StatemapInputMetadata,
+    ) -> Result<(), eyre::Error> {
+        let serde_json::Value::Object(json) = metadata.to_json()? else {
+            unreachable!("The header is always a JSON object");
+        };
+        let mut insert = self
+            .conn
+            .prepare("INSERT INTO metadata (key, value) VALUES (?1, ?2)")?;
+        for (key, value) in json {
+            if key == "states" {
+                continue;
+            }
+            let value = match value {
+                serde_json::Value::String(value) => value,
+                value => value.to_string(),
+            };
+            insert.execute(params![key, value])?;
+        }
+        let mut insert = self
+            .conn
+            .prepare("INSERT INTO states (id, name, color) VALUES (?1, ?2, ?3)")?;
+        for (name, state) in metadata.states_sorted_by_value() {
+            insert.execute(params![state.value, name.as_str(), state.color.as_deref()])?;
+        }
+        Ok(())
+    }
+
+    fn write_datum(
+        &mut self,
+        _writer: &mut dyn Write,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Result<(), eyre::Error> {
+        self.end_time = self.end_time.max(datum.time);
+        if let Some(prev) = self.open.insert(datum.entity.clone(), datum.clone()) {
+            self.close(&prev, datum.time)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, _writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        for (_, datum) in std::mem::take(&mut self.open) {
+            self.close(&datum, self.end_time)?;
+        }
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colors::ColorScheme;
+
+    #[test]
+    fn test_sqlite_output() {
+        let path = std::env::temp_dir().join(format!("sqlite_test.{}.db", std::process::id()));
+        let mut sink = SqliteSink::create(&path).unwrap();
+        let metadata = StatemapInputMetadata {
+            start: [1, 500],
+            title: "CPU".into(),
+            host: Some("host".into()),
+            entityKind: None,
+            arch: None,
+            states: ColorScheme::Default.states(),
+            cycleFrequencyGHz: None,
+            schemaVersion: None,
+            entities: None,
+            duration_ns: None,
+            event_count: None,
+            extensions: Default::default(),
+        };
+        sink.write_header(&mut std::io::sink(), &metadata).unwrap();
+        for (time, entity, state) in [
+            (10, "0", CpuState::Irq),
+            (15, "1", CpuState::Irq),
+            (30, "0", CpuState::User),
+            (45, "1", CpuState::Idle),
+        ] {
+            let datum = StatemapInputDatum::builder()
+                .time(time)
+                .entity(entity)
+                .state(state)
+                .tag(Some("x"))
+                .build();
+            sink.write_datum(&mut std::io::sink(), &datum).unwrap();
+        }
+        sink.finish(&mut std::io::sink()).unwrap();
+        drop(sink);
+
+        let conn = Connection::open(&path).unwrap();
+        let host: String = conn
+            .query_row("SELECT value FROM metadata WHERE key = 'host'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(host, "host");
+        let avg: f64 = conn
+            .query_row(
+                "SELECT AVG(duration_ns) FROM events_named WHERE state_name = 'Irq'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(avg, 25.0);
+        let count: u64 = conn
+            .query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 4);
+        drop(conn);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
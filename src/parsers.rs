@@ -1,11 +1,33 @@
+use crate::classify_rules::ClassifyRules;
+use crate::irq_names::IrqNames;
+use crate::string_pool::StringPool;
+use crate::symbols::KallsymsResolver;
 use crate::tracepoints::Tracepoint;
 use crate::tracepoints::irq::IrqHandlerEntry;
+use crate::tracepoints::irq::IrqHandlerExit;
 use crate::tracepoints::irq::SoftirqEntry;
+use crate::tracepoints::irq::SoftirqRaise;
 use crate::tracepoints::irq::TaskletEntry;
-use crate::tracepoints::parser::FormatParser;
+use crate::tracepoints::irq::TaskletHiEntry;
+use crate::tracepoints::parser::LazyParser;
+use crate::tracepoints::power::CpuFrequency;
+use crate::tracepoints::printk_formats::PrintkFormats;
+use crate::tracepoints::rcu::RcuStallWarning;
+use crate::tracepoints::rcu::RcuUtilization;
 use crate::tracepoints::sched::SchedMigrateTask;
+use crate::tracepoints::sched::SchedMoveNuma;
+use crate::tracepoints::sched::SchedPiSetprio;
+use crate::tracepoints::sched::SchedProcessExec;
+use crate::tracepoints::sched::SchedProcessExit;
+use crate::tracepoints::sched::SchedProcessWait;
+use crate::tracepoints::sched::SchedStatIOWait;
+use crate::tracepoints::sched::SchedStatRuntime;
+use crate::tracepoints::sched::SchedStatSleep;
 use crate::tracepoints::sched::SchedSwitch;
+use crate::tracepoints::sched::SchedWakeup;
+use crate::tracepoints::sched::SchedWaking;
 use crate::types::CpuState;
+use crate::types::ThreadState;
 use byteorder::BigEndian;
 use byteorder::ByteOrder;
 use byteorder::LittleEndian;
@@ -13,16 +35,70 @@ use compact_str::CompactString;
 use compact_str::format_compact;
 use linux_perf_data::Endianness;
 use linux_perf_data::linux_perf_event_reader::RawData;
+use std::rc::Rc;
+
+/// Longest process name the kernel keeps, in bytes (`TASK_COMM_LEN` without
+/// the terminating NUL).
+const MAX_COMM_LEN: usize = 15;
+
+/// The name the kernel gives a process that executes `filename`: the
+/// basename, truncated to [`MAX_COMM_LEN`] bytes (here on a character
+/// boundary).
+fn exec_comm(filename: &str) -> &str {
+    let name = filename.rsplit_once('/').map_or(filename, |(_, name)| name);
+    let mut end = name.len().min(MAX_COMM_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
 
 /// Parser for `CLOCK_DATA` *file header.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ClockData {
-    pub version: u32, /* version = 1 */
+    pub version: u32,
+    pub common: ClockDataCommon,
+    /// The version is unknown, and the data was parsed with the version 1
+    /// layout on a best-effort basis.
+    pub version_warning: bool,
+}
+
+/// The `CLOCK_DATA` fields that every version has.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClockDataCommon {
     pub clockid: u32,
     pub wall_clock_ns: u64,
     pub clockid_time_ns: u64,
 }
 
+/// Layout of version 1 of `CLOCK_DATA`, after the version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ClockDataV1 {
+    clockid: u32,
+    wall_clock_ns: u64,
+    clockid_time_ns: u64,
+}
+
+impl ClockDataV1 {
+    fn parse_impl<O: ByteOrder>(data: &mut RawData<'_>) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            clockid: data.read_u32::<O>()?,
+            wall_clock_ns: data.read_u64::<O>()?,
+            clockid_time_ns: data.read_u64::<O>()?,
+        })
+    }
+}
+
+impl From<ClockDataV1> for ClockDataCommon {
+    fn from(v1: ClockDataV1) -> Self {
+        Self {
+            clockid: v1.clockid,
+            wall_clock_ns: v1.wall_clock_ns,
+            clockid_time_ns: v1.clockid_time_ns,
+        }
+    }
+}
+
 impl ClockData {
     pub fn parse(data: RawData<'_>, endian: Endianness) -> Result<Self, std::io::Error> {
         match endian {
@@ -33,55 +109,310 @@ impl ClockData {
 
     pub fn parse_impl<O: ByteOrder>(mut data: RawData<'_>) -> Result<Self, std::io::Error> {
         let version = data.read_u32::<O>()?;
-        if version != 1 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Unsupported clock data version: {version}"),
-            ));
+        let version_warning = version != 1;
+        if version_warning {
+            // Later versions are expected to extend version 1.
+            log::warn!(
+                "Unsupported clock data version {version}, trying to parse it as version 1. \
+                 The start time of the trace may be wrong."
+            );
         }
-        let clockid = data.read_u32::<O>()?;
-        let wall_clock_ns = data.read_u64::<O>()?;
-        let clockid_time_ns = data.read_u64::<O>()?;
-
+        let common = ClockDataV1::parse_impl::<O>(&mut data)?.into();
         Ok(Self {
             version,
-            clockid,
-            wall_clock_ns,
-            clockid_time_ns,
+            common,
+            version_warning,
+        })
+    }
+}
+
+/// Parser for the body of a `PERF_RECORD_AUX` record, which says that new
+/// hardware trace data (such as Intel PT or ARM CoreSight) landed in the AUX
+/// buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuxRecord {
+    pub aux_offset: u64,
+    pub aux_size: u64,
+    /// `PERF_AUX_FLAG_*` flags, such as truncated or overwrite.
+    pub flags: u64,
+}
+
+impl AuxRecord {
+    pub fn parse(data: RawData<'_>, endian: Endianness) -> Result<Self, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::parse_impl::<LittleEndian>(data),
+            Endianness::BigEndian => Self::parse_impl::<BigEndian>(data),
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(mut data: RawData<'_>) -> Result<Self, std::io::Error> {
+        let aux_offset = data.read_u64::<O>()?;
+        let aux_size = data.read_u64::<O>()?;
+        let flags = data.read_u64::<O>()?;
+        Ok(Self {
+            aux_offset,
+            aux_size,
+            flags,
         })
     }
 }
 
 /// Describes what parser to use for a given tracepoint sample record.
-#[derive(Debug, Clone)]
+///
+/// The parsers are reference counted, so that actions are cheap to clone, and
+/// only load the tracepoint format when the first event is parsed.
+#[derive(Clone)]
 pub enum Action {
     Ignore,
-    Switch(FormatParser),
-    Migrate(FormatParser),
-    EnterIrq(FormatParser),
-    ExitIrq(FormatParser),
-    EnterSoftirq(FormatParser),
-    ExitSoftirq(FormatParser),
-    EnterTasklet(FormatParser),
-    ExitTasklet(FormatParser),
+    /// Sample from a last level cache miss counter.
+    CacheMiss,
+    /// Context switch. Threads are classified with the user defined rules,
+    /// if any, before the built-in ones.
+    Switch(Rc<LazyParser>, Option<Rc<ClassifyRules>>),
+    Migrate(Rc<LazyParser>),
+    NumaMove(Rc<LazyParser>),
+    /// Exec, classified as for [`Self::Switch`].
+    Exec(Rc<LazyParser>, Option<Rc<ClassifyRules>>),
+    Waking(Rc<LazyParser>),
+    Wakeup(Rc<LazyParser>),
+    PiSetprio(Rc<LazyParser>),
+    StatRuntime(Rc<LazyParser>),
+    ProcessWait(Rc<LazyParser>),
+    ProcessExit(Rc<LazyParser>),
+    StatSleep(Rc<LazyParser>),
+    StatIowait(Rc<LazyParser>),
+    CpuFrequency(Rc<LazyParser>),
+    /// IRQ handler entry. Generic IRQ names are looked up in the IRQ names,
+    /// if given.
+    EnterIrq(Rc<LazyParser>, Option<Rc<IrqNames>>),
+    ExitIrq(Rc<LazyParser>),
+    RaiseSoftirq(Rc<LazyParser>),
+    EnterSoftirq(Rc<LazyParser>),
+    ExitSoftirq(Rc<LazyParser>),
+    /// Tasklet entry, with symbols for resolving the function if available.
+    EnterTasklet(Rc<LazyParser>, Option<Rc<KallsymsResolver>>),
+    ExitTasklet(Rc<LazyParser>),
+    /// High priority tasklet entry, as for [`Self::EnterTasklet`].
+    EnterTaskletHi(Rc<LazyParser>, Option<Rc<KallsymsResolver>>),
+    ExitTaskletHi(Rc<LazyParser>),
+    /// RCU phase changes. The strings are resolved with the printk formats.
+    RcuUtilization(Rc<LazyParser>, Rc<PrintkFormats>),
+    RcuStallWarning(Rc<LazyParser>, Rc<PrintkFormats>),
+}
+
+impl Action {
+    /// Name of the variant.
+    pub fn name(&self) -> &'static str {
+        self.parts().0
+    }
+
+    /// Name of the variant, and the parser if any.
+    fn parts(&self) -> (&'static str, Option<&LazyParser>) {
+        match self {
+            Self::Ignore => ("Ignore", None),
+            Self::CacheMiss => ("CacheMiss", None),
+            Self::Switch(parser, _) => ("Switch", Some(parser)),
+            Self::Migrate(parser) => ("Migrate", Some(parser)),
+            Self::NumaMove(parser) => ("NumaMove", Some(parser)),
+            Self::Exec(parser, _) => ("Exec", Some(parser)),
+            Self::Waking(parser) => ("Waking", Some(parser)),
+            Self::Wakeup(parser) => ("Wakeup", Some(parser)),
+            Self::PiSetprio(parser) => ("PiSetprio", Some(parser)),
+            Self::StatRuntime(parser) => ("StatRuntime", Some(parser)),
+            Self::ProcessWait(parser) => ("ProcessWait", Some(parser)),
+            Self::ProcessExit(parser) => ("ProcessExit", Some(parser)),
+            Self::StatSleep(parser) => ("StatSleep", Some(parser)),
+            Self::StatIowait(parser) => ("StatIowait", Some(parser)),
+            Self::CpuFrequency(parser) => ("CpuFrequency", Some(parser)),
+            Self::EnterIrq(parser, _) => ("EnterIrq", Some(parser)),
+            Self::ExitIrq(parser) => ("ExitIrq", Some(parser)),
+            Self::RaiseSoftirq(parser) => ("RaiseSoftirq", Some(parser)),
+            Self::EnterSoftirq(parser) => ("EnterSoftirq", Some(parser)),
+            Self::ExitSoftirq(parser) => ("ExitSoftirq", Some(parser)),
+            Self::EnterTasklet(parser, _) => ("EnterTasklet", Some(parser)),
+            Self::ExitTasklet(parser) => ("ExitTasklet", Some(parser)),
+            Self::EnterTaskletHi(parser, _) => ("EnterTaskletHi", Some(parser)),
+            Self::ExitTaskletHi(parser) => ("ExitTaskletHi", Some(parser)),
+            Self::RcuUtilization(parser, _) => ("RcuUtilization", Some(parser)),
+            Self::RcuStallWarning(parser, _) => ("RcuStallWarning", Some(parser)),
+        }
+    }
+}
+
+/// Only shows the number of fields of the parser, the full parser is very
+/// verbose.
+impl std::fmt::Debug for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.parts() {
+            (name, None) => f.write_str(name),
+            (name, Some(parser)) => match parser.loaded() {
+                Some(parser) => write!(f, "{name}(parser: {} fields)", parser.num_fields()),
+                None => write!(f, "{name}(parser: not loaded)"),
+            },
+        }
+    }
+}
+
+/// A name for the handler, for messages to the user.
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.parts() {
+            (_, Some(parser)) => {
+                let event_name = parser.event_name();
+                let name = event_name
+                    .split_once(':')
+                    .map_or(event_name, |(_, name)| name);
+                write!(f, "{name} handler")
+            }
+            ("CacheMiss", None) => f.write_str("cache miss counter handler"),
+            (_, None) => f.write_str("ignored event"),
+        }
+    }
 }
 
 /// A parsed tracepoint sample record turns into an `Event`.
+///
+/// Every variant has the `time` of the event, relative to the start of the
+/// trace.
 #[derive(Debug, Clone)]
 pub enum Event {
     BeginThread {
+        time: u64,
         state: CpuState,
         comm: CompactString,
         pid: i32,
+        /// Kernel priority of the thread, see [`Event::thread_tag`].
+        prio: i32,
+        /// The thread that was switched out.
+        prev_pid: i32,
+        /// State the thread that was switched out was left in.
+        prev_state: ThreadState,
+    },
+    /// A hardware IRQ handler started.
+    BeginIrq {
+        time: u64,
+        irq: i32,
+        tag: CompactString,
+    },
+    /// A softirq handler started.
+    BeginSoftirq {
+        time: u64,
+        vec: u32,
+        tag: CompactString,
+    },
+    /// A softirq was raised (queued to run). This doesn't change the state
+    /// of the CPU.
+    RaiseSoftirq {
+        time: u64,
+        vec: u32,
     },
     BeginOther {
+        time: u64,
         state: CpuState,
         tag: CompactString,
     },
-    End,
+    End {
+        time: u64,
+    },
+    /// An IRQ handler returned without handling the IRQ (a spurious IRQ).
+    /// Otherwise the same as `End`.
+    UnhandledIrq {
+        time: u64,
+        irq: i32,
+    },
+    /// A thread was moved to a different CPU.
     Migrate {
+        time: u64,
         from: i32,
         to: i32,
+        comm: CompactString,
+        pid: i32,
+    },
+    /// A thread was moved to a CPU on a different NUMA node, by NUMA
+    /// balancing.
+    NumaMove {
+        time: u64,
+        pid: i32,
+        src_cpu: i32,
+        src_nid: i32,
+        dst_cpu: i32,
+        dst_nid: i32,
+    },
+    /// The thread running on the CPU replaced its program image.
+    Exec {
+        time: u64,
+        state: CpuState,
+        comm: CompactString,
+        pid: i32,
+        /// PID before the exec. This differs from `pid` when a thread other
+        /// than the thread group leader executes, as it takes over the PID of
+        /// the leader.
+        old_pid: i32,
+        filename: CompactString,
+    },
+    /// The frequency of a CPU changed. This is not a CPU state.
+    CpuFrequency {
+        time: u64,
+        cpu: u32,
+        khz: u32,
+    },
+    /// A thread was marked runnable. This doesn't change the state of any
+    /// CPU by itself.
+    Waking {
+        time: u64,
+        pid: i32,
+    },
+    /// A thread was put on the runqueue of `target_cpu`. This doesn't change
+    /// the state of any CPU by itself.
+    Wakeup {
+        time: u64,
+        target_cpu: i32,
+    },
+    /// The priority of a thread changed due to priority inheritance. Lower
+    /// values are higher priorities.
+    PiSetprio {
+        time: u64,
+        comm: CompactString,
+        pid: i32,
+        oldprio: i32,
+        newprio: i32,
+    },
+    /// A thread used `runtime` ns of CPU time since the last such event.
+    /// This doesn't change the state of any CPU.
+    StatRuntime {
+        time: u64,
+        comm: CompactString,
+        pid: i32,
+        runtime: u64,
+    },
+    /// Thread `pid` started waiting for the process `child` to exit, or for
+    /// any child if `child` is 0 or less. This doesn't change the state of
+    /// any CPU.
+    ProcessWait {
+        time: u64,
+        comm: CompactString,
+        pid: i32,
+        child: i32,
+    },
+    /// A process exited. This doesn't change the state of any CPU.
+    ProcessExit {
+        time: u64,
+        pid: i32,
+    },
+    /// A sleeping thread was woken up after sleeping for `delay` ns, in
+    /// I/O wait if `iowait`. Only emitted with schedstats enabled. This
+    /// doesn't change the state of any CPU.
+    StatSleep {
+        time: u64,
+        pid: i32,
+        delay: u64,
+        iowait: bool,
+    },
+    /// An RCU phase change that isn't a stall. This doesn't change the state
+    /// of the CPU, only annotates its tag.
+    RcuUtilization {
+        time: u64,
+        phase: CompactString,
     },
 }
 
@@ -90,68 +421,361 @@ impl Event {
         action: &Action,
         data: RawData<'_>,
         endian: Endianness,
+        time: u64,
+        pool: &mut StringPool,
     ) -> Result<Self, eyre::Error> {
         match endian {
-            Endianness::LittleEndian => Self::parse_impl::<LittleEndian>(action, data),
-            Endianness::BigEndian => Self::parse_impl::<BigEndian>(action, data),
+            Endianness::LittleEndian => Self::parse_impl::<LittleEndian>(action, data, time, pool),
+            Endianness::BigEndian => Self::parse_impl::<BigEndian>(action, data, time, pool),
         }
     }
 
     pub fn parse_impl<O: ByteOrder>(
         action: &Action,
         data: RawData<'_>,
+        time: u64,
+        pool: &mut StringPool,
     ) -> Result<Self, eyre::Error> {
         // We need to use dynamic parsers here, since the tracepoint format does change
         // between kernel versions.
         match action {
-            Action::Ignore => unreachable!(),
-            Action::Switch(parser) => {
-                let parsed = SchedSwitch::parse::<O>(parser, &data)?;
+            Action::Ignore | Action::CacheMiss => unreachable!(),
+            Action::Switch(parser, rules) => {
+                let parsed = SchedSwitch::parse::<O>(parser.get()?, &data)?;
 
                 Ok(Self::BeginThread {
-                    state: Self::classify(parsed.next_comm.as_bytes()),
+                    time,
+                    state: Self::classify(parsed.next_comm.as_bytes(), rules.as_deref()),
                     comm: parsed.next_comm,
                     pid: parsed.next_pid,
+                    prio: parsed.next_prio,
+                    prev_pid: parsed.prev_pid,
+                    prev_state: ThreadState::from_prev_state_bits(parsed.prev_state),
                 })
             }
             Action::Migrate(parser) => {
-                let parsed = SchedMigrateTask::parse::<O>(parser, &data)?;
+                let parsed = SchedMigrateTask::parse::<O>(parser.get()?, &data)?;
                 Ok(Self::Migrate {
+                    time,
                     from: parsed.orig_cpu,
                     to: parsed.dest_cpu,
+                    comm: pool.intern(&parsed.comm),
+                    pid: parsed.pid,
                 })
             }
-            Action::EnterIrq(parser) => {
-                let parsed = IrqHandlerEntry::parse::<O>(parser, &data)?;
-                Ok(Self::BeginOther {
-                    state: CpuState::Irq,
-                    tag: format_compact!("IRQ {}: {}", parsed.irq, parsed.name),
+            Action::NumaMove(parser) => {
+                let parsed = SchedMoveNuma::parse::<O>(parser.get()?, &data)?;
+                Ok(Self::NumaMove {
+                    time,
+                    pid: parsed.pid,
+                    src_cpu: parsed.src_cpu,
+                    src_nid: parsed.src_nid,
+                    dst_cpu: parsed.dst_cpu,
+                    dst_nid: parsed.dst_nid,
                 })
             }
-            Action::ExitIrq(_parser) => Ok(Self::End),
+            Action::Exec(parser, rules) => {
+                let parsed = SchedProcessExec::parse::<O>(parser.get()?, &data)?;
+                let comm = CompactString::from(exec_comm(&parsed.filename));
+                Ok(Self::Exec {
+                    time,
+                    state: Self::classify(comm.as_bytes(), rules.as_deref()),
+                    comm,
+                    pid: parsed.pid,
+                    old_pid: parsed.old_pid,
+                    filename: parsed.filename,
+                })
+            }
+            Action::Waking(parser) => {
+                let parsed = SchedWaking::parse::<O>(parser.get()?, &data)?;
+                Ok(Self::Waking {
+                    time,
+                    pid: parsed.pid,
+                })
+            }
+            Action::Wakeup(parser) => {
+                let parsed = SchedWakeup::parse::<O>(parser.get()?, &data)?;
+                Ok(Self::Wakeup {
+                    time,
+                    target_cpu: parsed.target_cpu,
+                })
+            }
+            Action::PiSetprio(parser) => {
+                let parsed = SchedPiSetprio::parse::<O>(parser.get()?, &data)?;
+                Ok(Self::PiSetprio {
+                    time,
+                    comm: parsed.comm,
+                    pid: parsed.pid,
+                    oldprio: parsed.oldprio,
+                    newprio: parsed.newprio,
+                })
+            }
+            Action::StatRuntime(parser) => {
+                let parsed = SchedStatRuntime::parse::<O>(parser.get()?, &data)?;
+                Ok(Self::StatRuntime {
+                    time,
+                    comm: parsed.comm,
+                    pid: parsed.pid,
+                    runtime: parsed.runtime,
+                })
+            }
+            Action::ProcessWait(parser) => {
+                let parsed = SchedProcessWait::parse::<O>(parser.get()?, &data)?;
+                Ok(Self::ProcessWait {
+                    time,
+                    comm: parsed.comm,
+                    pid: parsed.common_pid,
+                    child: parsed.pid,
+                })
+            }
+            Action::ProcessExit(parser) => {
+                let parsed = SchedProcessExit::parse::<O>(parser.get()?, &data)?;
+                Ok(Self::ProcessExit {
+                    time,
+                    pid: parsed.pid,
+                })
+            }
+            Action::StatSleep(parser) => {
+                let parsed = SchedStatSleep::parse::<O>(parser.get()?, &data)?;
+                Ok(Self::StatSleep {
+                    time,
+                    pid: parsed.pid,
+                    delay: parsed.delay,
+                    iowait: false,
+                })
+            }
+            Action::StatIowait(parser) => {
+                let parsed = SchedStatIOWait::parse::<O>(parser.get()?, &data)?;
+                Ok(Self::StatSleep {
+                    time,
+                    pid: parsed.pid,
+                    delay: parsed.delay,
+                    iowait: true,
+                })
+            }
+            Action::CpuFrequency(parser) => {
+                let parsed = CpuFrequency::parse::<O>(parser.get()?, &data)?;
+                Ok(Self::CpuFrequency {
+                    time,
+                    cpu: parsed.cpu_id,
+                    khz: parsed.state,
+                })
+            }
+            Action::EnterIrq(parser, irq_names) => {
+                let parsed = IrqHandlerEntry::parse::<O>(parser.get()?, &data)?;
+                let name = match irq_names {
+                    Some(irq_names) => irq_names.name(parsed.irq, &parsed.name),
+                    None => &parsed.name,
+                };
+                Ok(Self::BeginIrq {
+                    time,
+                    irq: parsed.irq,
+                    tag: pool.intern_fmt(format_args!("IRQ {}: {name}", parsed.irq)),
+                })
+            }
+            Action::ExitIrq(parser) => {
+                let parsed = IrqHandlerExit::parse::<O>(parser.get()?, &data)?;
+                if parsed.ret == 0 {
+                    Ok(Self::UnhandledIrq {
+                        time,
+                        irq: parsed.irq,
+                    })
+                } else {
+                    Ok(Self::End { time })
+                }
+            }
             Action::EnterSoftirq(parser) => {
-                let parsed = SoftirqEntry::parse::<O>(parser, &data)?;
+                let parsed = SoftirqEntry::parse::<O>(parser.get()?, &data)?;
+                Ok(Self::BeginSoftirq {
+                    time,
+                    vec: parsed.vec,
+                    tag: pool.intern_fmt(format_args!("Softirq {}", parsed.vec)),
+                })
+            }
+            Action::RaiseSoftirq(parser) => {
+                let parsed = SoftirqRaise::parse::<O>(parser.get()?, &data)?;
+                Ok(Self::RaiseSoftirq {
+                    time,
+                    vec: parsed.vec,
+                })
+            }
+            Action::ExitSoftirq(_parser) => Ok(Self::End { time }),
+            Action::EnterTasklet(parser, symbols) => {
+                let parsed = TaskletEntry::parse::<O>(parser.get()?, &data)?;
+                let tag = match symbols {
+                    Some(symbols) => {
+                        pool.intern_fmt(format_args!("Tasklet: {}", symbols.format(parsed.func)))
+                    }
+                    None => pool.intern_fmt(format_args!("Tasklet {:#x}", parsed.tasklet)),
+                };
                 Ok(Self::BeginOther {
-                    state: CpuState::Softirq,
-                    tag: format_compact!("Softirq {}", parsed.vec),
+                    time,
+                    state: CpuState::Tasklet,
+                    tag,
                 })
             }
-            Action::ExitSoftirq(_parser) => Ok(Self::End),
-            Action::EnterTasklet(parser) => {
-                let parsed = TaskletEntry::parse::<O>(parser, &data)?;
+            Action::ExitTasklet(_parser) => Ok(Self::End { time }),
+            Action::EnterTaskletHi(parser, symbols) => {
+                let parsed = TaskletHiEntry::parse::<O>(parser.get()?, &data)?;
+                let tag = match symbols {
+                    Some(symbols) => {
+                        pool.intern_fmt(format_args!("TaskletHi: {}", symbols.format(parsed.func)))
+                    }
+                    None => pool.intern_fmt(format_args!("TaskletHi {:#x}", parsed.tasklet)),
+                };
                 Ok(Self::BeginOther {
+                    time,
                     state: CpuState::Tasklet,
-                    tag: format_compact!("Tasklet {:#x}", parsed.tasklet),
+                    tag,
                 })
             }
-            Action::ExitTasklet(_parser) => Ok(Self::End),
+            Action::ExitTaskletHi(_parser) => Ok(Self::End { time }),
+            Action::RcuUtilization(parser, formats) => {
+                let parsed = RcuUtilization::parse::<O>(parser.get()?, &data)?;
+                let phase = resolve_string(formats, parsed.s);
+                if !phase.to_ascii_lowercase().contains("stall") {
+                    return Ok(Self::RcuUtilization { time, phase });
+                }
+                Ok(Self::BeginOther {
+                    time,
+                    state: CpuState::RcuStall,
+                    tag: pool.intern_fmt(format_args!("RCU: {phase}")),
+                })
+            }
+            Action::RcuStallWarning(parser, formats) => {
+                let parsed = RcuStallWarning::parse::<O>(parser.get()?, &data)?;
+                Ok(Self::BeginOther {
+                    time,
+                    state: CpuState::RcuStall,
+                    tag: pool.intern_fmt(format_args!(
+                        "RCU stall: {} {}",
+                        resolve_string(formats, parsed.rcuname),
+                        resolve_string(formats, parsed.msg)
+                    )),
+                })
+            }
+        }
+    }
+
+    /// Time of the event, relative to the start of the trace.
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            Self::BeginThread { time, .. }
+            | Self::BeginIrq { time, .. }
+            | Self::BeginSoftirq { time, .. }
+            | Self::RaiseSoftirq { time, .. }
+            | Self::BeginOther { time, .. }
+            | Self::End { time }
+            | Self::UnhandledIrq { time, .. }
+            | Self::Migrate { time, .. }
+            | Self::NumaMove { time, .. }
+            | Self::Exec { time, .. }
+            | Self::CpuFrequency { time, .. }
+            | Self::Waking { time, .. }
+            | Self::Wakeup { time, .. }
+            | Self::PiSetprio { time, .. }
+            | Self::StatRuntime { time, .. }
+            | Self::ProcessWait { time, .. }
+            | Self::ProcessExit { time, .. }
+            | Self::StatSleep { time, .. }
+            | Self::RcuUtilization { time, .. } => *time,
+        }
+    }
+
+    /// Short name of the kind of event, for statistics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::BeginThread { .. } => "switch",
+            Self::BeginIrq { .. } => "irq",
+            Self::BeginSoftirq { .. } => "softirq",
+            Self::RaiseSoftirq { .. } => "softirq_raise",
+            Self::BeginOther { state, .. } => match state {
+                CpuState::Tasklet => "tasklet",
+                CpuState::RcuStall => "rcu_stall",
+                _ => "other",
+            },
+            Self::End { .. } => "end",
+            Self::UnhandledIrq { .. } => "unhandled_irq",
+            Self::Migrate { .. } => "migrate",
+            Self::NumaMove { .. } => "numa_move",
+            Self::Exec { .. } => "exec",
+            Self::Waking { .. } => "waking",
+            Self::Wakeup { .. } => "wakeup",
+            Self::PiSetprio { .. } => "pi_setprio",
+            Self::StatRuntime { .. } => "stat_runtime",
+            Self::ProcessWait { .. } => "process_wait",
+            Self::ProcessExit { .. } => "process_exit",
+            Self::StatSleep { iowait: false, .. } => "stat_sleep",
+            Self::StatSleep { iowait: true, .. } => "stat_iowait",
+            Self::CpuFrequency { .. } => "cpu_frequency",
+            Self::RcuUtilization { .. } => "rcu_utilization",
+        }
+    }
+
+    /// Whether the CPU enters a new state: a thread switch or the start of
+    /// an interrupt handler.
+    pub fn is_begin(&self) -> bool {
+        matches!(
+            self,
+            Self::BeginThread { .. }
+                | Self::BeginIrq { .. }
+                | Self::BeginSoftirq { .. }
+                | Self::BeginOther { .. }
+        )
+    }
+
+    /// Whether this is a switch to the idle task (`swapper/N`). Idle that is
+    /// restored after an interrupt doesn't count.
+    pub fn is_switch_to_swapper(&self) -> bool {
+        matches!(self, Self::BeginThread { comm, .. } if comm.starts_with("swapper/"))
+    }
+
+    /// Whether an interrupt handler returned, restoring the state before it.
+    pub fn is_end(&self) -> bool {
+        matches!(self, Self::End { .. } | Self::UnhandledIrq { .. })
+    }
+
+    /// The state the CPU enters, for events that change it directly.
+    pub fn cpu_state(&self) -> Option<CpuState> {
+        match self {
+            Self::BeginThread { state, .. }
+            | Self::BeginOther { state, .. }
+            | Self::Exec { state, .. } => Some(*state),
+            Self::BeginIrq { .. } => Some(CpuState::Irq),
+            Self::BeginSoftirq { .. } => Some(CpuState::Softirq),
+            _ => None,
+        }
+    }
+
+    /// The tag of the state the CPU enters, if the event carries one. Thread
+    /// tags are built when processing the event, see [`Self::thread_tag`].
+    pub fn tag(&self) -> Option<&CompactString> {
+        match self {
+            Self::BeginIrq { tag, .. }
+            | Self::BeginSoftirq { tag, .. }
+            | Self::BeginOther { tag, .. } => Some(tag),
+            _ => None,
+        }
+    }
+
+    /// Tag for a running thread. The kernel priority is included if it isn't
+    /// the default: 0-99 are real-time priorities and 100-139 normal ones,
+    /// with lower values being higher priority.
+    pub fn thread_tag(pool: &mut StringPool, comm: &str, pid: i32, prio: i32) -> CompactString {
+        match prio {
+            DEFAULT_PRIO => pool.intern_fmt(format_args!("{comm}:{pid}")),
+            ..MAX_RT_PRIO => pool.intern_fmt(format_args!("{comm}:{pid} [RT prio={prio}]")),
+            _ => pool.intern_fmt(format_args!("{comm}:{pid} [prio={prio}]")),
         }
     }
 
     /// Attempt to classify into user space vs kernel space threads.
     ///
-    /// Not very accurate.
-    fn classify(comm: &[u8]) -> CpuState {
+    /// Not very accurate, so user defined `rules` are checked first.
+    fn classify(comm: &[u8], rules: Option<&ClassifyRules>) -> CpuState {
+        if let Some(state) = rules.and_then(|rules| rules.classify(comm)) {
+            return state;
+        }
         if comm.starts_with(b"swapper/") {
             return CpuState::Idle;
         }
@@ -177,3 +801,307 @@ impl Event {
         CpuState::User
     }
 }
+
+/// Kernel priority of normal threads with the default nice value.
+const DEFAULT_PRIO: i32 = 120;
+
+/// Kernel priorities below this are real-time.
+const MAX_RT_PRIO: i32 = 100;
+
+/// Resolve a kernel string pointer, falling back to showing the address.
+fn resolve_string(formats: &PrintkFormats, addr: u64) -> CompactString {
+    formats
+        .resolve(addr)
+        .cloned()
+        .unwrap_or_else(|| format_compact!("{addr:#x}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracepoints::irq::SoftirqExit;
+    use crate::tracepoints::irq::TaskletExit;
+    use crate::tracepoints::irq::TaskletHiExit;
+    use std::path::Path;
+
+    #[test]
+    fn test_clock_data() {
+        let data = |version: u32| -> Vec<u8> {
+            let mut data = Vec::new();
+            data.extend(version.to_le_bytes());
+            data.extend(4u32.to_le_bytes());
+            data.extend(1_700_000_000_000_000_000u64.to_le_bytes());
+            data.extend(5_000u64.to_le_bytes());
+            data
+        };
+        let common = ClockDataCommon {
+            clockid: 4,
+            wall_clock_ns: 1_700_000_000_000_000_000,
+            clockid_time_ns: 5_000,
+        };
+        let v1 = data(1);
+        let clock = ClockData::parse(RawData::Single(&v1), Endianness::LittleEndian).unwrap();
+        assert_eq!(
+            clock,
+            ClockData {
+                version: 1,
+                common: common.clone(),
+                version_warning: false,
+            }
+        );
+        // Unknown versions are parsed as version 1, with a warning.
+        let v2 = data(2);
+        let clock = ClockData::parse(RawData::Single(&v2), Endianness::LittleEndian).unwrap();
+        assert_eq!(
+            clock,
+            ClockData {
+                version: 2,
+                common,
+                version_warning: true,
+            }
+        );
+        assert!(ClockData::parse(RawData::Single(&v1[..12]), Endianness::LittleEndian).is_err());
+    }
+
+    #[test]
+    fn test_aux_record() {
+        let data: Vec<u8> = [0x1000u64, 64, 1, 0xdead]
+            .iter()
+            .flat_map(|v| v.to_be_bytes())
+            .collect();
+        let record = AuxRecord::parse(RawData::Single(&data), Endianness::BigEndian).unwrap();
+        assert_eq!(
+            record,
+            AuxRecord {
+                aux_offset: 0x1000,
+                aux_size: 64,
+                flags: 1,
+            }
+        );
+        assert!(AuxRecord::parse(RawData::Single(&data[..16]), Endianness::BigEndian).is_err());
+    }
+
+    #[test]
+    fn test_event_accessors() {
+        let irq = Event::BeginIrq {
+            time: 1,
+            irq: 9,
+            tag: "acpi".into(),
+        };
+        assert!(irq.is_begin());
+        assert!(!irq.is_end());
+        assert_eq!(irq.cpu_state(), Some(CpuState::Irq));
+        assert_eq!(irq.tag().map(CompactString::as_str), Some("acpi"));
+
+        let switch = Event::BeginThread {
+            time: 2,
+            state: CpuState::User,
+            comm: "bash".into(),
+            pid: 10,
+            prio: 120,
+            prev_pid: 0,
+            prev_state: ThreadState::Running,
+        };
+        assert!(switch.is_begin());
+        assert_eq!(switch.cpu_state(), Some(CpuState::User));
+        assert_eq!(switch.tag(), None);
+
+        for end in [
+            Event::End { time: 3 },
+            Event::UnhandledIrq { time: 3, irq: 9 },
+        ] {
+            assert!(end.is_end());
+            assert!(!end.is_begin());
+            assert_eq!(end.cpu_state(), None);
+            assert_eq!(end.tag(), None);
+        }
+
+        let waking = Event::Waking { time: 4, pid: 10 };
+        assert!(!waking.is_begin());
+        assert!(!waking.is_end());
+        assert_eq!(waking.cpu_state(), None);
+    }
+
+    #[test]
+    fn test_is_switch_to_swapper() {
+        let switch = |comm: &str, state| Event::BeginThread {
+            time: 1,
+            state,
+            comm: comm.into(),
+            pid: 0,
+            prio: 120,
+            prev_pid: 10,
+            prev_state: ThreadState::Sleeping,
+        };
+        let irq = Event::BeginIrq {
+            time: 2,
+            irq: 9,
+            tag: "acpi".into(),
+        };
+        // An IRQ while idle: only the switch is left out with
+        // --exclude-idle, the idle restored after the IRQ is not.
+        let events = [
+            switch("swapper/0", CpuState::Idle),
+            irq,
+            Event::End { time: 3 },
+        ];
+        let skipped: Vec<_> = events.iter().map(Event::is_switch_to_swapper).collect();
+        assert_eq!(skipped, [true, false, false]);
+        // Other idle-like threads are shown as usual.
+        assert!(!switch("migration/0", CpuState::Idle).is_switch_to_swapper());
+        assert!(!switch("bash", CpuState::User).is_switch_to_swapper());
+        // A CPU that only ever runs swapper has no datums left.
+        assert!(
+            (0..3)
+                .map(|_| switch("swapper/1", CpuState::Idle))
+                .all(|event| event.is_switch_to_swapper())
+        );
+    }
+
+    #[test]
+    fn test_exec_comm() {
+        assert_eq!(exec_comm("/usr/bin/bash"), "bash");
+        assert_eq!(exec_comm("make"), "make");
+        assert_eq!(
+            exec_comm("/opt/a-very-long-program-name"),
+            "a-very-long-pro"
+        );
+        // 7 two-byte characters fit in 15 bytes, the 8th doesn't.
+        assert_eq!(exec_comm("/bin/ääääääääää"), "äääääää");
+    }
+
+    #[test]
+    fn test_thread_tag() {
+        let mut pool = StringPool::default();
+        assert_eq!(Event::thread_tag(&mut pool, "bash", 10, 120), "bash:10");
+        assert_eq!(
+            Event::thread_tag(&mut pool, "irq/5-eth0", 20, 49),
+            "irq/5-eth0:20 [RT prio=49]"
+        );
+        assert_eq!(
+            Event::thread_tag(&mut pool, "make", 30, 139),
+            "make:30 [prio=139]"
+        );
+    }
+
+    #[test]
+    fn test_action_display() {
+        fn lazy<T: Tracepoint>() -> Rc<LazyParser> {
+            Rc::new(LazyParser::new::<T>(Path::new("/nonexistent")))
+        }
+        let printk = Rc::new(PrintkFormats::default());
+        let actions = [
+            (Action::CacheMiss, "cache miss counter handler"),
+            (
+                Action::Switch(lazy::<SchedSwitch>(), None),
+                "sched_switch handler",
+            ),
+            (
+                Action::Migrate(lazy::<SchedMigrateTask>()),
+                "sched_migrate_task handler",
+            ),
+            (
+                Action::NumaMove(lazy::<SchedMoveNuma>()),
+                "sched_move_numa handler",
+            ),
+            (
+                Action::Exec(lazy::<SchedProcessExec>(), None),
+                "sched_process_exec handler",
+            ),
+            (
+                Action::Waking(lazy::<SchedWaking>()),
+                "sched_waking handler",
+            ),
+            (
+                Action::Wakeup(lazy::<SchedWakeup>()),
+                "sched_wakeup handler",
+            ),
+            (
+                Action::PiSetprio(lazy::<SchedPiSetprio>()),
+                "sched_pi_setprio handler",
+            ),
+            (
+                Action::StatRuntime(lazy::<SchedStatRuntime>()),
+                "sched_stat_runtime handler",
+            ),
+            (
+                Action::ProcessWait(lazy::<SchedProcessWait>()),
+                "sched_process_wait handler",
+            ),
+            (
+                Action::ProcessExit(lazy::<SchedProcessExit>()),
+                "sched_process_exit handler",
+            ),
+            (
+                Action::StatSleep(lazy::<SchedStatSleep>()),
+                "sched_stat_sleep handler",
+            ),
+            (
+                Action::StatIowait(lazy::<SchedStatIOWait>()),
+                "sched_stat_iowait handler",
+            ),
+            (
+                Action::CpuFrequency(lazy::<CpuFrequency>()),
+                "cpu_frequency handler",
+            ),
+            (
+                Action::EnterIrq(lazy::<IrqHandlerEntry>(), None),
+                "irq_handler_entry handler",
+            ),
+            (
+                Action::ExitIrq(lazy::<IrqHandlerExit>()),
+                "irq_handler_exit handler",
+            ),
+            (
+                Action::RaiseSoftirq(lazy::<SoftirqRaise>()),
+                "softirq_raise handler",
+            ),
+            (
+                Action::EnterSoftirq(lazy::<SoftirqEntry>()),
+                "softirq_entry handler",
+            ),
+            (
+                Action::ExitSoftirq(lazy::<SoftirqExit>()),
+                "softirq_exit handler",
+            ),
+            (
+                Action::EnterTasklet(lazy::<TaskletEntry>(), None),
+                "tasklet_entry handler",
+            ),
+            (
+                Action::ExitTasklet(lazy::<TaskletExit>()),
+                "tasklet_exit handler",
+            ),
+            (
+                Action::EnterTaskletHi(lazy::<TaskletHiEntry>(), None),
+                "tasklet_hi_entry handler",
+            ),
+            (
+                Action::ExitTaskletHi(lazy::<TaskletHiExit>()),
+                "tasklet_hi_exit handler",
+            ),
+            (
+                Action::RcuUtilization(lazy::<RcuUtilization>(), Rc::clone(&printk)),
+                "rcu_utilization handler",
+            ),
+            (
+                Action::RcuStallWarning(lazy::<RcuStallWarning>(), printk),
+                "rcu_stall_warning handler",
+            ),
+        ];
+        for (action, expected) in &actions {
+            assert_eq!(action.to_string(), *expected);
+        }
+        assert_eq!(format!("{:?}", actions[1].0), "Switch(parser: not loaded)");
+        assert_eq!(format!("{:?}", Action::Ignore), "Ignore");
+        // Failing to load a format names the event.
+        for (action, _) in &actions[1..] {
+            let (_, Some(parser)) = action.parts() else {
+                panic!("{action} has no parser");
+            };
+            let err = format!("{:#}", parser.get().unwrap_err());
+            assert!(err.contains(parser.event_name()), "{err}");
+        }
+        assert!(Action::Ignore.parts().1.is_none());
+    }
+}
@@ -0,0 +1,120 @@
+//! Aggregate metrics about the perf.data input and the conversion of it.
+
+use crate::stats::Stats;
+use linux_perf_data::UserRecordType;
+use linux_perf_data::linux_perf_event_reader::RecordType;
+use std::collections::BTreeMap;
+
+/// Metrics about a conversion. The record and action counts are collected
+/// while reading the input, the rest is filled in by [`Self::finish`] and
+/// once the output is finished.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PerfDataStats {
+    /// Number of kernel records, by record type.
+    pub records: BTreeMap<u32, u64>,
+    /// Number of records added by perf itself, by record type.
+    pub user_records: BTreeMap<UserRecordType, u64>,
+    /// Number of `SAMPLE` records.
+    pub samples: u64,
+    /// Number of samples that weren't ignored, by [`Action`] variant.
+    ///
+    /// [`Action`]: crate::parsers::Action
+    pub actions: BTreeMap<&'static str, u64>,
+    /// Number of events the kernel reported as lost.
+    pub lost_events: u64,
+    /// First and last sample time, in ns.
+    pub time_range: Option<[u64; 2]>,
+    pub num_cpus: usize,
+    /// Deepest nesting of interrupts seen, indexed by CPU.
+    pub max_irq_depth: Vec<u32>,
+    /// Number of datums written to the output.
+    pub datums: u64,
+}
+
+impl PerfDataStats {
+    pub fn count_record(&mut self, record_type: RecordType) {
+        *self.records.entry(record_type.0).or_default() += 1;
+    }
+
+    pub fn count_user_record(&mut self, record_type: UserRecordType) {
+        *self.user_records.entry(record_type).or_default() += 1;
+    }
+
+    pub fn count_action(&mut self, action: &'static str) {
+        *self.actions.entry(action).or_default() += 1;
+    }
+
+    /// Fill in the metrics that are only known after reading the input.
+    pub fn finish(&mut self, stats: &Stats, time_range: Option<[u64; 2]>, num_cpus: usize) {
+        self.samples = stats.samples;
+        self.lost_events = stats.lost_events;
+        self.time_range = time_range;
+        self.num_cpus = num_cpus;
+        self.max_irq_depth = stats
+            .interrupts
+            .iter()
+            .map(|counts| counts.max_depth)
+            .collect();
+    }
+}
+
+impl std::fmt::Display for PerfDataStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "perf_data:")?;
+        writeln!(f, "  records:")?;
+        for (&record_type, count) in &self.records {
+            writeln!(f, "    {:?}: {count}", RecordType(record_type))?;
+        }
+        for (record_type, count) in &self.user_records {
+            // The Debug output of known user record types is quoted.
+            let name = format!("{record_type:?}");
+            writeln!(f, "    {}: {count}", name.trim_matches('"'))?;
+        }
+        writeln!(f, "  samples: {}", self.samples)?;
+        writeln!(f, "  actions:")?;
+        for (action, count) in &self.actions {
+            writeln!(f, "    {action}: {count}")?;
+        }
+        writeln!(f, "  lost_events: {}", self.lost_events)?;
+        if let Some([first, last]) = self.time_range {
+            writeln!(f, "  time_range: {first}..{last} ns")?;
+        }
+        writeln!(f, "  cpus: {}", self.num_cpus)?;
+        if self.max_irq_depth.iter().any(|&depth| depth > 0) {
+            writeln!(f, "  max_irq_depth:")?;
+            for (cpu, depth) in self.max_irq_depth.iter().enumerate() {
+                writeln!(f, "    CPU {cpu}: {depth}")?;
+            }
+        }
+        writeln!(f, "  datums: {}", self.datums)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::CpuState;
+
+    #[test]
+    fn test_perf_data_stats() {
+        let mut perf_data_stats = PerfDataStats::default();
+        for record_type in [RecordType::SAMPLE, RecordType::COMM, RecordType::SAMPLE] {
+            perf_data_stats.count_record(record_type);
+        }
+        perf_data_stats.count_user_record(UserRecordType::PERF_FINISHED_ROUND);
+        perf_data_stats.count_action("Switch");
+        perf_data_stats.count_action("EnterIrq");
+        perf_data_stats.count_action("Switch");
+
+        let mut stats = Stats {
+            samples: 2,
+            lost_events: 3,
+            ..Default::default()
+        };
+        stats.record_interrupt_entry(1, CpuState::Irq, Some(30));
+        stats.record_interrupt_entry(1, CpuState::Softirq, None);
+        perf_data_stats.finish(&stats, Some([100, 900]), 2);
+        perf_data_stats.datums = 5;
+        insta::assert_snapshot!(perf_data_stats);
+    }
+}
@@ -0,0 +1,117 @@
+//! Detection of windows with an abnormally high context switch rate.
+//!
+//! Context switches are counted per CPU in fixed size windows. Windows where
+//! the count is more than three standard deviations above the mean for that
+//! CPU are reported as preemption storms. These are typically caused by
+//! pathological scheduler behaviour such as priority inversions or thundering
+//! herd wakeups.
+
+/// A window with an abnormally high number of context switches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PreemptionStorm {
+    /// Start of the window, in ns since the start of the trace.
+    pub start: u64,
+    /// CPU the context switches happened on.
+    pub cpu: usize,
+    /// Number of context switches in the window.
+    pub switches: u64,
+}
+
+/// Counts context switches per CPU and window.
+#[derive(Debug, Clone)]
+pub struct PreemptionStormDetector {
+    /// Size of each window in ns.
+    window_ns: u64,
+    /// Number of context switches in each window, per CPU.
+    counts: Vec<Vec<u64>>,
+}
+
+impl PreemptionStormDetector {
+    pub fn new(num_cpus: usize, window_ns: u64) -> Self {
+        Self {
+            window_ns,
+            counts: vec![Vec::new(); num_cpus],
+        }
+    }
+
+    /// Track `num_cpus` CPUs, if that is more than currently tracked.
+    pub fn resize(&mut self, num_cpus: usize) {
+        if num_cpus > self.counts.len() {
+            self.counts.resize(num_cpus, Vec::new());
+        }
+    }
+
+    /// Record a context switch on `cpu` at `time`.
+    pub fn record_switch(&mut self, cpu: usize, time: u64) {
+        let window = (time / self.window_ns) as usize;
+        let counts = &mut self.counts[cpu];
+        if counts.len() <= window {
+            counts.resize(window + 1, 0);
+        }
+        counts[window] += 1;
+    }
+
+    /// Get all preemption storms, sorted by time.
+    pub fn storms(&self) -> Vec<PreemptionStorm> {
+        let mut result = Vec::new();
+        for (cpu, counts) in self.counts.iter().enumerate() {
+            if counts.len() < 2 {
+                continue;
+            }
+            let n = counts.len() as f64;
+            let mean = counts.iter().map(|&c| c as f64).sum::<f64>() / n;
+            let variance = counts
+                .iter()
+                .map(|&c| (c as f64 - mean).powi(2))
+                .sum::<f64>()
+                / n;
+            let threshold = mean + 3.0 * variance.sqrt();
+            result.extend(
+                counts
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &c)| c as f64 > threshold)
+                    .map(|(window, &switches)| PreemptionStorm {
+                        start: window as u64 * self.window_ns,
+                        cpu,
+                        switches,
+                    }),
+            );
+        }
+        result.sort_unstable();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storms() {
+        let mut detector = PreemptionStormDetector::new(2, 1000);
+        for window in 0..20 {
+            detector.record_switch(0, window * 1000 + 10);
+            detector.record_switch(1, window * 1000 + 10);
+        }
+        for i in 0..50 {
+            detector.record_switch(1, 7000 + i);
+            detector.record_switch(0, 3000 + i);
+        }
+        assert_eq!(
+            detector.storms(),
+            vec![
+                PreemptionStorm {
+                    start: 3000,
+                    cpu: 0,
+                    switches: 51
+                },
+                PreemptionStorm {
+                    start: 7000,
+                    cpu: 1,
+                    switches: 51
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//! Progress reporting for long conversions.
+
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
+use std::io::IsTerminal;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::time::Instant;
+
+/// Update the message every this many events.
+const UPDATE_INTERVAL: u64 = 4096;
+
+/// Create a progress bar on stderr for reading `total_bytes`, or `None` if
+/// stderr is not a terminal.
+pub fn progress_bar(total_bytes: u64) -> Option<ProgressBar> {
+    if !std::io::stderr().is_terminal() {
+        return None;
+    }
+    let bar = ProgressBar::new(total_bytes);
+    bar.set_style(
+        ProgressStyle::with_template(
+            "{bar:40} {binary_bytes}/{binary_total_bytes} (ETA {eta}) {msg}",
+        )
+        .unwrap_or_else(|_| ProgressStyle::default_bar()),
+    );
+    Some(bar)
+}
+
+/// Reader that advances a progress bar by the bytes read.
+///
+/// `PerfFileReader` doesn't expose how far into the file it is, so this
+/// tracks it from below.
+#[derive(Debug)]
+pub struct ProgressReader<R> {
+    inner: R,
+    bar: Option<ProgressBar>,
+}
+
+impl<R> ProgressReader<R> {
+    pub fn new(inner: R, bar: Option<ProgressBar>) -> Self {
+        Self { inner, bar }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(bar) = &self.bar {
+            bar.inc(read as u64);
+        }
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for ProgressReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = self.inner.seek(pos)?;
+        if let Some(bar) = &self.bar {
+            bar.set_position(pos);
+        }
+        Ok(pos)
+    }
+}
+
+/// Shows the event rate and the current time in the trace next to the bar.
+#[derive(Debug)]
+pub struct EventRate {
+    bar: ProgressBar,
+    start: Instant,
+}
+
+impl EventRate {
+    pub fn new(bar: ProgressBar) -> Self {
+        Self {
+            bar,
+            start: Instant::now(),
+        }
+    }
+
+    /// Update after `events` events, the last of which was at `time` ns
+    /// into the trace.
+    pub fn update(&self, events: u64, time: u64) {
+        if !events.is_multiple_of(UPDATE_INTERVAL) {
+            return;
+        }
+        let rate = events as f64 / self.start.elapsed().as_secs_f64().max(1e-3);
+        self.bar
+            .set_message(format!("{rate:.0} events/s, at {:.3} s", time as f64 / 1e9));
+    }
+
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_progress_reader() {
+        let bar = ProgressBar::hidden();
+        let mut reader = ProgressReader::new(Cursor::new(vec![0u8; 100]), Some(bar.clone()));
+        let mut buf = [0; 30];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(bar.position(), 30);
+        reader.seek(SeekFrom::Start(90)).unwrap();
+        assert_eq!(bar.position(), 90);
+        assert_eq!(reader.read(&mut buf).unwrap(), 10);
+        assert_eq!(bar.position(), 100);
+    }
+}
@@ -0,0 +1,68 @@
+//! Metrics about the trace in the Prometheus text exposition format, for
+//! building dashboards from trace analysis.
+
+use crate::stats::Stats;
+use crate::types::CpuState;
+use std::io::Write;
+
+/// Write the metrics in the Prometheus text format.
+pub fn write_metrics(stats: &Stats, writer: &mut impl Write) -> std::io::Result<()> {
+    writeln!(
+        writer,
+        "# HELP perf_statemap_events_total Number of processed events."
+    )?;
+    writeln!(writer, "# TYPE perf_statemap_events_total counter")?;
+    for ((cpu, kind), count) in &stats.cpu_events {
+        writeln!(
+            writer,
+            "perf_statemap_events_total{{cpu=\"{cpu}\",type=\"{kind}\"}} {count}"
+        )?;
+    }
+
+    writeln!(
+        writer,
+        "# HELP perf_statemap_state_duration_ns Time spent in each state."
+    )?;
+    writeln!(writer, "# TYPE perf_statemap_state_duration_ns counter")?;
+    for (cpu, times) in stats.state_times.durations().iter().enumerate() {
+        for state in CpuState::ALL {
+            writeln!(
+                writer,
+                "perf_statemap_state_duration_ns{{cpu=\"{cpu}\",state=\"{}\"}} {}",
+                state.name(),
+                times[state as usize]
+            )?;
+        }
+    }
+
+    writeln!(
+        writer,
+        "# HELP perf_statemap_lost_events_total Number of events the kernel reported as lost."
+    )?;
+    writeln!(writer, "# TYPE perf_statemap_lost_events_total counter")?;
+    writeln!(
+        writer,
+        "perf_statemap_lost_events_total {}",
+        stats.lost_events
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let mut stats = Stats::default();
+        stats.count_event(0, "switch");
+        stats.count_event(0, "switch");
+        stats.count_event(1, "irq");
+        stats.record_state(0, CpuState::User, 0);
+        stats.finish_state_times(100);
+        stats.lost_events = 3;
+        let mut out = Vec::new();
+        write_metrics(&stats, &mut out).unwrap();
+        insta::assert_snapshot!(String::from_utf8(out).unwrap());
+    }
+}
@@ -0,0 +1,167 @@
+//! Markdown report of the summary statistics, for embedding in pull requests
+//! or incident reports.
+
+use crate::stats::Stats;
+use crate::stats::format_duration;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use std::fmt::Write;
+
+/// Number of tags to list in the report.
+const TOP_TAGS: usize = 10;
+
+/// Format of the `--stats-only` report.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap_derive::ValueEnum)]
+pub enum StatsFormat {
+    /// Plain text
+    #[default]
+    Text,
+    /// Markdown, written to `<output>.md` (or stdout without an output)
+    Markdown,
+}
+
+/// Information about the traced system.
+#[derive(Debug, Default, Clone)]
+pub struct SystemInfo {
+    pub hostname: Option<CompactString>,
+    pub cpu_model: Option<CompactString>,
+    /// Time from the first to the last sample.
+    pub duration_ns: u64,
+}
+
+/// Create the Markdown report.
+pub fn markdown_report(stats: &Stats, info: &SystemInfo) -> Result<String, std::fmt::Error> {
+    let mut out = String::new();
+    let num_cpus = stats.state_times.durations().len();
+    writeln!(out, "# CPU state report")?;
+    writeln!(out)?;
+
+    writeln!(out, "## System")?;
+    writeln!(out)?;
+    writeln!(out, "| | |")?;
+    writeln!(out, "|---|---|")?;
+    let unknown = || "unknown".to_owned();
+    writeln!(
+        out,
+        "| Hostname | {} |",
+        info.hostname.as_deref().map_or_else(unknown, escape)
+    )?;
+    writeln!(
+        out,
+        "| CPU model | {} |",
+        info.cpu_model.as_deref().map_or_else(unknown, escape)
+    )?;
+    writeln!(out, "| CPUs | {num_cpus} |")?;
+    writeln!(
+        out,
+        "| Trace duration | {} |",
+        format_duration(info.duration_ns)
+    )?;
+    writeln!(out)?;
+
+    writeln!(out, "## Summary")?;
+    writeln!(out)?;
+    write_narrative(&mut out, stats, info)?;
+    writeln!(out)?;
+
+    if num_cpus > 0 {
+        writeln!(out, "## Time in state")?;
+        writeln!(out)?;
+        write!(out, "| CPU |")?;
+        for state in CpuState::ALL {
+            write!(out, " {} |", state.name())?;
+        }
+        writeln!(out)?;
+        write!(out, "|---|")?;
+        for _ in CpuState::ALL {
+            write!(out, "---:|")?;
+        }
+        writeln!(out)?;
+        for (cpu, times) in stats.state_times.durations().iter().enumerate() {
+            write!(out, "| {cpu} |")?;
+            for state in CpuState::ALL {
+                write!(
+                    out,
+                    " {} ({:.1}%) |",
+                    format_duration(times[state as usize]),
+                    stats.state_percent(cpu, state)
+                )?;
+            }
+            writeln!(out)?;
+        }
+        writeln!(out)?;
+    }
+
+    let tags = stats.top_tags(TOP_TAGS);
+    if !tags.is_empty() {
+        writeln!(out, "## Most frequent tags")?;
+        writeln!(out)?;
+        writeln!(out, "| Tag | State changes |")?;
+        writeln!(out, "|---|---:|")?;
+        for (tag, count) in tags {
+            writeln!(out, "| {} | {count} |", escape(tag))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(out)
+}
+
+/// Write a few sentences describing the trace.
+fn write_narrative(out: &mut String, stats: &Stats, info: &SystemInfo) -> std::fmt::Result {
+    let num_cpus = stats.state_times.durations().len();
+    write!(
+        out,
+        "The trace covers {} on {num_cpus} CPUs.",
+        format_duration(info.duration_ns)
+    )?;
+    if num_cpus > 0 {
+        let busy = |cpu: usize| 100.0 - stats.state_percent(cpu, CpuState::Idle);
+        let average = (0..num_cpus).map(busy).sum::<f64>() / num_cpus as f64;
+        let busiest = (0..num_cpus)
+            .max_by(|&a, &b| busy(a).total_cmp(&busy(b)))
+            .unwrap_or_default();
+        write!(
+            out,
+            " On average the CPUs were busy {average:.1}% of the time. The busiest CPU was \
+             CPU {busiest}, busy {:.1}% of the time.",
+            busy(busiest)
+        )?;
+    }
+    if let Some((tag, count)) = stats.top_tags(1).first() {
+        write!(
+            out,
+            " The most frequent tag was `{}`, with {count} state changes.",
+            tag.replace('`', "'")
+        )?;
+    }
+    writeln!(out)
+}
+
+/// Escape characters that would break a Markdown table cell.
+fn escape(text: &str) -> String {
+    text.replace('|', "\\|")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markdown_report() {
+        let mut stats = Stats::default();
+        stats.record_state(0, CpuState::User, 0);
+        stats.record_state(1, CpuState::Idle, 0);
+        stats.record_state(0, CpuState::Idle, 3_000_000_000);
+        stats.record_state(1, CpuState::Kernel, 3_000_000_000);
+        stats.finish_state_times(4_000_000_000);
+        for tag in ["bash:10", "a|b:20", "bash:10"] {
+            stats.record_tag(&tag.into());
+        }
+        let info = SystemInfo {
+            hostname: Some("host".into()),
+            cpu_model: None,
+            duration_ns: 4_000_000_000,
+        };
+        insta::assert_snapshot!(markdown_report(&stats, &info).unwrap());
+    }
+}
@@ -0,0 +1,103 @@
+//! Estimation of the runqueue depth of each CPU.
+//!
+//! A thread is counted as queued on a CPU from when it is woken up with that
+//! CPU as the target (`sched_wakeup`), until it is switched out in a sleeping
+//! state there (`sched_switch` with a non-running `prev_state`). Threads that
+//! were already runnable when the trace started are not seen, so this is a
+//! lower bound, and it can drift if wakeups are lost.
+
+use crate::statemap::StatemapInputDatum;
+use crate::types::CpuState;
+use compact_str::format_compact;
+
+/// Tracks the runqueue depth of each CPU.
+#[derive(Debug, Clone)]
+pub struct RunqueueDepths {
+    depths: Vec<u32>,
+    /// Warn when the depth goes above this.
+    alert: Option<u32>,
+}
+
+impl RunqueueDepths {
+    pub fn new(num_cpus: usize, alert: Option<u32>) -> Self {
+        Self {
+            depths: vec![0; num_cpus],
+            alert,
+        }
+    }
+
+    /// Track `num_cpus` CPUs, if that is more than currently tracked.
+    pub fn resize(&mut self, num_cpus: usize) {
+        if num_cpus > self.depths.len() {
+            self.depths.resize(num_cpus, 0);
+        }
+    }
+
+    /// Current depth of the runqueue of `cpu`.
+    pub fn depth(&self, cpu: usize) -> u32 {
+        self.depths[cpu]
+    }
+
+    /// A thread was woken up onto `cpu` at `time`.
+    pub fn wakeup(&mut self, cpu: usize, time: u64) {
+        self.depths[cpu] += 1;
+        let depth = self.depths[cpu];
+        // Only warn when crossing the threshold, not for every wakeup above it.
+        if self.alert.is_some_and(|alert| depth == alert + 1) {
+            log::warn!("Runqueue depth of CPU {cpu} reached {depth} at {time} ns");
+        }
+    }
+
+    /// A thread on `cpu` went to sleep.
+    pub fn sleep(&mut self, cpu: usize) {
+        // The thread may have been woken before the trace started.
+        self.depths[cpu] = self.depths[cpu].saturating_sub(1);
+    }
+
+    /// Copy of `datum` for `cpu` with the runqueue depth appended to the tag.
+    pub fn annotate(
+        &self,
+        cpu: usize,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> StatemapInputDatum<CpuState> {
+        let depth = self.depth(cpu);
+        StatemapInputDatum {
+            tag: Some(match datum.tag.as_deref() {
+                Some(tag) if !tag.is_empty() => format_compact!("{tag} (rq:{depth})"),
+                _ => format_compact!("(rq:{depth})"),
+            }),
+            ..datum.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runqueue_depths() {
+        let mut depths = RunqueueDepths::new(1, Some(1));
+        depths.sleep(0);
+        assert_eq!(depths.depth(0), 0);
+        depths.wakeup(0, 10);
+        depths.wakeup(0, 20);
+        depths.resize(2);
+        depths.wakeup(1, 30);
+        assert_eq!(depths.depth(0), 2);
+        assert_eq!(depths.depth(1), 1);
+        depths.sleep(0);
+        assert_eq!(depths.depth(0), 1);
+
+        let datum = StatemapInputDatum::builder()
+            .entity("0")
+            .tag(Some("a:1"))
+            .build();
+        assert_eq!(
+            depths.annotate(0, &datum).tag.as_deref(),
+            Some("a:1 (rq:1)")
+        );
+        let idle = StatemapInputDatum::builder().entity("1").build();
+        assert_eq!(depths.annotate(1, &idle).tag.as_deref(), Some("(rq:1)"));
+    }
+}
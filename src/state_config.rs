@@ -0,0 +1,155 @@
+//! User defined states, loaded from a TOML file.
+//!
+//! This allows adding states for domain specific tracepoints (such as GPU
+//! states) to the statemap header, alongside the built-in CPU states:
+//!
+//! ```toml
+//! [[states]]
+//! name = "WaitGPU"
+//! color = "#7B68EE"
+//! value = 10
+//! ```
+
+use crate::statemap::InvalidStates;
+use crate::statemap::StatemapInputState;
+use crate::statemap::validate_color;
+use crate::statemap::validate_states;
+use compact_str::CompactString;
+use eyre::Context;
+use eyre::eyre;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Contents of a state config file.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StateConfig {
+    #[serde(default)]
+    pub states: Vec<StateDefinition>,
+}
+
+/// A single user defined state.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StateDefinition {
+    pub name: CompactString,
+    pub color: Option<CompactString>,
+    pub value: usize,
+}
+
+impl StateConfig {
+    /// Load a state config from a TOML file.
+    pub fn load(path: &Path) -> Result<Self, eyre::Error> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read \"{}\"", path.display()))?;
+        toml::from_str(&contents)
+            .wrap_err_with(|| format!("Failed to parse state config \"{}\"", path.display()))
+    }
+
+    /// Merge the user defined states into `states`.
+    ///
+    /// All problems are reported at once: names conflicting with existing
+    /// states, invalid colors, and values that together with the existing
+    /// states are not contiguous from 0.
+    pub fn merge_into(
+        &self,
+        states: &mut HashMap<CompactString, StatemapInputState>,
+    ) -> Result<(), eyre::Error> {
+        let mut errors = Vec::new();
+        let mut names: HashMap<&str, &str> = states
+            .keys()
+            .map(|name| (name.as_str(), "built-in"))
+            .collect();
+        for state in &self.states {
+            if let Some(kind) = names.insert(&state.name, "user defined") {
+                errors.push(format!(
+                    "State \"{}\" conflicts with a {kind} state",
+                    state.name
+                ));
+            }
+            if let Some(color) = &state.color
+                && !validate_color(color)
+            {
+                errors.push(format!(
+                    "State \"{}\" has invalid color \"{color}\", expected #RGB or #RRGGBB",
+                    state.name
+                ));
+            }
+        }
+
+        // Conflicting states are already reported, leave them out to not
+        // also get confusing errors about values.
+        let mut merged = states.clone();
+        merged.extend(
+            self.states
+                .iter()
+                .filter(|state| !states.contains_key(&state.name))
+                .map(|state| {
+                    (
+                        state.name.clone(),
+                        StatemapInputState {
+                            color: state.color.clone(),
+                            value: state.value,
+                        },
+                    )
+                }),
+        );
+        if let Err(InvalidStates(invalid)) = validate_states(&merged) {
+            errors.extend(invalid.iter().map(ToString::to_string));
+        }
+
+        if !errors.is_empty() {
+            return Err(eyre!("Invalid state config:\n  {}", errors.join("\n  ")));
+        }
+        *states = merged;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colors::ColorScheme;
+    use indoc::indoc;
+
+    #[test]
+    fn test_merge() {
+        let config: StateConfig = toml::from_str(indoc! {r##"
+            [[states]]
+            name = "WaitGPU"
+            color = "#7B68EE"
+            value = 10
+
+            [[states]]
+            name = "FpgaStage"
+            value = 11
+        "##})
+        .unwrap();
+        let mut states = ColorScheme::Default.states();
+        config.merge_into(&mut states).unwrap();
+        assert_eq!(states.len(), 12);
+        assert_eq!(states["WaitGPU"].value, 10);
+        assert_eq!(states["WaitGPU"].color.as_deref(), Some("#7B68EE"));
+        assert_eq!(states["FpgaStage"].color, None);
+    }
+
+    #[test]
+    fn test_validation() {
+        let config: StateConfig = toml::from_str(indoc! {r##"
+            [[states]]
+            name = "Idle"
+            color = "blue"
+            value = 10
+
+            [[states]]
+            name = "WaitGPU"
+            value = 12
+        "##})
+        .unwrap();
+        let mut states = ColorScheme::Default.states();
+        let err = config.merge_into(&mut states).unwrap_err().to_string();
+        insta::assert_snapshot!(err);
+        assert_eq!(states.len(), 10);
+    }
+}
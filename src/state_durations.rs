@@ -0,0 +1,75 @@
+//! Accumulating the time each CPU spends in each state.
+
+use crate::types::CpuState;
+
+/// Number of [`CpuState`]s.
+pub const NUM_STATES: usize = CpuState::ALL.len();
+
+/// Time spent in each state, per CPU, from the state transitions.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StateDurationAccumulator {
+    /// Time (in ns) in each state, indexed by CPU and state value.
+    durations: Vec<[u64; NUM_STATES]>,
+    /// Time and state of the last transition on each CPU, once there has
+    /// been one.
+    current: Vec<Option<(u64, CpuState)>>,
+}
+
+impl StateDurationAccumulator {
+    /// Record that `cpu` entered `new_state` at `time`. The time since the
+    /// previous transition on `cpu` is accounted to the previous state.
+    pub fn record_transition(&mut self, cpu: usize, new_state: CpuState, time: u64) {
+        if self.current.len() <= cpu {
+            self.current.resize(cpu + 1, None);
+            self.durations.resize(cpu + 1, [0; NUM_STATES]);
+        }
+        if let Some((prev_time, prev_state)) = self.current[cpu] {
+            self.durations[cpu][prev_state as usize] += time.saturating_sub(prev_time);
+        }
+        self.current[cpu] = Some((time, new_state));
+    }
+
+    /// Account the time from the last transition on each CPU until `time`,
+    /// the end of the trace.
+    pub fn finish(&mut self, time: u64) {
+        for cpu in 0..self.current.len() {
+            if let Some((_, state)) = self.current[cpu] {
+                self.record_transition(cpu, state, time);
+            }
+        }
+    }
+
+    /// Time `cpu` spent in `state`, in ns.
+    pub fn duration_ns(&self, cpu: usize, state: CpuState) -> u64 {
+        self.durations
+            .get(cpu)
+            .map_or(0, |times| times[state as usize])
+    }
+
+    /// Time spent in each state, indexed by CPU and state value.
+    pub fn durations(&self) -> &[[u64; NUM_STATES]] {
+        &self.durations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_durations() {
+        let mut durations = StateDurationAccumulator::default();
+        durations.record_transition(1, CpuState::Kernel, 100);
+        durations.record_transition(1, CpuState::User, 250);
+        durations.record_transition(1, CpuState::Kernel, 300);
+        durations.record_transition(1, CpuState::User, 310);
+        durations.finish(1000);
+        assert_eq!(durations.duration_ns(1, CpuState::Kernel), 160);
+        assert_eq!(durations.duration_ns(1, CpuState::User), 740);
+        assert_eq!(durations.duration_ns(1, CpuState::Idle), 0);
+        // No transitions on CPU 0, and CPU 2 is out of range.
+        assert_eq!(durations.duration_ns(0, CpuState::Idle), 0);
+        assert_eq!(durations.duration_ns(2, CpuState::User), 0);
+        assert_eq!(durations.durations().len(), 2);
+    }
+}
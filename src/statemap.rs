@@ -6,15 +6,17 @@
 //! Also, `StatemapInputDatum` was made generic over an enum type.
 
 use compact_str::CompactString;
+use eyre::eyre;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 
 /*
  * The StatemapInput* types denote the structure of the concatenated JSON
  * in the input file.
  */
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct StatemapInputState {
     pub color: Option<CompactString>, // color for state, if any
@@ -26,11 +28,98 @@ pub struct StatemapInputState {
 pub struct StatemapInputDatum<T: serde::Serialize + Default + Copy + Clone + std::fmt::Debug> {
     #[serde(serialize_with = "serialize_as_string")]
     pub time: u64, // time of this datum
-    pub entity: CompactString,      // name of entity
-    pub state: T,                   // state entity is in at time
+    pub entity: CompactString, // name of entity
+    pub state: T,              // state entity is in at time
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub tag: Option<CompactString>, // tag for this state, if any
 }
 
+impl<T: serde::Serialize + Default + Copy + Clone + std::fmt::Debug> StatemapInputDatum<T> {
+    /// Start building a datum. The entity must be set before it can be built.
+    pub fn builder() -> StatemapInputDatumBuilder<T, NoEntity> {
+        StatemapInputDatumBuilder {
+            time: 0,
+            entity: NoEntity,
+            state: T::default(),
+            tag: None,
+        }
+    }
+
+    /// Write the datum as MessagePack. Fields are written by name, and the
+    /// time as a string, the same as in JSON.
+    #[cfg(feature = "msgpack")]
+    pub fn encode_msgpack<W: std::io::Write + ?Sized>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), rmp_serde::encode::Error> {
+        rmp_serde::encode::write_named(writer, self)
+    }
+}
+
+/// Marker for a [`StatemapInputDatumBuilder`] that has no entity set yet.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoEntity;
+
+/// Builder for [`StatemapInputDatum`].
+///
+/// `E` tracks whether the entity has been set, so that `build()` is only
+/// available once it has.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct StatemapInputDatumBuilder<T, E> {
+    time: u64,
+    entity: E,
+    state: T,
+    tag: Option<CompactString>,
+}
+
+impl<T, E> StatemapInputDatumBuilder<T, E> {
+    /// Set the time of the datum (default 0).
+    pub fn time(mut self, time: u64) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Set the entity the datum is for.
+    pub fn entity(
+        self,
+        entity: impl Into<CompactString>,
+    ) -> StatemapInputDatumBuilder<T, CompactString> {
+        StatemapInputDatumBuilder {
+            time: self.time,
+            entity: entity.into(),
+            state: self.state,
+            tag: self.tag,
+        }
+    }
+
+    /// Set the state (default is `T::default()`).
+    pub fn state(mut self, state: T) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Set the tag (default none).
+    pub fn tag(mut self, tag: Option<impl Into<CompactString>>) -> Self {
+        self.tag = tag.map(Into::into);
+        self
+    }
+}
+
+impl<T: serde::Serialize + Default + Copy + Clone + std::fmt::Debug>
+    StatemapInputDatumBuilder<T, CompactString>
+{
+    /// Create the datum.
+    pub fn build(self) -> StatemapInputDatum<T> {
+        StatemapInputDatum {
+            time: self.time,
+            entity: self.entity,
+            state: self.state,
+            tag: self.tag,
+        }
+    }
+}
+
 // I'm not sure why the format uses strings for this. I guess it is because
 // JS has issues with large integers since it uses floats...?
 fn serialize_as_string<S>(value: &u64, serializer: S) -> Result<S::Ok, S::Error>
@@ -41,6 +130,25 @@ where
     serializer.serialize_str(&s)
 }
 
+/// States in order of their value (then name, in case of duplicates).
+fn sorted_by_value(
+    states: &HashMap<CompactString, StatemapInputState>,
+) -> Vec<(&CompactString, &StatemapInputState)> {
+    let mut states: Vec<_> = states.iter().collect();
+    states.sort_unstable_by_key(|&(name, state)| (state.value, name));
+    states
+}
+
+fn serialize_states_by_value<S>(
+    states: &HashMap<CompactString, StatemapInputState>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_map(sorted_by_value(states))
+}
+
 #[derive(Serialize, Debug)]
 #[serde(deny_unknown_fields)]
 #[allow(dead_code)]
@@ -49,25 +157,99 @@ struct StatemapInputDescription {
     description: String, // description of entity
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 #[allow(non_snake_case)]
 #[serde(deny_unknown_fields)]
 pub struct StatemapInputMetadata {
-    pub start: Vec<u64>,
+    pub start: [u64; 2],
     pub title: CompactString,
     pub host: Option<CompactString>,
     pub entityKind: Option<CompactString>,
+    /// Architecture of the traced system, such as `x86_64`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<CompactString>,
+    /// Serialized in order of value, to give stable output.
+    #[serde(serialize_with = "serialize_states_by_value")]
     pub states: HashMap<CompactString, StatemapInputState>,
+    /// Set when times are expressed in clock cycles rather than nanoseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cycleFrequencyGHz: Option<f64>,
+    /// Set to 2 for the v2 format, which has entity lifecycle events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schemaVersion: Option<u32>,
+    /// Full names of the entities, by ID, when the records use short IDs
+    /// as entity names.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entities: Option<BTreeMap<usize, CompactString>>,
+    /// Time from the first to the last sample of the trace, in ns. This is
+    /// in ns even when the record times are in cycles (see
+    /// `cycleFrequencyGHz`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ns: Option<u64>,
+    /// Number of sample records in the trace. Only counted on request,
+    /// since it takes an extra pass over the input.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_count: Option<u64>,
+    /// Custom fields for downstream tools, merged into the JSON object by
+    /// [`Self::to_json`].
+    #[serde(skip)]
+    pub extensions: serde_json::Map<String, serde_json::Value>,
 }
 
-#[derive(Deserialize, Debug)]
+impl StatemapInputMetadata {
+    /// The states, in order of their value.
+    pub fn states_sorted_by_value(&self) -> Vec<(&CompactString, &StatemapInputState)> {
+        sorted_by_value(&self.states)
+    }
+
+    /// Convert to JSON, including the extension fields.
+    pub fn to_json(&self) -> Result<serde_json::Value, eyre::Error> {
+        let mut value = serde_json::to_value(self)?;
+        if let serde_json::Value::Object(object) = &mut value {
+            for (key, extension) in &self.extensions {
+                if object.contains_key(key) {
+                    return Err(eyre::eyre!(
+                        "Extension field \"{key}\" conflicts with a standard header field"
+                    ));
+                }
+                object.insert(key.clone(), extension.clone());
+            }
+        }
+        Ok(value)
+    }
+}
+
+/// Parse a `key=value` header extension. The value is an integer, float or
+/// bool if it parses as one, otherwise a string.
+pub fn parse_extension(arg: &str) -> Result<(String, serde_json::Value), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("Expected KEY=VALUE, got \"{arg}\""))?;
+    if key.is_empty() {
+        return Err(format!("Empty key in \"{arg}\""));
+    }
+    let value = if let Ok(int) = value.parse::<i64>() {
+        int.into()
+    } else if let Ok(float) = value.parse::<f64>()
+        && float.is_finite()
+    {
+        float.into()
+    } else if let Ok(boolean) = value.parse::<bool>() {
+        boolean.into()
+    } else {
+        value.into()
+    };
+    Ok((key.to_owned(), value))
+}
+
+#[derive(Serialize, Debug)]
 #[serde(deny_unknown_fields)]
-#[allow(dead_code)]
-struct StatemapInputEvent {
-    time: String,           // time of this datum
-    entity: String,         // name of entity
-    event: String,          // type of event
-    target: Option<String>, // target for event, if any
+pub struct StatemapInputEvent {
+    #[serde(serialize_with = "serialize_as_string")]
+    pub time: u64, // time of this datum
+    pub entity: CompactString,         // name of entity
+    pub event: CompactString,          // type of event
+    pub target: Option<CompactString>, // target for event, if any
 }
 
 #[derive(Deserialize, Debug)]
@@ -76,3 +258,212 @@ struct StatemapInputTag {
     state: u32,  // state for this tag
     tag: String, // tag itself
 }
+
+/// A problem with the values of the states in the header.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum StateValueError {
+    /// The lowest state value is not 0.
+    #[error("State values must start at 0, but the lowest is {0}")]
+    NonZeroStart(usize),
+    /// Some values between the lowest and highest are not used.
+    #[error("State values are not contiguous: {first}..={last} are unused")]
+    Gap { first: usize, last: usize },
+    /// Several states have the same value.
+    #[error("State value {value} is used by several states: {}", names.join(", "))]
+    Duplicate {
+        value: usize,
+        names: Vec<CompactString>,
+    },
+}
+
+/// All problems found by [`validate_states`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Invalid statemap states:\n  {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n  "))]
+pub struct InvalidStates(pub Vec<StateValueError>);
+
+/// Check that state values are unique and contiguous from 0, as required by
+/// the statemap format.
+pub fn validate_states(
+    states: &HashMap<CompactString, StatemapInputState>,
+) -> Result<(), InvalidStates> {
+    let mut by_value: Vec<(usize, &CompactString)> = states
+        .iter()
+        .map(|(name, state)| (state.value, name))
+        .collect();
+    by_value.sort_unstable();
+
+    let mut errors = Vec::new();
+    if let Some(&(first, _)) = by_value.first()
+        && first != 0
+    {
+        errors.push(StateValueError::NonZeroStart(first));
+    }
+    for group in by_value.chunk_by(|a, b| a.0 == b.0) {
+        if group.len() > 1 {
+            errors.push(StateValueError::Duplicate {
+                value: group[0].0,
+                names: group.iter().map(|&(_, name)| name.clone()).collect(),
+            });
+        }
+    }
+    for window in by_value.windows(2) {
+        if window[1].0 > window[0].0 + 1 {
+            errors.push(StateValueError::Gap {
+                first: window[0].0 + 1,
+                last: window[1].0 - 1,
+            });
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(InvalidStates(errors))
+    }
+}
+
+/// Check for a CSS color of the form `#RRGGBB`, or the short form `#RGB`
+/// which is the same as doubling each digit.
+pub fn validate_color(s: &str) -> bool {
+    s.strip_prefix('#')
+        .is_some_and(|hex| matches!(hex.len(), 3 | 6) && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Check that the colors of all states are valid, see [`validate_color`].
+pub fn validate_colors(
+    states: &HashMap<CompactString, StatemapInputState>,
+) -> Result<(), eyre::Error> {
+    let mut states: Vec<_> = states.iter().collect();
+    states.sort_unstable_by_key(|(name, _)| *name);
+    for (name, state) in states {
+        if let Some(s) = &state.color
+            && !validate_color(s)
+        {
+            return Err(eyre!("Invalid color '{s}' for state '{name}'"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn states(values: &[(&str, usize)]) -> HashMap<CompactString, StatemapInputState> {
+        values
+            .iter()
+            .map(|&(name, value)| (name.into(), StatemapInputState { color: None, value }))
+            .collect()
+    }
+
+    #[test]
+    fn test_datum_builder() {
+        let datum = StatemapInputDatum::builder()
+            .time(10)
+            .tag(Some("a:1"))
+            .entity("3")
+            .state(5u8)
+            .build();
+        assert_eq!(datum.time, 10);
+        assert_eq!(datum.entity, "3");
+        assert_eq!(datum.state, 5);
+        assert_eq!(datum.tag.as_deref(), Some("a:1"));
+    }
+
+    #[test]
+    fn test_extensions() {
+        assert_eq!(parse_extension("a=42"), Ok(("a".into(), 42.into())));
+        assert_eq!(parse_extension("a=-1.5"), Ok(("a".into(), (-1.5).into())));
+        assert_eq!(parse_extension("a=true"), Ok(("a".into(), true.into())));
+        assert_eq!(parse_extension("a=x=y"), Ok(("a".into(), "x=y".into())));
+        assert_eq!(parse_extension("a=inf"), Ok(("a".into(), "inf".into())));
+        assert!(parse_extension("a").is_err());
+        assert!(parse_extension("=1").is_err());
+
+        let mut metadata = StatemapInputMetadata {
+            start: [1, 2],
+            title: "CPU".into(),
+            host: None,
+            entityKind: None,
+            arch: None,
+            states: HashMap::new(),
+            cycleFrequencyGHz: None,
+            schemaVersion: None,
+            entities: None,
+            duration_ns: None,
+            event_count: None,
+            extensions: [parse_extension("run=7").unwrap()].into_iter().collect(),
+        };
+        let json = metadata.to_json().unwrap();
+        assert_eq!(json["start"], serde_json::json!([1, 2]));
+        assert_eq!(json["run"], 7);
+        assert_eq!(json["title"], "CPU");
+        assert!(json.get("entities").is_none());
+        assert!(json.get("duration_ns").is_none());
+        metadata.duration_ns = Some(1000);
+        metadata.event_count = Some(3);
+        let json = metadata.to_json().unwrap();
+        assert_eq!(json["duration_ns"], 1000);
+        assert_eq!(json["event_count"], 3);
+        metadata.entities = Some([(0, "1/0".into())].into());
+        assert_eq!(metadata.to_json().unwrap()["entities"]["0"], "1/0");
+        metadata
+            .extensions
+            .extend([parse_extension("title=x").unwrap()]);
+        assert!(metadata.to_json().is_err());
+    }
+
+    #[test]
+    fn test_states_sorted_by_value() {
+        let metadata = StatemapInputMetadata {
+            start: [0, 0],
+            title: "CPU".into(),
+            host: None,
+            entityKind: None,
+            arch: None,
+            states: states(&[("b", 0), ("a", 2), ("c", 1)]),
+            cycleFrequencyGHz: None,
+            schemaVersion: None,
+            entities: None,
+            duration_ns: None,
+            event_count: None,
+            extensions: Default::default(),
+        };
+        let names: Vec<_> = metadata
+            .states_sorted_by_value()
+            .into_iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["b", "c", "a"]);
+        let json = serde_json::to_string(&metadata).unwrap();
+        assert!(json.contains(
+            r#""states":{"b":{"color":null,"value":0},"c":{"color":null,"value":1},"a":{"color":null,"value":2}}"#
+        ), "{json}");
+    }
+
+    #[test]
+    fn test_validate_states() {
+        assert_eq!(validate_states(&states(&[])), Ok(()));
+        assert_eq!(validate_states(&states(&[("a", 1), ("b", 0)])), Ok(()));
+        let err = validate_states(&states(&[("a", 1), ("b", 1), ("c", 4)])).unwrap_err();
+        insta::assert_snapshot!(err);
+    }
+
+    #[test]
+    fn test_validate_color() {
+        for color in ["#7B68EE", "#abc", "#000000"] {
+            assert!(validate_color(color), "{color}");
+        }
+        for color in ["red", "#GGGGGG", "#12345", "7B68EE", "#", "#1234567"] {
+            assert!(!validate_color(color), "{color}");
+        }
+
+        let mut states = states(&[("a", 0), ("b", 1)]);
+        assert!(validate_colors(&states).is_ok());
+        states.get_mut("b").unwrap().color = Some("red".into());
+        assert_eq!(
+            validate_colors(&states).unwrap_err().to_string(),
+            "Invalid color 'red' for state 'b'"
+        );
+    }
+}
@@ -0,0 +1,571 @@
+//! Summary statistics, reported instead of the statemap with `--stats-only`.
+
+use crate::preemption_storms::PreemptionStorm;
+use crate::state_durations::StateDurationAccumulator;
+use crate::types::CpuState;
+use compact_str::CompactString;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Number of threads to list in the CPU time report.
+const TOP_THREADS: usize = 10;
+
+/// How much a sleep reported by `sched_stat_sleep` may differ from the
+/// observed one before it counts as a mismatch. They are measured at
+/// slightly different points in the scheduler.
+pub const SLEEP_DELAY_TOLERANCE_NS: u64 = 10_000;
+
+/// Statistics collected while processing the trace.
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    /// Number of sample records seen (including ignored ones).
+    pub samples: u64,
+    /// Number of processed events, by kind.
+    pub events: BTreeMap<&'static str, u64>,
+    /// Number of processed events, by CPU and kind.
+    pub cpu_events: BTreeMap<(usize, &'static str), u64>,
+    /// Number of events the kernel reported as lost.
+    pub lost_events: u64,
+    /// Longest time from a thread being woken up until it was scheduled.
+    pub max_wakeup_latency_ns: Option<u64>,
+    /// Longest time from a softirq being raised until it ran.
+    pub max_softirq_latency_ns: Option<u64>,
+    /// Number of `sched_stat_sleep`/`sched_stat_iowait` delays checked
+    /// against the time between switching out and waking up.
+    pub sleep_delay_checks: u64,
+    /// Number of those that differed by more than
+    /// [`SLEEP_DELAY_TOLERANCE_NS`].
+    pub sleep_delay_mismatches: u64,
+    /// Largest difference between a reported and an observed sleep.
+    pub max_sleep_delay_error_ns: Option<u64>,
+    /// Total time all CPUs were idle at the same time.
+    pub system_idle_ns: Option<u64>,
+    /// Number of IRQs that no handler claimed, by IRQ number.
+    pub unhandled_irqs: BTreeMap<i32, u64>,
+    /// Number of NUMA balancing migrations, by source and destination node.
+    pub numa_moves: BTreeMap<(i32, i32), u64>,
+    /// Largest priority inheritance boost of each thread (by PID), with its
+    /// name.
+    pub pi_boosts: BTreeMap<i32, (CompactString, i32)>,
+    /// CPU time used by each thread (by PID), with its name, from
+    /// `sched_stat_runtime`.
+    pub thread_runtimes: HashMap<i32, (CompactString, u64)>,
+    /// Number of entries into each RCU phase, from `rcu_utilization`.
+    pub rcu_phases: BTreeMap<CompactString, u64>,
+    /// Total time each thread (by PID) spent waiting for children, with its
+    /// name.
+    pub child_waits: BTreeMap<i32, (CompactString, u64)>,
+    /// Number of execs by each thread (by PID), with its name after the
+    /// last one.
+    pub execs: BTreeMap<i32, (CompactString, u64)>,
+    /// Number of `PERF_RECORD_AUX` records (hardware trace data).
+    pub aux_records: u64,
+    /// Total size of the AUX data in bytes.
+    pub aux_bytes: u64,
+    /// Size of the input file in bytes, if known.
+    pub input_size: Option<u64>,
+    /// Number of state changes with each tag.
+    pub tag_counts: HashMap<CompactString, u64>,
+    /// Interrupt entries on each CPU, indexed by CPU.
+    pub interrupts: Vec<InterruptCounts>,
+    /// Number of entries of each hardware IRQ, on all CPUs.
+    pub irq_entries: BTreeMap<i32, u64>,
+    /// Windows with abnormally many context switches, sorted by time.
+    pub preemption_storms: Vec<PreemptionStorm>,
+    /// Time each CPU spent in each state.
+    pub state_times: StateDurationAccumulator,
+}
+
+/// Interrupt statistics for a CPU.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InterruptCounts {
+    pub irqs: u64,
+    pub softirqs: u64,
+    pub tasklets: u64,
+    /// Deepest nesting of interrupts seen.
+    pub max_depth: u32,
+    /// Current nesting of interrupts.
+    depth: u32,
+}
+
+impl Stats {
+    /// Record that an event of the given kind was processed on `cpu`.
+    pub fn count_event(&mut self, cpu: usize, kind: &'static str) {
+        *self.events.entry(kind).or_default() += 1;
+        *self.cpu_events.entry((cpu, kind)).or_default() += 1;
+    }
+
+    /// Record the latency of a single wakeup.
+    pub fn record_wakeup_latency(&mut self, latency: u64) {
+        self.max_wakeup_latency_ns = self.max_wakeup_latency_ns.max(Some(latency));
+    }
+
+    /// Record the sleep `delay` reported by `sched_stat_sleep` or
+    /// `sched_stat_iowait`, for a sleep `observed` from the thread being
+    /// switched out until the event.
+    pub fn record_sleep_delay(&mut self, observed: u64, delay: u64) {
+        let error = observed.abs_diff(delay);
+        self.sleep_delay_checks += 1;
+        if error > SLEEP_DELAY_TOLERANCE_NS {
+            self.sleep_delay_mismatches += 1;
+        }
+        self.max_sleep_delay_error_ns = self.max_sleep_delay_error_ns.max(Some(error));
+    }
+
+    /// Record a `PERF_RECORD_AUX` record of `size` bytes of AUX data.
+    pub fn record_aux(&mut self, size: u64) {
+        self.aux_records += 1;
+        self.aux_bytes += size;
+    }
+
+    /// Record the latency from raising a softirq until it ran.
+    pub fn record_softirq_latency(&mut self, latency: u64) {
+        self.max_softirq_latency_ns = self.max_softirq_latency_ns.max(Some(latency));
+    }
+
+    /// Record an IRQ that wasn't handled.
+    pub fn record_unhandled_irq(&mut self, irq: i32) {
+        *self.unhandled_irqs.entry(irq).or_default() += 1;
+    }
+
+    /// Record that `cpu` entered an interrupt of the given kind. `irq` is the
+    /// IRQ number for hardware IRQs.
+    pub fn record_interrupt_entry(&mut self, cpu: usize, state: CpuState, irq: Option<i32>) {
+        if self.interrupts.len() <= cpu {
+            self.interrupts.resize(cpu + 1, InterruptCounts::default());
+        }
+        let counts = &mut self.interrupts[cpu];
+        match state {
+            CpuState::Irq => counts.irqs += 1,
+            CpuState::Softirq => counts.softirqs += 1,
+            CpuState::Tasklet => counts.tasklets += 1,
+            _ => return,
+        }
+        counts.depth += 1;
+        counts.max_depth = counts.max_depth.max(counts.depth);
+        if let Some(irq) = irq {
+            *self.irq_entries.entry(irq).or_default() += 1;
+        }
+    }
+
+    /// Record that `cpu` returned from an interrupt.
+    pub fn record_interrupt_exit(&mut self, cpu: usize) {
+        // The trace may start inside an interrupt.
+        if let Some(counts) = self.interrupts.get_mut(cpu) {
+            counts.depth = counts.depth.saturating_sub(1);
+        }
+    }
+
+    /// Record a NUMA balancing migration from node `src` to node `dst`.
+    pub fn record_numa_move(&mut self, src: i32, dst: i32) {
+        *self.numa_moves.entry((src, dst)).or_default() += 1;
+    }
+
+    /// Record a priority inheritance boost of `boost` priority levels.
+    pub fn record_pi_boost(&mut self, pid: i32, comm: &CompactString, boost: i32) {
+        let entry = self
+            .pi_boosts
+            .entry(pid)
+            .or_insert_with(|| (comm.clone(), boost));
+        entry.1 = entry.1.max(boost);
+    }
+
+    /// Record an entry into the RCU `phase`.
+    pub fn record_rcu_phase(&mut self, phase: &CompactString) {
+        if let Some(count) = self.rcu_phases.get_mut(phase) {
+            *count += 1;
+        } else {
+            self.rcu_phases.insert(phase.clone(), 1);
+        }
+    }
+
+    /// Record that a thread used `runtime` ns of CPU time.
+    pub fn record_runtime(&mut self, pid: i32, comm: &CompactString, runtime: u64) {
+        let entry = self
+            .thread_runtimes
+            .entry(pid)
+            .or_insert_with(|| (comm.clone(), 0));
+        // The name may change on exec.
+        entry.0.clone_from(comm);
+        entry.1 += runtime;
+    }
+
+    /// Record that a thread waited `duration` ns for a child to exit.
+    pub fn record_child_wait(&mut self, pid: i32, comm: &CompactString, duration: u64) {
+        let entry = self
+            .child_waits
+            .entry(pid)
+            .or_insert_with(|| (comm.clone(), 0));
+        entry.1 += duration;
+    }
+
+    /// Record that a thread executed a program, and is now named `comm`.
+    pub fn record_exec(&mut self, pid: i32, comm: &CompactString) {
+        let entry = self.execs.entry(pid).or_insert_with(|| (comm.clone(), 0));
+        entry.0.clone_from(comm);
+        entry.1 += 1;
+    }
+
+    /// Record that `cpu` changed to `state` at `time`.
+    pub fn record_state(&mut self, cpu: usize, state: CpuState, time: u64) {
+        self.state_times.record_transition(cpu, state, time);
+    }
+
+    /// Record a state change with the given tag.
+    pub fn record_tag(&mut self, tag: &CompactString) {
+        if let Some(count) = self.tag_counts.get_mut(tag) {
+            *count += 1;
+        } else {
+            self.tag_counts.insert(tag.clone(), 1);
+        }
+    }
+
+    /// The `n` most frequent tags, most frequent first.
+    pub fn top_tags(&self, n: usize) -> Vec<(&CompactString, u64)> {
+        let mut tags: Vec<_> = self
+            .tag_counts
+            .iter()
+            .map(|(tag, &count)| (tag, count))
+            .collect();
+        tags.sort_by_key(|&(tag, count)| (std::cmp::Reverse(count), tag));
+        tags.truncate(n);
+        tags
+    }
+
+    /// Percentage of the time `cpu` spent in `state`.
+    pub fn state_percent(&self, cpu: usize, state: CpuState) -> f64 {
+        let times = &self.state_times.durations()[cpu];
+        let total: u64 = times.iter().sum();
+        if total == 0 {
+            0.0
+        } else {
+            times[state as usize] as f64 * 100.0 / total as f64
+        }
+    }
+
+    /// Account the time from the last state change on each CPU until `time`,
+    /// the end of the trace.
+    pub fn finish_state_times(&mut self, time: u64) {
+        self.state_times.finish(time);
+    }
+
+    /// Write a human readable report.
+    pub fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(writer, "samples: {}", self.samples)?;
+        writeln!(writer, "events:")?;
+        for (kind, count) in &self.events {
+            writeln!(writer, "  {kind}: {count}")?;
+        }
+        if self.lost_events > 0 {
+            writeln!(writer, "lost_events: {}", self.lost_events)?;
+        }
+        if let Some(latency) = self.max_wakeup_latency_ns {
+            writeln!(writer, "max_wakeup_latency_ns: {latency}")?;
+        }
+        if let Some(latency) = self.max_softirq_latency_ns {
+            writeln!(writer, "max_softirq_latency_ns: {latency}")?;
+        }
+        if let Some(error) = self.max_sleep_delay_error_ns {
+            writeln!(
+                writer,
+                "sleep_delay_mismatches: {} of {} (max error {error} ns)",
+                self.sleep_delay_mismatches, self.sleep_delay_checks
+            )?;
+        }
+        if let Some(idle) = self.system_idle_ns {
+            writeln!(writer, "system_idle_ns: {idle}")?;
+        }
+        if self.aux_records > 0 {
+            writeln!(writer, "aux_records: {}", self.aux_records)?;
+            match self.input_size {
+                Some(size) if size > 0 => writeln!(
+                    writer,
+                    "aux_bytes: {} ({:.1}% of the input file)",
+                    self.aux_bytes,
+                    self.aux_bytes as f64 * 100.0 / size as f64
+                )?,
+                _ => writeln!(writer, "aux_bytes: {}", self.aux_bytes)?,
+            }
+        }
+        if !self.unhandled_irqs.is_empty() {
+            writeln!(writer, "unhandled_irqs:")?;
+            for (irq, count) in &self.unhandled_irqs {
+                writeln!(writer, "  IRQ {irq}: {count}")?;
+            }
+        }
+        if !self.numa_moves.is_empty() {
+            writeln!(writer, "numa_moves:")?;
+            for ((src, dst), count) in &self.numa_moves {
+                writeln!(writer, "  node {src} → node {dst}: {count}")?;
+            }
+        }
+        if !self.pi_boosts.is_empty() {
+            writeln!(writer, "max_pi_boosts:")?;
+            for (pid, (comm, boost)) in &self.pi_boosts {
+                writeln!(writer, "  {comm}:{pid}: {boost}")?;
+            }
+        }
+        if !self.rcu_phases.is_empty() {
+            writeln!(writer, "rcu_phases:")?;
+            for (phase, count) in &self.rcu_phases {
+                writeln!(writer, "  {phase}: {count}")?;
+            }
+        }
+        if !self.thread_runtimes.is_empty() {
+            writeln!(writer, "top_threads_by_cpu_time:")?;
+            let mut threads: Vec<_> = self.thread_runtimes.iter().collect();
+            threads.sort_by_key(|(pid, (_, runtime))| (std::cmp::Reverse(*runtime), **pid));
+            for (pid, (comm, runtime)) in threads.into_iter().take(TOP_THREADS) {
+                writeln!(writer, "  {comm}:{pid}: {}", format_duration(*runtime))?;
+            }
+        }
+        if !self.child_waits.is_empty() {
+            writeln!(writer, "child_wait_time:")?;
+            for (pid, (comm, duration)) in &self.child_waits {
+                writeln!(writer, "  {comm}:{pid}: {}", format_duration(*duration))?;
+            }
+        }
+        if !self.execs.is_empty() {
+            writeln!(writer, "execs:")?;
+            for (pid, (comm, count)) in &self.execs {
+                writeln!(writer, "  {comm}:{pid}: {count}")?;
+            }
+        }
+        if !self.interrupts.is_empty() {
+            self.write_interrupts(writer)?;
+        }
+        if !self.preemption_storms.is_empty() {
+            writeln!(writer, "preemption_storms:")?;
+            for storm in &self.preemption_storms {
+                writeln!(
+                    writer,
+                    "  {} ns: CPU {}, {} switches",
+                    storm.start, storm.cpu, storm.switches
+                )?;
+            }
+        }
+        if !self.state_times.durations().is_empty() {
+            self.write_state_times(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Write the interrupt counts as a table with a row per CPU.
+    fn write_interrupts(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(writer, "interrupts:")?;
+        writeln!(
+            writer,
+            "  {:8} {:>10} {:>10} {:>10} {:>10}",
+            "", "irq", "softirq", "tasklet", "max_depth"
+        )?;
+        for (cpu, counts) in self.interrupts.iter().enumerate() {
+            writeln!(
+                writer,
+                "  {:8} {:>10} {:>10} {:>10} {:>10}",
+                format!("CPU {cpu}"),
+                counts.irqs,
+                counts.softirqs,
+                counts.tasklets,
+                counts.max_depth
+            )?;
+        }
+        // Lowest IRQ number on ties.
+        let busiest = self
+            .irq_entries
+            .iter()
+            .max_by_key(|&(irq, count)| (count, std::cmp::Reverse(irq)));
+        if let Some((irq, count)) = busiest {
+            writeln!(writer, "busiest_irq: IRQ {irq} ({count} entries)")?;
+        }
+        Ok(())
+    }
+
+    /// Write the time spent in each state as a table with a column per CPU.
+    fn write_state_times(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        const WIDTH: usize = 21;
+        let percent = |cpu: usize, state: CpuState| self.state_percent(cpu, state);
+
+        writeln!(writer, "state_times:")?;
+        write!(writer, "  {:10}", "")?;
+        let num_cpus = self.state_times.durations().len();
+        for cpu in 0..num_cpus {
+            write!(writer, " {:>WIDTH$}", format!("CPU {cpu}"))?;
+        }
+        writeln!(writer)?;
+        for state in CpuState::ALL {
+            write!(writer, "  {:10}", state.name())?;
+            for cpu in 0..num_cpus {
+                let cell = format!(
+                    "{} ({:.1}%)",
+                    format_duration(self.state_times.duration_ns(cpu, state)),
+                    percent(cpu, state)
+                );
+                write!(writer, " {cell:>WIDTH$}")?;
+            }
+            writeln!(writer)?;
+        }
+
+        for (label, state) in [("irq", CpuState::Irq), ("user", CpuState::User)] {
+            let highest =
+                (0..num_cpus).max_by(|&a, &b| percent(a, state).total_cmp(&percent(b, state)));
+            if let Some(cpu) = highest {
+                writeln!(
+                    writer,
+                    "highest_{label}_load: CPU {cpu} ({:.1}%)",
+                    percent(cpu, state)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Format nanoseconds as `HH:MM:SS.mmm`.
+pub fn format_duration(ns: u64) -> String {
+    let ms = ns / 1_000_000;
+    let (s, ms) = (ms / 1000, ms % 1000);
+    let (m, s) = (s / 60, s % 60);
+    let (h, m) = (m / 60, m % 60);
+    format!("{h:02}:{m:02}:{s:02}.{ms:03}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_times() {
+        let mut stats = Stats::default();
+        stats.record_state(0, CpuState::User, 0);
+        stats.record_state(1, CpuState::Irq, 500_000_000);
+        stats.record_state(0, CpuState::Irq, 1_500_000_000);
+        stats.record_state(0, CpuState::User, 2_000_000_000);
+        stats.finish_state_times(4_000_000_000);
+        let times = &stats.state_times;
+        assert_eq!(times.duration_ns(0, CpuState::User), 3_500_000_000);
+        assert_eq!(times.duration_ns(0, CpuState::Irq), 500_000_000);
+        assert_eq!(times.duration_ns(1, CpuState::Irq), 3_500_000_000);
+
+        let mut out = Vec::new();
+        stats.write_state_times(&mut out).unwrap();
+        insta::assert_snapshot!(String::from_utf8(out).unwrap());
+        assert_eq!(format_duration(3_723_004_000_000), "01:02:03.004");
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let mut stats = Stats::default();
+        stats.record_interrupt_exit(0);
+        stats.record_interrupt_entry(1, CpuState::Irq, Some(30));
+        stats.record_interrupt_entry(1, CpuState::Softirq, None);
+        stats.record_interrupt_exit(1);
+        stats.record_interrupt_exit(1);
+        stats.record_interrupt_entry(0, CpuState::Irq, Some(9));
+        stats.record_interrupt_exit(0);
+        stats.record_interrupt_entry(1, CpuState::Irq, Some(9));
+        stats.record_interrupt_entry(1, CpuState::Tasklet, None);
+        stats.record_interrupt_entry(1, CpuState::RcuStall, None);
+        assert_eq!(stats.interrupts[0].max_depth, 1);
+        assert_eq!(stats.interrupts[1].max_depth, 2);
+
+        let mut out = Vec::new();
+        stats.write_interrupts(&mut out).unwrap();
+        insta::assert_snapshot!(String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn test_aux() {
+        let mut stats = Stats::default();
+        stats.record_aux(100);
+        stats.record_aux(150);
+        stats.input_size = Some(1000);
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("aux_records: 2\naux_bytes: 250 (25.0% of the input file)\n"));
+    }
+
+    #[test]
+    fn test_sleep_delays() {
+        let mut stats = Stats::default();
+        stats.record_sleep_delay(100_000, 99_000);
+        stats.record_sleep_delay(100_000, 150_000);
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("sleep_delay_mismatches: 1 of 2 (max error 50000 ns)\n"));
+    }
+
+    #[test]
+    fn test_pi_boosts() {
+        let mut stats = Stats::default();
+        stats.record_pi_boost(10, &"a".into(), 5);
+        stats.record_pi_boost(10, &"a".into(), 20);
+        stats.record_pi_boost(10, &"a".into(), 3);
+        stats.record_pi_boost(7, &"b".into(), 1);
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("max_pi_boosts:\n  b:7: 1\n  a:10: 20\n"));
+    }
+
+    #[test]
+    fn test_rcu_phases() {
+        let mut stats = Stats::default();
+        for phase in [
+            "Start context switch",
+            "End context switch",
+            "Start context switch",
+        ] {
+            stats.record_rcu_phase(&phase.into());
+        }
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("rcu_phases:\n  End context switch: 1\n  Start context switch: 2\n"));
+    }
+
+    #[test]
+    fn test_child_waits() {
+        let mut stats = Stats::default();
+        stats.record_child_wait(10, &"bash".into(), 2_000_000);
+        stats.record_child_wait(10, &"bash".into(), 1_000_000);
+        stats.record_child_wait(7, &"make".into(), 500_000_000);
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains("child_wait_time:\n  make:7: 00:00:00.500\n  bash:10: 00:00:00.003\n")
+        );
+    }
+
+    #[test]
+    fn test_execs() {
+        let mut stats = Stats::default();
+        stats.record_exec(10, &"sh".into());
+        stats.record_exec(10, &"make".into());
+        stats.record_exec(7, &"cc1".into());
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("execs:\n  cc1:7: 1\n  make:10: 2\n"));
+    }
+
+    #[test]
+    fn test_top_threads() {
+        let mut stats = Stats::default();
+        for pid in 0..12 {
+            stats.record_runtime(pid, &"t".into(), u64::from(pid.unsigned_abs()) * 1_000_000);
+        }
+        stats.record_runtime(3, &"u".into(), 20_000_000);
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let (_, top) = out.split_once("top_threads_by_cpu_time:\n").unwrap();
+        let top: Vec<_> = top.lines().take_while(|l| l.starts_with("  ")).collect();
+        assert_eq!(top.len(), TOP_THREADS);
+        assert_eq!(top[0], "  u:3: 00:00:00.023");
+        assert_eq!(top[1], "  t:11: 00:00:00.011");
+    }
+}
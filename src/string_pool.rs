@@ -0,0 +1,73 @@
+//! Interning of frequently repeated strings, such as tags.
+
+use compact_str::CompactString;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Pool of interned strings.
+///
+/// Short strings are stored inline in a [`CompactString`] and never allocate.
+/// Longer strings are leaked and referenced as `&'static str`, so cloning an
+/// interned string never allocates. This trades a bounded amount of memory
+/// (one copy of each unique string, for the rest of the process) for not
+/// allocating on every event, so the pool is only for the binary, which
+/// converts one trace and exits.
+#[derive(Debug, Default)]
+pub struct StringPool {
+    /// Key and value are the same string, so we can look up by `&str` and
+    /// hand out clones of the shared value.
+    pool: HashMap<CompactString, CompactString>,
+    /// Reusable buffer for formatting strings before interning them.
+    scratch: String,
+}
+
+impl StringPool {
+    /// Get the interned copy of `s`, adding it to the pool if needed.
+    pub fn intern(&mut self, s: &str) -> CompactString {
+        if let Some(value) = self.pool.get(s) {
+            return value.clone();
+        }
+        let value = if s.len() <= size_of::<CompactString>() {
+            CompactString::new(s)
+        } else {
+            CompactString::const_new(Box::leak(s.into()))
+        };
+        self.pool.insert(value.clone(), value.clone());
+        value
+    }
+
+    /// Format and intern a string, without allocating if it is already in
+    /// the pool.
+    pub fn intern_fmt(&mut self, args: std::fmt::Arguments<'_>) -> CompactString {
+        let mut scratch = std::mem::take(&mut self.scratch);
+        scratch.clear();
+        scratch
+            .write_fmt(args)
+            .expect("Formatting to a String cannot fail");
+        let value = self.intern(&scratch);
+        self.scratch = scratch;
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let mut pool = StringPool::default();
+        let short = pool.intern("IRQ 23: xhci_hcd");
+        assert_eq!(short, "IRQ 23: xhci_hcd");
+        assert!(!short.is_heap_allocated());
+
+        let long = "IRQ 128: a rather long interrupt handler name";
+        let a = pool.intern_fmt(format_args!("IRQ {}: {}", 128, &long[9..]));
+        let b = pool.intern(long);
+        assert_eq!(a, long);
+        assert!(!a.is_heap_allocated());
+        let (a, b) = (a.as_static_str().unwrap(), b.as_static_str().unwrap());
+        assert!(std::ptr::eq(a, b));
+        assert_eq!(pool.pool.len(), 2);
+    }
+}
@@ -0,0 +1,106 @@
+//! Histogram of the time between context switches.
+//!
+//! The time between consecutive context switches on the same CPU is how long
+//! each thread got to run, which is a standard measure when analysing
+//! scheduling latency.
+
+use std::io::Write;
+
+/// Percentiles marked in the output.
+const PERCENTILES: [(&str, f64); 4] =
+    [("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("p99.9", 99.9)];
+
+/// Collects the time between context switches on each CPU.
+#[derive(Debug, Clone)]
+pub struct SwitchHistogram {
+    /// Size of each bucket in ns.
+    bucket_ns: u64,
+    /// Time of the last context switch on each CPU.
+    last_switch: Vec<Option<u64>>,
+    /// All intervals seen, in ns.
+    intervals: Vec<u64>,
+}
+
+impl SwitchHistogram {
+    pub fn new(num_cpus: usize, bucket_ns: u64) -> Self {
+        Self {
+            bucket_ns,
+            last_switch: vec![None; num_cpus],
+            intervals: Vec::new(),
+        }
+    }
+
+    /// Track `num_cpus` CPUs, if that is more than currently tracked.
+    pub fn resize(&mut self, num_cpus: usize) {
+        if num_cpus > self.last_switch.len() {
+            self.last_switch.resize(num_cpus, None);
+        }
+    }
+
+    /// Record a context switch on `cpu` at `time`.
+    pub fn record_switch(&mut self, cpu: usize, time: u64) {
+        if let Some(last) = self.last_switch[cpu].replace(time) {
+            self.intervals.push(time.saturating_sub(last));
+        }
+    }
+
+    /// Value at the given percentile (nearest rank) of sorted `intervals`.
+    fn percentile(intervals: &[u64], percentile: f64) -> u64 {
+        let rank = (percentile / 100.0 * intervals.len() as f64).ceil() as usize;
+        intervals[rank.clamp(1, intervals.len()) - 1]
+    }
+
+    /// Write the histogram as CSV, followed by the percentiles as comment
+    /// lines. Empty buckets are left out.
+    pub fn write_csv(mut self, writer: &mut dyn Write) -> Result<(), eyre::Error> {
+        writeln!(writer, "bucket_start_ns,bucket_end_ns,count,cumulative_pct")?;
+        if self.intervals.is_empty() {
+            return Ok(());
+        }
+        self.intervals.sort_unstable();
+        let total = self.intervals.len();
+        let mut cumulative = 0;
+        for bucket in self
+            .intervals
+            .chunk_by(|a, b| a / self.bucket_ns == b / self.bucket_ns)
+        {
+            let start = bucket[0] / self.bucket_ns * self.bucket_ns;
+            cumulative += bucket.len();
+            writeln!(
+                writer,
+                "{start},{},{},{:.3}",
+                start + self.bucket_ns,
+                bucket.len(),
+                cumulative as f64 * 100.0 / total as f64
+            )?;
+        }
+        for (name, percentile) in PERCENTILES {
+            writeln!(
+                writer,
+                "# {name}: {} ns",
+                Self::percentile(&self.intervals, percentile)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_switch_histogram() {
+        let mut histogram = SwitchHistogram::new(1, 100);
+        histogram.resize(2);
+        // CPU 0: intervals 50, 150, 120. CPU 1: interval 30.
+        for time in [0, 50, 200, 320] {
+            histogram.record_switch(0, time);
+        }
+        histogram.record_switch(1, 1000);
+        histogram.record_switch(1, 1030);
+        let mut out = Vec::new();
+        histogram.write_csv(&mut out).unwrap();
+        insta::assert_snapshot!(String::from_utf8(out).unwrap());
+    }
+}
@@ -0,0 +1,150 @@
+//! Rate limiting of context switches in the output.
+//!
+//! On heavily loaded systems a CPU can switch tasks tens of thousands of times
+//! per second, which makes the statemap huge without adding much insight.
+//! Once a CPU exceeds the switch limit within a 1 ms window, the rest of the
+//! window is collapsed into a single [`CpuState::Busy`] datum tagged with the
+//! number of switches.
+
+use crate::statemap::StatemapInputDatum;
+use crate::types::CpuState;
+use compact_str::format_compact;
+
+const NS_PER_MS: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Default)]
+struct CpuWindow {
+    /// Index of the current 1 ms window.
+    window: u64,
+    /// Number of switches seen in the current window.
+    switches: u64,
+    /// The busy datum covering the remainder of the window, once the limit
+    /// has been exceeded.
+    busy: Option<StatemapInputDatum<CpuState>>,
+}
+
+impl CpuWindow {
+    /// Take the busy datum (if any), with the final switch count as tag.
+    fn take_busy(&mut self) -> Option<StatemapInputDatum<CpuState>> {
+        let mut busy = self.busy.take()?;
+        busy.tag = Some(format_compact!("{} switches", self.switches));
+        Some(busy)
+    }
+}
+
+/// What to do with a datum passed through the [`SwitchRateLimiter`].
+#[derive(Debug, Clone)]
+pub struct Filtered {
+    /// A finished busy datum to write before the current datum.
+    pub busy: Option<StatemapInputDatum<CpuState>>,
+    /// Whether the current datum should be dropped.
+    pub suppress: bool,
+}
+
+/// Limits the number of context switches written per CPU and millisecond.
+#[derive(Debug, Clone)]
+pub struct SwitchRateLimiter {
+    /// Maximum number of switches per window to write as is.
+    max_switches: u64,
+    cpus: Vec<CpuWindow>,
+}
+
+impl SwitchRateLimiter {
+    pub fn new(num_cpus: usize, max_switches: u64) -> Self {
+        Self {
+            max_switches,
+            cpus: vec![CpuWindow::default(); num_cpus],
+        }
+    }
+
+    /// Track `num_cpus` CPUs, if that is more than currently tracked.
+    pub fn resize(&mut self, num_cpus: usize) {
+        if num_cpus > self.cpus.len() {
+            self.cpus.resize(num_cpus, CpuWindow::default());
+        }
+    }
+
+    /// Pass a datum for `cpu` through the limiter. `time` is in nanoseconds
+    /// (while the datum time may be normalized).
+    pub fn filter(
+        &mut self,
+        cpu: usize,
+        time: u64,
+        is_switch: bool,
+        datum: &StatemapInputDatum<CpuState>,
+    ) -> Filtered {
+        let state = &mut self.cpus[cpu];
+        let window = time / NS_PER_MS;
+        let mut busy = None;
+        if window != state.window {
+            busy = state.take_busy();
+            state.window = window;
+            state.switches = 0;
+        }
+        if is_switch {
+            state.switches += 1;
+        }
+        if state.busy.is_none() && state.switches > self.max_switches {
+            state.busy = Some(StatemapInputDatum {
+                state: CpuState::Busy,
+                tag: None,
+                ..datum.clone()
+            });
+        }
+        Filtered {
+            busy,
+            suppress: state.busy.is_some(),
+        }
+    }
+
+    /// Busy datums for windows still open at the end of the trace.
+    pub fn finish(&mut self) -> impl Iterator<Item = StatemapInputDatum<CpuState>> + '_ {
+        self.cpus.iter_mut().filter_map(CpuWindow::take_busy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datum(time: u64) -> StatemapInputDatum<CpuState> {
+        StatemapInputDatum {
+            time,
+            entity: "0".into(),
+            state: CpuState::User,
+            tag: Some("a:1".into()),
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut limiter = SwitchRateLimiter::new(1, 2);
+        for (time, is_switch) in [(100, true), (200, true), (250, false)] {
+            // Non-switch events don't count towards the limit
+            let filtered = limiter.filter(0, time, is_switch, &datum(time));
+            assert!(!filtered.suppress);
+            assert!(filtered.busy.is_none());
+        }
+        for time in [300, 400, 500] {
+            let filtered = limiter.filter(0, time, true, &datum(time));
+            assert!(filtered.suppress);
+            assert!(filtered.busy.is_none());
+        }
+        assert!(limiter.filter(0, 600, false, &datum(600)).suppress);
+
+        let filtered = limiter.filter(0, 1_000_100, true, &datum(1_000_100));
+        assert!(!filtered.suppress);
+        let busy = filtered.busy.unwrap();
+        assert_eq!(busy.time, 300);
+        assert_eq!(busy.state, CpuState::Busy);
+        assert_eq!(busy.tag.as_deref(), Some("5 switches"));
+
+        for time in [1_000_200, 1_000_300] {
+            limiter.filter(0, time, true, &datum(time));
+        }
+        let open: Vec<_> = limiter.finish().collect();
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].time, 1_000_300);
+        assert_eq!(open[0].tag.as_deref(), Some("3 switches"));
+    }
+}
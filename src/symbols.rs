@@ -0,0 +1,98 @@
+//! Resolution of kernel addresses to symbol names.
+
+use compact_str::CompactString;
+use compact_str::format_compact;
+use eyre::Context;
+use eyre::eyre;
+use std::path::Path;
+
+/// Symbol table loaded from a file in `/proc/kallsyms` format.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KallsymsResolver {
+    /// Text symbols, sorted by address.
+    symbols: Vec<(u64, CompactString)>,
+}
+
+impl KallsymsResolver {
+    /// Parse `/proc/kallsyms` format: `<address> <type> <name> [<module>]`.
+    ///
+    /// Only text (function) symbols are kept, since the addresses we resolve
+    /// are function pointers.
+    pub fn parse(contents: &str) -> Self {
+        let mut symbols: Vec<_> = contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_ascii_whitespace();
+                let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+                let kind = fields.next()?;
+                let name = fields.next()?;
+                matches!(kind, "t" | "T" | "w" | "W").then(|| (addr, CompactString::from(name)))
+            })
+            .collect();
+        symbols.sort_by_key(|&(addr, _)| addr);
+        Self { symbols }
+    }
+
+    /// Load symbols from a file.
+    pub fn from_file(path: &Path) -> Result<Self, eyre::Error> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read \"{}\"", path.display()))?;
+        let resolver = Self::parse(&contents);
+        if resolver.symbols.iter().all(|&(addr, _)| addr == 0) {
+            return Err(eyre!(
+                "No usable symbols in \"{}\" (was it read with kptr_restrict in effect?)",
+                path.display()
+            ));
+        }
+        Ok(resolver)
+    }
+
+    /// Find the symbol containing `addr` and the start address of it.
+    fn lookup(&self, addr: u64) -> Option<(u64, &CompactString)> {
+        let idx = self.symbols.partition_point(|&(start, _)| start <= addr);
+        let (start, name) = self.symbols.get(idx.checked_sub(1)?)?;
+        Some((*start, name))
+    }
+
+    /// Get the name of the symbol containing `addr`.
+    #[allow(dead_code)]
+    pub fn resolve(&self, addr: u64) -> Option<&CompactString> {
+        self.lookup(addr).map(|(_, name)| name)
+    }
+
+    /// Format `addr` as `symbol+offset`, or as hex if it can't be resolved.
+    pub fn format(&self, addr: u64) -> CompactString {
+        match self.lookup(addr) {
+            Some((start, name)) => format_compact!("{name}+{:#x}", addr - start),
+            None => format_compact!("{addr:#x}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let resolver = KallsymsResolver::parse(indoc::indoc! {"
+            ffffffff81000000 T _stext
+            ffffffff81000100 t ksoftirqd_do_softirq
+            ffffffff81000080 D some_data
+            ffffffffc0000000 t e1000_clean\t[e1000e]
+        "});
+        assert_eq!(resolver.resolve(0xffffffff81000000).unwrap(), "_stext");
+        assert_eq!(resolver.resolve(0xffffffff81000090).unwrap(), "_stext");
+        assert_eq!(resolver.resolve(0xffffffffc0000010).unwrap(), "e1000_clean");
+        assert_eq!(resolver.resolve(0x1000), None);
+        assert_eq!(
+            resolver.format(0xffffffff81000100),
+            "ksoftirqd_do_softirq+0x0"
+        );
+        assert_eq!(
+            resolver.format(0xffffffff81000123),
+            "ksoftirqd_do_softirq+0x23"
+        );
+        assert_eq!(resolver.format(0x1000), "0x1000");
+    }
+}
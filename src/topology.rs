@@ -0,0 +1,223 @@
+//! CPU topology, either from the `CPU_TOPOLOGY` feature section of perf.data
+//! or from sysfs.
+
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+use eyre::Context;
+use linux_perf_data::Endianness;
+use linux_perf_data::linux_perf_event_reader::RawData;
+use std::path::Path;
+
+/// Topology information for a single CPU.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuInfo {
+    /// Physical package (socket) the CPU belongs to.
+    pub package_id: Option<u32>,
+    /// Core ID within the package.
+    pub core_id: Option<u32>,
+}
+
+/// Topology of all CPUs in a trace.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CpuTopology {
+    /// Per CPU topology information, indexed by CPU number.
+    pub cpus: Vec<CpuInfo>,
+    /// Sets of CPUs sharing a package.
+    pub core_siblings: Vec<Vec<u32>>,
+    /// Sets of CPUs sharing a core (SMT siblings).
+    pub thread_siblings: Vec<Vec<u32>>,
+}
+
+impl CpuTopology {
+    /// Parse the `CPU_TOPOLOGY` feature section.
+    pub fn parse(
+        data: RawData<'_>,
+        endian: Endianness,
+        nr_cpus: usize,
+    ) -> Result<Self, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::parse_impl::<LittleEndian>(data, nr_cpus),
+            Endianness::BigEndian => Self::parse_impl::<BigEndian>(data, nr_cpus),
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(
+        mut data: RawData<'_>,
+        nr_cpus: usize,
+    ) -> Result<Self, std::io::Error> {
+        let core_siblings = read_cpu_lists::<O>(&mut data)?;
+        let thread_siblings = read_cpu_lists::<O>(&mut data)?;
+        let mut cpus = vec![CpuInfo::default(); nr_cpus];
+        // Older versions of perf don't write the per CPU core and socket IDs.
+        if !data.is_empty() {
+            for cpu in &mut cpus {
+                cpu.core_id = Some(data.read_u32::<O>()?);
+                cpu.package_id = Some(data.read_u32::<O>()?);
+            }
+        } else {
+            for (package, siblings) in core_siblings.iter().enumerate() {
+                for &cpu in siblings {
+                    if let Some(info) = cpus.get_mut(cpu as usize) {
+                        info.package_id = Some(package as u32);
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            cpus,
+            core_siblings,
+            thread_siblings,
+        })
+    }
+
+    /// Read topology from `<sysroot>/sys/devices/system/cpu`.
+    pub fn from_sysroot(sysroot: &Path, nr_cpus: usize) -> Result<Self, eyre::Error> {
+        let base = sysroot.join("sys/devices/system/cpu");
+        let mut topology = Self::default();
+        for cpu in 0..nr_cpus {
+            let dir = base.join(format!("cpu{cpu}/topology"));
+            let read = |name: &str| {
+                let path = dir.join(name);
+                std::fs::read_to_string(&path)
+                    .wrap_err_with(|| format!("Failed to read \"{}\"", path.display()))
+            };
+            let read_id = |name: &str| -> Result<u32, eyre::Error> {
+                let value = read(name)?;
+                value
+                    .trim()
+                    .parse()
+                    .wrap_err_with(|| format!("Invalid {name} for CPU {cpu}: {value}"))
+            };
+            topology.cpus.push(CpuInfo {
+                package_id: Some(read_id("physical_package_id")?),
+                core_id: Some(read_id("core_id")?),
+            });
+            for (name, sets) in [
+                ("core_siblings_list", &mut topology.core_siblings),
+                ("thread_siblings_list", &mut topology.thread_siblings),
+            ] {
+                let siblings = parse_cpu_list(read(name)?.trim())?;
+                if !sets.contains(&siblings) {
+                    sets.push(siblings);
+                }
+            }
+        }
+        Ok(topology)
+    }
+
+    /// Load topology from perf.data if available, otherwise from sysfs under
+    /// `sysroot`.
+    pub fn load(
+        perf_file: &linux_perf_data::PerfFile,
+        sysroot: &Path,
+        nr_cpus: usize,
+    ) -> Result<Self, eyre::Error> {
+        match perf_file.feature_section_data(linux_perf_data::Feature::CPU_TOPOLOGY) {
+            Some(data) => Self::parse(RawData::Single(data), perf_file.endian(), nr_cpus)
+                .wrap_err("Failed to parse CPU_TOPOLOGY feature"),
+            None => {
+                log::warn!("No CPU_TOPOLOGY feature found in perf.data, reading sysfs instead");
+                Self::from_sysroot(sysroot, nr_cpus)
+            }
+        }
+    }
+
+    /// Get the package a CPU belongs to.
+    pub fn package_of(&self, cpu: usize) -> Option<u32> {
+        self.cpus.get(cpu).and_then(|info| info.package_id)
+    }
+}
+
+/// Read a list of `perf_header_string` CPU lists, prefixed by a count.
+fn read_cpu_lists<O: ByteOrder>(data: &mut RawData<'_>) -> Result<Vec<Vec<u32>>, std::io::Error> {
+    let count = data.read_u32::<O>()?;
+    (0..count)
+        .map(|_| {
+            let len = data.read_u32::<O>()? as usize;
+            let bytes = data.split_off_prefix(len)?.as_slice();
+            let nulbyte = memchr::memchr(0, &bytes).unwrap_or(bytes.len());
+            let list = std::str::from_utf8(&bytes[..nulbyte])
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+            parse_cpu_list(list)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+        })
+        .collect()
+}
+
+/// Parse a CPU list such as `0-3,8,10-11`.
+pub fn parse_cpu_list(list: &str) -> Result<Vec<u32>, eyre::Error> {
+    let mut cpus = Vec::new();
+    for part in list.split(',').filter(|part| !part.is_empty()) {
+        let parse = |s: &str| {
+            s.parse::<u32>()
+                .wrap_err_with(|| format!("Invalid CPU number \"{s}\" in CPU list \"{list}\""))
+        };
+        match part.split_once('-') {
+            Some((first, last)) => cpus.extend(parse(first)?..=parse(last)?),
+            None => cpus.push(parse(part)?),
+        }
+    }
+    Ok(cpus)
+}
+
+/// Number of CPUs the traced system can have, from
+/// `<sysroot>/sys/devices/system/cpu/possible`.
+pub fn possible_cpus(sysroot: &Path) -> Result<usize, eyre::Error> {
+    let path = sysroot.join("sys/devices/system/cpu/possible");
+    let list = std::fs::read_to_string(&path)
+        .wrap_err_with(|| format!("Failed to read \"{}\"", path.display()))?;
+    let cpus = parse_cpu_list(list.trim())?;
+    Ok(cpus.iter().max().map_or(0, |&cpu| cpu as usize + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11").unwrap(),
+            vec![0, 1, 2, 3, 8, 10, 11]
+        );
+        assert_eq!(parse_cpu_list("").unwrap(), Vec::<u32>::new());
+        assert!(parse_cpu_list("0-x").is_err());
+    }
+
+    #[test]
+    fn test_parse_topology() {
+        fn header_string(out: &mut Vec<u8>, s: &str) {
+            let mut bytes = s.as_bytes().to_vec();
+            bytes.resize(8, 0);
+            out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            out.extend_from_slice(&bytes);
+        }
+        let mut data = Vec::new();
+        data.extend_from_slice(&2u32.to_le_bytes());
+        header_string(&mut data, "0-1");
+        header_string(&mut data, "2-3");
+        data.extend_from_slice(&2u32.to_le_bytes());
+        header_string(&mut data, "0,2");
+        header_string(&mut data, "1,3");
+        let topology = CpuTopology::parse_impl::<LittleEndian>(RawData::Single(&data), 4).unwrap();
+        assert_eq!(topology.package_of(0), Some(0));
+        assert_eq!(topology.package_of(3), Some(1));
+        assert_eq!(topology.thread_siblings, vec![vec![0, 2], vec![1, 3]]);
+
+        // With explicit core and socket IDs
+        for (core, socket) in [(0u32, 1u32), (1, 1), (0, 0), (1, 0)] {
+            data.extend_from_slice(&core.to_le_bytes());
+            data.extend_from_slice(&socket.to_le_bytes());
+        }
+        let topology = CpuTopology::parse_impl::<LittleEndian>(RawData::Single(&data), 4).unwrap();
+        assert_eq!(topology.package_of(0), Some(1));
+        assert_eq!(
+            topology.cpus[3],
+            CpuInfo {
+                package_id: Some(0),
+                core_id: Some(1)
+            }
+        );
+    }
+}
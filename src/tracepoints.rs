@@ -1,12 +1,51 @@
 //! Support for tracepoint parsing and handling.
 
+use eyre::WrapErr;
+use std::collections::HashMap;
 use std::path::Path;
 
 pub mod format;
 pub mod irq;
 pub mod parser;
+pub mod power;
+pub mod printk_formats;
+pub mod rcu;
 pub mod sched;
 
+/// Names (such as "`sched:sched_switch`") of the tracepoints under
+/// `<sysroot>/sys/kernel/tracing/events`, by tracepoint ID.
+pub fn names_by_id(sysroot: &Path) -> Result<HashMap<u64, String>, eyre::Error> {
+    let events = sysroot.join("sys/kernel/tracing/events");
+    let read_dir = |path: &Path| {
+        std::fs::read_dir(path).wrap_err_with(|| format!("Failed to read \"{}\"", path.display()))
+    };
+    let mut names = HashMap::new();
+    for category in read_dir(&events)? {
+        let category = category?;
+        if !category.file_type()?.is_dir() {
+            continue;
+        }
+        for event in read_dir(&category.path())? {
+            let path = event?.path().join("id");
+            // Directories without an ID, and files such as `enable`.
+            let Ok(id) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            let id = id
+                .trim()
+                .parse()
+                .wrap_err_with(|| format!("Invalid tracepoint ID in \"{}\"", path.display()))?;
+            let category = category.file_name();
+            let name = path.parent().and_then(Path::file_name).unwrap_or_default();
+            names.insert(
+                id,
+                format!("{}:{}", category.to_string_lossy(), name.to_string_lossy()),
+            );
+        }
+    }
+    Ok(names)
+}
+
 /// Trait for tracepoint structs that can be parsed from a tracepoint format.
 pub trait Tracepoint {
     /// Name of the tracepoint this struct corresponds to. E.g.
@@ -25,18 +64,32 @@ pub trait Tracepoint {
     ///
     /// This needs read access to
     /// `<sysroot>/sys/kernel/tracing/events/<category>/<name>/format`
+    #[allow(dead_code)]
     fn parser_from_sysroot<P: AsRef<Path>>(path: P) -> Result<parser::FormatParser, eyre::Error>;
 
     /// Create a parser from the given tracepoint format file.
     #[allow(dead_code)]
     fn parser_from_file(path: &Path) -> Result<parser::FormatParser, eyre::Error>;
 
+    /// Create a parser from the contents of a tracepoint format file, such
+    /// as one embedded in the `TRACING_DATA` of a perf.data file.
+    #[allow(dead_code)]
+    fn parser_from_string(format_str: &str) -> Result<parser::FormatParser, eyre::Error>;
+
     /// Create a parser matching this struct for the given dynamic tracepoint
     /// format.
     fn parser_from_format(
         format: &format::TracepointFormat,
     ) -> Result<parser::FormatParser, eyre::Error>;
 
+    /// Check that the fields of this struct match the given tracepoint
+    /// format, returning every difference found. `long_size` is the size of
+    /// `long` on the traced system, if known.
+    fn validate_format(
+        format: &format::TracepointFormat,
+        long_size: Option<u32>,
+    ) -> Result<(), Vec<parser::SchemaError>>;
+
     /// Parse raw data using this struct
     fn parse<O: byteorder::ByteOrder>(
         format: &parser::FormatParser,
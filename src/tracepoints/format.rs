@@ -13,16 +13,54 @@ pub enum TracepointFormatError {
     /// IO error when reading tracepoint format file.
     #[error("Failed to read tracepoint format file: {0}")]
     IoError(#[from] std::io::Error),
+    /// Parse error on a line of the tracepoint format (numbered from 1).
+    #[error("Failed to parse tracepoint format on line {number}: {message}: {line:?}")]
+    AtLine {
+        number: usize,
+        line: String,
+        message: String,
+    },
+}
+
+impl TracepointFormatError {
+    /// Attach the line a parse error is on.
+    fn at_line(self, number: usize, line: &str) -> Self {
+        match self {
+            Self::ParseError(message) => Self::AtLine {
+                number,
+                line: line.to_string(),
+                message,
+            },
+            err => err,
+        }
+    }
+}
+
+/// All the errors found when parsing a tracepoint format.
+#[derive(Debug, thiserror::Error)]
+pub struct TracepointFormatErrors(pub Vec<TracepointFormatError>);
+
+impl std::fmt::Display for TracepointFormatErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.as_slice() {
+            [err] => write!(f, "{err}"),
+            errors => {
+                write!(f, "{} errors in tracepoint format:", errors.len())?;
+                for err in errors {
+                    write!(f, "\n  {err}")?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
 /// Represents a tracepoint format.
 #[derive(Debug, Clone)]
 pub struct TracepointFormat {
     /// The name of the tracepoint, e.g. `sched_switch`.
-    #[allow(dead_code)]
     pub name: CompactString,
     /// The ID of the tracepoint.
-    #[allow(dead_code)]
     pub id: u32,
     /// The print format string for the tracepoint.
     #[allow(dead_code)]
@@ -32,7 +70,9 @@ pub struct TracepointFormat {
 }
 
 impl TracepointFormat {
-    pub fn parse(lines: &str) -> Result<Self, TracepointFormatError> {
+    /// Parse a format file. Parsing continues past errors, so that all of
+    /// them can be reported at once.
+    pub fn parse(lines: &str) -> Result<Self, TracepointFormatErrors> {
         #[derive(PartialEq, Eq)]
         enum Mode {
             Normal,
@@ -44,37 +84,42 @@ impl TracepointFormat {
         let mut tp_name = CompactString::default();
         let mut id = 0;
         let mut print_fmt = String::new();
+        let mut errors = Vec::new();
 
-        for line in lines.split('\n') {
+        for (idx, line) in lines.split('\n').enumerate() {
+            let mut error = |message: &str| {
+                errors.push(
+                    TracepointFormatError::ParseError(message.to_string()).at_line(idx + 1, line),
+                );
+            };
             if line.is_empty() {
                 continue; // Skip empty lines
             }
             if mode == Mode::Format {
                 if line.as_bytes()[0] == b'\t' {
-                    let field = TracepointField::parse(line)?;
-                    if let Some(field) = field {
-                        fields.push(field);
+                    match TracepointField::parse(line) {
+                        Ok(Some(field)) => fields.push(field),
+                        Ok(None) => {}
+                        Err(err) => errors.push(err.at_line(idx + 1, line)),
                     }
                     continue;
                 }
                 mode = Mode::Normal; // End of format section
             }
 
-            let (name, value) = line.split_once(":").ok_or_else(|| {
-                TracepointFormatError::ParseError(
-                    "Tracepoint format line does not contain a colon".to_string(),
-                )
-            })?;
+            let Some((name, value)) = line.split_once(":") else {
+                error("Tracepoint format line does not contain a colon");
+                continue;
+            };
             let value = value.strip_prefix(" ").unwrap_or(value); // Remove leading space
             match name {
                 "name" => {
                     tp_name.push_str(value);
                 }
-                "ID" => {
-                    id = value.parse::<u32>().map_err(|_| {
-                        TracepointFormatError::ParseError("Invalid ID value".to_string())
-                    })?;
-                }
+                "ID" => match value.parse::<u32>() {
+                    Ok(value) => id = value,
+                    Err(_) => error("Invalid ID value"),
+                },
                 "format" => {
                     mode = Mode::Format; // Switch to format mode
                     continue;
@@ -83,13 +128,12 @@ impl TracepointFormat {
                     // Store the print format
                     print_fmt.push_str(value);
                 }
-                _ => {
-                    return Err(TracepointFormatError::ParseError(format!(
-                        "Unknown tracepoint key: {name}"
-                    )));
-                }
+                _ => error(&format!("Unknown tracepoint key: {name}")),
             }
         }
+        if !errors.is_empty() {
+            return Err(TracepointFormatErrors(errors));
+        }
         Ok(Self {
             name: tp_name,
             id,
@@ -97,6 +141,15 @@ impl TracepointFormat {
             fields,
         })
     }
+
+    /// Fields typed as `long` or pointers that aren't `long_size` bytes,
+    /// which means the format is from a system with a different
+    /// architecture.
+    pub fn long_size_mismatches(&self, long_size: u32) -> impl Iterator<Item = &TracepointField> {
+        self.fields
+            .iter()
+            .filter(move |field| field.is_long_sized() && field.size != long_size)
+    }
 }
 
 /// Represents the type of an array in a tracepoint format.
@@ -117,6 +170,18 @@ pub enum TracepointArrayType {
     // length is strlen. I have not yet observed these in practice.
 }
 
+impl TracepointArrayType {
+    /// Name of the array type, for error messages.
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            Self::None => "None",
+            Self::Fixed => "Fixed",
+            Self::Trailing => "Trailing",
+            Self::DataLoc4 => "DataLoc4",
+        }
+    }
+}
+
 /// Represents a field in a tracepoint format.
 #[derive(Debug, Clone)]
 pub struct TracepointField {
@@ -135,16 +200,104 @@ pub struct TracepointField {
     pub array_type: TracepointArrayType,
 }
 
+/// Concise summary of a tracepoint format, for debugging format
+/// compatibility issues.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TracepointSummary {
+    pub name: String,
+    pub id: u32,
+    /// Name, offset, size, signedness and expected Rust type (see
+    /// [`TracepointField::expected_rust_type`]) of each field.
+    pub fields: Vec<(String, u32, u32, bool, &'static str)>,
+}
+
+impl From<&TracepointFormat> for TracepointSummary {
+    fn from(format: &TracepointFormat) -> Self {
+        Self {
+            name: format.name.to_string(),
+            id: format.id,
+            fields: format
+                .fields
+                .iter()
+                .map(|field| {
+                    (
+                        field.field_name.to_string(),
+                        field.offset,
+                        field.size,
+                        field.signed,
+                        field.expected_rust_type(),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for TracepointSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (ID {})", self.name, self.id)?;
+        let width = self.fields.iter().map(|field| field.0.len()).max();
+        for (name, offset, size, signed, rust_type) in &self.fields {
+            write!(
+                f,
+                "\n  {name:width$}  off={offset}  size={size}  signed={signed}  type={rust_type}",
+                width = width.unwrap_or_default()
+            )?;
+        }
+        Ok(())
+    }
+}
+
 static FIXED_REGEX: std::sync::LazyLock<regex::Regex> = std::sync::LazyLock::new(|| {
     regex::Regex::new(r"\[[0-9]+\]$").expect("Failed to compile regex")
 });
 
 impl TracepointField {
-    fn parse(line: &str) -> Result<Option<Self>, TracepointFormatError> {
+    /// Whether the C type is `long` or a pointer, whose size depends on the
+    /// architecture.
+    pub fn is_long_sized(&self) -> bool {
+        let ty = self.field_type.trim();
+        if ty.ends_with('*') {
+            return true;
+        }
+        let ty = ty
+            .strip_prefix("unsigned ")
+            .or_else(|| ty.strip_prefix("signed "))
+            .unwrap_or(ty);
+        matches!(ty, "long" | "long int")
+    }
+
+    /// The Rust type that matches the C type of the field: a sized integer
+    /// for scalars, otherwise the raw bytes.
+    pub fn expected_rust_type(&self) -> &'static str {
+        if self.array_type != TracepointArrayType::None {
+            return "&[u8]";
+        }
+        match (self.size, self.signed) {
+            (1, false) => "u8",
+            (1, true) => "i8",
+            (2, false) => "u16",
+            (2, true) => "i16",
+            (4, false) => "u32",
+            (4, true) => "i32",
+            (8, false) => "u64",
+            (8, true) => "i64",
+            _ => "&[u8]",
+        }
+    }
+
+    /// Parse a `field:` line of a format file. Returns `None` for lines
+    /// that aren't field definitions.
+    pub fn parse(line: &str) -> Result<Option<Self>, TracepointFormatError> {
         if line.is_empty() {
             return Ok(None);
         }
-        let parts: SmallVec<[&str; 4]> = line[1..].split('\t').collect();
+        let line = line.strip_prefix('\t').ok_or_else(|| {
+            TracepointFormatError::ParseError(
+                "Field definition does not start with a tab".to_string(),
+            )
+        })?;
+        let parts: SmallVec<[&str; 4]> = line.split('\t').collect();
         if parts.len() != 4 {
             return Err(TracepointFormatError::ParseError(format!(
                 "Invalid tracepoint field format (expected 4 fields, got {})",
@@ -264,6 +417,7 @@ mod tests {
         "};
         let format = TracepointFormat::parse(input);
         insta::assert_debug_snapshot!(format);
+        insta::assert_snapshot!(TracepointSummary::from(&format.unwrap()));
     }
 
     #[test]
@@ -314,4 +468,49 @@ mod tests {
         assert_eq!(field.size, 0);
         assert!(!field.signed);
     }
+
+    #[test]
+    fn test_tracepoint_format_errors() {
+        let input = indoc::indoc! {"
+        name: broken
+        ID: x
+        format:
+        \tfield:int common_pid;\toffset:4;\tsize:4;\tsigned:1;
+        \tfield:int a;\toffset:8;\tsize:four;\tsigned:1;
+        \tfield:int b;\toffset:12;\tsize:4;
+        colour: blue
+        "};
+        let errors = TracepointFormat::parse(input).unwrap_err();
+        assert_eq!(errors.0.len(), 4);
+        insta::assert_snapshot!(errors);
+    }
+
+    #[test]
+    fn test_long_size_mismatches() {
+        // A 32-bit format: longs and pointers are 4 bytes.
+        let input = indoc::indoc! {"
+        name: softirq_raise
+        ID: 12
+        format:
+        \tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;
+        \tfield:unsigned long ip;\toffset:4;\tsize:4;\tsigned:0;
+        \tfield:void * data;\toffset:8;\tsize:4;\tsigned:0;
+        \tfield:long args[2];\toffset:12;\tsize:8;\tsigned:1;
+        \tfield:u64 ts;\toffset:20;\tsize:8;\tsigned:0;
+        "};
+        let format = TracepointFormat::parse(input).unwrap();
+        let long_sized: Vec<_> = format
+            .fields
+            .iter()
+            .filter(|field| field.is_long_sized())
+            .map(|field| field.field_name.as_str())
+            .collect();
+        assert_eq!(long_sized, ["ip", "data"]);
+        assert_eq!(format.long_size_mismatches(4).count(), 0);
+        let mismatches: Vec<_> = format
+            .long_size_mismatches(8)
+            .map(|field| field.field_name.as_str())
+            .collect();
+        assert_eq!(mismatches, ["ip", "data"]);
+    }
 }
@@ -19,17 +19,24 @@ tracepoint_parser!(
     }
 );
 
+tracepoint_parser!(
+    #[event_name("irq:softirq_raise")]
+    pub struct SoftirqRaise {
+        vec: u32,
+    }
+);
+
 tracepoint_parser!(
     #[event_name("irq:softirq_entry")]
     pub struct SoftirqEntry {
-        vec: i32,
+        vec: u32,
     }
 );
 
 tracepoint_parser!(
     #[event_name("irq:softirq_exit")]
     pub struct SoftirqExit {
-        vec: i32,
+        vec: u32,
     }
 );
 
@@ -48,3 +55,19 @@ tracepoint_parser!(
         func: u64,
     }
 );
+
+tracepoint_parser!(
+    #[event_name("irq:tasklet_hi_entry")]
+    pub struct TaskletHiEntry {
+        tasklet: u64,
+        func: u64,
+    }
+);
+
+tracepoint_parser!(
+    #[event_name("irq:tasklet_hi_exit")]
+    pub struct TaskletHiExit {
+        tasklet: u64,
+        func: u64,
+    }
+);
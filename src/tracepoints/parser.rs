@@ -1,22 +1,49 @@
 //! Code taking a [`super::format::TracepointFormat`] and making a parser
 //! to a specific tracepoint struct.
 
+use crate::tracepoints::Tracepoint;
 use crate::tracepoints::format::TracepointArrayType;
 use crate::tracepoints::format::TracepointField;
 use crate::tracepoints::format::TracepointFormat;
+use crate::tracepoints::format::TracepointSummary;
 use byteorder::ByteOrder;
 use compact_str::CompactString;
 use eyre::Context;
 use linux_perf_data::linux_perf_event_reader::RawData;
 use pastey::paste;
 use std::borrow::Cow;
+use std::cell::OnceCell;
 use std::collections::HashMap;
+use std::fmt::Write;
 use std::path::Path;
+use std::path::PathBuf;
+use std::time::Instant;
 
 /// Struct for applying parsing operations based on a tracepoint format.
 #[derive(Debug, Clone)]
 pub struct FormatParser {
     ops: Vec<ParseOp>,
+    /// Lookup from field name to index in `ops`, for the `_by_name` API.
+    by_name: HashMap<CompactString, usize>,
+}
+
+/// Convert a process name (or other string from the kernel) to a string.
+/// Names that aren't valid UTF-8 are shown as hex, like `<deadbeef>`, since
+/// replacing the invalid bytes would make different names look the same.
+pub fn format_comm_name(bytes: &[u8]) -> CompactString {
+    match std::str::from_utf8(bytes) {
+        Ok(name) => CompactString::from(name),
+        Err(_) => {
+            let mut hex = CompactString::with_capacity(bytes.len() * 2 + 2);
+            hex.push('<');
+            for byte in bytes {
+                // Writing to a string can't fail.
+                let _ = write!(hex, "{byte:02x}");
+            }
+            hex.push('>');
+            hex
+        }
+    }
 }
 
 /// Parser for a scalar type
@@ -30,15 +57,26 @@ macro_rules! scalar_parser {
                 record: &RawData<'_>,
             ) -> Result<$ty, std::io::Error> {
                 let op = &self.ops[index];
+                // Signed types must be parsed from signed fields. Unsigned types
+                // are also used for pointers, which the kernel marks as unsigned.
                 debug_assert!(
                     op.size as usize == size_of::<$ty>()
                         && op.array_type == TracepointArrayType::None
-                        && op.signed,
+                        && (<$ty>::MIN == 0 || op.signed),
                     "Tracepoint format mismatch"
                 );
                 let data = op.get_bytes(record)?;
                 Ok(O::[<read_ $ty>](data.as_ref()))
             }
+
+            /// Parse a field by name, for fields not known until runtime.
+            pub fn [<parse_ $ty _by_name>]<O: ByteOrder>(
+                &self,
+                name: &str,
+                record: &RawData<'_>,
+            ) -> Result<$ty, std::io::Error> {
+                self.[<parse_ $ty>]::<O>(self.index_of(name)?, record)
+            }
         }
     };
 }
@@ -54,16 +92,65 @@ macro_rules! byte_parser {
                 record: &RawData<'_>,
             ) -> Result<$ty, std::io::Error> {
                 let op = &self.ops[index];
+                // Signed types must be parsed from signed fields. Unsigned types
+                // are also used for pointers, which the kernel marks as unsigned.
                 debug_assert!(
                     op.size as usize == size_of::<$ty>()
                         && op.array_type == TracepointArrayType::None
-                        && op.signed,
+                        && (<$ty>::MIN == 0 || op.signed),
                     "Tracepoint format mismatch"
                 );
                 let data = op.get_bytes(record)?;
                 #[allow(trivial_numeric_casts)]
                 Ok(data[0] as $ty)
             }
+
+            /// Parse a field by name, for fields not known until runtime.
+            pub fn [<parse_ $ty _by_name>](
+                &self,
+                name: &str,
+                record: &RawData<'_>,
+            ) -> Result<$ty, std::io::Error> {
+                self.[<parse_ $ty>](self.index_of(name)?, record)
+            }
+        }
+    };
+}
+
+/// Parser for i64 and u64, which are also used for `long` and pointer
+/// fields. On 32-bit systems those are 4 bytes, and are extended to 64 bits
+/// according to the signedness of the field.
+macro_rules! long_parser {
+    ($ty: tt) => {
+        paste! {
+            pub fn [<parse_ $ty>]<O: ByteOrder>(
+                &self,
+                index: usize,
+                record: &RawData<'_>,
+            ) -> Result<$ty, std::io::Error> {
+                let op = &self.ops[index];
+                debug_assert!(
+                    (op.size == 4 || op.size as usize == size_of::<$ty>())
+                        && op.array_type == TracepointArrayType::None
+                        && (<$ty>::MIN == 0 || op.signed),
+                    "Tracepoint format mismatch"
+                );
+                let data = op.get_bytes(record)?;
+                Ok(match (op.size, op.signed) {
+                    (4, true) => O::read_i32(data.as_ref()) as $ty,
+                    (4, false) => O::read_u32(data.as_ref()) as $ty,
+                    _ => O::[<read_ $ty>](data.as_ref()),
+                })
+            }
+
+            /// Parse a field by name, for fields not known until runtime.
+            pub fn [<parse_ $ty _by_name>]<O: ByteOrder>(
+                &self,
+                name: &str,
+                record: &RawData<'_>,
+            ) -> Result<$ty, std::io::Error> {
+                self.[<parse_ $ty>]::<O>(self.index_of(name)?, record)
+            }
         }
     };
 }
@@ -81,9 +168,51 @@ impl FormatParser {
 
     scalar_parser!(u32);
 
-    scalar_parser!(i64);
+    long_parser!(i64);
+
+    long_parser!(u64);
 
-    scalar_parser!(u64);
+    /// Number of fields this parser parses.
+    pub fn num_fields(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Look up the index of a field by name, for use with the index based
+    /// API.
+    pub fn index_of(&self, name: &str) -> Result<usize, std::io::Error> {
+        self.by_name.get(name).copied().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Field not in parser: {name}"),
+            )
+        })
+    }
+
+    /// Look up the indices of several fields, see [`Self::subset`].
+    pub fn indices_for_names(&self, names: &[&str]) -> Result<Vec<usize>, std::io::Error> {
+        names.iter().map(|name| self.index_of(name)).collect()
+    }
+
+    /// Create a parser for some of the fields of this one, in the order
+    /// given. The offsets are kept, so it parses the same records.
+    ///
+    /// Panics if an index is out of range.
+    pub fn subset(&self, indices: &[usize]) -> Self {
+        Self::from_ops(indices.iter().map(|&idx| self.ops[idx].clone()).collect())
+    }
+
+    /// The fields this parser extracts, in index order.
+    pub fn iter_ops(&self) -> impl Iterator<Item = (&str, ParseOpSummary)> {
+        self.ops.iter().map(|op| {
+            let summary = ParseOpSummary {
+                offset: op.offset,
+                size: op.size,
+                signed: op.signed,
+                array_type: op.array_type,
+            };
+            (op.name.as_str(), summary)
+        })
+    }
 
     #[allow(dead_code)]
     pub fn parse_string<O: ByteOrder>(
@@ -110,7 +239,14 @@ impl FormatParser {
         // Convert the C-style string to a Rust string
         let nulbyte = memchr::memchr(0, data).unwrap_or(data.len());
         let data = &data[..nulbyte];
-        Ok(CompactString::from_utf8_lossy(data))
+        // Process names are almost always ASCII, and checking for that is
+        // cheaper than full UTF-8 validation.
+        if data.is_ascii() {
+            // SAFETY: ASCII is valid UTF-8.
+            Ok(unsafe { CompactString::from_utf8_unchecked(data) })
+        } else {
+            Ok(format_comm_name(data))
+        }
     }
 
     pub fn parse_array<'data, O: ByteOrder>(
@@ -132,38 +268,127 @@ impl FormatParser {
                 let ptr = ptr & 0xFFFF;
                 Ok(record
                     .get(ptr as usize..(ptr + len) as usize)
-                    .ok_or_else(|| {
-                        std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Not enough data")
-                    })?
+                    .ok_or_else(|| op.not_enough_data(ptr, record.len()))?
                     .as_slice())
             }
         }
     }
 
     /// Create a parser from the given tracepoint format file
-    pub fn new(fields: &[TracepointField], names: &[&str]) -> Result<Self, eyre::Error> {
-        let mapping: HashMap<&str, &TracepointField> = fields
+    pub fn new(format: &TracepointFormat, names: &[&str]) -> Result<Self, eyre::Error> {
+        Self::new_from_iter(format, names.iter().copied())
+    }
+
+    /// Create a parser for the named fields, in the order given.
+    pub fn new_from_iter<'a>(
+        format: &TracepointFormat,
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, eyre::Error> {
+        let mapping: HashMap<&str, &TracepointField> = format
+            .fields
             .iter()
             .map(|field| (field.field_name.as_str(), field))
             .collect();
 
-        let ops = names
-            .iter()
+        let specs = names
+            .into_iter()
             .map(|name| {
                 mapping
                     .get(name)
-                    .ok_or_else(|| eyre::eyre!("Missing field: {}", name))
-                    .map(|field| ParseOp::from(*field))
+                    .ok_or_else(|| {
+                        let available: Vec<_> = format
+                            .fields
+                            .iter()
+                            .map(|field| field.field_name.as_str())
+                            .collect();
+                        eyre::eyre!(
+                            "Field '{name}' not found in format '{}' (available: {})",
+                            format.name,
+                            available.join(", ")
+                        )
+                    })
+                    .map(|field| ParseSpec::from(*field))
             })
             .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::from_specs(specs))
+    }
+
+    /// Create a parser for every field in the format, in format order. Useful
+    /// for debugging.
+    #[allow(dead_code)]
+    pub fn new_all(format: &TracepointFormat) -> Self {
+        Self::from_specs(format.fields.iter().map(ParseSpec::from).collect())
+    }
+
+    /// Create a parser for fields described without a [`TracepointFormat`],
+    /// such as from known kernel ABI layouts, in the order given.
+    pub fn from_specs(specs: Vec<ParseSpec>) -> Self {
+        Self::from_ops(specs.into_iter().map(ParseOp::from).collect())
+    }
 
-        Ok(Self { ops })
+    fn from_ops(ops: Vec<ParseOp>) -> Self {
+        let by_name = ops
+            .iter()
+            .enumerate()
+            .map(|(idx, op)| (op.name.clone(), idx))
+            .collect();
+        Self { ops, by_name }
     }
 }
 
+/// Description of a field to parse, for creating a [`FormatParser`] with
+/// [`FormatParser::from_specs`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseSpec {
+    pub name: CompactString,
+    /// The offset in bytes from the start of the record
+    pub offset: u32,
+    /// The size of the field in bytes
+    pub size: u32,
+    pub signed: bool,
+    pub array_type: TracepointArrayType,
+}
+
+impl From<TracepointField> for ParseSpec {
+    fn from(field: TracepointField) -> Self {
+        Self {
+            name: field.field_name,
+            offset: field.offset,
+            size: field.size,
+            signed: field.signed,
+            array_type: field.array_type,
+        }
+    }
+}
+
+impl From<&TracepointField> for ParseSpec {
+    fn from(field: &TracepointField) -> Self {
+        Self {
+            name: field.field_name.clone(),
+            offset: field.offset,
+            size: field.size,
+            signed: field.signed,
+            array_type: field.array_type,
+        }
+    }
+}
+
+/// Description of what a [`FormatParser`] extracts for a field, see
+/// [`FormatParser::iter_ops`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOpSummary {
+    /// The offset in bytes from the start of the record
+    pub offset: u32,
+    /// The size of the field in bytes
+    pub size: u32,
+    pub signed: bool,
+    pub array_type: TracepointArrayType,
+}
+
 /// A parsing operation for a tracepoint field.
 #[derive(Debug, Clone)]
 struct ParseOp {
+    name: CompactString,
     offset: u32,
     size: u32,
     signed: bool,
@@ -177,9 +402,7 @@ impl ParseOp {
     ) -> Result<Cow<'data, [u8]>, std::io::Error> {
         let data = record
             .get(self.offset as usize..(self.offset + self.size) as usize)
-            .ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Not enough data")
-            })?
+            .ok_or_else(|| self.not_enough_data(self.offset, record.len()))?
             .as_slice();
         Ok(data)
     }
@@ -191,36 +414,232 @@ impl ParseOp {
     ) -> Result<Cow<'data, [u8]>, std::io::Error> {
         let data = record
             .get(self.offset as usize..self.offset as usize + length)
-            .ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Not enough data")
-            })?
+            .ok_or_else(|| self.not_enough_data(self.offset, record.len()))?
             .as_slice();
         Ok(data)
     }
+
+    /// Error for a record too short for the field, whose data is at `offset`.
+    ///
+    /// Kept out of line, as this is only hit for corrupt records and the
+    /// callers are on the hot path of parsing every sample.
+    #[cold]
+    #[inline(never)]
+    fn not_enough_data(&self, offset: u32, record_len: usize) -> std::io::Error {
+        let field = match self.array_type {
+            TracepointArrayType::None => format!("field '{}'", self.name),
+            array_type => format!("{} array field '{}'", array_type.display_name(), self.name),
+        };
+        std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!(
+                "Not enough data reading {field} at offset {offset} in {record_len}-byte record"
+            ),
+        )
+    }
 }
 
-impl From<TracepointField> for ParseOp {
-    fn from(field: TracepointField) -> Self {
+impl From<ParseSpec> for ParseOp {
+    fn from(spec: ParseSpec) -> Self {
         Self {
-            offset: field.offset,
-            size: field.size,
-            signed: field.signed,
-            array_type: field.array_type,
+            name: spec.name,
+            offset: spec.offset,
+            size: spec.size,
+            signed: spec.signed,
+            array_type: spec.array_type,
         }
     }
 }
 
-impl From<&TracepointField> for ParseOp {
-    fn from(field: &TracepointField) -> Self {
-        Self {
-            offset: field.offset,
-            size: field.size,
-            signed: field.signed,
-            array_type: field.array_type,
+/// The kind of value a tracepoint struct field or format field holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    /// A scalar integer of the given size in bytes.
+    Integer { size: u32, signed: bool },
+    /// A string, stored as any kind of array.
+    String,
+}
+
+impl FieldKind {
+    /// The kind of a field in a tracepoint format.
+    pub fn of(field: &TracepointField) -> Self {
+        match field.array_type {
+            TracepointArrayType::None => Self::Integer {
+                size: field.size,
+                signed: field.signed,
+            },
+            _ => Self::String,
         }
     }
 }
 
+impl std::fmt::Display for FieldKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Integer { size, signed } => {
+                write!(f, "{}{}", if *signed { 'i' } else { 'u' }, size * 8)
+            }
+            Self::String => f.write_str("string"),
+        }
+    }
+}
+
+/// Rust types that can be used as fields in [`tracepoint_parser!`] structs.
+pub trait FieldType {
+    const KIND: FieldKind;
+}
+
+macro_rules! integer_field_type {
+    ($($ty: ty),*) => {
+        $(
+            impl FieldType for $ty {
+                const KIND: FieldKind = FieldKind::Integer {
+                    size: size_of::<$ty>() as u32,
+                    signed: <$ty>::MIN != 0,
+                };
+            }
+        )*
+    };
+}
+
+integer_field_type!(i8, u8, i16, u16, i32, u32, i64, u64);
+
+impl FieldType for CompactString {
+    const KIND: FieldKind = FieldKind::String;
+}
+
+/// A field of a tracepoint struct, as expected in the format.
+#[derive(Debug, Clone, Copy)]
+pub struct FieldSpec {
+    pub name: &'static str,
+    pub kind: FieldKind,
+}
+
+/// A difference between a tracepoint struct and the format of the
+/// tracepoint on the traced system.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SchemaError {
+    /// The format doesn't have the field.
+    #[error("Field {field} is missing")]
+    Missing { field: &'static str },
+    /// The field has a different type in the format.
+    #[error(
+        "Field {field} is {actual}, expected {expected}{}",
+        rust_type_hint(*actual, rust_type)
+    )]
+    Mismatch {
+        field: &'static str,
+        expected: FieldKind,
+        actual: FieldKind,
+        /// See [`TracepointField::expected_rust_type`].
+        rust_type: &'static str,
+    },
+}
+
+/// Hint at the Rust type to parse a field as, when it isn't obvious from the
+/// field kind.
+fn rust_type_hint(actual: FieldKind, rust_type: &str) -> String {
+    if actual.to_string() == rust_type {
+        String::new()
+    } else {
+        format!(" (parse it as {rust_type})")
+    }
+}
+
+impl SchemaError {
+    /// Whether the field can't be parsed at all. Kernel formats are not
+    /// consistent about signedness (pointers are unsigned, `pid_t` is signed,
+    /// ...), so a difference in only that is harmless.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Self::Missing { .. } => true,
+            Self::Mismatch {
+                expected: FieldKind::Integer { size: expected, .. },
+                actual: FieldKind::Integer { size: actual, .. },
+                ..
+            } => expected != actual,
+            Self::Mismatch { .. } => true,
+        }
+    }
+}
+
+/// Whether `field` is a 4-byte `long` or pointer, which is parsed into a
+/// 64-bit struct field of `kind` by extending it. That is only done if
+/// longs are 4 bytes on the traced system, or that isn't known.
+fn is_narrow_long(field: &TracepointField, kind: FieldKind, long_size: Option<u32>) -> bool {
+    field.is_long_sized()
+        && field.size == 4
+        && field.array_type == TracepointArrayType::None
+        && long_size.is_none_or(|size| size == 4)
+        && matches!(kind, FieldKind::Integer { size: 8, .. })
+}
+
+#[doc(hidden)]
+pub fn validate_fields(
+    fields: &[TracepointField],
+    specs: &[FieldSpec],
+    long_size: Option<u32>,
+) -> Result<(), Vec<SchemaError>> {
+    let errors: Vec<_> = specs
+        .iter()
+        .filter_map(|spec| {
+            let Some(field) = fields.iter().find(|field| field.field_name == spec.name) else {
+                return Some(SchemaError::Missing { field: spec.name });
+            };
+            let actual = FieldKind::of(field);
+            if is_narrow_long(field, spec.kind, long_size) {
+                return None;
+            }
+            (actual != spec.kind).then(|| SchemaError::Mismatch {
+                field: spec.name,
+                expected: spec.kind,
+                actual,
+                rust_type: field.expected_rust_type(),
+            })
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Check the result of [`Tracepoint::validate_format`], failing if any
+/// field can't be parsed and warning about harmless differences.
+fn check_schema(event_name: &str, result: Result<(), Vec<SchemaError>>) -> Result<(), eyre::Error> {
+    let Err(errors) = result else {
+        return Ok(());
+    };
+    if errors.iter().any(SchemaError::is_fatal) {
+        let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+        return Err(eyre::eyre!(
+            "Tracepoint {event_name} has an incompatible format:\n  {}",
+            errors.join("\n  ")
+        ));
+    }
+    for error in errors {
+        log::warn!("Tracepoint {event_name}: {error}");
+    }
+    Ok(())
+}
+
+/// Warn about `long` and pointer fields whose size doesn't match the traced
+/// system, which means the format file is likely from another system.
+fn check_long_size(event_name: &str, format: &TracepointFormat, long_size: u32) {
+    for field in format.long_size_mismatches(long_size) {
+        log::warn!(
+            "Tracepoint {event_name}: field {} ({}) is {} bytes, but longs and pointers are \
+             {long_size} bytes on the traced system. The format may be from a different \
+             system, consider setting --sysroot (or --arch if the recorded \
+             architecture is wrong)",
+            field.field_name,
+            field.field_type,
+            field.size
+        );
+    }
+}
+
 #[doc(hidden)]
 #[allow(dead_code)]
 pub fn make_parser_from_system(
@@ -231,26 +650,28 @@ pub fn make_parser_from_system(
         .split_once(':')
         .ok_or_else(|| eyre::eyre!("Invalid event name: {}", event_name))?;
     let path = format!("/sys/kernel/tracing/events/{cat}/{name}/format");
-    make_parser_from_file(Path::new(&path), parser_from_format)
+    make_parser_from_file(event_name, Path::new(&path), parser_from_format)
 }
 
 #[doc(hidden)]
 pub fn make_parser_from_sysroot(
     event_name: &str,
     sysroot_path: &Path,
-    parser_from_format: fn(&TracepointFormat) -> Result<FormatParser, eyre::Error>,
+    parser_from_format: impl FnOnce(&TracepointFormat) -> Result<FormatParser, eyre::Error>,
 ) -> Result<FormatParser, eyre::Error> {
     let (cat, name) = event_name
         .split_once(':')
         .ok_or_else(|| eyre::eyre!("Invalid event name: {}", event_name))?;
-    let path = format!("/sys/kernel/tracing/events/{cat}/{name}/format");
-    make_parser_from_file(&sysroot_path.join(path), parser_from_format)
+    // Relative, since joining an absolute path replaces the sysroot.
+    let path = format!("sys/kernel/tracing/events/{cat}/{name}/format");
+    make_parser_from_file(event_name, &sysroot_path.join(path), parser_from_format)
 }
 
 #[doc(hidden)]
 pub fn make_parser_from_file(
+    event_name: &str,
     path: &Path,
-    parser_from_format: fn(&TracepointFormat) -> Result<FormatParser, eyre::Error>,
+    parser_from_format: impl FnOnce(&TracepointFormat) -> Result<FormatParser, eyre::Error>,
 ) -> Result<FormatParser, eyre::Error> {
     let data = std::fs::read_to_string(path).wrap_err_with(|| {
         format!(
@@ -258,10 +679,97 @@ pub fn make_parser_from_file(
             path.display()
         )
     })?;
-    let format = TracepointFormat::parse(&data)?;
+    let context = || {
+        format!(
+            "While building parser for {event_name} from {}",
+            path.display()
+        )
+    };
+    make_parser_from_string(&data, parser_from_format).wrap_err_with(context)
+}
+
+#[doc(hidden)]
+pub fn make_parser_from_string(
+    format_str: &str,
+    parser_from_format: impl FnOnce(&TracepointFormat) -> Result<FormatParser, eyre::Error>,
+) -> Result<FormatParser, eyre::Error> {
+    let format = TracepointFormat::parse(format_str)?;
     parser_from_format(&format)
 }
 
+/// A [`FormatParser`] for a tracepoint in a sysroot, created on first use.
+///
+/// A trace lists every event that was enabled, but often only a few of them
+/// were hit (`perf sched record` enables over a dozen). This avoids reading
+/// format files for the rest, which matters when the sysroot is on a slow
+/// (network) filesystem.
+#[derive(Debug)]
+pub struct LazyParser {
+    event_name: &'static str,
+    sysroot: PathBuf,
+    /// Size of `long` on the traced system, to check the format against.
+    long_size: Option<u32>,
+    create: fn(&Path, Option<u32>) -> Result<FormatParser, eyre::Error>,
+    parser: OnceCell<FormatParser>,
+}
+
+impl LazyParser {
+    pub fn new<T: Tracepoint>(sysroot: &Path) -> Self {
+        Self {
+            event_name: T::EVENT_NAME,
+            sysroot: sysroot.to_owned(),
+            long_size: None,
+            create: |sysroot, long_size| {
+                make_parser_from_sysroot(T::EVENT_NAME, sysroot, |format| {
+                    log::debug!(
+                        "Loaded tracepoint format {}",
+                        TracepointSummary::from(format)
+                    );
+                    if let Some(long_size) = long_size {
+                        check_long_size(T::EVENT_NAME, format, long_size);
+                    }
+                    check_schema(T::EVENT_NAME, T::validate_format(format, long_size))?;
+                    T::parser_from_format(format)
+                })
+            },
+            parser: OnceCell::new(),
+        }
+    }
+
+    /// Check the format against the size of `long` (and pointers) on the
+    /// traced system when it is loaded.
+    pub fn with_long_size(mut self, long_size: Option<u32>) -> Self {
+        self.long_size = long_size;
+        self
+    }
+
+    /// Name of the tracepoint, such as `sched:sched_switch`.
+    pub fn event_name(&self) -> &'static str {
+        self.event_name
+    }
+
+    /// The parser, if it has been loaded.
+    pub fn loaded(&self) -> Option<&FormatParser> {
+        self.parser.get()
+    }
+
+    /// Get the parser, loading the format file if this is the first use.
+    pub fn get(&self) -> Result<&FormatParser, eyre::Error> {
+        if let Some(parser) = self.parser.get() {
+            return Ok(parser);
+        }
+        let start = Instant::now();
+        let parser = (self.create)(&self.sysroot, self.long_size)
+            .wrap_err_with(|| format!("Failed to load the format of {}", self.event_name))?;
+        log::debug!(
+            "Loaded the format of {} in {:?}",
+            self.event_name,
+            start.elapsed()
+        );
+        Ok(self.parser.get_or_init(|| parser))
+    }
+}
+
 #[doc(hidden)]
 #[macro_export]
 macro_rules! struct_def {
@@ -289,7 +797,18 @@ macro_rules! common_members {
         }
 
         fn parser_from_file(path: &std::path::Path) -> Result<$crate::tracepoints::parser::FormatParser, eyre::Error> {
-            $crate::tracepoints::parser::make_parser_from_file(path, Self::parser_from_format)
+            $crate::tracepoints::parser::make_parser_from_file(
+                Self::EVENT_NAME,
+                path,
+                Self::parser_from_format,
+            )
+        }
+
+        fn parser_from_string(format_str: &str) -> Result<$crate::tracepoints::parser::FormatParser, eyre::Error> {
+            $crate::tracepoints::parser::make_parser_from_string(
+                format_str,
+                Self::parser_from_format,
+            )
         }
 
         fn parser_from_sysroot<P: AsRef<std::path::Path>>(path: P) -> Result<$crate::tracepoints::parser::FormatParser, eyre::Error> {
@@ -306,7 +825,23 @@ macro_rules! common_members {
                     stringify!($field),
                 )*
             ];
-            $crate::tracepoints::parser::FormatParser::new(&format.fields, NAMES)
+            $crate::tracepoints::parser::FormatParser::new(format, NAMES)
+        }
+
+        fn validate_format(
+            format: &$crate::tracepoints::format::TracepointFormat,
+            long_size: Option<u32>,
+        ) -> Result<(), Vec<$crate::tracepoints::parser::SchemaError>> {
+            use $crate::tracepoints::parser::FieldType;
+            static FIELDS: &[$crate::tracepoints::parser::FieldSpec] = &[
+                $(
+                    $crate::tracepoints::parser::FieldSpec {
+                        name: stringify!($field),
+                        kind: <$type as FieldType>::KIND,
+                    },
+                )*
+            ];
+            $crate::tracepoints::parser::validate_fields(&format.fields, FIELDS, long_size)
         }
     };
 }
@@ -351,3 +886,440 @@ macro_rules! tracepoint_parser {
 
 #[doc(inline)]
 pub use tracepoint_parser;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::LittleEndian;
+
+    #[test]
+    fn test_parse_each_type_by_name() {
+        let mut offset = 0;
+        let specs = [
+            ("i8", 1, true),
+            ("u8", 1, false),
+            ("i16", 2, true),
+            ("u16", 2, false),
+            ("i32", 4, true),
+            ("u32", 4, false),
+            ("i64", 8, true),
+            ("u64", 8, false),
+        ]
+        .map(|(name, size, signed)| {
+            let spec = ParseSpec {
+                name: name.into(),
+                offset,
+                size,
+                signed,
+                array_type: TracepointArrayType::None,
+            };
+            offset += size;
+            spec
+        });
+        let parser = FormatParser::from_specs(specs.to_vec());
+        // All bits set in every field.
+        let data = [0xffu8; 30];
+        let record = RawData::Single(&data);
+        assert_eq!(parser.parse_i8_by_name("i8", &record).unwrap(), -1);
+        assert_eq!(parser.parse_u8_by_name("u8", &record).unwrap(), u8::MAX);
+        assert_eq!(
+            parser
+                .parse_i16_by_name::<LittleEndian>("i16", &record)
+                .unwrap(),
+            -1
+        );
+        assert_eq!(
+            parser
+                .parse_u16_by_name::<LittleEndian>("u16", &record)
+                .unwrap(),
+            u16::MAX
+        );
+        assert_eq!(
+            parser
+                .parse_i32_by_name::<LittleEndian>("i32", &record)
+                .unwrap(),
+            -1
+        );
+        assert_eq!(
+            parser
+                .parse_u32_by_name::<LittleEndian>("u32", &record)
+                .unwrap(),
+            u32::MAX
+        );
+        assert_eq!(
+            parser
+                .parse_i64_by_name::<LittleEndian>("i64", &record)
+                .unwrap(),
+            -1
+        );
+        assert_eq!(
+            parser
+                .parse_u64_by_name::<LittleEndian>("u64", &record)
+                .unwrap(),
+            u64::MAX
+        );
+        assert!(parser.parse_u8_by_name("missing", &record).is_err());
+    }
+
+    #[test]
+    fn test_parse_by_name() {
+        let input = indoc::indoc! {"
+        name: softirq_entry
+        ID: 10
+        format:
+        \tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;
+        \tfield:int vec;\toffset:4;\tsize:4;\tsigned:1;
+        \tfield:int other;\toffset:8;\tsize:4;\tsigned:1;
+
+        print fmt: \"vec=%u\", REC->vec
+        "};
+        let format = TracepointFormat::parse(input).unwrap();
+        // Deliberately request the fields in a different order than the format.
+        let parser = FormatParser::new(&format, &["other", "vec"]).unwrap();
+        let data = [0u8, 0, 0, 0, 7, 0, 0, 0, 0xfe, 0xff, 0xff, 0xff];
+        let record = RawData::Single(&data);
+        assert_eq!(
+            parser
+                .parse_i32_by_name::<LittleEndian>("vec", &record)
+                .unwrap(),
+            7
+        );
+        assert_eq!(
+            parser
+                .parse_i32_by_name::<LittleEndian>("other", &record)
+                .unwrap(),
+            -2
+        );
+        assert_eq!(parser.index_of("vec").unwrap(), 1);
+        assert!(
+            parser
+                .parse_i32_by_name::<LittleEndian>("missing", &record)
+                .is_err()
+        );
+
+        let names = [CompactString::from("vec")];
+        let parser =
+            FormatParser::new_from_iter(&format, names.iter().map(CompactString::as_str)).unwrap();
+        assert_eq!(parser.index_of("vec").unwrap(), 0);
+        let err = FormatParser::new_from_iter(&format, ["nope"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Field 'nope' not found in format 'softirq_entry' (available: common_type, vec, other)"
+        );
+
+        let parser = FormatParser::new_all(&format);
+        assert_eq!(parser.index_of("common_type").unwrap(), 0);
+        assert_eq!(
+            parser
+                .parse_i32_by_name::<LittleEndian>("other", &record)
+                .unwrap(),
+            -2
+        );
+
+        let indices = parser.indices_for_names(&["other", "vec"]).unwrap();
+        assert_eq!(indices, [2, 1]);
+        assert!(parser.indices_for_names(&["vec", "missing"]).is_err());
+        let subset = parser.subset(&indices[..1]);
+        assert_eq!(subset.num_fields(), 1);
+        assert_eq!(
+            subset.iter_ops().collect::<Vec<_>>(),
+            [(
+                "other",
+                ParseOpSummary {
+                    offset: 8,
+                    size: 4,
+                    signed: true,
+                    array_type: TracepointArrayType::None,
+                }
+            )]
+        );
+        assert_eq!(subset.parse_i32::<LittleEndian>(0, &record).unwrap(), -2);
+        assert!(subset.index_of("vec").is_err());
+
+        let parser = FormatParser::from_specs(vec![ParseSpec {
+            name: "other".into(),
+            offset: 8,
+            size: 4,
+            signed: true,
+            array_type: TracepointArrayType::None,
+        }]);
+        assert_eq!(
+            parser
+                .parse_i32_by_name::<LittleEndian>("other", &record)
+                .unwrap(),
+            -2
+        );
+        assert_eq!(
+            ParseSpec::from(&format.fields[1]),
+            ParseSpec {
+                name: "vec".into(),
+                offset: 4,
+                size: 4,
+                signed: true,
+                array_type: TracepointArrayType::None,
+            }
+        );
+
+        let truncated = RawData::Single(&data[..10]);
+        let err = parser
+            .parse_i32_by_name::<LittleEndian>("other", &truncated)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Not enough data reading field 'other' at offset 8 in 10-byte record"
+        );
+    }
+
+    #[test]
+    fn test_parse_compact_string() {
+        let format = TracepointFormat::parse(indoc::indoc! {"
+        name: task_rename
+        ID: 20
+        format:
+        \tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;
+        \tfield:char comm[8];\toffset:2;\tsize:8;\tsigned:0;
+
+        print fmt: \"comm=%s\", REC->comm
+        "})
+        .unwrap();
+        let parser = FormatParser::new(&format, &["comm"]).unwrap();
+        let parse = |comm: &[u8]| {
+            let mut data = vec![0u8; 10];
+            data[2..2 + comm.len()].copy_from_slice(comm);
+            parser
+                .parse_compact_string::<LittleEndian>(0, &RawData::Single(&data))
+                .unwrap()
+        };
+        assert_eq!(parse(b"bash"), "bash");
+        assert_eq!(parse(b"12345678"), "12345678");
+        assert_eq!(parse("caf\u{e9}".as_bytes()), "caf\u{e9}");
+        assert_eq!(parse(b"a\xffb"), "<61ff62>");
+        assert_eq!(format_comm_name(b"\xde\xad\xbe\xef"), "<deadbeef>");
+    }
+
+    #[test]
+    fn test_validate_format() {
+        use crate::tracepoints::irq::IrqHandlerEntry;
+        let format = |irq: &str, name: &str| {
+            TracepointFormat::parse(&format!(
+                "name: irq_handler_entry\nID: 10\nformat:\n\t{irq}\n\t{name}\n\nprint fmt: \"\"\n"
+            ))
+            .unwrap()
+        };
+        let name = "field:__data_loc char[] name;\toffset:12;\tsize:4;\tsigned:0;";
+        assert_eq!(
+            IrqHandlerEntry::validate_format(
+                &format("field:int irq;\toffset:8;\tsize:4;\tsigned:1;", name),
+                None
+            ),
+            Ok(())
+        );
+
+        let errors = IrqHandlerEntry::validate_format(
+            &format(
+                "field:unsigned int irq;\toffset:8;\tsize:4;\tsigned:0;",
+                name,
+            ),
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(!errors[0].is_fatal());
+        assert_eq!(errors[0].to_string(), "Field irq is u32, expected i32");
+        let errors = IrqHandlerEntry::validate_format(
+            &format("field:char irq[4];\toffset:8;\tsize:4;\tsigned:0;", name),
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "Field irq is string, expected i32 (parse it as &[u8])"
+        );
+
+        let errors = IrqHandlerEntry::validate_format(
+            &format(
+                "field:long irq;\toffset:8;\tsize:8;\tsigned:1;",
+                "field:int other;\toffset:16;\tsize:4;\tsigned:1;",
+            ),
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                SchemaError::Mismatch {
+                    field: "irq",
+                    expected: FieldKind::Integer {
+                        size: 4,
+                        signed: true
+                    },
+                    actual: FieldKind::Integer {
+                        size: 8,
+                        signed: true
+                    },
+                    rust_type: "i64",
+                },
+                SchemaError::Missing { field: "name" },
+            ]
+        );
+        assert!(errors.iter().all(SchemaError::is_fatal));
+        assert!(check_schema("irq:irq_handler_entry", Err(errors)).is_err());
+    }
+
+    #[test]
+    fn test_lazy_parser() {
+        let sysroot = std::env::temp_dir().join(format!("lazy-parser-{}", std::process::id()));
+        let parser = LazyParser::new::<crate::tracepoints::irq::SoftirqEntry>(&sysroot);
+        // Nothing is read until the parser is used.
+        assert!(parser.get().is_err());
+
+        let dir = sysroot.join("sys/kernel/tracing/events/irq/softirq_entry");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("format"),
+            indoc::indoc! {"
+            name: softirq_entry
+            ID: 10
+            format:
+            \tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;
+            \tfield:unsigned int vec;\toffset:8;\tsize:4;\tsigned:0;
+
+            print fmt: \"vec=%u\", REC->vec
+            "},
+        )
+        .unwrap();
+        assert_eq!(parser.get().unwrap().index_of("vec").unwrap(), 0);
+        std::fs::remove_dir_all(sysroot).unwrap();
+    }
+
+    #[test]
+    fn test_narrow_long() {
+        use crate::tracepoints::Tracepoint;
+        use crate::tracepoints::sched::SchedSwitch;
+        let sysroot = std::env::temp_dir().join(format!("narrow-long-{}", std::process::id()));
+        let dir = sysroot.join("sys/kernel/tracing/events/sched/sched_switch");
+        std::fs::create_dir_all(&dir).unwrap();
+        // From 32-bit ARM, where `long` is 4 bytes.
+        std::fs::write(
+            dir.join("format"),
+            indoc::indoc! {"
+            name: sched_switch
+            ID: 316
+            format:
+            \tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;
+            \tfield:unsigned char common_flags;\toffset:2;\tsize:1;\tsigned:0;
+            \tfield:unsigned char common_preempt_count;\toffset:3;\tsize:1;\tsigned:0;
+            \tfield:int common_pid;\toffset:4;\tsize:4;\tsigned:1;
+
+            \tfield:char prev_comm[16];\toffset:8;\tsize:16;\tsigned:0;
+            \tfield:pid_t prev_pid;\toffset:24;\tsize:4;\tsigned:1;
+            \tfield:int prev_prio;\toffset:28;\tsize:4;\tsigned:1;
+            \tfield:long prev_state;\toffset:32;\tsize:4;\tsigned:1;
+            \tfield:char next_comm[16];\toffset:36;\tsize:16;\tsigned:0;
+            \tfield:pid_t next_pid;\toffset:52;\tsize:4;\tsigned:1;
+            \tfield:int next_prio;\toffset:56;\tsize:4;\tsigned:1;
+
+            print fmt: \"prev_comm=%s\", REC->prev_comm
+            "},
+        )
+        .unwrap();
+        let mut data = [0u8; 60];
+        data[8..12].copy_from_slice(b"prev");
+        data[24..28].copy_from_slice(&10i32.to_le_bytes());
+        data[32..36].copy_from_slice(&(-2i32).to_le_bytes());
+        data[36..40].copy_from_slice(b"next");
+        data[52..56].copy_from_slice(&20i32.to_le_bytes());
+        data[56..60].copy_from_slice(&120i32.to_le_bytes());
+        let record = RawData::Single(&data);
+
+        // As with `--arch arm`.
+        let parser = LazyParser::new::<SchedSwitch>(&sysroot).with_long_size(Some(4));
+        let switch = SchedSwitch::parse::<LittleEndian>(parser.get().unwrap(), &record).unwrap();
+        assert_eq!(switch.prev_comm, "prev");
+        assert_eq!(switch.prev_pid, 10);
+        assert_eq!(switch.prev_state, -2);
+        assert_eq!(switch.next_comm, "next");
+        assert_eq!(switch.next_pid, 20);
+        assert_eq!(switch.next_prio, 120);
+
+        // Not knowing the size of `long` goes by the format.
+        let parser = LazyParser::new::<SchedSwitch>(&sysroot);
+        assert!(parser.get().is_ok());
+        // A format from a 32-bit system can't be used for a 64-bit trace.
+        let parser = LazyParser::new::<SchedSwitch>(&sysroot).with_long_size(Some(8));
+        assert!(parser.get().is_err());
+        std::fs::remove_dir_all(sysroot).unwrap();
+    }
+
+    #[test]
+    fn test_parser_from_sysroot() {
+        use crate::tracepoints::Tracepoint;
+        use crate::tracepoints::irq::SoftirqEntry;
+        let sysroot = std::env::temp_dir().join(format!("sysroot-{}", std::process::id()));
+        let err = SoftirqEntry::parser_from_sysroot(&sysroot).unwrap_err();
+        assert!(
+            format!("{err:#}").contains(&sysroot.display().to_string()),
+            "{err:#}"
+        );
+
+        let dir = sysroot.join("sys/kernel/tracing/events/irq/softirq_entry");
+        std::fs::create_dir_all(&dir).unwrap();
+        // An offset no real kernel uses, to tell this format apart from the
+        // one of the running system.
+        std::fs::write(
+            dir.join("format"),
+            indoc::indoc! {"
+            name: softirq_entry
+            ID: 10
+            format:
+            \tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;
+            \tfield:unsigned int vec;\toffset:12;\tsize:4;\tsigned:0;
+
+            print fmt: \"vec=%u\", REC->vec
+            "},
+        )
+        .unwrap();
+        let parser = SoftirqEntry::parser_from_sysroot(&sysroot).unwrap();
+        assert_eq!(parser.iter_ops().next().unwrap().1.offset, 12);
+        std::fs::remove_dir_all(sysroot).unwrap();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Tracepoint format mismatch")]
+    fn test_parse_signed_from_unsigned() {
+        let format = TracepointFormat::parse(indoc::indoc! {"
+        name: softirq_entry
+        ID: 10
+        format:
+        \tfield:unsigned int vec;\toffset:0;\tsize:4;\tsigned:0;
+
+        print fmt: \"vec=%u\", REC->vec
+        "})
+        .unwrap();
+        let parser = FormatParser::new(&format, &["vec"]).unwrap();
+        let data = [3u8, 0, 0, 0];
+        let _ = parser.parse_i32::<LittleEndian>(0, &RawData::Single(&data));
+    }
+
+    #[test]
+    fn test_parser_from_string() {
+        use crate::tracepoints::Tracepoint;
+        use crate::tracepoints::irq::SoftirqEntry;
+        let parser = SoftirqEntry::parser_from_string(indoc::indoc! {"
+            name: softirq_entry
+            ID: 10
+            format:
+            \tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;
+            \tfield:unsigned int vec;\toffset:8;\tsize:4;\tsigned:0;
+
+            print fmt: \"vec=%u\", REC->vec
+        "})
+        .unwrap();
+        let data = [0u8, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0];
+        let entry = SoftirqEntry::parse::<LittleEndian>(&parser, &RawData::Single(&data)).unwrap();
+        assert_eq!(entry.vec, 3);
+
+        assert!(SoftirqEntry::parser_from_string("name: softirq_entry\nformat:\n").is_err());
+    }
+}
@@ -0,0 +1,11 @@
+//! Tracepoints for power management events.
+
+use super::parser::tracepoint_parser;
+
+tracepoint_parser!(
+    #[event_name("power:cpu_frequency")]
+    pub struct CpuFrequency {
+        state: u32,
+        cpu_id: u32,
+    }
+);
@@ -0,0 +1,80 @@
+//! Resolution of constant strings referenced by tracepoints.
+//!
+//! Some tracepoints (such as the RCU ones) only record a pointer to a string
+//! in the kernel. The kernel exports these strings together with their
+//! addresses in `/sys/kernel/tracing/printk_formats`.
+
+use compact_str::CompactString;
+use eyre::Context;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Map from kernel address to constant string.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PrintkFormats {
+    strings: HashMap<u64, CompactString>,
+}
+
+impl PrintkFormats {
+    /// Parse the contents of a `printk_formats` file.
+    ///
+    /// Lines have the form `0xffffffff82a1b2c3 : "Start scheduler-tick"`.
+    /// Lines that don't match are skipped.
+    pub fn parse(contents: &str) -> Self {
+        let strings = contents
+            .lines()
+            .filter_map(|line| {
+                let (addr, string) = line.split_once(" : ")?;
+                let addr = u64::from_str_radix(addr.trim().strip_prefix("0x")?, 16).ok()?;
+                let string = string.trim();
+                let string = string
+                    .strip_prefix('"')
+                    .and_then(|s| s.strip_suffix('"'))
+                    .unwrap_or(string);
+                Some((addr, CompactString::from(string)))
+            })
+            .collect();
+        Self { strings }
+    }
+
+    /// Read `<sysroot>/sys/kernel/tracing/printk_formats`.
+    pub fn from_sysroot(sysroot: &Path) -> Result<Self, eyre::Error> {
+        let path = sysroot.join("sys/kernel/tracing/printk_formats");
+        let contents = std::fs::read_to_string(&path)
+            .wrap_err_with(|| format!("Failed to read \"{}\"", path.display()))?;
+        Ok(Self::parse(&contents))
+    }
+
+    /// Look up the string at a kernel address.
+    pub fn resolve(&self, addr: u64) -> Option<&CompactString> {
+        self.strings.get(&addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let formats = PrintkFormats::parse(indoc::indoc! {r#"
+            0xffffffff82f0a1c8 : "Start scheduler-tick"
+            0xffffffff82f0a1e0 : "End scheduler-tick"
+            garbage
+            0xffffffff82f0a200 : "%s: %d\n"
+        "#});
+        assert_eq!(
+            formats
+                .resolve(0xffffffff82f0a1c8)
+                .map(CompactString::as_str),
+            Some("Start scheduler-tick")
+        );
+        assert_eq!(
+            formats
+                .resolve(0xffffffff82f0a200)
+                .map(CompactString::as_str),
+            Some(r"%s: %d\n")
+        );
+        assert_eq!(formats.resolve(0), None);
+    }
+}
@@ -0,0 +1,21 @@
+//! Tracepoints for RCU events.
+//!
+//! The strings in these are `const char *` pointers into the kernel, which
+//! can be resolved using [`super::printk_formats::PrintkFormats`].
+
+use super::parser::tracepoint_parser;
+
+tracepoint_parser!(
+    #[event_name("rcu:rcu_utilization")]
+    pub struct RcuUtilization {
+        s: u64,
+    }
+);
+
+tracepoint_parser!(
+    #[event_name("rcu:rcu_stall_warning")]
+    pub struct RcuStallWarning {
+        rcuname: u64,
+        msg: u64,
+    }
+);
@@ -25,3 +25,97 @@ tracepoint_parser!(
         dest_cpu: i32,
     }
 );
+
+tracepoint_parser!(
+    #[event_name("sched:sched_process_exec")]
+    pub struct SchedProcessExec {
+        filename: CompactString,
+        pid: i32,
+        old_pid: i32,
+    }
+);
+
+tracepoint_parser!(
+    #[event_name("sched:sched_process_exit")]
+    pub struct SchedProcessExit {
+        comm: CompactString,
+        pid: i32,
+    }
+);
+
+// `pid` is the child waited for, or 0 or less for any child. The waiting
+// thread is the one the event is recorded for.
+tracepoint_parser!(
+    #[event_name("sched:sched_process_wait")]
+    pub struct SchedProcessWait {
+        comm: CompactString,
+        common_pid: i32,
+        pid: i32,
+    }
+);
+
+tracepoint_parser!(
+    #[event_name("sched:sched_waking")]
+    pub struct SchedWaking {
+        comm: CompactString,
+        pid: i32,
+        target_cpu: i32,
+    }
+);
+
+tracepoint_parser!(
+    #[event_name("sched:sched_wakeup")]
+    pub struct SchedWakeup {
+        comm: CompactString,
+        pid: i32,
+        target_cpu: i32,
+    }
+);
+
+tracepoint_parser!(
+    #[event_name("sched:sched_pi_setprio")]
+    pub struct SchedPiSetprio {
+        comm: CompactString,
+        pid: i32,
+        oldprio: i32,
+        newprio: i32,
+    }
+);
+
+tracepoint_parser!(
+    #[event_name("sched:sched_move_numa")]
+    pub struct SchedMoveNuma {
+        pid: i32,
+        src_cpu: i32,
+        src_nid: i32,
+        dst_cpu: i32,
+        dst_nid: i32,
+    }
+);
+
+tracepoint_parser!(
+    #[event_name("sched:sched_stat_runtime")]
+    pub struct SchedStatRuntime {
+        comm: CompactString,
+        pid: i32,
+        runtime: u64,
+    }
+);
+
+tracepoint_parser!(
+    #[event_name("sched:sched_stat_sleep")]
+    pub struct SchedStatSleep {
+        comm: CompactString,
+        pid: i32,
+        delay: u64,
+    }
+);
+
+tracepoint_parser!(
+    #[event_name("sched:sched_stat_iowait")]
+    pub struct SchedStatIOWait {
+        comm: CompactString,
+        pid: i32,
+        delay: u64,
+    }
+);
@@ -9,4 +9,154 @@ pub enum CpuState {
     Tasklet,
     Kernel,
     User,
+    RcuStall,
+    Busy,
+    Migrating,
+    NumaMove,
+}
+
+impl CpuState {
+    /// All states, in order of their value.
+    pub const ALL: [Self; 10] = [
+        Self::Idle,
+        Self::Irq,
+        Self::Softirq,
+        Self::Tasklet,
+        Self::Kernel,
+        Self::User,
+        Self::RcuStall,
+        Self::Busy,
+        Self::Migrating,
+        Self::NumaMove,
+    ];
+
+    /// Name of the state, as shown in the statemap.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Idle => "Idle",
+            Self::Irq => "Irq",
+            Self::Softirq => "Softirq",
+            Self::Tasklet => "Tasklet",
+            Self::Kernel => "Kernel",
+            Self::User => "User",
+            Self::RcuStall => "RcuStall",
+            Self::Busy => "Busy",
+            Self::Migrating => "Migrating",
+            Self::NumaMove => "NumaMove",
+        }
+    }
+
+    /// Look up a state by its [`name`](Self::name).
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|state| state.name() == name)
+    }
+}
+
+/// State of a thread when it was switched out, from the `prev_state`
+/// bitmask of `sched_switch`. This is the state of the thread, not of the
+/// CPU: callers decide what the CPU does meanwhile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    /// Runnable (R), the thread was preempted.
+    Running,
+    /// Interruptible sleep (S), waiting for an event.
+    Sleeping,
+    /// Uninterruptible sleep (D), usually waiting for IO.
+    DiskSleep,
+    /// Stopped by a signal (T).
+    Stopped,
+    /// Stopped by a debugger (t).
+    Traced,
+    /// Dead (X).
+    Dead,
+    /// Exited, but not yet reaped by its parent (Z).
+    Zombie,
+    /// A parked kernel thread (P).
+    Parked,
+}
+
+impl ThreadState {
+    /// Decode the `prev_state` bitmask of `sched_switch`.
+    ///
+    /// Like the kernel's `task_state_to_char`, the highest task state bit
+    /// wins, so uninterruptible sleep (D) beats interruptible sleep (S).
+    /// Bits above the task state bits (the "preempted" marker, whose value
+    /// varies with the kernel version) are ignored, a preempted thread is
+    /// still runnable.
+    pub fn from_prev_state_bits(bits: i64) -> Self {
+        /// `TASK_REPORT`: S, D, T, t, X, Z and P.
+        const TASK_REPORT: i64 = 0x7f;
+        match bits & TASK_REPORT {
+            0 => Self::Running,
+            state => match 63 - state.leading_zeros() {
+                0 => Self::Sleeping,
+                1 => Self::DiskSleep,
+                2 => Self::Stopped,
+                3 => Self::Traced,
+                4 => Self::Dead,
+                5 => Self::Zombie,
+                _ => Self::Parked,
+            },
+        }
+    }
+
+    /// Whether the thread is still runnable, i.e. on the runqueue.
+    pub fn is_runnable(self) -> bool {
+        self == Self::Running
+    }
+
+    /// Whether the thread is sleeping, interruptibly or not.
+    pub fn is_sleeping(self) -> bool {
+        matches!(self, Self::Sleeping | Self::DiskSleep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_prev_state_bits() {
+        let state = ThreadState::from_prev_state_bits;
+        assert_eq!(state(0), ThreadState::Running);
+        for (bit, expected) in [
+            (0x01, ThreadState::Sleeping),
+            (0x02, ThreadState::DiskSleep),
+            (0x04, ThreadState::Stopped),
+            (0x08, ThreadState::Traced),
+            (0x10, ThreadState::Dead),
+            (0x20, ThreadState::Zombie),
+            (0x40, ThreadState::Parked),
+        ] {
+            assert_eq!(state(bit), expected);
+            // Any higher state beats the lower ones.
+            assert_eq!(state(bit | (bit - 1)), expected);
+        }
+        assert_eq!(state(0x03), ThreadState::DiskSleep);
+        assert_eq!(state(0x7f), ThreadState::Parked);
+        // The preempted marker on various kernel versions.
+        for preempted in [0x80, 0x100, 0x400, 0x800, 0x1000] {
+            assert_eq!(state(preempted), ThreadState::Running);
+            assert_eq!(state(preempted | 0x01), ThreadState::Sleeping);
+            assert_eq!(state(preempted | 0x02), ThreadState::DiskSleep);
+        }
+    }
+
+    #[test]
+    fn test_thread_state_predicates() {
+        assert!(ThreadState::Running.is_runnable());
+        assert!(!ThreadState::Running.is_sleeping());
+        assert!(ThreadState::Sleeping.is_sleeping());
+        assert!(ThreadState::DiskSleep.is_sleeping());
+        for state in [
+            ThreadState::Stopped,
+            ThreadState::Traced,
+            ThreadState::Dead,
+            ThreadState::Zombie,
+            ThreadState::Parked,
+        ] {
+            assert!(!state.is_runnable());
+            assert!(!state.is_sleeping());
+        }
+    }
 }
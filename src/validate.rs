@@ -0,0 +1,224 @@
+//! Checking that a statemap file is well formed, for regression testing
+//! the conversion.
+
+use eyre::Context;
+use serde_json::Map;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// A problem found in a statemap file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// Line number (1-based) of the record with the problem.
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// What the header defines, that the datums are checked against.
+#[derive(Debug, Default)]
+struct Header {
+    /// Values of the states.
+    states: BTreeSet<u64>,
+    /// Entity names, if the header lists them.
+    entities: Option<BTreeSet<String>>,
+}
+
+/// Check the statemap (as JSON Lines) read from `reader`, returning all
+/// violations found. Lifecycle events (of the v2 format) and the record
+/// count line are skipped.
+pub fn validate(reader: impl BufRead) -> Result<Vec<Violation>, eyre::Error> {
+    let mut violations = Vec::new();
+    let mut header = None;
+    // Time of the last datum of each entity.
+    let mut last_times: HashMap<String, u64> = HashMap::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line_no = index + 1;
+        let line = line.wrap_err_with(|| format!("Failed to read line {line_no}"))?;
+        let mut violation = |message: String| {
+            violations.push(Violation {
+                line: line_no,
+                message,
+            });
+        };
+        let record = match serde_json::from_str::<Value>(&line) {
+            Ok(Value::Object(record)) => record,
+            Ok(_) => {
+                violation("not a JSON object".to_owned());
+                continue;
+            }
+            Err(err) => {
+                violation(format!("invalid JSON: {err}"));
+                continue;
+            }
+        };
+        let Some(header) = &header else {
+            header = Some(check_header(&record, &mut violation));
+            continue;
+        };
+        if record.contains_key("record_count") || record.contains_key("event") {
+            continue;
+        }
+        check_datum(&record, header, &mut last_times, &mut violation);
+    }
+    if header.is_none() {
+        violations.push(Violation {
+            line: 1,
+            message: "missing header".to_owned(),
+        });
+    }
+    Ok(violations)
+}
+
+fn check_header(record: &Map<String, Value>, violation: &mut impl FnMut(String)) -> Header {
+    let mut header = Header::default();
+    for field in ["start", "title", "states"] {
+        if !record.contains_key(field) {
+            violation(format!("header is missing \"{field}\""));
+        }
+    }
+    if let Some(start) = record.get("start")
+        && serde_json::from_value::<[u64; 2]>(start.clone()).is_err()
+    {
+        violation("header \"start\" is not [seconds, nanoseconds]".to_owned());
+    }
+    if let Some(title) = record.get("title")
+        && !title.is_string()
+    {
+        violation("header \"title\" is not a string".to_owned());
+    }
+    match record.get("states") {
+        Some(Value::Object(states)) => {
+            for (name, state) in states {
+                match state.get("value").and_then(Value::as_u64) {
+                    Some(value) => {
+                        if !header.states.insert(value) {
+                            violation(format!("state \"{name}\" has a duplicate value {value}"));
+                        }
+                    }
+                    None => violation(format!("state \"{name}\" has no integer value")),
+                }
+            }
+        }
+        Some(_) => violation("header \"states\" is not an object".to_owned()),
+        None => {}
+    }
+    match record.get("entities") {
+        Some(Value::Object(entities)) => {
+            header.entities = Some(entities.keys().cloned().collect());
+        }
+        Some(_) => violation("header \"entities\" is not an object".to_owned()),
+        None => {}
+    }
+    header
+}
+
+fn check_datum(
+    record: &Map<String, Value>,
+    header: &Header,
+    last_times: &mut HashMap<String, u64>,
+    violation: &mut impl FnMut(String),
+) {
+    // The time is written as a string, to not lose precision in JavaScript.
+    let time = match record.get("time") {
+        Some(Value::String(time)) => time.parse::<u64>().ok(),
+        Some(time) => time.as_u64(),
+        None => {
+            violation("datum is missing \"time\"".to_owned());
+            None
+        }
+    };
+    if time.is_none() && record.contains_key("time") {
+        violation("datum \"time\" is not an integer".to_owned());
+    }
+    let entity = match record.get("entity") {
+        Some(Value::String(entity)) => Some(entity),
+        Some(_) => {
+            violation("datum \"entity\" is not a string".to_owned());
+            None
+        }
+        None => {
+            violation("datum is missing \"entity\"".to_owned());
+            None
+        }
+    };
+    match record.get("state").map(Value::as_u64) {
+        Some(Some(state)) => {
+            if !header.states.contains(&state) {
+                violation(format!("state {state} is not defined in the header"));
+            }
+        }
+        Some(None) => violation("datum \"state\" is not an integer".to_owned()),
+        None => violation("datum is missing \"state\"".to_owned()),
+    }
+    let Some(entity) = entity else {
+        return;
+    };
+    if let Some(entities) = &header.entities
+        && !entities.contains(entity)
+    {
+        violation(format!("entity \"{entity}\" is not defined in the header"));
+    }
+    if let Some(time) = time
+        && let Some(last) = last_times.insert(entity.clone(), time)
+        && time < last
+    {
+        violation(format!(
+            "time {time} of entity \"{entity}\" is before the previous datum at {last}"
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn violations(input: &str) -> String {
+        validate(input.as_bytes())
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_valid() {
+        let input = indoc::indoc! {r##"
+        {"start":[1,500],"title":"CPU","states":{"Idle":{"value":0,"color":"#e0e0e0"},"User":{"value":1,"color":"#9BC362"}},"schemaVersion":2}
+        {"time":"0","entity":"0","event":"birth"}
+        {"time":"0","entity":"0","state":1,"tag":"bash"}
+        {"time":"10","entity":"1","state":0}
+        {"time":"10","entity":"0","state":0}
+        {"record_count":3                   }
+        "##};
+        assert_eq!(violations(input), "");
+    }
+
+    #[test]
+    fn test_violations() {
+        let input = indoc::indoc! {r##"
+        {"start":[1,500],"states":{"Idle":{"value":0},"User":{"value":1}},"entities":{"0":"0/0","1":"0/1"}}
+        {"time":"20","entity":"0","state":1}
+        {"time":"10","entity":"0","state":0}
+        {"time":"5","entity":"1","state":7}
+        {"time":"x","entity":"2","state":0}
+        {"entity":0}
+        [1, 2]
+        {"time":
+        "##};
+        insta::assert_snapshot!(violations(input));
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(violations(""), "line 1: missing header");
+    }
+}
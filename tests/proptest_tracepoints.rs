@@ -0,0 +1,96 @@
+//! Property based tests of the tracepoint format parsers: valid field
+//! definitions always parse, and no input makes the parsers panic.
+
+use perf_to_statemap::tracepoints::format::TracepointField;
+use perf_to_statemap::tracepoints::format::TracepointFormat;
+use proptest::prelude::*;
+
+/// Types as they appear in format files, without array suffixes.
+const TYPES: &[&str] = &[
+    "int",
+    "unsigned int",
+    "unsigned short",
+    "char",
+    "long",
+    "pid_t",
+    "u64",
+    "const char *",
+    "__data_loc char[]",
+];
+
+/// A valid field definition line, and the field it defines.
+fn valid_field() -> impl Strategy<Value = (String, String, u32, u32, bool)> {
+    (
+        "[a-z_][a-z0-9_]{0,23}",
+        prop::sample::select(TYPES),
+        prop::option::of(1u32..64),
+        any::<u32>(),
+        any::<u32>(),
+        any::<bool>(),
+    )
+        .prop_map(|(name, ty, len, offset, size, signed)| {
+            let array = len.map(|len| format!("[{len}]")).unwrap_or_default();
+            let line = format!(
+                "\tfield:{ty} {name}{array};\toffset:{offset};\tsize:{size};\tsigned:{};",
+                u8::from(signed)
+            );
+            (line, name, offset, size, signed)
+        })
+}
+
+/// A valid field definition damaged in some way: truncated, without
+/// semicolons or with a separator replaced.
+fn damaged_field() -> impl Strategy<Value = String> {
+    (
+        valid_field(),
+        any::<prop::sample::Index>(),
+        0..3,
+        prop::sample::select(&[' ', ':', ';', '\t', '[', ']'][..]),
+    )
+        .prop_map(|((line, ..), idx, damage, replacement)| match damage {
+            0 => line[..idx.index(line.len() + 1)].to_string(),
+            1 => line.replace(';', ""),
+            _ => {
+                let idx = idx.index(line.len());
+                let mut line = line.into_bytes();
+                line[idx] = replacement as u8;
+                String::from_utf8(line).expect("the line is ASCII")
+            }
+        })
+}
+
+proptest! {
+    #[test]
+    fn valid_fields_parse((line, name, offset, size, signed) in valid_field()) {
+        let field = TracepointField::parse(&line).unwrap().unwrap();
+        prop_assert_eq!(field.field_name, name);
+        prop_assert_eq!(field.offset, offset);
+        prop_assert_eq!(field.size, size);
+        prop_assert_eq!(field.signed, signed);
+    }
+
+    #[test]
+    fn damaged_fields_dont_panic(line in damaged_field()) {
+        let _ = TracepointField::parse(&line);
+    }
+
+    #[test]
+    fn arbitrary_fields_dont_panic(bytes in prop::collection::vec(any::<u8>(), 0..100)) {
+        let _ = TracepointField::parse(&String::from_utf8_lossy(&bytes));
+    }
+
+    #[test]
+    fn arbitrary_formats_dont_panic(
+        lines in prop::collection::vec(
+            prop_oneof![
+                damaged_field(),
+                valid_field().prop_map(|(line, ..)| line),
+                "(name|ID|format|print fmt)?:? ?[ -~]{0,20}",
+                any::<String>(),
+            ],
+            0..10,
+        )
+    ) {
+        let _ = TracepointFormat::parse(&lines.join("\n"));
+    }
+}